@@ -1,6 +1,7 @@
 #include <stdio.h>
+#include <sys/stat.h>
 
-#include "../../rftrace-frontend-ffi/rftrace_frontend_ffi.h"
+#include "../../rftrace-frontend-ffi/include/rftrace_frontend_ffi.h"
 
 void func3() {
     printf("Func3!\n");
@@ -18,10 +19,25 @@ void func1() {
 
 void main() {
     printf("Starting tracing...\n");
-    Events *events = rftrace_init(10000, false);
+    RftraceOptions options = rftrace_default_options();
+    options.max_event_count = 10000;
+    Events *events = rftrace_init_with(&options);
     rftrace_enable();
     func1();
     func1();
+
+    // Not recorded
+    rftrace_suppress();
+    func2();
+    rftrace_unsuppress();
+
+    mkdir("snapshotdir", 0755);
+    rftrace_snapshot_uftrace("snapshotdir", "test");
+
     func1();
-    rftrace_dump_full_uftrace(events, "tracedir", "test");
+    rftrace_dump_full_uftrace_with(events, "tracedir", "test", &options);
+
+    Stats stats;
+    rftrace_get_stats(&stats);
+    printf("%lu entries, %lu returns\n", stats.entries, stats.returns);
 }
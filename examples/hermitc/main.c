@@ -1,6 +1,6 @@
 #include <stdio.h>
 
-#include "../../rftrace-frontend-ffi/rftrace_frontend_ffi.h"
+#include "../../rftrace-frontend-ffi/include/rftrace_frontend_ffi.h"
 
 void func3() {
     printf("Func3!\n");
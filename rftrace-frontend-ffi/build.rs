@@ -0,0 +1,94 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "rftrace_frontend_ffi.h";
+
+/// Generates the C header with cbindgen into OUT_DIR, and updates the copy in `include/` if it changed.
+fn generate_header(crate_dir: &Path, out_dir: &Path) {
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("Unable to read cbindgen.toml!");
+
+    // A failing header generation should not break the build, the header in include/ is still usable
+    let bindings = match cbindgen::generate_with_config(crate_dir, config) {
+        Ok(bindings) => bindings,
+        Err(err) => {
+            println!("cargo:warning=Unable to generate C header: {}", err);
+            return;
+        }
+    };
+
+    let header = out_dir.join(HEADER);
+    bindings.write_to_file(&header);
+
+    // Only touch the checked-in header if it actually changed, so packaging does not see a modified source dir
+    let installed = crate_dir.join("include").join(HEADER);
+    let generated = fs::read(&header).unwrap();
+    if fs::read(&installed).ok().as_ref() != Some(&generated) {
+        println!("Updating {}", installed.display());
+        fs::create_dir_all(installed.parent().unwrap()).unwrap();
+        fs::write(&installed, generated).unwrap();
+    }
+}
+
+/// Writes a pkg-config file next to the built library, so C projects can use
+/// `PKG_CONFIG_PATH=<target dir>/<profile> pkg-config --cflags --libs rftrace_frontend_ffi`.
+///
+/// It links the static library, since the shared one is found first otherwise.
+fn generate_pkg_config(crate_dir: &Path, out_dir: &Path) {
+    // OUT_DIR is <target dir>/<profile>/build/<crate>-<hash>/out
+    let lib_dir = match out_dir.ancestors().nth(3) {
+        Some(lib_dir) => lib_dir,
+        None => return,
+    };
+
+    let pc = format!(
+        "libdir={}\n\
+         includedir={}\n\
+         \n\
+         Name: rftrace_frontend_ffi\n\
+         Description: {}\n\
+         Version: {}\n\
+         Libs: -L${{libdir}} -l:librftrace_frontend_ffi.a -lrftrace -lpthread -ldl\n\
+         Cflags: -I${{includedir}}\n",
+        lib_dir.display(),
+        crate_dir.join("include").display(),
+        env::var("CARGO_PKG_DESCRIPTION").unwrap(),
+        env::var("CARGO_PKG_VERSION").unwrap(),
+    );
+    fs::write(lib_dir.join("rftrace_frontend_ffi.pc"), pc).unwrap();
+}
+
+/// Sets the soname of the shared library to its ABI version, such as `librftrace_frontend_ffi.so.0.2`.
+///
+/// Like Cargo, we treat minor versions as incompatible before 1.0 and only major versions after.
+fn set_soname() {
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("linux") {
+        return;
+    }
+
+    let major = env::var("CARGO_PKG_VERSION_MAJOR").unwrap();
+    let abi = if major == "0" {
+        format!("0.{}", env::var("CARGO_PKG_VERSION_MINOR").unwrap())
+    } else {
+        major
+    };
+    println!(
+        "cargo:rustc-cdylib-link-arg=-Wl,-soname,librftrace_frontend_ffi.so.{}",
+        abi
+    );
+}
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    generate_header(&crate_dir, &out_dir);
+    generate_pkg_config(&crate_dir, &out_dir);
+    set_soname();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=../rftrace-frontend/src");
+}
@@ -1,2 +0,0 @@
-#!/bin/sh
-cbindgen > rftrace_frontend_ffi.h
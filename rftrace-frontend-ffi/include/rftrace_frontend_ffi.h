@@ -0,0 +1,365 @@
+#ifndef rftrace_frontend_ffi_h
+#define rftrace_frontend_ffi_h
+
+/* Generated with cbindgen:0.29.4 */
+
+/* Warning, this file is autogenerated by cbindgen. Don't modify this manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Takes the event buffer back from the backend, so it no longer writes to it. Defined by the backend's static library.
+ *
+ * Returns null if there is no buffer, or frontends are still attached to it.
+ */
+const void *rftrace_backend_get_events(void);
+
+/**
+ * Returns the global index of the next event, which is the number of events recorded so far. Defined by the backend's static library.
+ *
+ * The event with global index `i` is stored at `i % len` in the buffer. Stays valid after the buffer was taken back with
+ * `rftrace_backend_get_events()`, returning its final index, so frontends read it afterwards to order a wrapped buffer.
+ */
+uintptr_t rftrace_backend_get_events_index(void);
+
+/**
+ * Maximum payload size of a record event, see `rftrace_frontend::RECORD_PAYLOAD_LEN`.
+ */
+#define RFTRACE_RECORD_PAYLOAD_LEN 14
+
+/**
+ * Kind of an `RftraceEvent`.
+ */
+typedef enum RftraceEventKind {
+  /**
+   * Function entry, `from` is the call site and `to` the called function.
+   */
+  RftraceEventKind_Entry,
+  /**
+   * Function exit, `from` is the function returned from.
+   */
+  RftraceEventKind_Exit,
+  /**
+   * Record of `record_kind`, such as one written with `rftrace_record()`.
+   */
+  RftraceEventKind_Record,
+} RftraceEventKind;
+
+/**
+ * Return stack of a fiber or green thread, see `rftrace_switch_stack()`.
+ */
+typedef struct RftraceFiberStack RftraceFiberStack;
+
+typedef Events Events;
+
+/**
+ * Tracing options for `rftrace_init_with()` and `rftrace_dump_full_uftrace_with()`.
+ *
+ * Get the defaults with `rftrace_default_options()` and change only what is needed, so new fields keep their defaults.
+ */
+typedef struct RftraceOptions {
+  /**
+   * Size of the event buffer, see `rftrace_frontend::init()`.
+   */
+  uintptr_t max_event_count;
+  /**
+   * Use the event buffer as ring buffer, keeping only the most recent events.
+   */
+  bool overwriting;
+  /**
+   * Size of the flight recorder, or 0 for none. See `rftrace_frontend::init_flight_recorder()`.
+   */
+  uintptr_t flight_recorder_events;
+  /**
+   * Sampling period in TSC cycles, or 0 for full tracing. See `rftrace_frontend::set_sampling()`.
+   */
+  uint64_t sample_period;
+  /**
+   * See `DumpOptions::time_shift`.
+   */
+  uint32_t time_shift;
+  /**
+   * See `DumpOptions::max_recursion`, 0 meaning unlimited.
+   */
+  uintptr_t max_recursion;
+  /**
+   * See `DumpOptions::max_output_bytes`, 0 meaning unlimited.
+   */
+  uintptr_t max_output_bytes;
+  /**
+   * Added to all TIDs, see `rftrace_frontend::set_tid_offset()`.
+   */
+  uint64_t tid_offset;
+} RftraceOptions;
+
+/**
+ * An event in C layout, as returned by `rftrace_events_get()`.
+ *
+ * Fields which do not apply to the event's kind are 0.
+ */
+typedef struct RftraceEvent {
+  enum RftraceEventKind kind;
+  /**
+   * Timestamp in TSC cycles.
+   */
+  uint64_t time;
+  /**
+   * Thread ID, or 0 if the thread has none.
+   */
+  uint64_t tid;
+  uintptr_t from;
+  uintptr_t to;
+  uint8_t record_kind;
+  /**
+   * Number of valid bytes in `payload`.
+   */
+  uint8_t len;
+  uint8_t payload[RFTRACE_RECORD_PAYLOAD_LEN];
+} RftraceEvent;
+
+/**
+ * Counters maintained by the backend, for post-mortem diagnosis of a trace.
+ */
+typedef struct Stats {
+  /**
+   * Function entries seen while tracing was enabled.
+   */
+  uint64_t entries;
+  /**
+   * Function exits through the return trampoline.
+   */
+  uint64_t returns;
+  /**
+   * Function entries skipped by a filter.
+   */
+  uint64_t filtered;
+  /**
+   * Function entries whose return could not be hooked, since the return stack was full.
+   */
+  uint64_t retstack_overflows;
+  /**
+   * Calls from within the tracer's hooks, which are neither recorded nor hooked.
+   */
+  uint64_t recursion_hits;
+  /**
+   * Function entries not recorded, since the function exceeded its quota, see `set_function_quota()` in the frontend.
+   */
+  uint64_t demoted;
+  /**
+   * Function entries not recorded, since their thread exceeded its quota, see `set_thread_quota()` in the frontend.
+   */
+  uint64_t quota_exceeded;
+  /**
+   * Function entries only sampled, since tracing exceeded its overhead budget, see `set_overhead_budget()` in the frontend.
+   */
+  uint64_t throttled;
+} Stats;
+
+uint64_t marker(void);
+
+/**
+ * Wraps rftrace_frontend::control, printing its response.
+ *
+ * # Safety
+ * `events` has to be returned by `rftrace_init`, `binary_name` and `command` have to be valid C strings.
+ */
+int64_t rftrace_control(Events *events,
+                        const char *binary_name,
+                        const char *command);
+
+/**
+ * Returns the default options, as used by `rftrace_init()` with a buffer of `rftrace_frontend::DEFAULT_MAX_EVENT_COUNT` events.
+ */
+struct RftraceOptions rftrace_default_options(void);
+
+/**
+ * Wraps rftrace_frontend::disable();
+ */
+void rftrace_disable(void);
+
+/**
+ * Wraps rftrace_frontend::dump_full_uftrace
+ */
+int64_t rftrace_dump_full_uftrace(Events *events, const char *out_dir, const char *binary_name);
+
+/**
+ * Wraps rftrace_frontend::dump_full_uftrace_with, using the dump related fields of `options`.
+ *
+ * # Safety
+ * `events` has to be returned by `rftrace_init`, `out_dir` and `binary_name` have to be valid C strings
+ * and `options` has to point to valid options.
+ */
+int64_t rftrace_dump_full_uftrace_with(Events *events,
+                                       const char *out_dir,
+                                       const char *binary_name,
+                                       const struct RftraceOptions *options);
+
+/**
+ * Wraps rftrace_frontend::dump_trace
+ */
+int64_t rftrace_dump_trace(Events *events, const char *outfile);
+
+/**
+ * Wraps rftrace_frontend::enable()
+ */
+void rftrace_enable(void);
+
+/**
+ * Takes a snapshot of all events recorded so far, oldest first, and returns their number.
+ *
+ * The events can then be read with `rftrace_events_get()`, while tracing continues. The snapshot belongs to the
+ * calling thread and is kept until its next call. Returns 0 if no event buffer is initialized, or it was dumped already.
+ */
+uintptr_t rftrace_events_count(void);
+
+/**
+ * Copies event `i` of the snapshot taken by `rftrace_events_count()` to `out_event`.
+ *
+ * Returns false if `i` is out of range, leaving `out_event` untouched.
+ *
+ * # Safety
+ * `out_event` has to be valid for writes.
+ */
+bool rftrace_events_get(uintptr_t i, struct RftraceEvent *out_event);
+
+/**
+ * Frees a return stack created by `rftrace_fiber_stack_new()`.
+ *
+ * # Safety
+ * `stack` has to be null or created by `rftrace_fiber_stack_new()`, and must not be used afterwards.
+ */
+void rftrace_fiber_stack_free(struct RftraceFiberStack *stack);
+
+/**
+ * Wraps rftrace_frontend::FiberStack::new. Free it with `rftrace_fiber_stack_free()` once the fiber finished.
+ */
+struct RftraceFiberStack *rftrace_fiber_stack_new(uintptr_t capacity);
+
+/**
+ * Wraps rftrace_frontend::free, additionally freeing `events` itself, even if releasing the buffer fails.
+ *
+ * Returns -1 if the buffer could not be released, because it is attached by another frontend or was already dumped.
+ *
+ * # Safety
+ * `events` has to be returned by `rftrace_init` and must not be used afterwards.
+ */
+int64_t rftrace_free(Events *events);
+
+/**
+ * Wraps rftrace_frontend::full_trace. Fully traces the current thread until the matching `rftrace_full_trace_end()`.
+ */
+void rftrace_full_trace_begin(void);
+
+/**
+ * Ends the innermost `rftrace_full_trace_begin()` of the current thread.
+ */
+void rftrace_full_trace_end(void);
+
+/**
+ * Wraps rftrace_frontend::stats
+ *
+ * # Safety
+ * `stats` has to be valid for writes.
+ */
+void rftrace_get_stats(struct Stats *stats);
+
+/**
+ * Wraps rftrace_frontend::try_init();
+ *
+ * Returns null if the backend already records into another buffer, for example one initialized by the kernel.
+ */
+Events *rftrace_init(uintptr_t max_event_count,
+                     bool overwriting);
+
+/**
+ * Like `rftrace_init()`, but additionally sets up the flight recorder, sampling and TID offset as given by `options`.
+ *
+ * # Safety
+ * `options` has to point to valid options.
+ */
+Events *rftrace_init_with(const struct RftraceOptions *options);
+
+/**
+ * Wraps rftrace_frontend::inject_event, with a `tid` of 0 for none.
+ *
+ * Returns false for `RftraceEventKind_Record`, since only entries and exits can be injected.
+ */
+bool rftrace_inject_event(uint64_t tid, uint64_t time, enum RftraceEventKind kind, uintptr_t addr);
+
+/**
+ * Wraps rftrace_frontend::is_enabled();
+ */
+bool rftrace_is_enabled(void);
+
+/**
+ * Wraps rftrace_frontend::record
+ *
+ * # Safety
+ * `payload` has to be valid for reads of `len` bytes.
+ */
+void rftrace_record(uint8_t kind, const uint8_t *payload, uintptr_t len);
+
+/**
+ * Wraps rftrace_frontend::reset. Returns -1 if the buffer could not be taken back or handed to the backend again.
+ *
+ * # Safety
+ * `events` has to be returned by `rftrace_init`.
+ */
+int64_t rftrace_reset(Events *events);
+
+/**
+ * Wraps rftrace_frontend::set_context
+ */
+uint64_t rftrace_set_context(uint64_t context);
+
+/**
+ * Wraps rftrace_frontend::set_sampling
+ */
+void rftrace_set_sampling(uint64_t period);
+
+/**
+ * Writes a full uftrace directory of all events recorded so far, while tracing continues.
+ *
+ * Wraps rftrace_frontend::attach and write_full_uftrace. Returns -1 if no event buffer is initialized or writing failed.
+ *
+ * # Safety
+ * `out_dir` and `binary_name` have to be valid C strings.
+ */
+int64_t rftrace_snapshot_uftrace(const char *out_dir,
+                                 const char *binary_name);
+
+/**
+ * Wraps rftrace_frontend::suppress. Suppresses recording on the current thread until the matching `rftrace_unsuppress()`.
+ */
+void rftrace_suppress(void);
+
+/**
+ * Wraps rftrace_frontend::switch_stack. Call it right before switching from the fiber of `old_stack` to the one of `new_stack`.
+ *
+ * # Safety
+ * `old_stack` and `new_stack` have to be distinct stacks created by `rftrace_fiber_stack_new()`.
+ */
+void rftrace_switch_stack(struct RftraceFiberStack *old_stack,
+                          struct RftraceFiberStack *new_stack);
+
+/**
+ * Wraps rftrace_frontend::thread_exit. Call it from thread destructors or task teardown.
+ */
+void rftrace_thread_exit(void);
+
+/**
+ * Ends the innermost `rftrace_suppress()` of the current thread.
+ */
+void rftrace_unsuppress(void);
+
+/**
+ * Returns the version of this library as NUL-terminated string, such as "0.2.1".
+ *
+ * Useful to check the version of a shared library loaded at runtime.
+ */
+const char *rftrace_version(void);
+
+#endif  /* rftrace_frontend_ffi_h */
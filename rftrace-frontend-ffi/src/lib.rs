@@ -2,10 +2,119 @@
 //! You can find a usage example in the [repository](https://github.com/hermit-os/rftrace/examples/c)
 //! A lot of documentation can be found in the parent workspaces [readme](https://github.com/hermit-os/rftrace).
 
+use std::cell::RefCell;
 use std::ffi::CStr;
+use std::num::NonZeroU64;
 use std::os::raw::c_char;
+use std::{ptr, slice};
+
+use rftrace_frontend::{DumpOptions, Event, EventKind, FiberStack, FullTraceGuard, SuppressGuard};
 
 pub type Events = rftrace_frontend::Events;
+pub type Stats = rftrace_frontend::Stats;
+
+/// Tracing options for `rftrace_init_with()` and `rftrace_dump_full_uftrace_with()`.
+///
+/// Get the defaults with `rftrace_default_options()` and change only what is needed, so new fields keep their defaults.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RftraceOptions {
+    /// Size of the event buffer, see `rftrace_frontend::init()`.
+    pub max_event_count: usize,
+    /// Use the event buffer as ring buffer, keeping only the most recent events.
+    pub overwriting: bool,
+    /// Size of the flight recorder, or 0 for none. See `rftrace_frontend::init_flight_recorder()`.
+    pub flight_recorder_events: usize,
+    /// Sampling period in TSC cycles, or 0 for full tracing. See `rftrace_frontend::set_sampling()`.
+    pub sample_period: u64,
+    /// See `DumpOptions::time_shift`.
+    pub time_shift: u32,
+    /// See `DumpOptions::max_recursion`, 0 meaning unlimited.
+    pub max_recursion: usize,
+    /// See `DumpOptions::max_output_bytes`, 0 meaning unlimited.
+    pub max_output_bytes: usize,
+    /// Added to all TIDs, see `rftrace_frontend::set_tid_offset()`.
+    pub tid_offset: u64,
+}
+
+/// Return stack of a fiber or green thread, see `rftrace_switch_stack()`.
+pub struct RftraceFiberStack(FiberStack);
+
+/// Maximum payload size of a record event, see `rftrace_frontend::RECORD_PAYLOAD_LEN`.
+pub const RFTRACE_RECORD_PAYLOAD_LEN: usize = 14;
+const _: () = assert!(RFTRACE_RECORD_PAYLOAD_LEN == rftrace_frontend::RECORD_PAYLOAD_LEN);
+
+/// Kind of an `RftraceEvent`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RftraceEventKind {
+    /// Function entry, `from` is the call site and `to` the called function.
+    Entry,
+    /// Function exit, `from` is the function returned from.
+    Exit,
+    /// Record of `record_kind`, such as one written with `rftrace_record()`.
+    Record,
+}
+
+/// An event in C layout, as returned by `rftrace_events_get()`.
+///
+/// Fields which do not apply to the event's kind are 0.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RftraceEvent {
+    pub kind: RftraceEventKind,
+    /// Timestamp in TSC cycles.
+    pub time: u64,
+    /// Thread ID, or 0 if the thread has none.
+    pub tid: u64,
+    pub from: usize,
+    pub to: usize,
+    pub record_kind: u8,
+    /// Number of valid bytes in `payload`.
+    pub len: u8,
+    pub payload: [u8; RFTRACE_RECORD_PAYLOAD_LEN],
+}
+
+impl RftraceEvent {
+    fn new(event: &Event) -> Option<Self> {
+        let empty = |kind, time, tid: Option<std::num::NonZeroU64>| RftraceEvent {
+            kind,
+            time,
+            tid: tid.map_or(0, |tid| tid.get()),
+            from: 0,
+            to: 0,
+            record_kind: 0,
+            len: 0,
+            payload: [0; RFTRACE_RECORD_PAYLOAD_LEN],
+        };
+        match event {
+            Event::Entry(e) => Some(RftraceEvent {
+                from: e.from as usize,
+                to: e.to as usize,
+                ..empty(RftraceEventKind::Entry, e.time, e.tid)
+            }),
+            Event::Exit(e) => Some(RftraceEvent {
+                from: e.from as usize,
+                ..empty(RftraceEventKind::Exit, e.time, e.tid)
+            }),
+            Event::Record(r) => Some(RftraceEvent {
+                record_kind: r.kind,
+                len: r.len,
+                payload: r.payload,
+                ..empty(RftraceEventKind::Record, r.time, r.tid)
+            }),
+            Event::Empty => None,
+        }
+    }
+}
+
+thread_local! {
+    // Guards of rftrace_suppress() and rftrace_full_trace_begin(), which C can not hold on to itself
+    static SUPPRESS_GUARDS: RefCell<Vec<SuppressGuard>> = const { RefCell::new(Vec::new()) };
+    static FULL_TRACE_GUARDS: RefCell<Vec<FullTraceGuard>> = const { RefCell::new(Vec::new()) };
+    // Snapshot taken by rftrace_events_count(), which rftrace_events_get() indexes into
+    static EVENTS: RefCell<Vec<RftraceEvent>> = const { RefCell::new(Vec::new()) };
+}
 
 #[no_mangle]
 /// Wraps rftrace_frontend::enable()
@@ -20,9 +129,86 @@ pub unsafe extern "C" fn rftrace_disable() {
 }
 
 #[no_mangle]
-/// Wraps rftrace_frontend::init();
+/// Wraps rftrace_frontend::is_enabled();
+pub extern "C" fn rftrace_is_enabled() -> bool {
+    rftrace_frontend::is_enabled()
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::try_init();
+///
+/// Returns null if the backend already records into another buffer, for example one initialized by the kernel.
 pub unsafe extern "C" fn rftrace_init(max_event_count: usize, overwriting: bool) -> *mut Events {
-    rftrace_frontend::init(max_event_count, overwriting)
+    match rftrace_frontend::try_init(max_event_count, overwriting) {
+        Ok(events) => events,
+        Err(err) => {
+            println!("{}", err);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::free, additionally freeing `events` itself, even if releasing the buffer fails.
+///
+/// Returns -1 if the buffer could not be released, because it is attached by another frontend or was already dumped.
+///
+/// # Safety
+/// `events` has to be returned by `rftrace_init` and must not be used afterwards.
+pub unsafe extern "C" fn rftrace_free(events: *mut Events) -> i64 {
+    let result = rftrace_frontend::free(&mut *events);
+    drop(Box::from_raw(events));
+    if result.is_err() {
+        return -1;
+    }
+    0
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::reset. Returns -1 if the buffer could not be taken back or handed to the backend again.
+///
+/// # Safety
+/// `events` has to be returned by `rftrace_init`.
+pub unsafe extern "C" fn rftrace_reset(events: *mut Events) -> i64 {
+    if let Err(err) = rftrace_frontend::reset(&mut *events) {
+        println!("{}", err);
+        return -1;
+    }
+    0
+}
+
+#[no_mangle]
+/// Returns the default options, as used by `rftrace_init()` with a buffer of `rftrace_frontend::DEFAULT_MAX_EVENT_COUNT` events.
+pub extern "C" fn rftrace_default_options() -> RftraceOptions {
+    RftraceOptions {
+        max_event_count: rftrace_frontend::DEFAULT_MAX_EVENT_COUNT,
+        overwriting: false,
+        flight_recorder_events: 0,
+        sample_period: 0,
+        time_shift: 0,
+        max_recursion: 0,
+        max_output_bytes: 0,
+        tid_offset: 0,
+    }
+}
+
+#[no_mangle]
+/// Like `rftrace_init()`, but additionally sets up the flight recorder, sampling and TID offset as given by `options`.
+///
+/// # Safety
+/// `options` has to point to valid options.
+pub unsafe extern "C" fn rftrace_init_with(options: *const RftraceOptions) -> *mut Events {
+    let options = &*options;
+    rftrace_frontend::set_tid_offset(options.tid_offset);
+    let events = rftrace_init(options.max_event_count, options.overwriting);
+    if events.is_null() {
+        return events;
+    }
+    if options.flight_recorder_events > 0 {
+        rftrace_frontend::init_flight_recorder(options.flight_recorder_events);
+    }
+    rftrace_frontend::set_sampling(options.sample_period);
+    events
 }
 
 #[no_mangle]
@@ -41,6 +227,220 @@ pub unsafe extern "C" fn rftrace_dump_full_uftrace(
     0
 }
 
+#[no_mangle]
+/// Wraps rftrace_frontend::dump_full_uftrace_with, using the dump related fields of `options`.
+///
+/// # Safety
+/// `events` has to be returned by `rftrace_init`, `out_dir` and `binary_name` have to be valid C strings
+/// and `options` has to point to valid options.
+pub unsafe extern "C" fn rftrace_dump_full_uftrace_with(
+    events: *mut Events,
+    out_dir: *const c_char,
+    binary_name: *const c_char,
+    options: *const RftraceOptions,
+) -> i64 {
+    let out_dir = CStr::from_ptr(out_dir).to_string_lossy().into_owned();
+    let binary_name = CStr::from_ptr(binary_name).to_string_lossy().into_owned();
+    let options = dump_options(&*options);
+
+    if rftrace_frontend::dump_full_uftrace_with(&mut *events, &out_dir, &binary_name, &options)
+        .is_err()
+    {
+        return -1;
+    }
+    0
+}
+
+fn dump_options(options: &RftraceOptions) -> DumpOptions {
+    DumpOptions {
+        max_recursion: Some(options.max_recursion).filter(|&max| max > 0),
+        time_shift: options.time_shift,
+        max_output_bytes: Some(options.max_output_bytes).filter(|&max| max > 0),
+        ..DumpOptions::default()
+    }
+}
+
+#[no_mangle]
+/// Writes a full uftrace directory of all events recorded so far, while tracing continues.
+///
+/// Wraps rftrace_frontend::attach and write_full_uftrace. Returns -1 if no event buffer is initialized or writing failed.
+///
+/// # Safety
+/// `out_dir` and `binary_name` have to be valid C strings.
+pub unsafe extern "C" fn rftrace_snapshot_uftrace(
+    out_dir: *const c_char,
+    binary_name: *const c_char,
+) -> i64 {
+    let out_dir = CStr::from_ptr(out_dir).to_string_lossy().into_owned();
+    let binary_name = CStr::from_ptr(binary_name).to_string_lossy().into_owned();
+
+    let events = match rftrace_frontend::attach() {
+        Some(attachment) => attachment.snapshot(),
+        None => return -1,
+    };
+    if rftrace_frontend::write_full_uftrace(
+        &events,
+        &out_dir,
+        &binary_name,
+        &DumpOptions::default(),
+    )
+    .is_err()
+    {
+        return -1;
+    }
+    0
+}
+
+#[no_mangle]
+/// Takes a snapshot of all events recorded so far, oldest first, and returns their number.
+///
+/// The events can then be read with `rftrace_events_get()`, while tracing continues. The snapshot belongs to the
+/// calling thread and is kept until its next call. Returns 0 if no event buffer is initialized, or it was dumped already.
+pub extern "C" fn rftrace_events_count() -> usize {
+    let events: Vec<RftraceEvent> = match rftrace_frontend::attach() {
+        Some(attachment) => attachment
+            .snapshot()
+            .iter()
+            .filter_map(RftraceEvent::new)
+            .collect(),
+        None => Vec::new(),
+    };
+    let count = events.len();
+    EVENTS.with(|snapshot| *snapshot.borrow_mut() = events);
+    count
+}
+
+#[no_mangle]
+/// Copies event `i` of the snapshot taken by `rftrace_events_count()` to `out_event`.
+///
+/// Returns false if `i` is out of range, leaving `out_event` untouched.
+///
+/// # Safety
+/// `out_event` has to be valid for writes.
+pub unsafe extern "C" fn rftrace_events_get(i: usize, out_event: *mut RftraceEvent) -> bool {
+    match EVENTS.with(|snapshot| snapshot.borrow().get(i).copied()) {
+        Some(event) => {
+            *out_event = event;
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::stats
+///
+/// # Safety
+/// `stats` has to be valid for writes.
+pub unsafe extern "C" fn rftrace_get_stats(stats: *mut Stats) {
+    *stats = rftrace_frontend::stats();
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::set_sampling
+pub extern "C" fn rftrace_set_sampling(period: u64) {
+    rftrace_frontend::set_sampling(period);
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::suppress. Suppresses recording on the current thread until the matching `rftrace_unsuppress()`.
+pub extern "C" fn rftrace_suppress() {
+    SUPPRESS_GUARDS.with(|guards| guards.borrow_mut().push(rftrace_frontend::suppress()));
+}
+
+#[no_mangle]
+/// Ends the innermost `rftrace_suppress()` of the current thread.
+pub extern "C" fn rftrace_unsuppress() {
+    SUPPRESS_GUARDS.with(|guards| guards.borrow_mut().pop());
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::full_trace. Fully traces the current thread until the matching `rftrace_full_trace_end()`.
+pub extern "C" fn rftrace_full_trace_begin() {
+    FULL_TRACE_GUARDS.with(|guards| guards.borrow_mut().push(rftrace_frontend::full_trace()));
+}
+
+#[no_mangle]
+/// Ends the innermost `rftrace_full_trace_begin()` of the current thread.
+pub extern "C" fn rftrace_full_trace_end() {
+    FULL_TRACE_GUARDS.with(|guards| guards.borrow_mut().pop());
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::set_context
+pub extern "C" fn rftrace_set_context(context: u64) -> u64 {
+    rftrace_frontend::set_context(context)
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::thread_exit. Call it from thread destructors or task teardown.
+pub extern "C" fn rftrace_thread_exit() {
+    rftrace_frontend::thread_exit();
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::FiberStack::new. Free it with `rftrace_fiber_stack_free()` once the fiber finished.
+pub extern "C" fn rftrace_fiber_stack_new(capacity: usize) -> *mut RftraceFiberStack {
+    Box::into_raw(Box::new(RftraceFiberStack(FiberStack::new(capacity))))
+}
+
+#[no_mangle]
+/// Frees a return stack created by `rftrace_fiber_stack_new()`.
+///
+/// # Safety
+/// `stack` has to be null or created by `rftrace_fiber_stack_new()`, and must not be used afterwards.
+pub unsafe extern "C" fn rftrace_fiber_stack_free(stack: *mut RftraceFiberStack) {
+    if !stack.is_null() {
+        drop(Box::from_raw(stack));
+    }
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::switch_stack. Call it right before switching from the fiber of `old_stack` to the one of `new_stack`.
+///
+/// # Safety
+/// `old_stack` and `new_stack` have to be distinct stacks created by `rftrace_fiber_stack_new()`.
+#[link_section = "rftrace_notrace"]
+pub unsafe extern "C" fn rftrace_switch_stack(
+    old_stack: *mut RftraceFiberStack,
+    new_stack: *mut RftraceFiberStack,
+) {
+    rftrace_frontend::switch_stack(&mut (*old_stack).0, &mut (*new_stack).0);
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::record
+///
+/// # Safety
+/// `payload` has to be valid for reads of `len` bytes.
+pub unsafe extern "C" fn rftrace_record(kind: u8, payload: *const u8, len: usize) {
+    let payload = if payload.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(payload, len)
+    };
+    rftrace_frontend::record(kind, payload);
+}
+
+#[no_mangle]
+/// Wraps rftrace_frontend::inject_event, with a `tid` of 0 for none.
+///
+/// Returns false for `RftraceEventKind_Record`, since only entries and exits can be injected.
+pub extern "C" fn rftrace_inject_event(
+    tid: u64,
+    time: u64,
+    kind: RftraceEventKind,
+    addr: usize,
+) -> bool {
+    let kind = match kind {
+        RftraceEventKind::Entry => EventKind::Entry,
+        RftraceEventKind::Exit => EventKind::Exit,
+        RftraceEventKind::Record => return false,
+    };
+    rftrace_frontend::inject_event(NonZeroU64::new(tid), time, kind, addr);
+    true
+}
+
 #[no_mangle]
 /// Wraps rftrace_frontend::dump_trace
 pub unsafe extern "C" fn rftrace_dump_trace(events: *mut Events, outfile: *const c_char) -> i64 {
@@ -52,6 +452,39 @@ pub unsafe extern "C" fn rftrace_dump_trace(events: *mut Events, outfile: *const
     0
 }
 
+#[no_mangle]
+/// Wraps rftrace_frontend::control, printing its response.
+///
+/// # Safety
+/// `events` has to be returned by `rftrace_init`, `binary_name` and `command` have to be valid C strings.
+pub unsafe extern "C" fn rftrace_control(
+    events: *mut Events,
+    binary_name: *const c_char,
+    command: *const c_char,
+) -> i64 {
+    let binary_name = CStr::from_ptr(binary_name).to_string_lossy().into_owned();
+    let command = CStr::from_ptr(command).to_string_lossy().into_owned();
+
+    match rftrace_frontend::control(&mut *events, &binary_name, &command) {
+        Ok(response) => {
+            println!("{}", response);
+            0
+        }
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+/// Returns the version of this library as NUL-terminated string, such as "0.2.1".
+///
+/// Useful to check the version of a shared library loaded at runtime.
+pub extern "C" fn rftrace_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
 #[no_mangle]
 pub extern "C" fn marker() -> u64 {
     1337
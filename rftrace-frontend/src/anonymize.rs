@@ -0,0 +1,60 @@
+use std::fmt::Write as _;
+
+/// Replaces `name`, such as a symbol or binary name, by a stable hash of it, see `DumpOptions::anonymize`.
+///
+/// The same name always maps to the same hash, so anonymized symbols and binary names of a dump still match each other,
+/// and traces of different runs stay comparable. Common names, such as those of the standard library, can be recovered
+/// by hashing candidates, the names of proprietary code can not.
+pub fn anonymize_name(name: &str) -> String {
+    format!("anon_{:016x}", fnv1a(name.as_bytes()))
+}
+
+/// Anonymizes the symbol names in `nm`, the output of `nm -n`, with `anonymize_name()`. Addresses and types are kept.
+///
+/// Write the result to `<anonymized binary name>.sym` next to a dump made with `DumpOptions::anonymize`, so uftrace can
+/// still resolve all functions, for example to `anon_1f3c8e0d5a4b2c69`.
+pub fn anonymize_symbols(nm: &str) -> String {
+    let mut anonymized = String::with_capacity(nm.len());
+    for line in nm.lines() {
+        // Undefined symbols have no address, so the name is the last column rather than the third
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [.., _, name] => {
+                let kept = &line[..line.len() - name.len()];
+                writeln!(anonymized, "{}{}", kept, anonymize_name(name)).unwrap();
+            }
+            _ => writeln!(anonymized, "{}", line).unwrap(),
+        }
+    }
+    anonymized
+}
+
+/// Replaces the paths in a memory map such as `/proc/self/maps` by `anonymize_name()` of their file names.
+///
+/// uftrace looks up the symbols of each mapped binary by its file name, so they still match `anonymize_symbols()`.
+/// Pseudo paths such as `[stack]` are kept.
+pub(crate) fn anonymize_maps(maps: &str) -> String {
+    let mut anonymized = String::with_capacity(maps.len());
+    for line in maps.lines() {
+        // The path is the sixth column, and may contain spaces itself
+        let path_start = line
+            .match_indices(' ')
+            .filter(|&(i, _)| line.as_bytes().get(i + 1) != Some(&b' '))
+            .nth(4)
+            .map(|(i, _)| i + 1);
+        match path_start {
+            Some(start) if start < line.len() && !line[start..].starts_with('[') => {
+                let name = line[start..].rsplit('/').next().unwrap_or_default();
+                writeln!(anonymized, "{}{}", &line[..start], anonymize_name(name)).unwrap();
+            }
+            _ => writeln!(anonymized, "{}", line).unwrap(),
+        }
+    }
+    anonymized
+}
+
+/// 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+use crate::profile::{profile, FunctionProfile};
+use crate::symbols::Symbols;
+
+/// Returns the combined profile of all functions named `name` within `events`.
+///
+/// Panics if the symbols do not contain such a function.
+#[track_caller]
+fn profile_of(events: &[Event], symbols: &Symbols, name: &str) -> FunctionProfile {
+    let addrs = symbols.addresses(name);
+    assert!(!addrs.is_empty(), "No function named '{}' in symbols", name);
+
+    let profiles = profile(events);
+    addrs
+        .iter()
+        .filter_map(|addr| profiles.get(addr))
+        .fold(FunctionProfile::default(), |acc, p| FunctionProfile {
+            calls: acc.calls + p.calls,
+            total_time: acc.total_time + p.total_time,
+            max_time: acc.max_time.max(p.max_time),
+            max_depth: acc.max_depth.max(p.max_depth),
+        })
+}
+
+/// Asserts that the function `name` was called at least once in `events`, which have to be ordered oldest first.
+///
+/// Returns the function's profile for further checks. Like all assertions on traces, this panics if the symbols do not contain `name`.
+#[track_caller]
+pub fn assert_called(events: &[Event], symbols: &Symbols, name: &str) -> FunctionProfile {
+    let profile = profile_of(events, symbols, name);
+    assert!(profile.calls > 0, "'{}' was not called", name);
+    profile
+}
+
+/// Asserts that the function `name` was never called in `events`.
+#[track_caller]
+pub fn assert_not_called(events: &[Event], symbols: &Symbols, name: &str) {
+    let profile = profile_of(events, symbols, name);
+    assert!(
+        profile.calls == 0,
+        "'{}' was called {} times",
+        name,
+        profile.calls
+    );
+}
+
+/// Asserts that the function `name` was called exactly `calls` times in `events`.
+#[track_caller]
+pub fn assert_call_count(events: &[Event], symbols: &Symbols, name: &str, calls: u64) {
+    let profile = profile_of(events, symbols, name);
+    assert!(
+        profile.calls == calls,
+        "'{}' was called {} times, expected {}",
+        name,
+        profile.calls,
+        calls
+    );
+}
+
+/// Asserts that the function `name` was never called deeper than `max_depth` frames into the call stack.
+#[track_caller]
+pub fn assert_max_depth(events: &[Event], symbols: &Symbols, name: &str, max_depth: usize) {
+    let profile = profile_of(events, symbols, name);
+    assert!(
+        profile.max_depth <= max_depth,
+        "'{}' was called at depth {}, expected at most {}",
+        name,
+        profile.max_depth,
+        max_depth
+    );
+}
+
+/// Asserts that no call of the function `name` took longer than `max_time` TSC cycles, including its callees.
+#[track_caller]
+pub fn assert_max_duration(events: &[Event], symbols: &Symbols, name: &str, max_time: u64) {
+    let profile = profile_of(events, symbols, name);
+    assert!(
+        profile.max_time <= max_time,
+        "'{}' took {} cycles, expected at most {}",
+        name,
+        profile.max_time,
+        max_time
+    );
+}
+
+/// Asserts that the function `inner` was never called while `outer` was on the same thread's call stack,
+/// such as "no allocation inside the interrupt handler".
+#[track_caller]
+pub fn assert_not_called_within(events: &[Event], symbols: &Symbols, outer: &str, inner: &str) {
+    let outer_addrs = symbols.addresses(outer);
+    let inner_addrs = symbols.addresses(inner);
+    assert!(
+        !outer_addrs.is_empty(),
+        "No function named '{}' in symbols",
+        outer
+    );
+    assert!(
+        !inner_addrs.is_empty(),
+        "No function named '{}' in symbols",
+        inner
+    );
+
+    // Per-thread call stacks, tracking whether `outer` is active in each frame
+    let mut stacks: HashMap<Option<NonZeroU64>, Vec<bool>> = HashMap::new();
+    for e in events {
+        match e {
+            Event::Entry(e) => {
+                let stack = stacks.entry(e.tid).or_default();
+                let within = stack.last().copied().unwrap_or(false);
+                assert!(
+                    !(within && inner_addrs.contains(&(e.to as usize))),
+                    "'{}' was called within '{}' at time {}",
+                    inner,
+                    outer,
+                    e.time
+                );
+                stack.push(within || outer_addrs.contains(&(e.to as usize)));
+            }
+            Event::Exit(e) => {
+                stacks.entry(e.tid).or_default().pop();
+            }
+            Event::Record(_) | Event::Empty => {}
+        }
+    }
+}
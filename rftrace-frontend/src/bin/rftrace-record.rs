@@ -0,0 +1,107 @@
+//! Runs a binary traced and collects its uftrace directory along with symbols, like `uftrace record`.
+
+use std::{env, process};
+
+const USAGE: &str = "usage: rftrace-record [-d out_dir] [--max-events count] [--overwriting] [--time-shift bits] [--preload backend.so | --no-preload] <binary> [args...]";
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    use rftrace_frontend::{
+        preload_library, spawn_traced, ENV_MAX_EVENTS, ENV_OVERWRITING, ENV_PRELOAD,
+        ENV_TIME_SHIFT, PRELOAD_LIBRARY,
+    };
+
+    let mut args = env::args().skip(1);
+    let mut out_dir = String::from("rftrace.data");
+    let binary = loop {
+        let arg = match args.next() {
+            Some(arg) => arg,
+            None => usage(),
+        };
+        // Options are passed to spawn_traced() via our own environment, which it forwards to the child
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-d" | "--data" => out_dir = value(),
+            "--max-events" => env::set_var(ENV_MAX_EVENTS, value()),
+            "--overwriting" => env::set_var(ENV_OVERWRITING, "1"),
+            "--time-shift" => env::set_var(ENV_TIME_SHIFT, value()),
+            "--preload" => env::set_var(ENV_PRELOAD, value()),
+            "--no-preload" => env::set_var(ENV_PRELOAD, ""),
+            "--" => break args.next().unwrap_or_else(|| usage()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            arg if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => break arg,
+        }
+    };
+
+    // Without a backend, binaries which do not link rftrace would silently run untraced
+    let no_preload = env::var_os(ENV_PRELOAD).is_some_and(|preload| preload.is_empty());
+    if !no_preload && preload_library().is_none() {
+        eprintln!(
+            "No backend library to preload. Build {} with `cargo build -p rftrace-preload`, pass one with --preload, or use --no-preload if the binary links rftrace itself.",
+            PRELOAD_LIBRARY
+        );
+        process::exit(1);
+    }
+
+    // spawn_traced() looks up the symbols of the binary by its path, so resolve it like the shell would
+    let binary = if binary.contains('/') {
+        binary
+    } else {
+        env::var_os("PATH")
+            .and_then(|path| {
+                env::split_paths(&path)
+                    .map(|dir| dir.join(&binary))
+                    .find(|candidate| candidate.is_file())
+            })
+            .map_or(binary, |path| path.to_string_lossy().into_owned())
+    };
+    let mut cmd = Command::new(binary);
+    cmd.args(args);
+    let status = match spawn_traced(cmd, &out_dir) {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Could not run traced binary: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if Path::new(&out_dir).join("info").exists() {
+        println!(
+            "Trace written to {}, view it with `uftrace replay -d {}`",
+            out_dir, out_dir
+        );
+    } else {
+        eprintln!(
+            "No trace was written to {}. The binary has to be compiled with mcount instrumentation, and call init_from_env() and dump_from_env() if it links rftrace itself.",
+            out_dir
+        );
+    }
+    // Exit like the traced binary did, so rftrace-record can stand in for it in scripts
+    process::exit(
+        status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+    );
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("rftrace-record is only supported on Linux\n{}", USAGE);
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,37 @@
+//! Reconstructs the uftrace directory from a core dump or raw memory snapshot of a crashed, traced program.
+
+use std::{env, fs, process};
+
+use rftrace_frontend::{recover_uftrace, MemoryImage};
+
+const USAGE: &str = "usage: rftrace-recover <core|snapshot> <out_dir> [binary_name] [raw snapshot base address, hex]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 || args.len() > 4 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let binary_name = args.get(2).map_or("unknown", String::as_str);
+    let base = match args
+        .get(3)
+        .map(|base| usize::from_str_radix(base.trim_start_matches("0x"), 16))
+    {
+        None => 0,
+        Some(Ok(base)) => base,
+        Some(Err(err)) => {
+            eprintln!("Invalid base address: {}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = MemoryImage::load(&args[0], base).and_then(|image| {
+        fs::create_dir_all(&args[1])?;
+        recover_uftrace(&image, &args[1], binary_name)
+    });
+    if let Err(err) = result {
+        eprintln!("Could not recover trace: {}", err);
+        process::exit(1);
+    }
+}
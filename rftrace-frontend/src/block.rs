@@ -0,0 +1,143 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// A request to the kernel's block device, see `RECORD_KIND_BLOCK_SUBMIT` and `RECORD_KIND_BLOCK_COMPLETE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRequest {
+    /// Thread which submitted the request.
+    pub tid: Option<NonZeroU64>,
+    /// Timestamp of the submission.
+    pub submitted: u64,
+    /// Timestamp of the completion, `None` if the request was still pending at the end of the trace.
+    pub completed: Option<u64>,
+    /// First sector of the request.
+    pub sector: u64,
+    /// Length of the request in bytes.
+    pub len: u32,
+    pub write: bool,
+}
+
+impl BlockRequest {
+    /// Time in TSC cycles from submission to completion.
+    pub fn latency(&self) -> Option<u64> {
+        self.completed.map(|completed| completed - self.submitted)
+    }
+}
+
+/// Decodes a `RECORD_KIND_BLOCK_SUBMIT` or `RECORD_KIND_BLOCK_COMPLETE` record into whether it is a completion, the
+/// sector, length and whether it is a write. Returns `None` for other records.
+pub(crate) fn block_record(record: &Record) -> Option<(bool, u64, u32, bool)> {
+    let completion = match record.kind {
+        RECORD_KIND_BLOCK_SUBMIT => false,
+        RECORD_KIND_BLOCK_COMPLETE => true,
+        _ => return None,
+    };
+    if record.len < 13 {
+        return None;
+    }
+    Some((
+        completion,
+        u64::from_le_bytes(record.payload[0..8].try_into().unwrap()),
+        u32::from_le_bytes(record.payload[8..12].try_into().unwrap()),
+        record.payload[12] != 0,
+    ))
+}
+
+/// Returns all block requests in `events`, which have to be ordered oldest first, in the order they were submitted.
+///
+/// Completions are paired with the oldest pending submission of the same sector and direction, since the driver might
+/// complete them on another thread, such as in its interrupt handler. Completions without submission, which was
+/// overwritten or recorded before tracing was enabled, are left out.
+pub fn block_requests(events: &[Event]) -> Vec<BlockRequest> {
+    let mut requests = Vec::new();
+    let mut pending: HashMap<(u64, bool), VecDeque<usize>> = HashMap::new();
+    for r in events.iter().filter_map(|e| match e {
+        Event::Record(r) => Some(r),
+        _ => None,
+    }) {
+        let (completion, sector, len, write) = match block_record(r) {
+            Some(block) => block,
+            None => continue,
+        };
+        let queue = pending.entry((sector, write)).or_default();
+        if completion {
+            if let Some(idx) = queue.pop_front() {
+                requests[idx] = BlockRequest {
+                    completed: Some(r.time),
+                    ..requests[idx]
+                };
+            }
+        } else {
+            queue.push_back(requests.len());
+            requests.push(BlockRequest {
+                tid: r.tid,
+                submitted: r.time,
+                completed: None,
+                sector,
+                len,
+                write,
+            });
+        }
+    }
+    requests
+}
+
+/// Formats the block requests in `events` as human-readable report, one line per direction.
+///
+/// Lists the number of requests and bytes along with their average and maximum latency in TSC cycles, followed by the
+/// slowest requests, so they can be looked up in the function trace at the time they were submitted.
+pub fn block_report(events: &[Event]) -> String {
+    let requests = block_requests(events);
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{:>5}  {:>10}  {:>14}  {:>14}  {:>14}  {:>8}",
+        "dir", "requests", "bytes", "avg latency", "max latency", "pending"
+    )
+    .unwrap();
+    for &write in [false, true].iter() {
+        let requests: Vec<&BlockRequest> = requests.iter().filter(|r| r.write == write).collect();
+        if requests.is_empty() {
+            continue;
+        }
+        let latencies: Vec<u64> = requests.iter().filter_map(|r| r.latency()).collect();
+        let avg = latencies.iter().sum::<u64>() / (latencies.len() as u64).max(1);
+        writeln!(
+            report,
+            "{:>5}  {:>10}  {:>14}  {:>14}  {:>14}  {:>8}",
+            if write { "write" } else { "read" },
+            requests.len(),
+            requests.iter().map(|r| u64::from(r.len)).sum::<u64>(),
+            avg,
+            latencies.iter().max().copied().unwrap_or(0),
+            requests.len() - latencies.len()
+        )
+        .unwrap();
+    }
+
+    let mut slowest: Vec<&BlockRequest> =
+        requests.iter().filter(|r| r.completed.is_some()).collect();
+    slowest.sort_by_key(|r| Reverse(r.latency()));
+    if !slowest.is_empty() {
+        writeln!(report, "\nSlowest requests:").unwrap();
+    }
+    for r in slowest.iter().take(10) {
+        writeln!(
+            report,
+            "  {:>14}  {} of {} bytes at sector {}, submitted at {} by TID {}",
+            r.latency().unwrap(),
+            if r.write { "write" } else { "read" },
+            r.len,
+            r.sector,
+            r.submitted,
+            r.tid.map_or(0, NonZeroU64::get)
+        )
+        .unwrap();
+    }
+    report
+}
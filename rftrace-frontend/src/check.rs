@@ -0,0 +1,450 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::{self};
+
+/// Result of inspecting a binary with `check_binary()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BinaryCheck {
+    /// Number of `mcount` calls, which are placed after the function prologue.
+    pub mcount_calls: usize,
+    /// Number of `__fentry__` calls, which are placed before the function prologue.
+    pub fentry_calls: usize,
+    /// Number of functions calling `mcount`, which set up a frame pointer before.
+    /// The backend locates the return address via `rbp` in these, unless built with the `fentry` feature.
+    pub frame_pointer_functions: usize,
+    /// Number of functions calling `mcount` without setting up a frame pointer, as far as they could be found in the symbols.
+    pub frameless_functions: usize,
+    /// Whether the binary defines `mcount` itself, usually because the rftrace backend is linked in.
+    pub defines_mcount: bool,
+    /// Whether the binary is marked as compatible with indirect branch tracking (CET IBT).
+    pub ibt: bool,
+    /// Whether the binary is marked as compatible with hardware shadow stacks (CET SHSTK).
+    pub shadow_stack: bool,
+    /// Whether the binary requires static TLS (initial-exec model), so it can not be loaded with `dlopen()` late.
+    pub static_tls: bool,
+    /// Backend features needed to trace the binary, such as `fentry`.
+    pub features: Vec<&'static str>,
+    /// Reasons why tracing the binary will not work, or yield empty or broken traces.
+    pub problems: Vec<String>,
+    /// Remarks which do not prevent tracing.
+    pub notes: Vec<String>,
+}
+
+impl BinaryCheck {
+    /// Returns whether rftrace can trace the binary, with the backend `features`.
+    pub fn is_traceable(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for BinaryCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} mcount calls, {} fentry calls",
+            self.mcount_calls, self.fentry_calls
+        )?;
+        if self.is_traceable() {
+            write!(f, "traceable")?;
+            if !self.features.is_empty() {
+                write!(f, " with backend features: {}", self.features.join(", "))?;
+            }
+            writeln!(f)?;
+        } else {
+            writeln!(f, "NOT traceable:")?;
+            for problem in &self.problems {
+                writeln!(f, "  - {}", problem)?;
+            }
+        }
+        for note in &self.notes {
+            writeln!(f, "note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// `endbr64`, the landing pad functions start with when compiled with CET IBT.
+const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+/// `push rbp; mov rbp, rsp`, setting up a frame pointer.
+const FRAME_SETUP: [u8; 4] = [0x55, 0x48, 0x89, 0xe5];
+
+/// A section of an ELF file.
+struct Section<'a> {
+    name: &'a str,
+    kind: u32,
+    flags: usize,
+    addr: usize,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+/// Minimal reader for little-endian ELF64 files, such as x86_64 binaries.
+struct Elf<'a> {
+    data: &'a [u8],
+    sections: Vec<Section<'a>>,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> io::Result<Self> {
+        if !data.starts_with(b"\x7fELF\x02\x01") {
+            return Err(invalid("not a little-endian ELF64 file"));
+        }
+        let mut elf = Self {
+            data,
+            sections: Vec::new(),
+        };
+
+        let shoff = elf.u64_at(0x28)?;
+        let shentsize = elf.u16_at(0x3a)?;
+        let shnum = elf.u16_at(0x3c)?;
+        let shstrndx = elf.u16_at(0x3e)?;
+        let headers = (0..shnum)
+            .map(|i| {
+                let sh = shoff + i * shentsize;
+                Ok((
+                    elf.u32_at(sh)?,
+                    elf.u32_at(sh + 4)? as u32,
+                    elf.u64_at(sh + 8)?,
+                    elf.u64_at(sh + 16)?,
+                    elf.u64_at(sh + 24)?,
+                    elf.u64_at(sh + 32)?,
+                    elf.u32_at(sh + 40)?,
+                ))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let names = headers
+            .get(shstrndx)
+            .map_or(0..0, |&(_, _, _, _, offset, size, _)| offset..offset + size);
+        for (name, kind, flags, addr, offset, size, link) in headers {
+            let name = elf.str_at(names.start + name).unwrap_or("");
+            elf.sections.push(Section {
+                name,
+                kind,
+                flags,
+                addr,
+                offset,
+                size,
+                link,
+            });
+        }
+        Ok(elf)
+    }
+
+    fn u16_at(&self, offset: usize) -> io::Result<usize> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()) as usize)
+    }
+
+    fn u32_at(&self, offset: usize) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()) as usize)
+    }
+
+    fn u64_at(&self, offset: usize) -> io::Result<usize> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into().unwrap()) as usize)
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| invalid("truncated ELF file"))
+    }
+
+    fn str_at(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.data.get(offset..)?;
+        let end = bytes.iter().position(|&b| b == 0)?;
+        std::str::from_utf8(&bytes[..end]).ok()
+    }
+
+    fn section(&self, name: &str) -> Option<&Section<'a>> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    fn contents(&self, section: &Section) -> &'a [u8] {
+        const SHT_NOBITS: u32 = 8;
+        if section.kind == SHT_NOBITS {
+            return &[];
+        }
+        self.data
+            .get(section.offset..section.offset + section.size)
+            .unwrap_or(&[])
+    }
+
+    /// Returns the bytes at virtual address `addr`, up to the end of its section.
+    fn code_at(&self, addr: usize) -> &'a [u8] {
+        self.sections
+            .iter()
+            .find(|s| s.addr != 0 && addr >= s.addr && addr < s.addr + s.size)
+            .map_or(&[], |s| &self.contents(s)[addr - s.addr..])
+    }
+
+    /// Returns name, value, section index and type of all symbols in the symbol table `section`.
+    fn symbols(&self, section: &str) -> Vec<(&'a str, usize, usize, u8)> {
+        let Some(symtab) = self.section(section) else {
+            return Vec::new();
+        };
+        let strtab = self.sections.get(symtab.link).map_or(0, |s| s.offset);
+        self.contents(symtab)
+            .chunks_exact(24)
+            .map(|sym| {
+                let name = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+                let shndx = u16::from_le_bytes(sym[6..8].try_into().unwrap()) as usize;
+                let value = u64::from_le_bytes(sym[8..16].try_into().unwrap()) as usize;
+                (
+                    self.str_at(strtab + name).unwrap_or(""),
+                    value,
+                    shndx,
+                    sym[4] & 0xf,
+                )
+            })
+            .collect()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Inspects the x86_64 ELF binary at `path` and reports whether rftrace can trace it, and with which backend features.
+///
+/// Checks for calls to `mcount` or `__fentry__`, whether functions calling `mcount` set up a frame pointer,
+/// CET markers and the TLS model. This catches the usual reasons for silently empty or broken traces
+/// before running the binary. Call sites are found by scanning the code for calls, so the results are estimates.
+pub fn check_binary(path: &str) -> io::Result<BinaryCheck> {
+    let data = fs::read(path)?;
+    let elf = Elf::parse(&data)?;
+    let mut check = BinaryCheck::default();
+
+    let symbols = elf.symbols(".symtab");
+    let dynsyms = elf.symbols(".dynsym");
+    const STT_FUNC: u8 = 2;
+    let mut functions: Vec<usize> = symbols
+        .iter()
+        .filter(|&&(_, value, shndx, kind)| kind == STT_FUNC && shndx != 0 && value != 0)
+        .map(|&(_, value, _, _)| value)
+        .collect();
+    functions.sort_unstable();
+    functions.dedup();
+
+    check.defines_mcount = symbols
+        .iter()
+        .chain(&dynsyms)
+        .any(|&(name, _, shndx, _)| name == "mcount" && shndx != 0);
+
+    let mcount = call_targets(&elf, &symbols, &dynsyms, &["mcount", "_mcount"]);
+    let fentry = call_targets(&elf, &symbols, &dynsyms, &["__fentry__"]);
+
+    let mut frameless = HashSet::new();
+    let mut frame_pointer = HashSet::new();
+    for section in &elf.sections {
+        const SHF_EXECINSTR: usize = 4;
+        if section.flags & SHF_EXECINSTR == 0 || section.addr == 0 {
+            continue;
+        }
+        let code = elf.contents(section);
+        for i in 0..code.len().saturating_sub(5) {
+            let ret = section.addr + i + 5;
+            let target = match code[i] {
+                // call rel32
+                0xe8 => ret.wrapping_add(
+                    i32::from_le_bytes(code[i + 1..i + 5].try_into().unwrap()) as usize,
+                ),
+                // call [rip + rel32], for calls without PLT
+                0xff if code.get(i + 1) == Some(&0x15) && i + 6 <= code.len() => {
+                    let slot = (ret + 1).wrapping_add(i32::from_le_bytes(
+                        code[i + 2..i + 6].try_into().unwrap(),
+                    ) as usize);
+                    slot | GOT_SLOT
+                }
+                _ => continue,
+            };
+
+            if fentry.contains(&target) {
+                check.fentry_calls += 1;
+            } else if mcount.contains(&target) {
+                check.mcount_calls += 1;
+                let call = section.addr + i;
+                let idx = functions.partition_point(|&start| start <= call);
+                if let Some(&start) = idx.checked_sub(1).and_then(|idx| functions.get(idx)) {
+                    let code = elf.code_at(start);
+                    let prologue = code.strip_prefix(&ENDBR64[..]).unwrap_or(code);
+                    if prologue.starts_with(&FRAME_SETUP) {
+                        frame_pointer.insert(start);
+                    } else {
+                        frameless.insert(start);
+                    }
+                }
+            }
+        }
+    }
+    check.frame_pointer_functions = frame_pointer.len();
+    check.frameless_functions = frameless.len();
+
+    let (ibt, shadow_stack) = cet_features(&elf);
+    check.ibt = ibt;
+    check.shadow_stack = shadow_stack;
+    check.static_tls = has_static_tls(&elf);
+
+    if check.mcount_calls == 0 && check.fentry_calls == 0 {
+        check.problems.push(
+            "no calls to mcount or __fentry__ found, the binary is not instrumented (compile with `-Z instrument-mcount` or `-pg`)".into(),
+        );
+    }
+    if check.mcount_calls > 0 && check.fentry_calls > 0 {
+        check.problems.push(
+            "calls both mcount and __fentry__, the backend can only locate return addresses for one of them".into(),
+        );
+    }
+    if check.fentry_calls > 0 {
+        check.features.push("fentry");
+    } else if check.frameless_functions > 0 {
+        check.problems.push(format!(
+            "{} of {} functions calling mcount do not set up a frame pointer (compile with `-C force-frame-pointers=yes`)",
+            check.frameless_functions,
+            check.frameless_functions + check.frame_pointer_functions
+        ));
+    }
+    if check.shadow_stack {
+        check.problems.push(
+            "marked as compatible with CET shadow stacks, which fault on the hooked return addresses once enabled".into(),
+        );
+    }
+    if !check.defines_mcount && check.mcount_calls + check.fentry_calls > 0 {
+        check.notes.push(
+            "does not define mcount, the backend has to be linked (`extern crate rftrace as _;`) or preloaded".into(),
+        );
+    }
+    if check.ibt {
+        check
+            .notes
+            .push("compiled with CET IBT, functions start with endbr64".into());
+    }
+    if check.static_tls {
+        check.notes.push(
+            "uses static TLS, so it can only be preloaded, not loaded with dlopen() late".into(),
+        );
+    }
+    if symbols.is_empty() {
+        check
+            .notes
+            .push("no symbol table, so frame pointers can not be checked and traces can not be symbolized".into());
+    }
+    Ok(check)
+}
+
+/// Marks GOT slots in the targets of `call_targets()`, so they do not clash with code addresses.
+const GOT_SLOT: usize = 1 << 63;
+
+/// Returns the addresses which calls to one of the functions `names` target: the function itself,
+/// its PLT stub or its GOT slot (marked with `GOT_SLOT`).
+fn call_targets(
+    elf: &Elf,
+    symbols: &[(&str, usize, usize, u8)],
+    dynsyms: &[(&str, usize, usize, u8)],
+    names: &[&str],
+) -> HashSet<usize> {
+    let mut targets: HashSet<usize> = symbols
+        .iter()
+        .chain(dynsyms)
+        .filter(|&&(name, value, shndx, _)| names.contains(&name) && shndx != 0 && value != 0)
+        .map(|&(_, value, _, _)| value)
+        .collect();
+
+    // Imported functions are called through GOT slots, which are filled in by the dynamic linker
+    let mut slots = HashSet::new();
+    for rela in [".rela.plt", ".rela.dyn"] {
+        let Some(section) = elf.section(rela) else {
+            continue;
+        };
+        for entry in elf.contents(section).chunks_exact(24) {
+            let slot = u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize;
+            let sym = (u64::from_le_bytes(entry[8..16].try_into().unwrap()) >> 32) as usize;
+            if dynsyms
+                .get(sym)
+                .is_some_and(|&(name, ..)| names.contains(&name))
+            {
+                slots.insert(slot);
+                targets.insert(slot | GOT_SLOT);
+            }
+        }
+    }
+
+    // PLT stubs jump through the GOT slot: [endbr64] [bnd] jmp [rip + rel32]
+    for plt in [".plt", ".plt.sec", ".plt.got"] {
+        let Some(section) = elf.section(plt) else {
+            continue;
+        };
+        let code = elf.contents(section);
+        for i in 0..code.len().saturating_sub(6) {
+            if code[i] != 0xff || code[i + 1] != 0x25 {
+                continue;
+            }
+            let next = section.addr + i + 6;
+            let slot = next
+                .wrapping_add(i32::from_le_bytes(code[i + 2..i + 6].try_into().unwrap()) as usize);
+            if !slots.contains(&slot) {
+                continue;
+            }
+            let mut stub = i;
+            if stub > 0 && code[stub - 1] == 0xf2 {
+                stub -= 1;
+            }
+            if stub >= 4 && code[stub - 4..stub] == ENDBR64 {
+                stub -= 4;
+            }
+            targets.insert(section.addr + stub);
+        }
+    }
+    targets
+}
+
+/// Returns whether the binary is marked as compatible with IBT and SHSTK in its GNU property note.
+fn cet_features(elf: &Elf) -> (bool, bool) {
+    const NT_GNU_PROPERTY_TYPE_0: usize = 5;
+    const GNU_PROPERTY_X86_FEATURE_1_AND: u32 = 0xc000_0002;
+
+    let Some(note) = elf.section(".note.gnu.property") else {
+        return (false, false);
+    };
+    let note = elf.contents(note);
+    let field = |offset: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(note.get(offset..offset + 4)?.try_into().ok()?) as usize)
+    };
+    let (Some(namesz), Some(descsz), Some(kind)) = (field(0), field(4), field(8)) else {
+        return (false, false);
+    };
+    if kind != NT_GNU_PROPERTY_TYPE_0 {
+        return (false, false);
+    }
+
+    // Properties are 8-byte aligned in ELF64
+    let mut offset = 12 + namesz.div_ceil(4) * 4;
+    let end = (offset + descsz).min(note.len());
+    while offset + 8 <= end {
+        let (Some(pr_type), Some(pr_datasz)) = (field(offset), field(offset + 4)) else {
+            break;
+        };
+        if pr_type as u32 == GNU_PROPERTY_X86_FEATURE_1_AND {
+            let features = field(offset + 8).unwrap_or(0);
+            return (features & 1 != 0, features & 2 != 0);
+        }
+        offset += 8 + pr_datasz.div_ceil(8) * 8;
+    }
+    (false, false)
+}
+
+/// Returns whether the dynamic section sets `DF_STATIC_TLS`, which the initial-exec TLS model requires.
+fn has_static_tls(elf: &Elf) -> bool {
+    const DT_FLAGS: usize = 30;
+    const DF_STATIC_TLS: usize = 0x10;
+
+    elf.section(".dynamic").is_some_and(|dynamic| {
+        elf.contents(dynamic).chunks_exact(16).any(|entry| {
+            let tag = u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize;
+            let value = u64::from_le_bytes(entry[8..16].try_into().unwrap()) as usize;
+            tag == DT_FLAGS && value & DF_STATIC_TLS != 0
+        })
+    })
+}
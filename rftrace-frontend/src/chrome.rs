@@ -0,0 +1,314 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::iter;
+use std::mem;
+use std::num::NonZeroU64;
+use std::path::Path;
+
+use crate::block::block_record;
+use crate::frontend::{disable, get_events, CallStack, DumpOptions, EmptyTrace, Events};
+use crate::gaps::tracing_gaps;
+use crate::interface::*;
+use crate::net::Packet;
+use crate::symbols::Symbols;
+use crate::uftrace::{parse_uftrace_records, UftraceRecordType};
+
+/// PID of all threads in traces written from events, the same as in the metadata written by `dump_full_uftrace()`.
+const PID: u64 = 42;
+
+/// Converts the uftrace directory `dir` into a JSON trace at `out`, for the Chrome trace viewer or Perfetto.
+///
+/// Works like `uftrace dump --chrome`, without needing uftrace, for any uftrace directory: dumps of rftrace as well as
+/// recordings of uftrace itself. Functions are named from `<exename>.sym`, relocated to where the session map places the
+/// binary if it is position independent, or by address if there are no symbols. Timestamps are taken as nanoseconds,
+/// like uftrace does. Encrypted traces can not be converted.
+pub fn convert_uftrace_dir_to_chrome(dir: &str, out: &str) -> io::Result<()> {
+    println!("Converting {} to {}", dir, out);
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let info = fs::read(format!("{}/info", dir))?;
+    if info.len() < 40 || &info[0..8] != b"Ftrace!\x00" {
+        return Err(invalid(format!("{}/info is not a uftrace info file", dir)));
+    }
+    let lines = String::from_utf8_lossy(&info[40..]);
+    let value = |key: &str| {
+        lines
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+    };
+    if value("rftrace:encryption").is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "encrypted traces can not be converted",
+        ));
+    }
+    let checksum = value("rftrace:checksum").is_some();
+    let tids: BTreeSet<u64> = value("taskinfo:tids")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|tid| tid.parse().ok())
+        .collect();
+
+    // e.g. SESS timestamp=0.0 pid=1 sid=00 exename="/bin/ls"
+    //      TASK timestamp=0.0 tid=2 pid=1
+    let task = fs::read_to_string(format!("{}/task.txt", dir)).unwrap_or_default();
+    let field = |line: &str, key: &str| -> Option<String> {
+        line.split_whitespace()
+            .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+            .map(|value| value.trim_matches('"').to_string())
+    };
+    let sess = task.lines().find(|line| line.starts_with("SESS "));
+    let sid = sess.and_then(|line| field(line, "sid"));
+    let exename = sess.and_then(|line| field(line, "exename"));
+    let pids: HashMap<u64, u64> = task
+        .lines()
+        .filter(|line| line.starts_with("TASK "))
+        .filter_map(|line| {
+            Some((
+                field(line, "tid")?.parse().ok()?,
+                field(line, "pid")?.parse().ok()?,
+            ))
+        })
+        .collect();
+
+    let symbols = exename.as_ref().and_then(|exename| {
+        let binary_name = Path::new(exename).file_name()?.to_string_lossy();
+        let mut symbols = Symbols::load(&format!("{}/{}.sym", dir, binary_name)).ok()?;
+        // Symbols of position independent executables start near 0, below where the binary is mapped
+        let base = sid
+            .as_ref()
+            .and_then(|sid| map_base(&format!("{}/sid-{}.map", dir, sid), exename));
+        let first = symbols.iter().next().map(|(addr, _)| addr);
+        if let (Some(base), Some(first)) = (base, first) {
+            if first < base {
+                symbols.relocate(base);
+            }
+        }
+        Some(symbols)
+    });
+    let name = |addr: u64| match symbols
+        .as_ref()
+        .and_then(|symbols| symbols.lookup(addr as usize))
+    {
+        Some((name, _)) => json_string(name),
+        None => format!("\"{:#x}\"", addr),
+    };
+
+    let mut json = BufWriter::new(File::create(out)?);
+    write!(json, "{{\"traceEvents\":[")?;
+    let mut first = true;
+    for tid in &tids {
+        let records = parse_uftrace_records(&fs::read(format!("{}/{}.dat", dir, tid))?, checksum)
+            .map_err(|err| invalid(format!("{}.dat: {}", tid, err)))?;
+        println!("  Converting {} records of TID {}", records.len(), tid);
+        let pid = pids.get(tid).copied().unwrap_or(*tid);
+        for record in records {
+            let (phase, name) = match record.kind {
+                UftraceRecordType::Entry => ("B", name(record.addr)),
+                UftraceRecordType::Exit => ("E", name(record.addr)),
+                UftraceRecordType::Lost => ("i", "\"lost\"".to_string()),
+                UftraceRecordType::Event => ("i", format!("\"event {:#x}\"", record.addr)),
+            };
+            if !first {
+                write!(json, ",")?;
+            }
+            first = false;
+            write!(
+                json,
+                "\n{{\"ts\":{}.{:03},\"ph\":\"{}\",\"pid\":{},\"tid\":{},\"name\":{}",
+                record.time / 1000,
+                record.time % 1000,
+                phase,
+                pid,
+                tid,
+                name
+            )?;
+            if phase == "i" {
+                write!(json, ",\"s\":\"t\"")?;
+            }
+            write!(json, "}}")?;
+        }
+    }
+    writeln!(json, "\n],\"displayTimeUnit\":\"ns\"}}")?;
+    json.flush()
+}
+
+/// Takes the event buffer back from the backend and writes it as JSON trace to `outfile`, for the Chrome trace viewer or Perfetto.
+///
+/// Unlike `dump_full_uftrace()` followed by `uftrace dump --chrome`, this needs no uftrace. Functions are named by address,
+/// use `write_chrome_trace()` to name them from symbols. Fails with `EmptyTrace` if no events were recorded.
+pub fn dump_chrome_trace(events: &mut Events, outfile: &str) -> io::Result<()> {
+    dump_chrome_trace_with(events, outfile, &DumpOptions::default())
+}
+
+/// Like `dump_chrome_trace()`, but with additional `options`, see `write_chrome_trace()`.
+pub fn dump_chrome_trace_with(
+    events: &mut Events,
+    outfile: &str,
+    options: &DumpOptions,
+) -> io::Result<()> {
+    disable();
+    let events = get_events(events)?;
+    write_chrome_trace(&events, outfile, options, None)
+}
+
+/// Writes `events`, ordered oldest first, as JSON trace in the Trace Event Format to `outfile`.
+///
+/// Calls are written as `B`/`E` events of their thread, named from `symbols` or by address. Timestamps are converted
+/// from TSC cycles to microseconds with the frequency of `options.clock`, or taken as nanoseconds if there is none, like
+/// uftrace does. Periods in which tracing was disabled (see `tracing_gaps()`) are marked by global instant events, so
+/// they are not mistaken for idle time. Network packets (see `packets()`) and block requests (see `block_requests()`)
+/// are marked by instant events of the thread handling them.
+/// Of the other options, only `exclude` and `max_recursion` apply.
+///
+/// Fails with `EmptyTrace` if there is not a single entry or exit.
+pub fn write_chrome_trace(
+    events: &[Event],
+    outfile: &str,
+    options: &DumpOptions,
+    symbols: Option<&Symbols>,
+) -> io::Result<()> {
+    if !events
+        .iter()
+        .any(|e| matches!(e, Event::Entry(_) | Event::Exit(_)))
+    {
+        return Err(io::Error::other(EmptyTrace));
+    }
+    println!("Creating Chrome trace at {}..", outfile);
+
+    let ts = |time: u64| {
+        let time = options
+            .clock
+            .as_ref()
+            .map_or(time, |clock| clock.convert(time));
+        format!("{}.{:03}", time / 1000, time % 1000)
+    };
+    let name = |addr: u64| match symbols.and_then(|symbols| symbols.lookup(addr as usize)) {
+        Some((name, _)) => json_string(name),
+        None => format!("\"{:#x}\"", addr),
+    };
+    let mut first = true;
+    let mut separator = || {
+        if mem::replace(&mut first, false) {
+            ""
+        } else {
+            ","
+        }
+    };
+
+    let mut json = BufWriter::new(File::create(outfile)?);
+    write!(json, "{{\"traceEvents\":[")?;
+    // Replayed per thread, so excluded and collapsed calls are dropped along with their exits
+    let mut stacks: HashMap<Option<NonZeroU64>, CallStack> = HashMap::new();
+    for e in events {
+        let (phase, time, tid, addr) = match e {
+            Event::Entry(e) => {
+                if !stacks.entry(e.tid).or_default().entry(e.to, options) {
+                    continue;
+                }
+                ("B", e.time, e.tid, e.to)
+            }
+            Event::Exit(e) => {
+                if !stacks.entry(e.tid).or_default().exit() {
+                    continue;
+                }
+                ("E", e.time, e.tid, e.from)
+            }
+            Event::Record(r) => {
+                // Device activity is shown as instants on the thread handling it, alongside its calls
+                if let Some(name) = instant_name(r) {
+                    write!(
+                        json,
+                        "{}\n{{\"ts\":{},\"ph\":\"i\",\"s\":\"t\",\"pid\":{},\"tid\":{},\"name\":\"{}\"}}",
+                        separator(),
+                        ts(r.time),
+                        PID,
+                        r.tid.map_or(0, NonZeroU64::get),
+                        name
+                    )?;
+                }
+                continue;
+            }
+            Event::Empty => continue,
+        };
+        write!(
+            json,
+            "{}\n{{\"ts\":{},\"ph\":\"{}\",\"pid\":{},\"tid\":{},\"name\":{}}}",
+            separator(),
+            ts(time),
+            phase,
+            PID,
+            tid.map_or(0, NonZeroU64::get),
+            name(addr)
+        )?;
+    }
+
+    for gap in tracing_gaps(events) {
+        let disabled = if gap.buffer_full {
+            "event buffer full"
+        } else {
+            "tracing disabled"
+        };
+        let marks = iter::once((gap.start, gap.tid, disabled))
+            .chain(gap.end.map(|end| (end, None, "tracing enabled")));
+        for (time, tid, name) in marks {
+            write!(
+                json,
+                "{}\n{{\"ts\":{},\"ph\":\"i\",\"s\":\"g\",\"pid\":{},\"tid\":{},\"name\":\"{}\"}}",
+                separator(),
+                ts(time),
+                PID,
+                tid.map_or(0, NonZeroU64::get),
+                name
+            )?;
+        }
+    }
+    writeln!(json, "\n],\"displayTimeUnit\":\"ns\"}}")?;
+    json.flush()
+}
+
+/// Returns the name of the instant event `record` is shown as, for network packets and block requests.
+fn instant_name(record: &Record) -> Option<String> {
+    if let Some(packet) = Packet::from_record(record) {
+        let dir = if packet.tx { "tx" } else { "rx" };
+        return Some(format!(
+            "net {} {} bytes (queue {})",
+            dir, packet.len, packet.queue
+        ));
+    }
+    let (completion, sector, len, write) = block_record(record)?;
+    Some(format!(
+        "block {} {} {} bytes at sector {}",
+        if write { "write" } else { "read" },
+        if completion { "complete" } else { "submit" },
+        len,
+        sector
+    ))
+}
+
+/// Returns the address `exename` is mapped at according to the session map `path`, in the format of `/proc/<pid>/maps`.
+fn map_base(path: &str, exename: &str) -> Option<usize> {
+    let maps = fs::read_to_string(path).ok()?;
+    maps.lines()
+        .filter(|line| line.ends_with(exename))
+        .find_map(|line| usize::from_str_radix(line.split('-').next()?, 16).ok())
+}
+
+/// Quotes and escapes `s` as JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => write!(quoted, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
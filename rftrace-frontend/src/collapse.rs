@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use crate::interface::*;
+use crate::symbols::Symbols;
+
+/// Path prefixes of Rust's standard library crates, the default for `collapse_library_frames()`.
+pub const LIBRARY_PREFIXES: &[&str] = &["core::", "alloc::", "std::"];
+
+/// Returns `events` without the calls of library functions, folding them into their application-level caller.
+///
+/// Functions are library functions if their name starts with one of `prefixes`, such as `LIBRARY_PREFIXES`.
+/// This includes trait implementations on library types, such as `<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop`,
+/// but not implementations of library traits on application types. Calls made by library functions, such as closures
+/// passed to iterator adapters, are kept and appear as direct callees of the application function.
+/// The time spent in library code is attributed to the caller, which makes exports of Rust code, such as flamegraphs, far more readable.
+/// Functions not found in `symbols` are kept. The result can be passed to any export, such as `write_full_uftrace()`.
+pub fn collapse_library_frames(
+    events: &[Event],
+    symbols: &Symbols,
+    prefixes: &[&str],
+) -> Vec<Event> {
+    let mut library: HashMap<usize, bool> = HashMap::new();
+    let mut is_library = |addr: usize| {
+        *library.entry(addr).or_insert_with(|| {
+            symbols.name(addr).is_some_and(|name| {
+                let path = name.strip_prefix('<').unwrap_or(name);
+                prefixes.iter().any(|prefix| path.starts_with(prefix))
+            })
+        })
+    };
+
+    let collapsed: Vec<Event> = events
+        .iter()
+        .filter(|e| match e {
+            Event::Entry(e) => !is_library(e.to as usize),
+            Event::Exit(e) => !is_library(e.from as usize),
+            Event::Record(_) | Event::Empty => true,
+        })
+        .copied()
+        .collect();
+    println!(
+        "  Collapsed {} library entries and exits",
+        events.len() - collapsed.len()
+    );
+    collapsed
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::num::NonZeroU64;
+
+use crate::frontend::{disable, get_events, write_foreign_uftrace, DumpOptions, Events};
+use crate::interface::*;
+
+const MAGIC: &[u8; 8] = b"rftrcmp\x01";
+
+// Flags in the first byte of each encoded event
+const FLAG_EXIT: u8 = 1 << 0;
+const FLAG_NEW_TID: u8 = 1 << 1;
+
+/// Dumps the events in a compact format, for guests with slow I/O. Convert it with `compact_to_uftrace()` afterwards.
+///
+/// Timestamps are stored as varint deltas to the previous event, addresses as varint deltas to the previous address of the same thread.
+/// This is usually 3-5x smaller than the uftrace format. Only function entries and exits are stored, records are dropped.
+/// Entries do not keep their call site, which uftrace does not use either.
+pub fn dump_compact(events: &mut Events, outfile: &str) -> io::Result<()> {
+    disable();
+    println!("Saving compact trace to {}...", outfile);
+    let events = get_events(events)?;
+    let mut out = BufWriter::new(File::create(outfile)?);
+    let written = write_compact(&events, &mut out)?;
+    out.flush()?;
+    println!("  Wrote {} events", written);
+    Ok(())
+}
+
+/// Encodes `events`, which have to be ordered oldest first, in the compact format of `dump_compact()`.
+///
+/// Returns the number of encoded events.
+pub fn write_compact<W: Write>(events: &[Event], out: &mut W) -> io::Result<usize> {
+    out.write_all(MAGIC)?;
+
+    let mut last_time = 0u64;
+    let mut last_tid = None;
+    let mut last_addrs: HashMap<Option<NonZeroU64>, u64> = HashMap::new();
+    let mut buf = Vec::with_capacity(32);
+    let mut written = 0;
+    for e in events {
+        let (flags, time, tid, addr) = match e {
+            Event::Entry(e) => (0, e.time, e.tid, e.to),
+            Event::Exit(e) => (FLAG_EXIT, e.time, e.tid, e.from),
+            Event::Record(_) | Event::Empty => continue,
+        };
+
+        buf.clear();
+        let new_tid = tid != last_tid;
+        buf.push(flags | if new_tid { FLAG_NEW_TID } else { 0 });
+        if new_tid {
+            write_varint(&mut buf, tid.map_or(0, |tid| tid.get()));
+        }
+        // Events of different threads are not strictly ordered, so deltas can be negative
+        write_varint(&mut buf, zigzag(time.wrapping_sub(last_time)));
+        let last_addr = last_addrs.entry(tid).or_insert(0);
+        write_varint(&mut buf, zigzag(addr.wrapping_sub(*last_addr)));
+        out.write_all(&buf)?;
+
+        last_time = time;
+        last_tid = tid;
+        *last_addr = addr;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Decodes a trace written by `write_compact()` back into events, oldest first.
+pub fn read_compact(data: &[u8]) -> io::Result<Vec<Event>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut data = data
+        .strip_prefix(&MAGIC[..])
+        .ok_or_else(|| invalid("Not a compact rftrace file"))?;
+
+    let mut events = Vec::new();
+    let mut time = 0u64;
+    let mut tid = None;
+    let mut addrs: HashMap<Option<NonZeroU64>, u64> = HashMap::new();
+    while let Some((&flags, rest)) = data.split_first() {
+        data = rest;
+        let mut next = || read_varint(&mut data).ok_or_else(|| invalid("Truncated event"));
+
+        if flags & FLAG_NEW_TID != 0 {
+            tid = NonZeroU64::new(next()?);
+        }
+        time = time.wrapping_add(unzigzag(next()?));
+        let delta = unzigzag(next()?);
+        let addr = addrs.entry(tid).or_insert(0);
+        *addr = addr.wrapping_add(delta);
+
+        events.push(if flags & FLAG_EXIT != 0 {
+            Event::Exit(Exit {
+                time,
+                from: *addr,
+                tid,
+            })
+        } else {
+            Event::Entry(Call {
+                time,
+                from: 0,
+                to: *addr,
+                tid,
+            })
+        });
+    }
+    Ok(events)
+}
+
+/// Converts a trace written by `dump_compact()` into a uftrace directory, see `write_full_uftrace()`.
+pub fn compact_to_uftrace(infile: &str, out_dir: &str, binary_name: &str) -> io::Result<()> {
+    println!("Converting compact trace {}...", infile);
+    let events = read_compact(&fs::read(infile)?)?;
+    write_foreign_uftrace(&events, out_dir, binary_name, &DumpOptions::default())
+}
+
+fn zigzag(value: u64) -> u64 {
+    (value << 1) ^ ((value as i64 >> 63) as u64)
+}
+
+fn unzigzag(value: u64) -> u64 {
+    (value >> 1) ^ (value & 1).wrapping_neg()
+}
+
+/// Writes `value` as LEB128 varint.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads a LEB128 varint, advancing `data`.
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// Splits `events`, which have to be ordered oldest first, by the context ID they were recorded in, see `set_context()`.
+///
+/// The context of each thread is replayed from its context switch records, which are dropped.
+/// Events recorded outside of any context, or before the first switch of their thread, end up under ID 0.
+/// Each group keeps the original order, so it can be dumped like a full trace, for example with `write_full_uftrace()`.
+pub fn split_by_context(events: &[Event]) -> HashMap<u64, Vec<Event>> {
+    let mut groups: HashMap<u64, Vec<Event>> = HashMap::new();
+    let mut contexts: HashMap<Option<NonZeroU64>, u64> = HashMap::new();
+
+    for e in events {
+        let tid = match e {
+            Event::Entry(e) => e.tid,
+            Event::Exit(e) => e.tid,
+            Event::Record(r) if r.kind == RECORD_KIND_CONTEXT => {
+                if let Ok(payload) = r.payload[..r.len as usize].try_into() {
+                    contexts.insert(r.tid, u64::from_le_bytes(payload));
+                }
+                continue;
+            }
+            Event::Record(r) => r.tid,
+            Event::Empty => continue,
+        };
+        let context = contexts.get(&tid).copied().unwrap_or(0);
+        groups.entry(context).or_default().push(*e);
+    }
+
+    groups
+}
@@ -0,0 +1,44 @@
+use std::io::{self};
+
+use crate::frontend::{disable, dump_full_uftrace, enable, is_enabled, stats, Events};
+
+const HELP: &str = "commands: enable, disable, status, dump [out_dir], help";
+
+/// Executes a textual control command, so tracing can be controlled at runtime from a debug console or a control hypercall.
+///
+/// Supported commands are `enable`, `disable`, `status`, `dump [out_dir]` and `help`.
+/// `dump` writes a full uftrace directory (see `dump_full_uftrace()`) into `out_dir`, defaulting to the current directory.
+/// Returns a human-readable response, or an `InvalidInput` error for unknown commands.
+pub fn control(events: &mut Events, binary_name: &str, command: &str) -> io::Result<String> {
+    let mut args = command.split_whitespace();
+    match args.next() {
+        Some("enable") => {
+            enable();
+            Ok("tracing enabled".into())
+        }
+        Some("disable") => {
+            disable();
+            Ok("tracing disabled".into())
+        }
+        Some("status") => {
+            let stats = stats();
+            Ok(format!(
+                "tracing {}, {} entries, {} returns, {} filtered",
+                if is_enabled() { "enabled" } else { "disabled" },
+                stats.entries,
+                stats.returns,
+                stats.filtered
+            ))
+        }
+        Some("dump") => {
+            let out_dir = args.next().unwrap_or(".");
+            dump_full_uftrace(events, out_dir, binary_name)?;
+            Ok(format!("trace dumped to {}", out_dir))
+        }
+        Some("help") | None => Ok(HELP.into()),
+        Some(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown command '{}', {}", other, HELP),
+        )),
+    }
+}
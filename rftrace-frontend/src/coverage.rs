@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+
+use crate::interface::*;
+use crate::lines::LineMap;
+use crate::symbols::Symbols;
+
+/// Counts the recorded entries of each function in `events`, keyed by function address.
+///
+/// In sampling mode, only sampled functions are counted, so use full tracing for coverage.
+pub fn coverage(events: &[Event]) -> HashMap<usize, u64> {
+    let mut counts: HashMap<usize, u64> = HashMap::new();
+    for e in events {
+        if let Event::Entry(e) = e {
+            *counts.entry(e.to as usize).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Writes an lcov tracefile with the function coverage of `events` to `path`, for `genhtml` or coverage services.
+///
+/// Covers all functions in `symbols`, which have to be relocated like the trace, so functions which were never entered
+/// show up as not hit. Source files and lines are taken from `lines`, which should resolve the addresses of all `symbols`,
+/// for example `LineMap::resolve(binary, symbols.iter().map(|(addr, _)| addr), load_base)`. Functions without a source
+/// location are left out. Only function granularity is known, so no line coverage is written.
+pub fn write_lcov(
+    events: &[Event],
+    symbols: &Symbols,
+    lines: &LineMap,
+    test_name: &str,
+    path: &str,
+) -> io::Result<()> {
+    let counts = coverage(events);
+
+    // Source file, and line, name and entry count of each of its functions
+    let mut files: BTreeMap<&str, Vec<(u32, &str, u64)>> = BTreeMap::new();
+    let mut last = None;
+    for (addr, name) in symbols.iter() {
+        // Aliases share the address of the first symbol
+        if last.replace(addr) == Some(addr) {
+            continue;
+        }
+        let (file, line) = match lines
+            .get(addr)
+            .and_then(|location| location.rsplit_once(':'))
+        {
+            Some((file, line)) => (file, line),
+            None => continue,
+        };
+        // addr2line might append a discriminator, or print `?` for unknown lines
+        let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+        let line = line[..digits].parse().unwrap_or(0);
+        let count = counts.get(&addr).copied().unwrap_or(0);
+        files.entry(file).or_default().push((line, name, count));
+    }
+
+    println!(
+        "Writing lcov coverage of {} source files to {}..",
+        files.len(),
+        path
+    );
+    let mut out = BufWriter::new(File::create(path)?);
+    for (file, functions) in &files {
+        writeln!(out, "TN:{}", test_name)?;
+        writeln!(out, "SF:{}", file)?;
+        for (line, name, _) in functions {
+            writeln!(out, "FN:{},{}", line, name)?;
+        }
+        for (_, name, count) in functions {
+            writeln!(out, "FNDA:{},{}", count, name)?;
+        }
+        let hit = functions.iter().filter(|(_, _, count)| *count > 0).count();
+        writeln!(out, "FNF:{}", functions.len())?;
+        writeln!(out, "FNH:{}", hit)?;
+        writeln!(out, "end_of_record")?;
+    }
+    out.flush()
+}
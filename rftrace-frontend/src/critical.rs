@@ -0,0 +1,186 @@
+use std::fmt::Write as _;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+use crate::symbols::Symbols;
+
+/// Start or end of the span a critical path is extracted from, see `critical_path()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathBound {
+    /// A record of this kind, such as an application record marking the arrival of a request, see `record()`.
+    Record(u8),
+    /// A call of the function at this address. The span starts at its entry, or ends at its exit.
+    Function(usize),
+}
+
+/// A call along a critical path, see `critical_path()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathStep {
+    /// Address of the called function.
+    pub addr: usize,
+    /// Start of the call, or of the span if the call started before it.
+    pub start: u64,
+    /// End of the call, or of the span if the call ended after it.
+    pub end: u64,
+}
+
+impl PathStep {
+    /// Duration of the call within the span, in TSC cycles.
+    pub fn duration(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// The longest chain of nested calls between two bounds, see `critical_path()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CriticalPath {
+    /// Thread the path was followed on.
+    pub tid: Option<NonZeroU64>,
+    /// Start of the span.
+    pub start: u64,
+    /// End of the span.
+    pub end: u64,
+    /// Calls along the path, outermost first. Each call is the longest one made by its predecessor within the span.
+    pub steps: Vec<PathStep>,
+}
+
+impl CriticalPath {
+    /// Formats the path as human-readable report, one call per line, with function names looked up in `symbols`.
+    ///
+    /// Lists the duration of each call and its self time, which is not spent in the next call along the path.
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut report = String::new();
+        writeln!(
+            report,
+            "TID {}: {} cycles from {} to {}",
+            self.tid.map_or(0, NonZeroU64::get),
+            self.end - self.start,
+            self.start,
+            self.end
+        )
+        .unwrap();
+        for (depth, step) in self.steps.iter().enumerate() {
+            let inner = self.steps.get(depth + 1).map_or(0, PathStep::duration);
+            let name = symbols
+                .name(step.addr)
+                .map_or_else(|| format!("{:#x}", step.addr), str::to_string);
+            writeln!(
+                report,
+                "  {:>12} {:>12}  {:indent$}{}",
+                step.duration(),
+                step.duration() - inner,
+                "",
+                name,
+                indent = 2 * depth
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+/// A completed or still running call, as matched from the events of a single thread.
+struct Call {
+    addr: usize,
+    start: u64,
+    end: u64,
+    depth: usize,
+}
+
+/// Extracts the critical path from `from` to `to` in `events`, which have to be ordered oldest first.
+///
+/// The span starts at the first event matching `from`, and ends at the first event matching `to` after it. Starting at
+/// the outermost call, the path repeatedly follows the longest call made within the span, so it shows where the time
+/// between the two bounds went, and where optimizing pays off. It stays on the thread of `from`, since threads block
+/// each other only through locks and channels rftrace does not see.
+///
+/// Returns `None` if either bound is not found, or no call overlaps the span.
+pub fn critical_path(events: &[Event], from: PathBound, to: PathBound) -> Option<CriticalPath> {
+    let (first, start, tid) = events
+        .iter()
+        .enumerate()
+        .find_map(|(i, e)| match (e, from) {
+            (Event::Record(r), PathBound::Record(kind)) if r.kind == kind => {
+                Some((i, r.time, r.tid))
+            }
+            (Event::Entry(e), PathBound::Function(addr)) if e.to as usize == addr => {
+                Some((i, e.time, e.tid))
+            }
+            _ => None,
+        })?;
+    let end = events[first + 1..].iter().find_map(|e| match (e, to) {
+        (Event::Record(r), PathBound::Record(kind)) if r.kind == kind => Some(r.time),
+        (Event::Exit(e), PathBound::Function(addr)) if e.from as usize == addr => Some(e.time),
+        _ => None,
+    })?;
+
+    let calls = thread_calls(events, tid);
+    let mut steps: Vec<PathStep> = Vec::new();
+    let mut depth = calls
+        .iter()
+        .filter(|call| call.start < end && call.end > start)
+        .map(|call| call.depth)
+        .min()?;
+    let (mut outer_start, mut outer_end) = (start, end);
+    // Calls at the next depth within the current step are its callees, since the calls of a thread nest
+    while let Some(call) = calls
+        .iter()
+        .filter(|call| call.depth == depth && call.start < outer_end && call.end > outer_start)
+        .max_by_key(|call| call.end.min(outer_end) - call.start.max(outer_start))
+    {
+        outer_start = call.start.max(outer_start);
+        outer_end = call.end.min(outer_end);
+        steps.push(PathStep {
+            addr: call.addr,
+            start: outer_start,
+            end: outer_end,
+        });
+        depth += 1;
+    }
+
+    Some(CriticalPath {
+        tid,
+        start,
+        end,
+        steps,
+    })
+}
+
+/// Matches the entries and exits of thread `tid` in `events` to calls.
+///
+/// Calls without a recorded exit last until the thread's last event. Exits without a matching entry are skipped.
+fn thread_calls(events: &[Event], tid: Option<NonZeroU64>) -> Vec<Call> {
+    let mut calls = Vec::new();
+    let mut stack: Vec<(usize, u64)> = Vec::new();
+    let mut last = 0;
+    for e in events {
+        match e {
+            Event::Entry(e) if e.tid == tid => {
+                stack.push((e.to as usize, e.time));
+                last = e.time;
+            }
+            Event::Exit(e) if e.tid == tid => {
+                if let Some((addr, start)) = stack.pop() {
+                    calls.push(Call {
+                        addr,
+                        start,
+                        end: e.time,
+                        depth: stack.len(),
+                    });
+                }
+                last = e.time;
+            }
+            Event::Record(r) if r.tid == tid => last = r.time,
+            _ => {}
+        }
+    }
+    while let Some((addr, start)) = stack.pop() {
+        calls.push(Call {
+            addr,
+            start,
+            end: last,
+            depth: stack.len(),
+        });
+    }
+    calls
+}
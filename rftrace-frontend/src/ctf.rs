@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self};
+use std::num::NonZeroU64;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::interface::*;
+
+/// Magic number starting every CTF packet.
+const CTF_MAGIC: u32 = 0xc1fc1fc1;
+
+/// Size of the packet header and context written before the events of each stream, in bytes.
+const PACKET_HEADER_LEN: usize = 40;
+
+const EVENT_ID_ENTRY: u32 = 0;
+const EVENT_ID_EXIT: u32 = 1;
+const EVENT_ID_RECORD: u32 = 2;
+
+/// Writes `events`, ordered oldest first, as Common Trace Format (CTF 1.8) trace into `out_dir`, which has to exist.
+///
+/// This allows loading traces in Trace Compass or babeltrace. The trace consists of a `metadata` file and one stream file per thread.
+/// Entries and exits use the event names and fields of LTTng's `lttng_ust_cyg_profile` tracepoints, so Trace Compass
+/// shows them in its call stack view. Records are written as `rftrace:record` events with their raw payload.
+///
+/// `tsc_freq` is the frequency of the timestamp counter in Hz, used to convert timestamps to wall time.
+pub fn write_ctf(events: &[Event], out_dir: &str, tsc_freq: u64) -> io::Result<()> {
+    println!("Creating CTF trace at {}..", out_dir);
+
+    // Each thread gets its own stream, so the events within one stream are ordered
+    let mut streams: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    let mut times: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for e in events {
+        let (tid, time) = match e {
+            Event::Entry(e) => (e.tid, e.time),
+            Event::Exit(e) => (e.tid, e.time),
+            Event::Record(r) => (r.tid, r.time),
+            Event::Empty => continue,
+        };
+        let tid = tid.map_or(0, NonZeroU64::get);
+        let out = streams.entry(tid).or_default();
+        let (begin, end) = times.entry(tid).or_insert((time, time));
+        *begin = time.min(*begin);
+        *end = time.max(*end);
+
+        match e {
+            Event::Entry(e) => {
+                write_event_header(out, EVENT_ID_ENTRY, e.time, tid);
+                out.write_u64::<LittleEndian>(e.to)?; // addr
+                out.write_u64::<LittleEndian>(e.from)?; // call_site
+            }
+            Event::Exit(e) => {
+                write_event_header(out, EVENT_ID_EXIT, e.time, tid);
+                out.write_u64::<LittleEndian>(e.from)?; // addr
+                out.write_u64::<LittleEndian>(0)?; // call_site, not recorded for exits
+            }
+            Event::Record(r) => {
+                write_event_header(out, EVENT_ID_RECORD, r.time, tid);
+                out.write_u8(r.kind)?;
+                out.write_u8(r.len)?;
+                out.write_all(&r.payload)?;
+            }
+            Event::Empty => unreachable!(),
+        }
+    }
+
+    for (tid, out) in &streams {
+        let (begin, end) = times[tid];
+        let size = (PACKET_HEADER_LEN + out.len()) as u64 * 8;
+
+        let mut packet = Vec::with_capacity(PACKET_HEADER_LEN);
+        packet.write_u32::<LittleEndian>(CTF_MAGIC)?;
+        packet.write_u32::<LittleEndian>(0)?; // stream_id
+        packet.write_u64::<LittleEndian>(begin)?;
+        packet.write_u64::<LittleEndian>(end)?;
+        packet.write_u64::<LittleEndian>(size)?; // content_size, in bits
+        packet.write_u64::<LittleEndian>(size)?; // packet_size, in bits
+
+        let filename = format!("{}/stream_{}", out_dir, tid);
+        println!("  Creating {} ({} bytes)", filename, size / 8);
+        let mut file = File::create(filename)?;
+        file.write_all(&packet)?;
+        file.write_all(out)?;
+    }
+
+    println!("  Creating {}/metadata", out_dir);
+    let mut metadata = File::create(format!("{}/metadata", out_dir))?;
+    write!(metadata, "{}", ctf_metadata(tsc_freq))?;
+    Ok(())
+}
+
+fn write_event_header(out: &mut Vec<u8>, id: u32, time: u64, tid: u64) {
+    out.write_u32::<LittleEndian>(id)
+        .expect("Write interrupted");
+    out.write_u64::<LittleEndian>(time)
+        .expect("Write interrupted");
+    // The _vtid event context, which is signed in LTTng
+    out.write_i32::<LittleEndian>(tid as i32)
+        .expect("Write interrupted");
+}
+
+/// Returns the TSDL metadata describing the stream files of `write_ctf()`.
+fn ctf_metadata(tsc_freq: u64) -> String {
+    // The LTTng environment makes Trace Compass apply its userspace analyses
+    format!(
+        r#"/* CTF 1.8 */
+
+typealias integer {{ size = 8; align = 8; signed = false; }} := uint8_t;
+typealias integer {{ size = 32; align = 8; signed = false; }} := uint32_t;
+typealias integer {{ size = 32; align = 8; signed = true; }} := int32_t;
+typealias integer {{ size = 64; align = 8; signed = false; }} := uint64_t;
+typealias integer {{ size = 64; align = 8; signed = false; base = 16; }} := uint64_xt;
+
+trace {{
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {{
+        uint32_t magic;
+        uint32_t stream_id;
+    }};
+}};
+
+env {{
+    domain = "ust";
+    tracer_name = "lttng-ust";
+    tracer_major = 2;
+    tracer_minor = 13;
+    producer = "rftrace";
+}};
+
+clock {{
+    name = "tsc";
+    description = "Timestamp counter";
+    freq = {};
+    offset = 0;
+}};
+
+typealias integer {{ size = 64; align = 8; signed = false; map = clock.tsc.value; }} := tsc_t;
+
+stream {{
+    id = 0;
+    packet.context := struct {{
+        tsc_t timestamp_begin;
+        tsc_t timestamp_end;
+        uint64_t content_size;
+        uint64_t packet_size;
+    }};
+    event.header := struct {{
+        uint32_t id;
+        tsc_t timestamp;
+    }};
+    event.context := struct {{
+        int32_t _vtid;
+    }};
+}};
+
+event {{
+    name = "lttng_ust_cyg_profile:func_entry";
+    id = {};
+    stream_id = 0;
+    fields := struct {{
+        uint64_xt _addr;
+        uint64_xt _call_site;
+    }};
+}};
+
+event {{
+    name = "lttng_ust_cyg_profile:func_exit";
+    id = {};
+    stream_id = 0;
+    fields := struct {{
+        uint64_xt _addr;
+        uint64_xt _call_site;
+    }};
+}};
+
+event {{
+    name = "rftrace:record";
+    id = {};
+    stream_id = 0;
+    fields := struct {{
+        uint8_t _kind;
+        uint8_t _len;
+        uint8_t _payload[{}];
+    }};
+}};
+"#,
+        tsc_freq, EVENT_ID_ENTRY, EVENT_ID_EXIT, EVENT_ID_RECORD, RECORD_PAYLOAD_LEN
+    )
+}
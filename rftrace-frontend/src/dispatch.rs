@@ -0,0 +1,95 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::interface::*;
+use crate::symbols::Symbols;
+
+/// All functions called from a single call site, see `call_sites()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallSite {
+    /// Return address of the call within the caller, right behind the call instruction.
+    pub site: usize,
+    /// Called function addresses and how often each was called, most frequent first.
+    pub targets: Vec<(usize, u64)>,
+}
+
+impl CallSite {
+    /// Returns whether more than one function was called from this site, which requires an indirect call.
+    pub fn is_polymorphic(&self) -> bool {
+        self.targets.len() > 1
+    }
+
+    /// Total number of calls made from this site.
+    pub fn calls(&self) -> u64 {
+        self.targets.iter().map(|&(_, count)| count).sum()
+    }
+}
+
+/// Aggregates the resolved targets of each call site in `events`, ordered by address.
+///
+/// Every entry records its call site besides the callee. Use `DumpOptions::call_sites` to keep them in dumps as well.
+pub fn call_sites(events: &[Event]) -> Vec<CallSite> {
+    let mut targets: HashMap<usize, HashMap<usize, u64>> = HashMap::new();
+    for e in events {
+        if let Event::Entry(e) = e {
+            *targets
+                .entry(e.from as usize)
+                .or_default()
+                .entry(e.to as usize)
+                .or_default() += 1;
+        }
+    }
+
+    let mut sites: Vec<CallSite> = targets
+        .into_iter()
+        .map(|(site, targets)| {
+            let mut targets: Vec<(usize, u64)> = targets.into_iter().collect();
+            targets.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            CallSite { site, targets }
+        })
+        .collect();
+    sites.sort_unstable_by_key(|site| site.site);
+    sites
+}
+
+/// Returns the call sites of `events` which dispatched to more than one function, such as calls through trait objects or function pointers.
+///
+/// Useful for devirtualization and for debugging unexpected dispatch. Indirect calls which always resolved to
+/// the same function can not be told apart from direct calls and are left out. Ordered by number of calls, most first.
+pub fn indirect_call_sites(events: &[Event]) -> Vec<CallSite> {
+    let mut sites: Vec<CallSite> = call_sites(events)
+        .into_iter()
+        .filter(CallSite::is_polymorphic)
+        .collect();
+    sites.sort_by_key(|site| Reverse(site.calls()));
+    sites
+}
+
+/// Formats `sites`, such as returned by `indirect_call_sites()`, as human-readable report, one call site per paragraph.
+///
+/// Call sites are shown as `caller+offset` and targets by name, if found in `symbols`.
+pub fn dispatch_report(sites: &[CallSite], symbols: Option<&Symbols>) -> String {
+    let mut report = String::new();
+    for site in sites {
+        let caller = match symbols.and_then(|symbols| symbols.lookup(site.site)) {
+            Some((name, offset)) => format!("{}+{:#x}", name, offset),
+            None => format!("{:#x}", site.site),
+        };
+        writeln!(
+            report,
+            "{} ({} calls, {} targets)",
+            caller,
+            site.calls(),
+            site.targets.len()
+        )
+        .unwrap();
+        for &(target, count) in &site.targets {
+            let target = symbols
+                .and_then(|symbols| symbols.name(target))
+                .map_or_else(|| format!("{:#x}", target), str::to_owned);
+            writeln!(report, "  {:>10}  {}", count, target).unwrap();
+        }
+    }
+    report
+}
@@ -0,0 +1,114 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self};
+
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce, Tag};
+
+/// Magic number starting every encrypted file. Also authenticated, so it can not be swapped.
+const MAGIC: &[u8; 8] = b"rftrenc\x01";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Size of the header written before the encrypted data, see `decrypt_trace()`.
+pub(crate) const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN;
+
+/// Size of the authentication tag written after the encrypted data.
+pub(crate) const TRAILER_LEN: usize = TAG_LEN;
+
+/// 256 bit key for encrypting dumped trace files with AES-256-GCM, see `DumpOptions::encryption`.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Uses `key`, which should be random, such as read from a key file or `/dev/urandom`.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    // Never print the key itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Returns a new nonce, read from the OS's random source.
+fn nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::fill(&mut nonce).expect("Could not read a nonce from the OS's random source");
+    nonce
+}
+
+/// Encrypts `data` in place, returning the header to write before and the tag to write after it.
+pub(crate) fn encrypt_in_place(
+    key: &EncryptionKey,
+    data: &mut [u8],
+) -> ([u8; HEADER_LEN], [u8; TRAILER_LEN]) {
+    let nonce = nonce();
+    let tag = key
+        .cipher()
+        .encrypt_in_place_detached(Nonce::from_slice(&nonce), MAGIC, data)
+        .expect("Trace too large to encrypt");
+
+    let mut header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&nonce);
+    (header, tag.into())
+}
+
+/// Decrypts a file written with `DumpOptions::encryption`, such as a trace file, with the `key` it was encrypted with.
+///
+/// Fails with `InvalidData` if `data` is not encrypted, was encrypted with another key, or was modified or truncated.
+/// The decrypted trace file can be parsed with `parse_uftrace_records()`.
+pub fn decrypt_trace(data: &[u8], key: &EncryptionKey) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if data.len() < HEADER_LEN + TRAILER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("file is not an encrypted rftrace file"));
+    }
+
+    let nonce = &data[MAGIC.len()..HEADER_LEN];
+    let (encrypted, tag) = data[HEADER_LEN..].split_at(data.len() - HEADER_LEN - TRAILER_LEN);
+    let tag: [u8; TAG_LEN] = tag.try_into().unwrap();
+    let mut decrypted = encrypted.to_vec();
+    key.cipher()
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            MAGIC,
+            &mut decrypted,
+            &Tag::from(tag),
+        )
+        .map_err(|_| {
+            invalid("decryption failed: wrong key, or the file was modified or truncated")
+        })?;
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_round_trip() {
+        let key = EncryptionKey::new([1; 32]);
+        let mut data = b"trace".to_vec();
+        let (header, trailer) = encrypt_in_place(&key, &mut data);
+        let file = [&header[..], &data, &trailer[..]].concat();
+        assert_eq!(decrypt_trace(&file, &key).unwrap(), b"trace");
+    }
+
+    #[test]
+    fn decrypt_wrong_key() {
+        let mut data = b"trace".to_vec();
+        let (header, trailer) = encrypt_in_place(&EncryptionKey::new([1; 32]), &mut data);
+        let file = [&header[..], &data, &trailer[..]].concat();
+        let err = decrypt_trace(&file, &EncryptionKey::new([2; 32])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
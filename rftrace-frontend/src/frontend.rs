@@ -1,72 +1,1378 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{self};
+use std::marker::PhantomData;
+use std::mem;
+use std::num::NonZeroU64;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, slice};
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
+use crate::anonymize::anonymize_name;
+#[cfg(feature = "encryption")]
+use crate::encryption::EncryptionKey;
 use crate::interface::*;
+use crate::resources::{write_resources_event, Resources, RESOURCES_EVENT_LEN};
+use crate::uftrace::{verify_uftrace_dir, write_footer, FOOTER_LEN};
 
 extern "C" {
     fn rftrace_backend_enable();
     fn rftrace_backend_disable();
-    fn rftrace_backend_init(bufptr: *mut Event, len: usize, overwriting: bool);
+    fn rftrace_backend_is_enabled() -> bool;
+    fn rftrace_backend_suppress();
+    fn rftrace_backend_unsuppress();
+    fn rftrace_backend_set_scoped(scoped: bool);
+    fn rftrace_backend_scope_begin();
+    fn rftrace_backend_scope_end();
+    fn rftrace_backend_init(bufptr: *mut Event, len: usize, overwriting: bool) -> InitStatus;
+    fn rftrace_backend_init_per_thread(
+        buffers: *const CoreBuffer,
+        len: usize,
+        overwriting: bool,
+    ) -> InitStatus;
+    fn rftrace_backend_take_per_thread() -> *const CoreBuffer;
+    fn rftrace_backend_replace_buffer(
+        bufptr: *mut Event,
+        len: usize,
+        overwriting: bool,
+        previous: *mut *const Event,
+    ) -> InitStatus;
+    fn rftrace_backend_swap_buffer(
+        bufptr: *mut Event,
+        len: usize,
+        index: *mut usize,
+    ) -> *const Event;
     fn rftrace_backend_get_events() -> *const Event;
     fn rftrace_backend_get_events_index() -> usize;
+    fn rftrace_backend_attach(len: *mut usize) -> *const Event;
+    fn rftrace_backend_detach();
+    fn rftrace_backend_interface_version() -> u32;
+    fn rftrace_backend_record(kind: u8, payload: *const u8, len: usize);
+    fn rftrace_backend_get_stats(stats: *mut Stats);
+    fn rftrace_backend_set_exclude(ranges: *const AddrRange, len: usize);
+    fn rftrace_backend_set_filter(ranges: *const AddrRange, len: usize);
+    fn rftrace_backend_set_sample_period(period: u64);
+    fn rftrace_backend_set_function_quota(quota: u64, rate: u64);
+    fn rftrace_backend_set_thread_quota(quota: u64);
+    fn rftrace_backend_set_max_depth(depth: usize);
+    fn rftrace_backend_set_tid_offset(offset: u64);
+    fn rftrace_backend_set_overhead_budget(budget: u64, period: u64);
+    fn rftrace_backend_set_pressure_handler(handler: Option<extern "C" fn() -> usize>);
+    fn rftrace_backend_full_trace_begin();
+    fn rftrace_backend_full_trace_end();
+    fn rftrace_backend_set_flight_recorder(bufptr: *mut Event, len: usize);
+    fn rftrace_backend_set_page_filter(bitmap: *const u64, len: usize, base_page: usize);
+    fn rftrace_backend_set_context(context: u64) -> u64;
+    fn rftrace_backend_thread_exit();
+    fn rftrace_backend_switch_stack(old: *mut StackContext, new: *mut StackContext);
+}
+
+// Resolves to null instead of failing to link if the staticlib backend is missing, see `check_linked()`.
+// mcount itself is no reliable probe, since glibc provides one for gprof.
+extern "C" {
+    #[linkage = "extern_weak"]
+    static RFTRACE_DESCRIPTOR: *const u8;
+}
+
+/// The staticlib backend of the `rftrace` crate, which is used unless another backend is set with `set_backend()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaticlibBackend;
+
+impl Backend for StaticlibBackend {
+    fn interface_version(&self) -> u32 {
+        unsafe { rftrace_backend_interface_version() }
+    }
+
+    fn enable(&self) {
+        unsafe { rftrace_backend_enable() }
+    }
+
+    fn disable(&self) {
+        unsafe { rftrace_backend_disable() }
+    }
+
+    fn is_enabled(&self) -> bool {
+        unsafe { rftrace_backend_is_enabled() }
+    }
+
+    unsafe fn init(&self, buf: *mut Event, len: usize, overwriting: bool) -> InitStatus {
+        rftrace_backend_init(buf, len, overwriting)
+    }
+
+    fn get_events(&self) -> *const Event {
+        unsafe { rftrace_backend_get_events() }
+    }
+
+    fn get_events_index(&self) -> usize {
+        unsafe { rftrace_backend_get_events_index() }
+    }
+}
+
+// None if the staticlib backend is used
+static BACKEND: OnceLock<Option<&'static dyn Backend>> = OnceLock::new();
+
+/// Uses `backend` instead of the staticlib backend for enabling, disabling, initializing and dumping.
+///
+/// Has to be called before any of these, returns `false` if a backend is already in use.
+/// All other functions, such as filters or `attach()`, are specific to the staticlib backend.
+pub fn set_backend(backend: &'static dyn Backend) -> bool {
+    BACKEND.set(Some(backend)).is_ok()
+}
+
+fn backend() -> &'static dyn Backend {
+    BACKEND.get_or_init(|| None).unwrap_or(&StaticlibBackend)
+}
+
+/// Returns an error if the staticlib backend is used, but not linked into the application. Checked by `init()`.
+///
+/// This happens if the application depends on the `rftrace` crate, but never uses it, so it is not linked and nothing calls the backend's `mcount`.
+/// The backend is probed with a weak symbol, since calling into it would crash or silently record nothing.
+/// A custom backend set with `set_backend()` needs no staticlib backend, so this always succeeds then.
+pub fn check_linked() -> io::Result<()> {
+    if let Some(Some(_)) = BACKEND.get() {
+        return Ok(());
+    }
+    if unsafe { RFTRACE_DESCRIPTOR }.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the rftrace backend is not linked, so nothing is traced. Add `extern crate rftrace as _;` to the application",
+        ));
+    }
+    Ok(())
 }
 
 /// Enables tracing in the backend.
+///
+/// Records the current wall-clock time as `RECORD_KIND_WALLCLOCK`, so timestamps can be shown as absolute times,
+/// see `wallclock_anchor()`.
 pub fn enable() {
-    unsafe { rftrace_backend_enable() }
+    backend().enable();
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        record(
+            RECORD_KIND_WALLCLOCK,
+            &(now.as_nanos() as u64).to_le_bytes(),
+        );
+    }
+}
+
+/// Returns the most recent TSC timestamp in `events` with the Unix time in nanoseconds it corresponds to, see `enable()`.
+///
+/// Can be passed as `OtlpOptions::anchor`. Dumps write it into the `info` file as `rftrace:wallclock_time` and
+/// `rftrace:wallclock_ns`, with the time converted like all other timestamps.
+pub fn wallclock_anchor(events: &[Event]) -> Option<(u64, u64)> {
+    events.iter().rev().find_map(|e| match e {
+        Event::Record(r) if r.kind == RECORD_KIND_WALLCLOCK && r.len >= 8 => Some((
+            r.time,
+            u64::from_le_bytes(r.payload[..8].try_into().unwrap()),
+        )),
+        _ => None,
+    })
+}
+
+/// Disables tracing in the backend.
+pub fn disable() {
+    backend().disable()
+}
+
+/// Returns whether the backend is currently recording.
+///
+/// Cheap enough to guard expensive annotation work, such as formatting a `record()` payload.
+/// Tracing may also have been disabled by the backend itself, once a non-overwriting buffer is full.
+pub fn is_enabled() -> bool {
+    backend().is_enabled()
+}
+
+/// Options for dumping a trace, see `dump_full_uftrace_with()`.
+///
+/// The default options dump every recorded event unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    /// Collapse directly self-recursive calls nested deeper than this.
+    ///
+    /// Only the outermost `max_recursion` frames of such a recursion are kept. They still span the full time,
+    /// so totals are preserved. The number of collapsed frames is reported while dumping.
+    pub max_recursion: Option<usize>,
+    /// Drop functions within these address ranges, such as idle or spin loops. Calls made by them are kept.
+    ///
+    /// Like `set_exclude_filter()`, but applied at dump time.
+    pub exclude: Vec<Range<usize>>,
+    /// Right-shift all timestamps by this many bits, dividing them by `2^time_shift`.
+    ///
+    /// Coarse traces rarely need full TSC resolution, and the dropped low bits make large traces compress much better.
+    /// The shift is recorded as `rftrace:time_shift` in the info file. uftrace itself is not aware of it, so it reports durations divided as well.
+    pub time_shift: u32,
+    /// Additionally write the call site of each dumped entry into a parallel `<tid>.callsites` file, see `read_call_sites()`.
+    ///
+    /// uftrace only keeps the callee of each call, which hides indirect call targets and cross-module call edges.
+    pub call_sites: bool,
+    /// Write the trace files in place through memory mappings, instead of building each one in memory first. Only on Linux, ignored elsewhere.
+    ///
+    /// Saves the memory and copying of a second full-size buffer, which makes dumping very large traces much faster.
+    pub mmap: bool,
+    /// Append a footer with the record count and CRC-32 to each trace file, so truncated or corrupted dumps can be detected.
+    ///
+    /// `read_uftrace_records()` verifies it. uftrace itself does not know the footer and warns about an invalid record at the end.
+    /// Recorded as `rftrace:checksum=crc32` in the info file.
+    pub checksum: bool,
+    /// Encrypt the trace and call sites files with AES-256-GCM using this key, for tracing proprietary workloads on shared infrastructure.
+    ///
+    /// The metadata files stay readable. Decrypt the files with `decrypt_trace()` before passing them to uftrace or `parse_uftrace_records()`.
+    /// Recorded as `rftrace:encryption=aes-256-gcm` in the info file.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<EncryptionKey>,
+    /// Convert all timestamps into this clock domain, such as the host's boot time for traces of a guest.
+    ///
+    /// Lets merged host and guest views, for example in Perfetto after `uftrace dump --chrome`, line up.
+    /// Applied before `time_shift`. Recorded as `rftrace:clock`, `rftrace:clock_freq` and `rftrace:clock_offset` in the info file.
+    pub clock: Option<ClockDomain>,
+    /// Where within the output directory the trace is written, and whether an existing trace may be overwritten.
+    pub layout: OutputLayout,
+    /// Retry writing files which fail with transient errors, as they happen on virtio-fs and network filesystems.
+    ///
+    /// With retries, a file which still can not be written does not abort the dump: the remaining files are written,
+    /// and the dump then fails with an error listing the missing files.
+    pub retry: Option<RetryPolicy>,
+    /// Re-parse the dumped directory with `verify_uftrace_dir()` once it is written, and print all problems found.
+    pub verify: bool,
+    /// Keep only the most recent events whose trace and call sites files fit into this many bytes, for guests with tiny filesystems.
+    ///
+    /// Calls stay paired within each thread: exits whose entry was dropped are dropped as well.
+    /// Checksum footers and encryption add a few bytes per file on top.
+    pub max_output_bytes: Option<usize>,
+    /// Replace the binary name and all paths in the metadata files by hashes, so traces can be shared without revealing the code structure.
+    ///
+    /// Timestamps and addresses are kept, so the timing structure stays intact. Write the symbols with `anonymize_symbols()`
+    /// into the `.sym` file of the hashed binary name, see `anonymize_name()`. Recorded as `rftrace:anonymized=fnv1a` in the info file.
+    pub anonymize: bool,
+}
+
+/// How often and how patiently failed writes are retried, see `DumpOptions::retry`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Number of retries per file.
+    pub retries: u32,
+    /// Delay before the first retry. It doubles with each further retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Layout of dumped uftrace directories, see `DumpOptions::layout`.
+///
+/// By default, the trace is written directly into the output directory, overwriting a previous trace there.
+/// With a `prefix` or `timestamped`, every dump gets its own session directory within it instead, so repeated runs do not clobber each other.
+#[derive(Clone, Debug, Default)]
+pub struct OutputLayout {
+    /// Write the trace into the session directory `<out_dir>/<prefix>`, such as `run-`.
+    pub prefix: Option<String>,
+    /// Append the current UTC time to the session directory name, such as `run-20261016-134501`.
+    pub timestamped: bool,
+    /// Fail with `AlreadyExists` instead of overwriting, if the directory already holds a trace.
+    pub no_overwrite: bool,
+}
+
+impl OutputLayout {
+    /// Returns the directory a trace is written to, creating it within `out_dir` if needed.
+    fn prepare(&self, out_dir: &str) -> io::Result<String> {
+        let dir = if self.prefix.is_some() || self.timestamped {
+            let mut name = self.prefix.clone().unwrap_or_default();
+            if self.timestamped {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs());
+                name.push_str(&utc_timestamp(now));
+            }
+            let dir = format!("{}/{}", out_dir, name);
+            fs::create_dir_all(&dir)?;
+            dir
+        } else {
+            out_dir.to_string()
+        };
+
+        if self.no_overwrite && Path::new(&dir).join("info").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already holds a trace", dir),
+            ));
+        }
+        Ok(dir)
+    }
+}
+
+/// Formats `secs` since the UNIX epoch as UTC date and time, such as `20261016-134501`.
+fn utc_timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Converts days to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Clock domain the timestamps of a dump are converted into, see `DumpOptions::clock`.
+#[derive(Clone, Debug, Default)]
+pub struct ClockDomain {
+    /// Name of the clock, such as `boottime` or `guest-tsc`. Only recorded, so tools know which timestamps they can merge.
+    pub name: String,
+    /// Frequency of the recorded timestamps in Hz, usually the TSC frequency. If set, timestamps are converted to nanoseconds.
+    ///
+    /// 0 keeps them in TSC cycles, for example if guest and host share the TSC.
+    pub freq: u64,
+    /// Added to every converted timestamp, such as the clock's value at TSC 0. Timestamps which would become negative are clamped to 0.
+    pub offset: i64,
+}
+
+impl ClockDomain {
+    /// Converts the TSC timestamp `time` into this clock domain.
+    pub(crate) fn convert(&self, time: u64) -> u64 {
+        let time = if self.freq > 0 {
+            u128::from(time) * 1_000_000_000 / u128::from(self.freq)
+        } else {
+            u128::from(time)
+        };
+        (time as i128 + i128::from(self.offset)).clamp(0, i128::from(u64::MAX)) as u64
+    }
+}
+
+/// Suppresses recording on the current thread until the returned guard is dropped.
+///
+/// Useful to exclude known-noisy regions, such as logging or serialization, without disabling tracing globally.
+/// Guards can be nested. Functions entered before the suppression still record their exits.
+pub fn suppress() -> SuppressGuard {
+    unsafe { rftrace_backend_suppress() };
+    SuppressGuard {
+        _thread: PhantomData,
+    }
+}
+
+/// Guard returned by `suppress()`. Resumes recording on the current thread when dropped.
+#[must_use = "recording resumes as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct SuppressGuard {
+    // Suppression is per thread, so the guard must not leave it
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for SuppressGuard {
+    fn drop(&mut self) {
+        unsafe { rftrace_backend_unsuppress() }
+    }
+}
+
+/// Used to keep track of event buffer given to the staticlib
+#[derive(Copy, Clone, Debug)]
+pub struct Events {
+    ptr: *mut Event,
+    len: usize,
+    cap: usize,
+    /// Buffers of `init_per_thread()`, recorded into instead of `ptr` if non-empty
+    threads: &'static [CoreBuffer],
+    overwriting: bool,
+}
+
+/// Takes the buffers of `events` back from the backend, which does not record into them anymore afterwards.
+fn take_buffers(events: &Events) -> io::Result<()> {
+    if !events.threads.is_empty() {
+        let ptr = unsafe { rftrace_backend_take_per_thread() };
+        if ptr.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Per-thread buffers are unavailable: already dumped",
+            ));
+        }
+        assert!(
+            ptr == events.threads.as_ptr(),
+            "Per-thread buffer pointer mismatch! Was the backend initialized again?"
+        );
+        return Ok(());
+    }
+
+    // Tell backend to not use the current buffer anymore. It waits for writers still in progress, so the buffer is ours afterwards.
+    let ptr = backend().get_events();
+    println!("{:?}, {:?}", ptr, events);
+    if ptr.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "Event buffer is unavailable: still attached by another frontend or already dumped",
+        ));
+    }
+    assert!(
+        ptr == events.ptr,
+        "Event buffer pointer mismatch! Was it replaced with replace_buffer()?"
+    );
+    Ok(())
+}
+
+/// Takes the event buffer back from the backend, returning the events oldest first.
+pub(crate) fn get_events(events: &mut Events) -> io::Result<Vec<Event>> {
+    take_buffers(events)?;
+    if !events.threads.is_empty() {
+        return Ok(merge_thread_events(events));
+    }
+
+    let mut eventvec = unsafe { Vec::from_raw_parts(events.ptr, events.len, events.cap) };
+
+    let idx = backend().get_events_index();
+    // Rotate the ring buffer, so the oldest event comes first
+    let cidx = idx % eventvec.len();
+    eventvec.rotate_left(cidx);
+    let injected = mem::take(&mut INJECTED.lock().unwrap().0);
+    Ok(splice_injected(eventvec, &injected))
+}
+
+/// Returns the events of all per-thread buffers taken back from the backend, merged by timestamp.
+fn merge_thread_events(events: &Events) -> Vec<Event> {
+    let threads =
+        unsafe { Box::from_raw(events.threads as *const [CoreBuffer] as *mut [CoreBuffer]) };
+    let mut merged = Vec::with_capacity(events.len);
+    for thread in threads.iter() {
+        let mut eventvec = unsafe { Vec::from_raw_parts(thread.events, thread.len, thread.len) };
+        // Rotate each ring buffer, so the oldest event comes first
+        let cidx = thread.index.load(Ordering::Relaxed) % eventvec.len();
+        eventvec.rotate_left(cidx);
+        merged.extend(eventvec.into_iter().filter(|e| event_time(e).is_some()));
+    }
+    // Each buffer is already ordered, so a stable sort merges them without reordering the events of a thread
+    merged.sort_by_key(event_time);
+    let injected = mem::take(&mut INJECTED.lock().unwrap().0);
+    splice_injected(merged, &injected)
+}
+
+/// Frees the buffers of `events`, which were taken back from the backend.
+fn drop_buffers(events: &mut Events) {
+    if events.threads.is_empty() {
+        drop(unsafe { Vec::from_raw_parts(events.ptr, events.len, events.cap) });
+    } else {
+        let threads =
+            unsafe { Box::from_raw(events.threads as *const [CoreBuffer] as *mut [CoreBuffer]) };
+        for thread in threads.iter() {
+            drop(unsafe { Vec::from_raw_parts(thread.events, thread.len, thread.len) });
+        }
+    }
+    *events = Events {
+        ptr: std::ptr::null_mut(),
+        len: 0,
+        cap: 0,
+        threads: &[],
+        overwriting: events.overwriting,
+    };
+}
+
+/// Disables tracing and frees the event buffer of `events`, discarding the events recorded so far.
+///
+/// `init()` leaks the buffer, since the backend might record into it until the end of the application. Once done
+/// tracing, long-running applications can release it with this instead, or dump it, which frees it as well.
+/// Tracing can be initialized again afterwards. Fails with `WouldBlock` if another frontend is attached to the buffer,
+/// or it was already dumped or freed.
+pub fn free(events: &mut Events) -> io::Result<()> {
+    disable();
+    take_buffers(events)?;
+    drop_buffers(events);
+    INJECTED.lock().unwrap().0.clear();
+    Ok(())
+}
+
+/// Discards the events recorded into `events` and enables tracing again, recording into the same buffer from the start.
+///
+/// Allows taking repeated traces with a single buffer, without allocating a new one with `init()` each time, for example
+/// after writing a snapshot with `attach()`. Fails like `free()`. If the backend rejects the cleared buffer, because
+/// someone else initialized it meanwhile, the buffer is freed and the error returned.
+pub fn reset(events: &mut Events) -> io::Result<()> {
+    disable();
+    take_buffers(events)?;
+    INJECTED.lock().unwrap().0.clear();
+
+    let status = if events.threads.is_empty() {
+        unsafe { slice::from_raw_parts_mut(events.ptr, events.len) }.fill(Event::Empty);
+        unsafe { backend().init(events.ptr, events.cap, events.overwriting) }
+    } else {
+        for thread in events.threads {
+            unsafe { slice::from_raw_parts_mut(thread.events, thread.len) }.fill(Event::Empty);
+            thread.index.store(0, Ordering::Relaxed);
+        }
+        unsafe {
+            rftrace_backend_init_per_thread(
+                events.threads.as_ptr(),
+                events.threads.len(),
+                events.overwriting,
+            )
+        }
+    };
+    if status != InitStatus::Ok {
+        drop_buffers(events);
+        return Err(init_error(status));
+    }
+    enable();
+    Ok(())
+}
+
+/// A frontend attached to an event buffer owned by someone else, such as the Hermit kernel.
+///
+/// Allows taking snapshots of the recorded events without taking the buffer away from its owner.
+/// While attached, the owner can not dump the buffer. Detaches when dropped.
+#[derive(Debug)]
+pub struct Attachment {
+    ptr: *const Event,
+    len: usize,
+}
+
+/// Attaches to the event buffer currently used by the backend.
+///
+/// Returns `None` if no event buffer has been initialized yet, or its owner is taking it back right now.
+pub fn attach() -> Option<Attachment> {
+    let mut len = 0;
+    let ptr = unsafe { rftrace_backend_attach(&mut len) };
+    if ptr.is_null() {
+        return None;
+    }
+    Some(Attachment { ptr, len })
+}
+
+impl Attachment {
+    /// Copies the events recorded so far, oldest first.
+    ///
+    /// Tracing is not stopped, so events recorded concurrently may be missing or torn.
+    pub fn snapshot(&self) -> Vec<Event> {
+        let events = unsafe { slice::from_raw_parts(self.ptr, self.len) };
+        let cidx = unsafe { rftrace_backend_get_events_index() } % self.len;
+
+        let mut snapshot = Vec::with_capacity(self.len);
+        snapshot.extend_from_slice(&events[cidx..]);
+        snapshot.extend_from_slice(&events[..cidx]);
+        splice_injected(snapshot, &INJECTED.lock().unwrap().0)
+    }
+
+    /// Returns the size of the event buffer in events.
+    pub fn buffer_len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the current global event index, see `events_since()`.
+    pub fn index(&self) -> usize {
+        unsafe { rftrace_backend_get_events_index() }
+    }
+
+    /// Copies the events recorded since the global event index `since`, oldest first, and returns the current index.
+    ///
+    /// Pass the returned index to the next call to continuously stream events while tracing continues.
+    /// This only makes sense for overwriting buffers, since a full non-overwriting buffer stops tracing.
+    /// If more events than fit into the buffer were recorded since, the overwritten ones are skipped.
+    pub fn events_since(&self, since: usize) -> (Vec<Event>, usize) {
+        let events = unsafe { slice::from_raw_parts(self.ptr, self.len) };
+        let index = unsafe { rftrace_backend_get_events_index() };
+
+        let start = since.max(index.saturating_sub(self.len));
+        if start > since {
+            println!("  Lost {} events, which were overwritten", start - since);
+        }
+
+        let new = (start..index).map(|i| events[i % self.len]).collect();
+        (new, index)
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        unsafe { rftrace_backend_detach() }
+    }
+}
+
+// Filter tables handed to the backend, kept alive until they are replaced. The backend no longer reads a replaced table
+// once its setter returned, so it can be freed then.
+static EXCLUDE_FILTER: Mutex<Vec<AddrRange>> = Mutex::new(Vec::new());
+static ADDRESS_FILTER: Mutex<Vec<AddrRange>> = Mutex::new(Vec::new());
+static PAGE_FILTER: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+fn addr_ranges(ranges: &[Range<usize>]) -> Vec<AddrRange> {
+    ranges
+        .iter()
+        .map(|range| AddrRange {
+            start: range.start,
+            end: range.end,
+        })
+        .collect()
+}
+
+/// Excludes functions within the given address ranges from being recorded, such as idle or spin loops.
+///
+/// Calls made by excluded functions are still recorded. Excluded entries are counted in `Stats::filtered`.
+/// Replaces previously set ranges, also while tracing. Must not be called from a signal handler interrupting traced code,
+/// since it waits for the backend's hooks to stop reading the previous ranges.
+pub fn set_exclude_filter(ranges: &[Range<usize>]) {
+    let mut current = EXCLUDE_FILTER.lock().unwrap();
+    let ranges = addr_ranges(ranges);
+    unsafe { rftrace_backend_set_exclude(ranges.as_ptr(), ranges.len()) }
+    *current = ranges;
+}
+
+/// Records only functions within the given address ranges, such as the application's own code without libstd or the kernel.
+///
+/// Calls made by other functions are still recorded if they are within the ranges. Dropped entries are counted in
+/// `Stats::filtered`, and `set_exclude_filter()` still applies within the ranges. An empty slice records all functions again.
+/// Replaces previously set ranges, like `set_exclude_filter()`.
+pub fn set_address_filter(ranges: &[Range<usize>]) {
+    let mut current = ADDRESS_FILTER.lock().unwrap();
+    let ranges = addr_ranges(ranges);
+    unsafe { rftrace_backend_set_filter(ranges.as_ptr(), ranges.len()) }
+    *current = ranges;
+}
+
+/// Excludes all functions on the 4 KiB code pages touched by the given address ranges from being recorded.
+///
+/// Unlike `set_exclude_filter()`, the check in the hot path is a single bitmap lookup independent of the number of ranges,
+/// so it is cheap enough to leave on in production. The price is page granularity: other functions sharing a page with
+/// an excluded one are excluded as well. Ranges can be obtained from resolved symbols, see `Symbols::ranges()`.
+/// Replaces a previously set page filter, like `set_exclude_filter()`.
+pub fn set_page_filter(ranges: &[Range<usize>]) {
+    const PAGE_SHIFT: usize = 12;
+    let pages =
+        |range: &Range<usize>| (range.start >> PAGE_SHIFT)..=((range.end - 1) >> PAGE_SHIFT);
+
+    let ranges: Vec<&Range<usize>> = ranges.iter().filter(|range| !range.is_empty()).collect();
+    let first = ranges.iter().map(|range| *pages(range).start()).min();
+    let last = ranges.iter().map(|range| *pages(range).end()).max();
+    let mut current = PAGE_FILTER.lock().unwrap();
+    let (first, last) = match first.zip(last) {
+        Some(bounds) => bounds,
+        None => {
+            unsafe { rftrace_backend_set_page_filter(core::ptr::null(), 0, 0) };
+            current.clear();
+            return;
+        }
+    };
+
+    let mut bitmap = vec![0u64; (last - first) / 64 + 1];
+    for range in ranges {
+        for page in pages(range) {
+            let bit = page - first;
+            bitmap[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+    println!(
+        "Filtering {} code pages",
+        bitmap.iter().map(|word| word.count_ones()).sum::<u32>()
+    );
+
+    unsafe { rftrace_backend_set_page_filter(bitmap.as_ptr(), bitmap.len(), first) }
+    *current = bitmap;
+}
+
+/// Switches between full tracing and sampling mode at runtime.
+///
+/// With a non-zero `period`, function calls are no longer recorded individually. Instead, each thread samples its current
+/// call stack at most every `period` TSC cycles, when entering a function. A sampled frame is written as an entry at the
+/// time it was first sampled and an exit when it actually returns, so samples can be dumped and viewed just like a full trace,
+/// as a low-overhead statistical profile. A `period` of 0 switches back to full tracing.
+///
+/// Switching is possible at any time. Use `full_trace()` to fully trace critical sections while sampling everything else.
+pub fn set_sampling(period: u64) {
+    unsafe { rftrace_backend_set_sample_period(period) }
+}
+
+/// Demotes hot functions to sampled recording, keeping the buffer representative even when one hot path dominates.
+///
+/// The first `quota` entries of each function are recorded as usual. Afterwards, only every `rate`-th entry of that function
+/// is recorded, together with its exit, so the recorded calls stay balanced. A `rate` of 0 records no further entries at all.
+/// The entries of callees are counted separately, so they are still recorded until they exceed their own quota.
+/// Entries which were not recorded are counted in `Stats::demoted`. A `quota` of 0 disables the mode, which is the default.
+///
+/// Setting a quota resets the counters of all functions. Only applies to full tracing, not to sampling mode (see `set_sampling()`).
+/// The backend tracks up to a few thousand distinct functions, functions beyond that are never demoted.
+pub fn set_function_quota(quota: u64, rate: u64) {
+    unsafe { rftrace_backend_set_function_quota(quota, rate) }
+}
+
+/// Limits each thread to recording `quota` function entries, so a single busy thread can not fill the shared buffer alone.
+///
+/// Once a thread exceeded its quota, further entries of it are not recorded, and neither are their exits, so the recorded
+/// calls stay balanced. A thread thus takes up at most about `2 * quota` events, plus its records. Entries which were not
+/// recorded are counted in `Stats::quota_exceeded`. A `quota` of 0 disables the limit, which is the default.
+///
+/// Setting a quota resets the counters of all threads. Only applies to full tracing, not to sampling mode (see `set_sampling()`).
+pub fn set_thread_quota(quota: u64) {
+    unsafe { rftrace_backend_set_thread_quota(quota) }
+}
+
+/// Records only calls up to `depth` levels deep on each thread, so deep recursion does not fill the buffer, like uftrace's `-D`.
+///
+/// Deeper calls are neither recorded nor hooked, and counted in `Stats::filtered`. Calls skipped by a filter do not count
+/// as a level. A `depth` of 0 disables the limit, which is the default. Threads already deeper than a newly set limit keep
+/// returning through their hooked frames as usual.
+pub fn set_max_depth(depth: usize) {
+    unsafe { rftrace_backend_set_max_depth(depth) }
+}
+
+/// Bounds the overhead of tracing, so it can be left enabled in performance-sensitive code.
+///
+/// The backend counts the cycles spent in its hooks. While they exceed `budget`, a fraction of all cycles since the budget
+/// was set such as `0.02` for 2%, full tracing is throttled to sampling mode with the given `period` (see `set_sampling()`),
+/// which costs far less per call. Once the overhead fell below the budget again, calls are fully traced again.
+/// Calls are throttled as a whole, so their entries and exits stay balanced. Throttled entries are counted in
+/// `Stats::throttled`. A `budget` of 0 disables the limit, which is the default.
+///
+/// Only the hooks themselves are measured, not the `mcount()` call and the return trampoline around them, nor cache misses
+/// they cause in the traced code, so the actual overhead is somewhat higher. Does not apply within `full_trace()`.
+pub fn set_overhead_budget(budget: f64, period: u64) {
+    let budget = (budget * 1_000_000.0) as u64;
+    unsafe { rftrace_backend_set_overhead_budget(budget, period) }
+}
+
+/// Adds `offset` to the TIDs of all threads, so traces of several guests or processes can be merged without collisions.
+///
+/// Every backend numbers its threads starting at 1. Giving each traced program its own offset, such as `guest * 1_000_000`,
+/// moves its threads into a distinct range. Task IDs passed to `kernel::task_create()` in the backend are offset as well.
+/// Only applies to threads seen afterwards, so call it before `init()`.
+pub fn set_tid_offset(offset: u64) {
+    unsafe { rftrace_backend_set_tid_offset(offset) }
+}
+
+/// Fully traces the current thread until the returned guard is dropped, even in sampling mode (see `set_sampling()`).
+///
+/// Guards can be nested. Has no effect in full tracing mode.
+pub fn full_trace() -> FullTraceGuard {
+    unsafe { rftrace_backend_full_trace_begin() };
+    FullTraceGuard {
+        _thread: PhantomData,
+    }
+}
+
+/// Guard returned by `full_trace()`. Resumes sampling on the current thread when dropped.
+#[must_use = "sampling resumes as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct FullTraceGuard {
+    // Full tracing is per thread, so the guard must not leave it
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for FullTraceGuard {
+    fn drop(&mut self) {
+        unsafe { rftrace_backend_full_trace_end() }
+    }
+}
+
+/// Switches scoped tracing on or off. While on, threads are only recorded while they run a closure passed to `trace_scope()`.
+///
+/// Tracing still has to be enabled with `enable()`. Functions entered before a thread left its scope still record their exits.
+pub fn set_scoped_tracing(scoped: bool) {
+    unsafe { rftrace_backend_set_scoped(scoped) }
+}
+
+/// Runs `f` and records it on the current thread, even if other code is not recorded in scoped tracing mode (see `set_scoped_tracing()`).
+///
+/// This makes it easy to trace specific jobs submitted to rayon or other thread pools, without recording the pool itself
+/// or unrelated jobs: `pool.spawn(|| rftrace_frontend::trace_scope(job))`. Scopes can be nested.
+pub fn trace_scope<R>(f: impl FnOnce() -> R) -> R {
+    // Leaves the scope even if `f` panics, so the pool thread is not recorded forever
+    struct Scope;
+
+    impl Drop for Scope {
+        fn drop(&mut self) {
+            unsafe { rftrace_backend_scope_end() }
+        }
+    }
+
+    unsafe { rftrace_backend_scope_begin() };
+    let _scope = Scope;
+    f()
+}
+
+/// Sets the ID of the logical context, such as an async task or a request, the current thread works on.
+///
+/// The ID applies to all subsequent events of the thread until it is changed again, 0 meaning no context.
+/// Async executors should set it whenever they start polling a task, so events can be grouped by task
+/// even if it migrates between threads, see `split_by_context()`. Returns the previous context ID.
+///
+/// The switch is only recorded while tracing is enabled.
+pub fn set_context(context: u64) -> u64 {
+    unsafe { rftrace_backend_set_context(context) }
+}
+
+/// Sets the context ID of the current thread until the returned guard is dropped, see `set_context()`.
+///
+/// Guards can be nested, dropping one restores the previous context.
+pub fn enter_context(context: u64) -> ContextGuard {
+    ContextGuard {
+        previous: set_context(context),
+        _thread: PhantomData,
+    }
+}
+
+/// Guard returned by `enter_context()`. Restores the previous context ID of the current thread when dropped.
+#[must_use = "the context is restored as soon as the guard is dropped"]
+#[derive(Debug)]
+pub struct ContextGuard {
+    previous: u64,
+    // Contexts are per thread, so the guard must not leave it
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        set_context(self.previous);
+    }
+}
+
+/// Releases the backend's state of the current thread, which is about to exit.
+///
+/// Functions the thread is still in are closed with exit events and a `RECORD_KIND_THREAD_EXIT` record is written.
+/// Afterwards the thread starts over, so a new thread reusing its thread-locals, such as a Hermit task, gets a fresh TID.
+/// Intended for thread destructors or task teardown. Tracing the thread any further is harmless, but gives it a new TID.
+pub fn thread_exit() {
+    unsafe { rftrace_backend_thread_exit() }
+}
+
+/// Return stack of a fiber or green thread, saved by `switch_stack()` while the fiber is suspended.
+#[derive(Debug)]
+pub struct FiberStack {
+    // Storage of `context.frames`, only written by the backend
+    _frames: Vec<SavedRet>,
+    context: StackContext,
+}
+
+// The frames are only accessed through `switch_stack()`, on whichever thread resumes the fiber
+unsafe impl Send for FiberStack {}
+
+impl FiberStack {
+    /// Creates an empty return stack, for a fiber which was not started yet.
+    ///
+    /// Keeps up to `capacity` hooked functions the fiber is in while suspended. Functions beyond that, the innermost ones,
+    /// are unhooked instead, so their exits are not recorded. `FiberStack::default()` never loses any.
+    pub fn new(capacity: usize) -> Self {
+        let mut frames = Vec::with_capacity(capacity);
+        let context = StackContext {
+            frames: frames.as_mut_ptr(),
+            capacity,
+            len: 0,
+            tid: 0,
+        };
+        Self {
+            _frames: frames,
+            context,
+        }
+    }
+}
+
+impl Default for FiberStack {
+    /// Creates an empty return stack as deep as the one of a thread.
+    fn default() -> Self {
+        Self::new(MAX_STACK_HEIGHT)
+    }
+}
+
+/// Switches the backend to the return stack of another fiber or green thread, so stackful coroutines are traced correctly.
+///
+/// Userspace schedulers call this right before switching from the fiber saved into `old` to the fiber saved in `new`.
+/// The thread's own stack needs a `FiberStack` as well, to switch back to it. Each fiber records with its own TID, even
+/// when it is resumed by another thread. Functions called between this and the actual switch must not return before
+/// the fiber is resumed again, which is the case for a scheduler function calling both.
+///
+/// Not hooked itself, since it returns on the stack switched away from.
+#[link_section = "rftrace_notrace"]
+pub fn switch_stack(old: &mut FiberStack, new: &mut FiberStack) {
+    unsafe { rftrace_backend_switch_stack(&mut old.context, &mut new.context) }
+}
+
+/// Returns the current statistics counters of the backend.
+pub fn stats() -> Stats {
+    let mut stats = Stats::default();
+    unsafe { rftrace_backend_get_stats(&mut stats) };
+    stats
+}
+
+/// Records a custom event of the given `kind` into the trace, see `Record`.
+///
+/// Kinds below `RECORD_KIND_USER` are reserved for rftrace. `payload` is truncated to `RECORD_PAYLOAD_LEN` bytes.
+/// Nothing is recorded while tracing is disabled.
+pub fn record(kind: u8, payload: &[u8]) {
+    unsafe { rftrace_backend_record(kind, payload.as_ptr(), payload.len()) }
+}
+
+/// Kind of an event passed to `inject_event()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// Entry into the function at the given address.
+    Entry,
+    /// Exit from the function at the given address.
+    Exit,
+}
+
+/// Events passed to `inject_event()`, ordered by time, until they are spliced into a dump.
+static INJECTED: Mutex<InjectedEvents> = Mutex::new(InjectedEvents(Vec::new()));
+
+struct InjectedEvents(Vec<Event>);
+
+// The addresses in the events are never dereferenced.
+unsafe impl Send for InjectedEvents {}
+
+/// Adds a synthetic event of thread `tid` at TSC timestamp `time` to the trace, for events from foreign sources.
+///
+/// Lets adapters, for example for virtio device interrupt logs or host-side timestamps, show their events in the same
+/// timeline, such as an interrupt handled by the host as a call of a pseudo function at `addr`. Injected events are
+/// kept aside and spliced into the recorded events by their timestamps once they are dumped, so they may be injected
+/// in any order, and even after tracing was disabled. They are included in `Attachment::snapshot()` as well.
+/// Entries and exits of each thread should still pair up, like recorded calls.
+pub fn inject_event(tid: Option<NonZeroU64>, time: u64, kind: EventKind, addr: usize) {
+    let event = match kind {
+        EventKind::Entry => Event::Entry(Call {
+            time,
+            from: 0,
+            to: addr as u64,
+            tid,
+        }),
+        EventKind::Exit => Event::Exit(Exit {
+            time,
+            from: addr as u64,
+            tid,
+        }),
+    };
+    let injected = &mut INJECTED.lock().unwrap().0;
+    let idx = injected.partition_point(|e| event_time(e) <= Some(time));
+    injected.insert(idx, event);
+}
+
+/// Merges `injected`, ordered by time, into `events`, ordered oldest first, see `inject_event()`.
+fn splice_injected(events: Vec<Event>, injected: &[Event]) -> Vec<Event> {
+    if injected.is_empty() {
+        return events;
+    }
+    let mut spliced = Vec::with_capacity(events.len() + injected.len());
+    let mut injected = injected.iter().peekable();
+    for e in events {
+        // Empty events have no time, they stay where they are
+        if let Some(time) = event_time(&e) {
+            while let Some(&i) = injected.next_if(|i| event_time(i) <= Some(time)) {
+                spliced.push(i);
+            }
+        }
+        spliced.push(e);
+    }
+    spliced.extend(injected);
+    spliced
+}
+
+/// Returns the timestamp of `event`, or `None` if it is empty.
+fn event_time(event: &Event) -> Option<u64> {
+    match event {
+        Event::Entry(e) => Some(e.time),
+        Event::Exit(e) => Some(e.time),
+        Event::Record(r) => Some(r.time),
+        Event::Empty => None,
+    }
+}
+
+/// Initializes a new event buffer.
+///
+/// Allocs a new buffer of size `max_event_count` and passes it to the backend.
+/// If `overwriting`, treats it as a ring-buffer, keeping only the most-recent entries, otherwise it stopps logging once it is full.
+/// `max_event_count` will not be filled completely, since space is left for the returns of hooked functions.
+/// Currently, the maximum stack-depth is 1000. Consequently, `max_event_count` has to be greater than 1000.
+///
+/// Panics if the backend already records into another buffer, see `try_init()`.
+pub fn init(max_event_count: usize, overwriting: bool) -> &'static mut Events {
+    try_init(max_event_count, overwriting)
+        .unwrap_or_else(|err| panic!("Could not initialize event buffer: {}", err))
+}
+
+/// Like `init()`, but returns an error if the backend rejects the buffer.
+///
+/// Fails with `AlreadyExists` if the backend already records into another buffer, for example one initialized by the Hermit kernel.
+/// Use `attach()` to read that buffer instead, or `replace_buffer()` to take over.
+pub fn try_init(max_event_count: usize, overwriting: bool) -> io::Result<&'static mut Events> {
+    assert!(
+        max_event_count > MAX_STACK_HEIGHT,
+        "Event buffer has to be larger than maximum stack height!"
+    );
+    check_linked()?;
+    let backend_version = backend().interface_version();
+    assert!(
+        backend_version == INTERFACE_VERSION,
+        "Backend interface version {} does not match frontend version {}!",
+        backend_version,
+        INTERFACE_VERSION
+    );
+    let buf = vec![Event::Empty; max_event_count];
+    let (ptr, len, cap) = buf.into_raw_parts();
+    let status = unsafe { backend().init(ptr, cap, overwriting) };
+    if status != InitStatus::Ok {
+        // The backend did not take the buffer, so it is still ours
+        drop(unsafe { Vec::from_raw_parts(ptr, len, cap) });
+        return Err(init_error(status));
+    }
+
+    // intentionally leak here! stacks have to live until they are dumped or released with free().
+    Ok(Box::leak(Box::new(Events {
+        ptr,
+        len,
+        cap,
+        threads: &[],
+        overwriting,
+    })))
+}
+
+fn init_error(status: InitStatus) -> io::Error {
+    match status {
+        InitStatus::AlreadyInitialized => io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the backend already records into another event buffer",
+        ),
+        _ => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the backend rejected the event buffer: {:?}", status),
+        ),
+    }
+}
+
+/// Initializes a new event buffer like `init()`, replacing the one the backend currently records into, if any.
+///
+/// Recording continues in the new buffer right away, starting empty. The previous buffer is left to its owner,
+/// which can not dump it anymore. Specific to the staticlib backend.
+///
+/// Panics if another frontend is `attach()`ed to the previous buffer, since its owner may free it once replaced.
+pub fn replace_buffer(max_event_count: usize, overwriting: bool) -> &'static mut Events {
+    assert!(
+        max_event_count > MAX_STACK_HEIGHT,
+        "Event buffer has to be larger than maximum stack height!"
+    );
+    let buf = vec![Event::Empty; max_event_count];
+    let (ptr, len, cap) = buf.into_raw_parts();
+    let mut previous = std::ptr::null();
+    let status = unsafe { rftrace_backend_replace_buffer(ptr, cap, overwriting, &mut previous) };
+    if status != InitStatus::Ok {
+        drop(unsafe { Vec::from_raw_parts(ptr, len, cap) });
+        panic!(
+            "Could not replace event buffer ({:?}), is another frontend still attached to it?",
+            status
+        );
+    }
+    if !previous.is_null() {
+        println!("Replaced event buffer {:?} with {:?}", previous, ptr);
+    }
+    Box::leak(Box::new(Events {
+        ptr,
+        len,
+        cap,
+        threads: &[],
+        overwriting,
+    }))
+}
+
+/// Initializes one event buffer of `events_per_thread` events for each of up to `max_threads` threads.
+///
+/// Unlike with `init()`, threads do not contend on a single index shared by all of them, which skews timings on many cores.
+/// Each thread claims a buffer when it records its first event, threads beyond `max_threads` share the buffers round robin.
+/// The buffers are merged by timestamp when dumping. Like `init()`, they are either overwriting or stop logging once one is full.
+/// Specific to the staticlib backend.
+pub fn init_per_thread(
+    max_threads: usize,
+    events_per_thread: usize,
+    overwriting: bool,
+) -> io::Result<&'static mut Events> {
+    assert!(max_threads > 0, "At least one per-thread buffer is needed!");
+    assert!(
+        events_per_thread > MAX_STACK_HEIGHT,
+        "Event buffer has to be larger than maximum stack height!"
+    );
+    check_linked()?;
+    let backend_version = unsafe { rftrace_backend_interface_version() };
+    assert!(
+        backend_version == INTERFACE_VERSION,
+        "Backend interface version {} does not match frontend version {}!",
+        backend_version,
+        INTERFACE_VERSION
+    );
+    let threads: Box<[CoreBuffer]> = (0..max_threads)
+        .map(|_| {
+            let buf = Box::leak(vec![Event::Empty; events_per_thread].into_boxed_slice());
+            CoreBuffer {
+                events: buf.as_mut_ptr(),
+                len: buf.len(),
+                index: AtomicUsize::new(0),
+            }
+        })
+        .collect();
+    let threads = Box::leak(threads);
+    let status =
+        unsafe { rftrace_backend_init_per_thread(threads.as_ptr(), threads.len(), overwriting) };
+    if status != InitStatus::Ok {
+        // The backend did not take the buffers, so they are still ours
+        let threads = unsafe { Box::from_raw(threads as *mut [CoreBuffer]) };
+        for thread in threads.iter() {
+            drop(unsafe { Vec::from_raw_parts(thread.events, thread.len, thread.len) });
+        }
+        return Err(init_error(status));
+    }
+
+    let len = max_threads * events_per_thread;
+    Ok(Box::leak(Box::new(Events {
+        ptr: std::ptr::null_mut(),
+        len,
+        cap: len,
+        threads,
+        overwriting,
+    })))
+}
+
+/// Two event buffers the backend alternately records into, for gapless continuous tracing. See `init_double_buffered()`.
+#[derive(Debug)]
+pub struct DoubleBuffer {
+    /// The buffer the backend currently records into
+    active: Events,
+    /// The buffer returned by the last `swap()`, reused by the next one
+    retired: Vec<Event>,
+    /// Number of valid events in `retired`
+    retired_count: usize,
+}
+
+// The active buffer is only touched by the backend, which waits for its writers before handing it back in `swap()`.
+unsafe impl Send for DoubleBuffer {}
+
+/// Initializes two event buffers of `max_event_count` events each, the backend recording into one of them.
+///
+/// Call `DoubleBuffer::swap()` periodically: the backend continues in the other buffer, while the events of the
+/// retired one can be written to disk, for example with `write_full_uftrace()`. Both buffers are overwriting,
+/// so tracing never stops, but events are lost if a buffer wraps between two swaps.
+/// Specific to the staticlib backend.
+pub fn init_double_buffered(max_event_count: usize) -> io::Result<DoubleBuffer> {
+    let active = try_init(max_event_count, true)?;
+    Ok(DoubleBuffer {
+        active: *active,
+        retired: vec![Event::Empty; max_event_count],
+        retired_count: 0,
+    })
+}
+
+impl DoubleBuffer {
+    /// Makes the backend record into the other buffer and returns the events of the retired one, oldest first.
+    ///
+    /// The backend records into the new buffer before retiring the old one, so no events are missed in between, but functions
+    /// running during the swap have their entry and exit in different buffers.
+    /// The returned events stay valid until the next swap. Fails with `WouldBlock` while another frontend is attached.
+    pub fn swap(&mut self) -> io::Result<&[Event]> {
+        // Events of the previous swap are no longer needed, so the buffer can be reused
+        let mut next = mem::take(&mut self.retired);
+        next.fill(Event::Empty);
+        let (ptr, len, cap) = next.into_raw_parts();
+
+        let mut index = 0;
+        let previous = unsafe { rftrace_backend_swap_buffer(ptr, cap, &mut index) };
+        if previous.is_null() {
+            // The backend did not take the buffer, so it is still ours
+            self.retired = unsafe { Vec::from_raw_parts(ptr, len, cap) };
+            self.retired_count = 0;
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Event buffer is unavailable: attached by another frontend or already dumped",
+            ));
+        }
+        assert!(
+            previous == self.active.ptr,
+            "Event buffer pointer mismatch! Was it replaced with replace_buffer()?"
+        );
+
+        let active = Events {
+            ptr,
+            len,
+            cap,
+            threads: &[],
+            overwriting: true,
+        };
+        let Events { ptr, len, cap, .. } = mem::replace(&mut self.active, active);
+        let mut retired = unsafe { Vec::from_raw_parts(ptr, len, cap) };
+        if index > retired.len() {
+            println!(
+                "  Lost {} events, which were overwritten",
+                index - retired.len()
+            );
+            // Rotate the ring buffer, so the oldest event comes first
+            let cidx = index % retired.len();
+            retired.rotate_left(cidx);
+        }
+        self.retired_count = index.min(retired.len());
+        self.retired = retired;
+        Ok(&self.retired[..self.retired_count])
+    }
+}
+
+/// Keeps recording the most recent `max_event_count` events into a small ring, even while tracing is disabled.
+///
+/// Once tracing is enabled, for example after a bug manifested, these events are copied into the event buffer first,
+/// so the trace also shows what happened right before. Should be called once, after `init()`.
+/// Since the hooks stay active while disabled, this costs about as much as tracing all the time.
+/// The ring is handed to the backend for good, so it is leaked.
+pub fn init_flight_recorder(max_event_count: usize) {
+    let ring = Box::leak(vec![Event::Empty; max_event_count].into_boxed_slice());
+    unsafe { rftrace_backend_set_flight_recorder(ring.as_mut_ptr(), ring.len()) }
+}
+
+/// Dump done by `on_memory_pressure()`, see `release_on_memory_pressure()`.
+struct PressureDump {
+    events: Events,
+    out_dir: String,
+    binary_name: String,
+    options: DumpOptions,
 }
 
-/// Disables tracing in the backend.
-pub fn disable() {
-    unsafe { rftrace_backend_disable() }
+// The event buffer is only touched again by the dump, once the backend handed it back.
+unsafe impl Send for PressureDump {}
+
+static PRESSURE_DUMP: Mutex<Option<PressureDump>> = Mutex::new(None);
+
+/// Dumps the trace and frees the event buffer once the kernel runs out of memory, so tracing can not push small guests into OOM.
+///
+/// The kernel's allocator calls `kernel::memory_pressure()` of the backend when an allocation fails. The trace recorded so far
+/// is then dumped into `out_dir` like with `dump_full_uftrace_with()`, after which the buffer is freed and tracing stays disabled.
+/// Dumping needs some memory itself, `DumpOptions::max_output_bytes` limits it. Replaces a previously registered dump.
+/// Call `forget_memory_pressure()` before dumping `events` yourself.
+pub fn release_on_memory_pressure(
+    events: &mut Events,
+    out_dir: &str,
+    binary_name: &str,
+    options: DumpOptions,
+) {
+    *PRESSURE_DUMP.lock().unwrap() = Some(PressureDump {
+        events: *events,
+        out_dir: out_dir.into(),
+        binary_name: binary_name.into(),
+        options,
+    });
+    unsafe { rftrace_backend_set_pressure_handler(Some(on_memory_pressure)) }
 }
 
-/// Used to keep track of event buffer given to the staticlib
-#[derive(Copy, Clone, Debug)]
-pub struct Events {
-    ptr: *mut Event,
-    len: usize,
-    cap: usize,
+/// Stops releasing the event buffer under memory pressure, see `release_on_memory_pressure()`.
+pub fn forget_memory_pressure() {
+    unsafe { rftrace_backend_set_pressure_handler(None) }
+    PRESSURE_DUMP.lock().unwrap().take();
 }
 
-fn get_events(events: &mut Events) -> (Vec<Event>, usize) {
-    // Tell backend to not use the current buffer anymore.
-    let ptr = unsafe { rftrace_backend_get_events() };
-    println!("{:?}, {:?}", ptr, events);
-    assert!(ptr == events.ptr, "Event buffer pointer mismatch!");
+extern "C" fn on_memory_pressure() -> usize {
+    // Not waiting for the lock, in case the allocation failed while it is held
+    let Some(mut dump) = PRESSURE_DUMP
+        .try_lock()
+        .ok()
+        .and_then(|mut dump| dump.take())
+    else {
+        return 0;
+    };
+    println!("Memory pressure, releasing the event buffer..");
+    let size = dump.events.cap * mem::size_of::<Event>();
+    match dump_full_uftrace_with(
+        &mut dump.events,
+        &dump.out_dir,
+        &dump.binary_name,
+        &dump.options,
+    ) {
+        Ok(()) => size,
+        Err(err) => {
+            println!("  Could not dump the trace: {}", err);
+            0
+        }
+    }
+}
+
+/// Environment variable naming the output directory of `dump_from_env()`. Tracing via `init_from_env()` is only enabled if it is set.
+pub const ENV_OUT_DIR: &str = "RFTRACE_OUT_DIR";
+/// Environment variable overriding the event buffer size used by `init_from_env()`.
+pub const ENV_MAX_EVENTS: &str = "RFTRACE_MAX_EVENTS";
+/// Environment variable which, if set to `1`, makes the buffer of `init_from_env()` overwriting.
+pub const ENV_OVERWRITING: &str = "RFTRACE_OVERWRITING";
+/// Environment variable setting `DumpOptions::time_shift` for `dump_from_env()`.
+pub const ENV_TIME_SHIFT: &str = "RFTRACE_TIME_SHIFT";
 
-    let eventvec = unsafe { Vec::from_raw_parts(events.ptr, events.len, events.cap) };
+/// Event buffer size used by `init_from_env()` if `RFTRACE_MAX_EVENTS` is not set.
+pub const DEFAULT_MAX_EVENT_COUNT: usize = 1000000;
 
-    let idx = unsafe { rftrace_backend_get_events_index() };
-    (eventvec, idx)
+/// Initializes and enables tracing as configured by the `RFTRACE_*` environment variables.
+///
+/// Returns `None` without touching the backend if `RFTRACE_OUT_DIR` is not set.
+/// This is the counterpart of `spawn_traced()`, which passes its configuration to the child via the environment.
+pub fn init_from_env() -> Option<&'static mut Events> {
+    env::var_os(ENV_OUT_DIR)?;
+
+    let max_event_count = env::var(ENV_MAX_EVENTS)
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_MAX_EVENT_COUNT);
+    let overwriting = env::var(ENV_OVERWRITING).is_ok_and(|o| o == "1");
+
+    let events = init(max_event_count, overwriting);
+    enable();
+    Some(events)
 }
 
-/// Initializes a new event buffer.
+/// Dumps a full uftrace directory into `RFTRACE_OUT_DIR`, naming the binary after the current executable.
+pub fn dump_from_env(events: &mut Events) -> io::Result<()> {
+    let out_dir = env::var(ENV_OUT_DIR)
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "RFTRACE_OUT_DIR is not set"))?;
+    let binary_name = env::current_exe()?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "unknown".into());
+
+    let options = DumpOptions {
+        time_shift: env::var(ENV_TIME_SHIFT)
+            .ok()
+            .and_then(|shift| shift.parse().ok())
+            .unwrap_or(0),
+        ..Default::default()
+    };
+
+    dump_full_uftrace_with(events, &out_dir, &binary_name, &options)
+}
+
+/// Error returned when dumping a trace without a single function entry or exit, for example because tracing was never enabled.
 ///
-/// Allocs a new buffer of size `max_event_count` and passes it to the backend.
-/// If `overwriting`, treats it as a ring-buffer, keeping only the most-recent entries, otherwise it stopps logging once it is full.
-/// `max_event_count` will not be filled completely, since space is left for the returns of hooked functions.
-/// Currently, the maximum stack-depth is 1000. Consequently, `max_event_count` has to be greater than 1000.
-pub fn init(max_event_count: usize, overwriting: bool) -> &'static mut Events {
-    assert!(
-        max_event_count > MAX_STACK_HEIGHT,
-        "Event buffer has to be larger than maximum stack height!"
-    );
-    let buf = vec![Event::Empty; max_event_count];
-    unsafe {
-        // intentionally leak here! stacks have to live until end of application.
-        let (ptr, len, cap) = buf.into_raw_parts();
-        rftrace_backend_init(ptr, cap, overwriting);
-        // TODO: free this leaked box somewhere. Create a drop() function or similar?
-        Box::leak(Box::new(Events { ptr, len, cap }))
+/// Nothing is written in that case, as uftrace cannot parse a data dir without tasks.
+/// It is wrapped into an `io::Error`, use `is_empty_trace()` to check for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyTrace;
+
+impl fmt::Display for EmptyTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trace is empty, no function was entered or exited")
     }
 }
 
+impl Error for EmptyTrace {}
+
+/// Returns whether `err` was caused by dumping an empty trace, see `EmptyTrace`.
+pub fn is_empty_trace(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<EmptyTrace>())
+}
+
 /// Dumps the traces with some faked metadata into the given folder. Uses the same format as uftrace, which should be used to parse them.
 ///
+/// Fails with `EmptyTrace` without writing anything if no events were recorded.
+///
 /// Will NOT generate symbols! You can generate them with `nm -n $BINARY > binary_name.sym`
 ///
 /// # Arguments
@@ -76,16 +1382,107 @@ pub fn init(max_event_count: usize, overwriting: bool) -> &'static mut Events {
 /// * `binary_name` - only relevant for this symbol file. Generated metadata instructs uftrace where to look for it.
 ///
 pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str) -> io::Result<()> {
+    dump_full_uftrace_with(events, out_dir, binary_name, &DumpOptions::default())
+}
+
+/// Like `dump_full_uftrace()`, but with additional `options`.
+pub fn dump_full_uftrace_with(
+    events: &mut Events,
+    out_dir: &str,
+    binary_name: &str,
+    options: &DumpOptions,
+) -> io::Result<()> {
+    let out_dir = &options.layout.prepare(out_dir)?;
+    let mut failed = Vec::new();
+    // TODO: create enable lock, to ensure no mcount() happens while we read the events array.
+    disable();
+    println!("Saving traces to disk...!");
+    let events = get_events(events)?;
+    // First lets create all traces.
+    let tids = write_traces(&events, out_dir, false, options, &mut failed)?;
+    write_metadata(
+        &tids,
+        out_dir,
+        binary_name,
+        options,
+        Some(stats()),
+        wallclock_anchor(&events),
+        &mut failed,
+    )?;
+    verify(out_dir, options);
+    incomplete(failed)
+}
+
+/// Like `dump_full_uftrace_with()`, but writes already collected events, such as an `Attachment::snapshot()`.
+///
+/// `events` have to be ordered oldest first.
+pub fn write_full_uftrace(
+    events: &[Event],
+    out_dir: &str,
+    binary_name: &str,
+    options: &DumpOptions,
+) -> io::Result<()> {
+    println!("Saving traces to disk...!");
+    let out_dir = &options.layout.prepare(out_dir)?;
+    let mut failed = Vec::new();
+    let tids = write_traces(events, out_dir, false, options, &mut failed)?;
+    write_metadata(
+        &tids,
+        out_dir,
+        binary_name,
+        options,
+        Some(stats()),
+        wallclock_anchor(events),
+        &mut failed,
+    )?;
+    verify(out_dir, options);
+    incomplete(failed)
+}
+
+/// Like `write_full_uftrace()`, but for events recorded by another program, so our backend statistics are left out.
+///
+/// Does not need the backend at all, so it can be used by tools which do not link it.
+pub(crate) fn write_foreign_uftrace(
+    events: &[Event],
+    out_dir: &str,
+    binary_name: &str,
+    options: &DumpOptions,
+) -> io::Result<()> {
+    println!("Saving traces to disk...!");
+    let out_dir = &options.layout.prepare(out_dir)?;
+    let mut failed = Vec::new();
+    let tids = write_traces(events, out_dir, false, options, &mut failed)?;
+    write_metadata(
+        &tids,
+        out_dir,
+        binary_name,
+        options,
+        None,
+        wallclock_anchor(events),
+        &mut failed,
+    )?;
+    verify(out_dir, options);
+    incomplete(failed)
+}
+
+/// Writes the faked uftrace metadata files for the given TIDs.
+///
+/// Files which could not be written despite `DumpOptions::retry` are added to `failed`.
+fn write_metadata(
+    tids: &[u64],
+    out_dir: &str,
+    binary_name: &str,
+    options: &DumpOptions,
+    stats: Option<Stats>,
+    anchor: Option<(u64, u64)>,
+    failed: &mut Vec<String>,
+) -> io::Result<()> {
     // arbitrary values for pid and sid
     let pid = 42;
     let sid = "00";
 
-    // First lets create all traces.
-    let tids = dump_traces(events, out_dir, false)?;
-
     if tids.is_empty() {
-        println!("Trace is empty!");
-        return Ok(());
+        return Err(io::Error::other(EmptyTrace));
     }
 
     println!("Creating fake uftrace data dir at {}..", out_dir);
@@ -141,30 +1538,76 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
         write!(info, ",{}", tid)?;
     }
     writeln!(info)?;
+    // backend statistics. uftrace only reads the lines announced in the info flags, so it ignores these.
+    if let Some(stats) = stats {
+        println!("    stats = {:?}", stats);
+        writeln!(info, "rftrace:entries={}", stats.entries)?;
+        writeln!(info, "rftrace:returns={}", stats.returns)?;
+        writeln!(info, "rftrace:filtered={}", stats.filtered)?;
+        writeln!(
+            info,
+            "rftrace:retstack_overflows={}",
+            stats.retstack_overflows
+        )?;
+        writeln!(info, "rftrace:recursion_hits={}", stats.recursion_hits)?;
+        writeln!(info, "rftrace:demoted={}", stats.demoted)?;
+        writeln!(info, "rftrace:quota_exceeded={}", stats.quota_exceeded)?;
+        writeln!(info, "rftrace:throttled={}", stats.throttled)?;
+    }
+    if options.time_shift > 0 {
+        println!("    time_shift = {}", options.time_shift);
+        writeln!(info, "rftrace:time_shift={}", options.time_shift)?;
+    }
+    if let Some(clock) = &options.clock {
+        println!("    clock = {:?}", clock);
+        writeln!(info, "rftrace:clock={}", clock.name)?;
+        writeln!(info, "rftrace:clock_freq={}", clock.freq)?;
+        writeln!(info, "rftrace:clock_offset={}", clock.offset)?;
+    }
+    if let Some((time, ns)) = anchor {
+        println!("    wallclock = {} at {}", ns, time);
+        writeln!(info, "rftrace:wallclock_time={}", timestamp(time, options))?;
+        writeln!(info, "rftrace:wallclock_ns={}", ns)?;
+    }
+    if options.checksum {
+        println!("    checksum = crc32");
+        writeln!(info, "rftrace:checksum=crc32")?;
+    }
+    if sealed(options) {
+        println!("    encryption = aes-256-gcm");
+        writeln!(info, "rftrace:encryption=aes-256-gcm")?;
+    }
+    let anonymized;
+    let binary_name: &str = if options.anonymize {
+        anonymized = anonymize_name(binary_name);
+        println!("    anonymized = fnv1a");
+        writeln!(info, "rftrace:anonymized=fnv1a")?;
+        &anonymized
+    } else {
+        binary_name
+    };
 
     let infofile = format!("{}/info", out_dir);
-    let mut infofile = File::create(infofile)?;
-    infofile.write_all(&info[..])?;
-    drop(infofile);
+    write_file(&infofile, &[&info], options, failed)?;
 
     println!("  Creating ./task.txt");
     let taskfile = format!("{}/task.txt", out_dir);
-    let mut taskfile = File::create(taskfile)?;
+    let mut task = Vec::new();
     println!("    pid = {}", pid);
     println!("    sid = {}", sid);
     println!("    exe = {}", binary_name);
     writeln!(
-        taskfile,
+        task,
         "SESS timestamp=0.0 pid={} sid={} exename=\"{}\"",
         pid, sid, binary_name
     )?;
     for tid in tids {
-        writeln!(taskfile, "TASK timestamp=0.0 tid={} pid={}", tid, pid)?;
+        writeln!(task, "TASK timestamp=0.0 tid={} pid={}", tid, pid)?;
     }
-    drop(taskfile);
+    write_file(&taskfile, &[&task], options, failed)?;
 
     let mapfilename = format!("{}/sid-{}.map", out_dir, sid);
-    let mut mapfile = File::create(mapfilename)?;
+    let mut mapfile = Vec::new();
     cfg_if::cfg_if! {
         if #[cfg(target_os = "linux")] {
             // see uftrace's record_proc_maps(..)
@@ -173,8 +1616,13 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
                 "  Creating (incorrect) ./sid-{}.map by copying /proc/self/maps",
                 sid
             );
-            let mut procfile = File::open("/proc/self/maps")?;
-            io::copy(&mut procfile, &mut mapfile)?;
+            let maps = fs::read("/proc/self/maps")?;
+            if options.anonymize {
+                let maps = crate::anonymize::anonymize_maps(&String::from_utf8_lossy(&maps));
+                mapfile.extend_from_slice(maps.as_bytes());
+            } else {
+                mapfile.extend_from_slice(&maps);
+            }
         } else if #[cfg(target_os = "hermit")] {
             extern "C" {
                 fn sys_image_start_addr() -> usize;
@@ -191,6 +1639,7 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
             writeln!(mapfile, "ffffffffffff-ffffffffffff rw-p 00000000 00:00 0                          [stack]")?;
         }
     }
+    write_file(&mapfilename, &[&mapfile], options, failed)?;
 
     if cfg!(target_os = "linux") {
         println!(
@@ -208,6 +1657,9 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
             out_dir, binary_name
         );
     }
+    if options.anonymize {
+        println!("Anonymize the symbols with `anonymize_symbols()` before writing them");
+    }
 
     Ok(())
 }
@@ -217,6 +1669,7 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
 /// `events` is the Events buffer as returned by `init`.
 /// outfile is the file into which the output trace is written.
 /// The trace itself has the same format as uftrace, but is not directly parsable due to the missing metadata.
+/// Fails with `EmptyTrace` if no events were recorded.
 ///
 /// # Format
 /// Packed array of uftrace_record structs
@@ -230,11 +1683,23 @@ pub fn dump_full_uftrace(events: &mut Events, out_dir: &str, binary_name: &str)
 ///     uint64_t addr:   48; /* child ip or uftrace_event_id */
 /// };
 pub fn dump_trace(events: &mut Events, outfile: &str) -> io::Result<()> {
-    dump_traces(events, outfile, true)?;
+    dump_traces(
+        events,
+        outfile,
+        true,
+        &DumpOptions::default(),
+        &mut Vec::new(),
+    )?;
     Ok(())
 }
 
-fn dump_traces(events: &mut Events, outpath: &str, singlefile: bool) -> io::Result<Vec<u64>> {
+fn dump_traces(
+    events: &mut Events,
+    outpath: &str,
+    singlefile: bool,
+    options: &DumpOptions,
+    failed: &mut Vec<String>,
+) -> io::Result<Vec<u64>> {
     // Uftraces trace format: a bunch of 64-bit fields, See https://github.com/namhyung/uftrace/wiki/Data-Format
     //
     // Array of 2x64 bit unsigned long: `[{time: u64, address: u64}, ...]`
@@ -253,19 +1718,37 @@ fn dump_traces(events: &mut Events, outpath: &str, singlefile: bool) -> io::Resu
     disable();
     println!("Saving traces to disk...!");
 
-    let (events, cidx) = get_events(events);
-    let cidx = cidx % events.len();
+    let events = get_events(events)?;
+    write_traces(&events, outpath, singlefile, options, failed)
+}
+
+/// Writes the uftrace trace files of `events`, which have to be ordered oldest first. Returns all seen TIDs.
+///
+/// Fails with `EmptyTrace` if there is not a single entry or exit, so the TIDs are never empty.
+/// Files which could not be written despite `DumpOptions::retry` are added to `failed`.
+fn write_traces(
+    events: &[Event],
+    outpath: &str,
+    singlefile: bool,
+    options: &DumpOptions,
+    failed: &mut Vec<String>,
+) -> io::Result<Vec<u64>> {
+    let truncated = options
+        .max_output_bytes
+        .map(|budget| most_recent(events, budget, options));
+    let events = truncated.as_deref().unwrap_or(events);
 
     // The following is somewhat inefficient, but is intended to solve two constraints:
     // - don't use too much memory. Here we have ~2x trace array.
     // - don't have multiple files open at once
 
-    // To avoid to many reallocs, use array with maximum size for all traces.
-    let mut out = Vec::<u8>::with_capacity(16 * events.len());
+    // To avoid to many reallocs, use array with maximum size for all traces. Not needed if written in place.
+    let mut out = Vec::<u8>::with_capacity(if options.mmap { 0 } else { 16 * events.len() });
+    let mut sites = Vec::<u8>::new();
 
     // Gather all tids so we can assemble metadata
     let mut tids: Vec<Option<core::num::NonZeroU64>> = Vec::new();
-    for e in events[cidx..].iter().chain(events[..cidx].iter()) {
+    for e in events {
         match e {
             Event::Exit(e) => {
                 if !tids.contains(&e.tid) {
@@ -277,54 +1760,125 @@ fn dump_traces(events: &mut Events, outpath: &str, singlefile: bool) -> io::Resu
                     tids.push(e.tid);
                 }
             }
-            Event::Empty => {}
+            // Resource snapshots are written as uftrace events, possibly by a thread without any calls
+            Event::Record(r) if r.kind == RECORD_KIND_RESOURCES => {
+                if !tids.contains(&r.tid) {
+                    tids.push(r.tid);
+                }
+            }
+            // Other records are not part of the uftrace trace files
+            Event::Record(_) | Event::Empty => {}
         }
     }
+    if tids.is_empty() {
+        return Err(io::Error::other(EmptyTrace));
+    }
 
     // For each TID, loop through the events array and save only the relevant items to disk
     for current_tid in &tids {
         // clear out vec in case it contains entries from previous tid
         out.clear();
+        sites.clear();
 
         let tid = current_tid.map_or(0, |tid| tid.get());
+        let filename = if singlefile {
+            outpath.into()
+        } else {
+            let file = format!("{}.dat", tid);
+            format!("{}/{}", outpath, file)
+        };
 
         println!("  Parsing TID {:?}...!", tid);
-        for e in events[cidx..].iter().chain(events[..cidx].iter()) {
-            match e {
-                Event::Exit(e) => {
-                    if !singlefile && current_tid != &e.tid {
-                        continue;
-                    };
-                    write_event(&mut out, e.time, e.from, 1);
-                }
-                Event::Entry(e) => {
-                    if !singlefile && current_tid != &e.tid {
-                        continue;
-                    };
-                    write_event(&mut out, e.time, e.to, 0);
-                }
-                Event::Empty => {
-                    continue;
+        let collapsed = if cfg!(target_os = "linux") && options.mmap {
+            // Count first, so the file can be mapped with its final size and written in place
+            let mut size = ByteCount(0);
+            let (_, records) =
+                write_thread(&mut size, None, events, current_tid, singlefile, options);
+            let footer = if options.checksum && size.0 > 0 {
+                FOOTER_LEN
+            } else {
+                0
+            };
+            if size.0 > 0 {
+                println!(
+                    "  Writing to disk: {} events, {} bytes ({}, mapped)",
+                    size.0 / 16,
+                    size.0,
+                    filename
+                );
+            }
+            let (header, trailer) = if sealed(options) && size.0 > 0 {
+                SEAL_OVERHEAD
+            } else {
+                (0, 0)
+            };
+            let mut collapsed = 0;
+            let mut fill = |mapped: &mut [u8]| {
+                // Filled again if the file is retried
+                sites.clear();
+                let (header_out, rest) = mapped.split_at_mut(header);
+                let (plain, trailer_out) = rest.split_at_mut(size.0 + footer);
+                let (data, mut footer_out) = plain.split_at_mut(size.0);
+                collapsed = write_thread(
+                    &mut &mut data[..],
+                    Some(&mut sites),
+                    events,
+                    current_tid,
+                    singlefile,
+                    options,
+                )
+                .0;
+                if footer > 0 {
+                    write_footer(&mut footer_out, data, records).expect("Write interrupted");
                 }
+                let (sealed_header, sealed_trailer) = seal(plain, options);
+                header_out.copy_from_slice(&sealed_header);
+                trailer_out.copy_from_slice(&sealed_trailer);
+            };
+            let len = header + size.0 + footer + trailer;
+            retrying(&filename, options, failed, || {
+                write_mapped(&filename, len, &mut fill)
+            })?;
+            collapsed
+        } else {
+            let (collapsed, records) = write_thread(
+                &mut out,
+                Some(&mut sites),
+                events,
+                current_tid,
+                singlefile,
+                options,
+            );
+            if options.checksum && !out.is_empty() {
+                let mut footer = Vec::with_capacity(FOOTER_LEN);
+                write_footer(&mut footer, &out, records)?;
+                out.extend_from_slice(&footer);
             }
+            if !out.is_empty() {
+                println!(
+                    "  Writing to disk: {} events, {} bytes ({})",
+                    out.len() / 16,
+                    out.len(),
+                    filename
+                );
+                let (header, trailer) = seal(&mut out, options);
+                write_file(&filename, &[&header, &out, &trailer], options, failed)?;
+            }
+            collapsed
+        };
+        if collapsed > 0 {
+            println!("  Collapsed {} recursive frames", collapsed);
         }
 
-        if !out.is_empty() {
+        if !sites.is_empty() {
             let filename = if singlefile {
-                outpath.into()
+                format!("{}.callsites", outpath)
             } else {
-                let file = format!("{}.dat", tid);
-                format!("{}/{}", outpath, file)
+                format!("{}/{}.callsites", outpath, tid)
             };
-
-            println!(
-                "  Writing to disk: {} events, {} bytes ({})",
-                out.len() / 16,
-                out.len(),
-                filename
-            );
-            let mut file = File::create(filename)?;
-            file.write_all(&out[..])?;
+            println!("  Writing call sites to disk: {}", filename);
+            let (header, trailer) = seal(&mut sites, options);
+            write_file(&filename, &[&header, &sites, &trailer], options, failed)?;
         }
     }
     println!("  Parsed all events!");
@@ -336,9 +1890,490 @@ fn dump_traces(events: &mut Events, outpath: &str, singlefile: bool) -> io::Resu
         .collect())
 }
 
+/// Returns the most recent `events` whose records fit into `budget` bytes, see `DumpOptions::max_output_bytes`.
+fn most_recent(events: &[Event], budget: usize, options: &DumpOptions) -> Vec<Event> {
+    let mut size = 0;
+    let start = events
+        .iter()
+        .rposition(|e| {
+            size += match e {
+                Event::Entry(_) if options.call_sites => 24,
+                Event::Entry(_) | Event::Exit(_) => 16,
+                Event::Record(r) if r.kind == RECORD_KIND_RESOURCES => RESOURCES_EVENT_LEN,
+                Event::Record(_) | Event::Empty => 0,
+            };
+            size > budget
+        })
+        .map_or(0, |i| i + 1);
+
+    // Call depth of each thread within the kept events, to drop exits of calls entered before them
+    let mut depths: HashMap<Option<NonZeroU64>, usize> = HashMap::new();
+    let kept: Vec<Event> = events[start..]
+        .iter()
+        .filter(|e| match e {
+            Event::Entry(e) => {
+                *depths.entry(e.tid).or_default() += 1;
+                true
+            }
+            Event::Exit(e) => {
+                let depth = depths.entry(e.tid).or_default();
+                *depth = match depth.checked_sub(1) {
+                    Some(depth) => depth,
+                    None => return false,
+                };
+                true
+            }
+            Event::Record(_) | Event::Empty => true,
+        })
+        .copied()
+        .collect();
+    if start > 0 {
+        println!(
+            "  Kept the most recent {} of {} events to fit into {} bytes",
+            kept.len(),
+            events.len(),
+            budget
+        );
+    }
+    kept
+}
+
+/// Writes the uftrace records of the thread `current_tid` to `out`, or those of all threads if `singlefile`.
+///
+/// Also writes the call site of each written entry to `sites`, if given and enabled in `options`.
+/// Returns the number of collapsed recursive frames and the number of written records.
+fn write_thread<W: Write>(
+    out: &mut W,
+    mut sites: Option<&mut Vec<u8>>,
+    events: &[Event],
+    current_tid: &Option<NonZeroU64>,
+    singlefile: bool,
+    options: &DumpOptions,
+) -> (u64, u64) {
+    // Per-thread call stacks for the dump-time passes. In singlefile mode, we see all threads at once.
+    let mut stacks: HashMap<Option<NonZeroU64>, CallStack> = HashMap::new();
+    let mut records = 0;
+    for e in events {
+        match e {
+            Event::Exit(e) => {
+                if !singlefile && current_tid != &e.tid {
+                    continue;
+                };
+                let stack = stacks.entry(e.tid).or_default();
+                if !stack.exit() {
+                    continue;
+                }
+                write_event(out, timestamp(e.time, options), e.from, 1, stack.depth());
+                records += 1;
+            }
+            Event::Entry(e) => {
+                if !singlefile && current_tid != &e.tid {
+                    continue;
+                };
+                let stack = stacks.entry(e.tid).or_default();
+                if !stack.entry(e.to, options) {
+                    continue;
+                }
+                write_event(out, timestamp(e.time, options), e.to, 0, stack.depth() - 1);
+                records += 1;
+                if let Some(sites) = sites.as_mut().filter(|_| options.call_sites) {
+                    sites
+                        .write_u64::<LittleEndian>(e.from)
+                        .expect("Write interrupted");
+                }
+            }
+            Event::Record(r) => {
+                if !singlefile && current_tid != &r.tid {
+                    continue;
+                };
+                if let Some(resources) = Resources::from_record(r) {
+                    write_resources_event(out, timestamp(r.time, options), &resources);
+                    records += 1;
+                }
+            }
+            Event::Empty => {
+                continue;
+            }
+        }
+    }
+    let collapsed = stacks.values().map(|stack| stack.collapsed).sum();
+    (collapsed, records)
+}
+
+/// Returns the dumped timestamp of an event recorded at `time`, see `DumpOptions::clock` and `DumpOptions::time_shift`.
+fn timestamp(time: u64, options: &DumpOptions) -> u64 {
+    let time = options
+        .clock
+        .as_ref()
+        .map_or(time, |clock| clock.convert(time));
+    time.checked_shr(options.time_shift).unwrap_or(0)
+}
+
+/// Returns whether `options` encrypt the dumped files.
+fn sealed(options: &DumpOptions) -> bool {
+    #[cfg(feature = "encryption")]
+    if options.encryption.is_some() {
+        return true;
+    }
+    let _ = options;
+    false
+}
+
+/// Bytes `seal()` adds before and after the encrypted data.
+#[cfg(feature = "encryption")]
+const SEAL_OVERHEAD: (usize, usize) = (
+    crate::encryption::HEADER_LEN,
+    crate::encryption::TRAILER_LEN,
+);
+#[cfg(not(feature = "encryption"))]
+const SEAL_OVERHEAD: (usize, usize) = (0, 0);
+
+/// Encrypts `data` in place if `options` say so, see `DumpOptions::encryption`.
+///
+/// Returns what has to be written before and after it, both empty if nothing was encrypted.
+fn seal(data: &mut [u8], options: &DumpOptions) -> (Vec<u8>, Vec<u8>) {
+    #[cfg(feature = "encryption")]
+    if let Some(key) = &options.encryption {
+        let (header, trailer) = crate::encryption::encrypt_in_place(key, data);
+        return (header.to_vec(), trailer.to_vec());
+    }
+    let _ = (data, options);
+    (Vec::new(), Vec::new())
+}
+
+/// Writer which only counts the bytes written to it.
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates the file `filename` with `len` bytes, which `fill` writes in place through a shared memory mapping.
+///
+/// Nothing is created if `len` is 0.
+#[cfg(target_os = "linux")]
+fn write_mapped(filename: &str, len: usize, fill: impl FnMut(&mut [u8])) -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // Mapping for writing requires the file to be opened for reading as well
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(filename)?;
+    file.set_len(len as u64)?;
+
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fill = fill;
+    fill(unsafe { slice::from_raw_parts_mut(ptr as *mut u8, len) });
+    // The kernel writes the dirty pages back on its own, also after unmapping
+    unsafe { libc::munmap(ptr, len) };
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_mapped(_filename: &str, _len: usize, _fill: impl FnMut(&mut [u8])) -> io::Result<()> {
+    unreachable!("memory mapped dumps are only supported on Linux")
+}
+
+/// Writes `parts` into the file `filename`, see `retrying()`.
+fn write_file(
+    filename: &str,
+    parts: &[&[u8]],
+    options: &DumpOptions,
+    failed: &mut Vec<String>,
+) -> io::Result<()> {
+    retrying(filename, options, failed, || {
+        let mut file = File::create(filename)?;
+        for part in parts {
+            file.write_all(part)?;
+        }
+        Ok(())
+    })
+}
+
+/// Runs `write`, which writes the file `filename`, retrying transient errors as configured by `DumpOptions::retry`.
+///
+/// With retries, a file which still fails is added to `failed`, so the dump can go on with the remaining files.
+/// Without, the error is returned right away.
+fn retrying(
+    filename: &str,
+    options: &DumpOptions,
+    failed: &mut Vec<String>,
+    mut write: impl FnMut() -> io::Result<()>,
+) -> io::Result<()> {
+    let retry = match options.retry {
+        Some(retry) => retry,
+        None => return write(),
+    };
+
+    let mut delay = retry.backoff;
+    let mut attempt = 0;
+    loop {
+        let err = match write() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if attempt == retry.retries || !is_transient(&err) {
+            println!("  Giving up on {}: {}", filename, err);
+            failed.push(filename.to_string());
+            return Ok(());
+        }
+        attempt += 1;
+        println!(
+            "  Writing {} failed: {}. Retry {}/{} in {:?}",
+            filename, err, attempt, retry.retries, delay
+        );
+        thread::sleep(delay);
+        delay = delay.saturating_mul(2);
+    }
+}
+
+/// Returns whether writing a file might succeed when retried after `err`.
+fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind;
+
+    if matches!(
+        err.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::WriteZero
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    ) {
+        return true;
+    }
+    // virtio-fs and NFS report hiccups of the host or server as EIO or ESTALE
+    #[cfg(target_os = "linux")]
+    if matches!(err.raw_os_error(), Some(libc::EIO | libc::ESTALE)) {
+        return true;
+    }
+    false
+}
+
+/// Verifies the dump in `out_dir` if enabled in `options`, see `DumpOptions::verify`.
+fn verify(out_dir: &str, options: &DumpOptions) {
+    if !options.verify {
+        return;
+    }
+    println!("Verifying {}..", out_dir);
+    match verify_uftrace_dir(out_dir) {
+        Ok(warnings) if warnings.is_empty() => println!("  No problems found"),
+        Ok(warnings) => {
+            for warning in warnings {
+                println!("  WARNING: {}", warning);
+            }
+        }
+        Err(err) => println!("  Unable to verify: {}", err),
+    }
+}
+
+/// Fails if any files of a dump are missing, after all others were written, see `DumpOptions::retry`.
+fn incomplete(failed: Vec<String>) -> io::Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    println!("Dump incomplete, {} files are missing!", failed.len());
+    Err(io::Error::other(format!(
+        "dump incomplete, failed to write {}",
+        failed.join(", ")
+    )))
+}
+
+/// Estimates the size of each file a full uftrace dump of `events` with `options` writes, in bytes.
+///
+/// Lets callers on space-constrained guests decide whether to trim, filter or compress before writing anything.
+/// Events are counted just like a dump filters and truncates them (see `DumpOptions::max_output_bytes`), so trace files are
+/// estimated exactly. The size of the metadata files is approximated. Returns file names relative to the output directory,
+/// in the order they are written.
+///
+/// `events` have to be ordered oldest first. To estimate the buffer of `init()` before dumping it, `attach()` and take a snapshot.
+pub fn estimated_dump_size(events: &[Event], options: &DumpOptions) -> Vec<(String, u64)> {
+    // Same as in `write_traces()`
+    let truncated = options
+        .max_output_bytes
+        .map(|budget| most_recent(events, budget, options));
+    let events = truncated.as_deref().unwrap_or(events);
+
+    // TID, bytes of its trace file and number of dumped entries, in order of appearance
+    let mut sizes: Vec<(u64, u64, u64)> = Vec::new();
+    let mut stacks: HashMap<Option<NonZeroU64>, CallStack> = HashMap::new();
+    fn size_of(sizes: &mut Vec<(u64, u64, u64)>, tid: Option<NonZeroU64>) -> &mut (u64, u64, u64) {
+        let tid = tid.map_or(0, NonZeroU64::get);
+        let i = match sizes.iter().position(|size| size.0 == tid) {
+            Some(i) => i,
+            None => {
+                sizes.push((tid, 0, 0));
+                sizes.len() - 1
+            }
+        };
+        &mut sizes[i]
+    }
+    for e in events {
+        match e {
+            Event::Exit(e) => {
+                let size = size_of(&mut sizes, e.tid);
+                if stacks.entry(e.tid).or_default().exit() {
+                    size.1 += 16;
+                }
+            }
+            Event::Entry(e) => {
+                let size = size_of(&mut sizes, e.tid);
+                if stacks.entry(e.tid).or_default().entry(e.to, options) {
+                    size.1 += 16;
+                    size.2 += 1;
+                }
+            }
+            Event::Record(r) if r.kind == RECORD_KIND_RESOURCES => {
+                // see `write_resources_event()`
+                size_of(&mut sizes, r.tid).1 += 48;
+            }
+            Event::Record(_) | Event::Empty => {}
+        }
+    }
+    if sizes.is_empty() {
+        return Vec::new();
+    }
+
+    let seal = if sealed(options) {
+        (SEAL_OVERHEAD.0 + SEAL_OVERHEAD.1) as u64
+    } else {
+        0
+    };
+    let mut files = Vec::new();
+    for &(tid, bytes, entries) in &sizes {
+        if bytes > 0 {
+            let footer = if options.checksum {
+                FOOTER_LEN as u64
+            } else {
+                0
+            };
+            files.push((format!("{}.dat", tid), bytes + footer + seal));
+        }
+        if options.call_sites && entries > 0 {
+            files.push((format!("{}.callsites", tid), entries * 8 + seal));
+        }
+    }
+
+    let tid_digits: u64 = sizes
+        .iter()
+        .map(|size| size.0.to_string().len() as u64 + 1)
+        .sum();
+    // Header, cmdline and taskinfo lines, plus roughly 200 bytes of statistics
+    let info = 40 + 20 + 18 + 27 + 15 + tid_digits + 200;
+    files.push(("info".into(), info));
+    // SESS line without the binary name, and one TASK line per TID
+    let task = 60 + sizes.len() as u64 * 30 + tid_digits;
+    files.push(("task.txt".into(), task));
+    let map = if cfg!(target_os = "linux") {
+        // A copy of it is written, see `write_metadata()`
+        fs::read("/proc/self/maps").map_or(0, |maps| maps.len() as u64)
+    } else {
+        160
+    };
+    files.push(("sid-00.map".into(), map));
+    files
+}
+
+/// Reads a call sites file written next to a trace file with `DumpOptions::call_sites`.
+///
+/// Returns the call site of each function entry in the trace file, in the same order.
+/// A call site is the return address within the caller, so it points right behind the call instruction.
+pub fn read_call_sites(path: &str) -> io::Result<Vec<usize>> {
+    let data = fs::read(path)?;
+    if data.len() % 8 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "call sites file is not a multiple of 8 bytes",
+        ));
+    }
+    Ok(data
+        .chunks_exact(8)
+        .map(|site| u64::from_le_bytes(site.try_into().unwrap()) as usize)
+        .collect())
+}
+
+/// Call stack of a single thread, replayed at dump time to decide which events are kept.
+#[derive(Default)]
+pub(crate) struct CallStack {
+    /// Function address, depth within a direct self-recursion and whether the frame is dumped.
+    frames: Vec<(u64, usize, bool)>,
+    /// Number of frames dropped by `DumpOptions::max_recursion`.
+    collapsed: u64,
+    /// Number of dumped frames on the stack.
+    depth: usize,
+}
+
+impl CallStack {
+    /// Pushes a function entry. Returns whether it should be dumped.
+    pub(crate) fn entry(&mut self, addr: u64, options: &DumpOptions) -> bool {
+        let recursion = match self.frames.last() {
+            Some(&(top, recursion, _)) if top == addr => recursion + 1,
+            _ => 1,
+        };
+        let excluded = options
+            .exclude
+            .iter()
+            .any(|range| range.contains(&(addr as usize)));
+        let collapse = options.max_recursion.is_some_and(|max| recursion > max);
+        if collapse {
+            self.collapsed += 1;
+        }
+        let keep = !excluded && !collapse;
+
+        self.frames.push((addr, recursion, keep));
+        self.depth += usize::from(keep);
+        keep
+    }
+
+    /// Pops a function exit. Returns whether it should be dumped.
+    ///
+    /// Exits without a matching entry, for example from before an overwritten part of the ring buffer, are always dumped.
+    pub(crate) fn exit(&mut self) -> bool {
+        let keep = self.frames.pop().is_none_or(|(_, _, keep)| keep);
+        if keep {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        keep
+    }
+
+    /// Returns the number of dumped frames on the stack, which is the call depth of the next dumped entry.
+    ///
+    /// Right after a dumped entry, the depth of that entry is one less. Right after a dumped exit, it is the depth of that exit.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
 #[allow(clippy::identity_op)]
 #[allow(clippy::erasing_op)]
-fn write_event(out: &mut Vec<u8>, time: u64, addr: *const usize, kind: u64) {
+pub(crate) fn write_event<W: Write>(out: &mut W, time: u64, addr: u64, kind: u64, depth: usize) {
     out.write_u64::<LittleEndian>(time)
         .expect("Write interrupted");
 
@@ -346,8 +2381,128 @@ fn write_event(out: &mut Vec<u8>, time: u64, addr: *const usize, kind: u64) {
     merged |= (kind & 0b11) << 0; // type = UFTRACE_EXIT / UFTRACE_ENTRY
     merged |= 0 << 2; // more, always 0
     merged |= 0b101 << 3; // magic, always 0b101
-    merged |= (0 & ((1 << 10) - 1)) << 6; // depth
-    merged |= (addr as u64 & ((1 << 48) - 1)) << 16; // actual address, limited to 48 bit.
+    merged |= (depth as u64).min((1 << 10) - 1) << 6; // depth, saturating at the 10 bit maximum
+    merged |= (addr & ((1 << 48) - 1)) << 16; // actual address, limited to 48 bit.
     out.write_u64::<LittleEndian>(merged)
         .expect("Write interrupted");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The frontend always links the staticlib backend, even if another one is used
+    extern crate rftrace as _;
+
+    /// Backend into which the test records directly, indexing its buffer like the staticlib backend.
+    struct RingBackend {
+        /// Buffer passed to `init()`, as address and length
+        buffer: Mutex<Option<(usize, usize)>>,
+        index: AtomicUsize,
+    }
+
+    impl RingBackend {
+        fn record(&self, event: Event) {
+            let (ptr, len) = self.buffer.lock().unwrap().unwrap();
+            let idx = self.index.fetch_add(1, Ordering::Relaxed);
+            unsafe { *(ptr as *mut Event).add(idx % len) = event };
+        }
+    }
+
+    impl Backend for RingBackend {
+        fn enable(&self) {}
+
+        fn disable(&self) {}
+
+        fn is_enabled(&self) -> bool {
+            true
+        }
+
+        unsafe fn init(&self, buf: *mut Event, len: usize, _overwriting: bool) -> InitStatus {
+            *self.buffer.lock().unwrap() = Some((buf as usize, len));
+            self.index.store(0, Ordering::Relaxed);
+            InitStatus::Ok
+        }
+
+        fn get_events(&self) -> *const Event {
+            self.buffer
+                .lock()
+                .unwrap()
+                .take()
+                .map_or(std::ptr::null(), |(ptr, _)| ptr as *const Event)
+        }
+
+        fn get_events_index(&self) -> usize {
+            self.index.load(Ordering::Relaxed)
+        }
+    }
+
+    static RING: RingBackend = RingBackend {
+        buffer: Mutex::new(None),
+        index: AtomicUsize::new(0),
+    };
+
+    #[test]
+    fn wrapped_ring_buffer_is_dumped_oldest_first() {
+        assert!(set_backend(&RING));
+        let len = MAX_STACK_HEIGHT + 1;
+        let events = init(len, true);
+        let recorded = 2 * len + len / 2;
+        for time in 0..recorded as u64 {
+            RING.record(Event::Exit(Exit {
+                time,
+                from: 0x1000,
+                tid: NonZeroU64::new(1),
+            }));
+        }
+
+        let times: Vec<u64> = get_events(events)
+            .unwrap()
+            .iter()
+            .filter_map(event_time)
+            .collect();
+        let expected: Vec<u64> = ((recorded - len) as u64..recorded as u64).collect();
+        assert_eq!(times, expected);
+    }
+
+    #[test]
+    fn empty_trace_writes_nothing() {
+        let dir = env::temp_dir().join(format!("rftrace-empty-trace-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut payload = [0; RECORD_PAYLOAD_LEN];
+        payload[..8].copy_from_slice(&42u64.to_le_bytes());
+        let events = [
+            Event::Empty,
+            Event::Record(Record {
+                time: 1,
+                tid: NonZeroU64::new(1),
+                kind: RECORD_KIND_CONTEXT,
+                len: 8,
+                payload,
+            }),
+            Event::Record(Record {
+                time: 2,
+                tid: NonZeroU64::new(1),
+                kind: RECORD_KIND_THREAD_EXIT,
+                len: 0,
+                payload: [0; RECORD_PAYLOAD_LEN],
+            }),
+            Event::Empty,
+        ];
+
+        let mut failed = Vec::new();
+        let result = write_traces(
+            &events,
+            dir.to_str().unwrap(),
+            false,
+            &DumpOptions::default(),
+            &mut failed,
+        );
+        let err = result.unwrap_err();
+        assert!(is_empty_trace(&err));
+        assert!(err.get_ref().unwrap().is::<EmptyTrace>());
+        assert!(failed.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// A period in which tracing was disabled, see `RECORD_KIND_TRACING`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TracingGap {
+    /// Time tracing was disabled.
+    pub start: u64,
+    /// Time tracing was enabled again, or `None` if it stayed disabled until the end of the trace.
+    pub end: Option<u64>,
+    /// Thread which disabled tracing.
+    pub tid: Option<NonZeroU64>,
+    /// Whether the backend disabled tracing itself, since the event buffer was full.
+    pub buffer_full: bool,
+}
+
+/// Returns the periods in `events`, which have to be ordered oldest first, in which tracing was disabled.
+///
+/// Missing events within these periods are expected, rather than lost. Exporters can show them to explain gaps in a trace.
+pub fn tracing_gaps(events: &[Event]) -> Vec<TracingGap> {
+    let mut gaps: Vec<TracingGap> = Vec::new();
+    for e in events {
+        let r = match e {
+            Event::Record(r) if r.kind == RECORD_KIND_TRACING && r.len > 0 => r,
+            _ => continue,
+        };
+        let open = gaps.last_mut().filter(|gap| gap.end.is_none());
+        match (r.payload[0], open) {
+            (TRACING_ENABLED, Some(gap)) => gap.end = Some(r.time),
+            (TRACING_DISABLED, None) | (TRACING_BUFFER_FULL, None) => gaps.push(TracingGap {
+                start: r.time,
+                end: None,
+                tid: r.tid,
+                buffer_full: r.payload[0] == TRACING_BUFFER_FULL,
+            }),
+            _ => {}
+        }
+    }
+    gaps
+}
@@ -0,0 +1,121 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::interface::*;
+use crate::profile::{profile, FunctionProfile};
+use crate::symbols::Symbols;
+
+/// Kind of compiler generated code, see `glue_kind()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum GlueKind {
+    /// `core::ptr::drop_in_place<T>`, the drop glue of `T`. Also called when dropping a `Box<dyn Trait>` through its vtable.
+    DropGlue,
+    /// `<T as Drop>::drop`, called by the drop glue of `T`.
+    DropImpl,
+    /// A closure, named after the function defining it.
+    Closure,
+}
+
+/// Combined cost of the drop glue or closures belonging to a single type or function, see `glue_costs()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GlueCost {
+    pub kind: GlueKind,
+    /// Type dropped, or function defining the closures.
+    pub owner: String,
+    /// Number of distinct functions grouped, such as nested closures.
+    pub functions: usize,
+    /// Combined profile of these functions. Times include callees, so nested drops of the same type are counted repeatedly.
+    pub profile: FunctionProfile,
+}
+
+/// Returns the kind of compiler generated code the demangled symbol `name` is, along with the type or function owning it.
+///
+/// Recognizes drop glue, `Drop` impls and closures by the names rustc gives them, such as `core::ptr::drop_in_place<my::Type>`
+/// and `my::function::{{closure}}`. Returns `None` for all other functions. Drop glue of rustc versions which did not
+/// include the type in the symbol is owned by `?`.
+pub fn glue_kind(name: &str) -> Option<(GlueKind, &str)> {
+    for prefix in ["core::ptr::drop_in_place", "core::ptr::real_drop_in_place"].iter() {
+        if let Some(rest) = name.strip_prefix(prefix) {
+            if rest.is_empty() {
+                return Some((GlueKind::DropGlue, "?"));
+            }
+            if let Some(owner) = rest.strip_prefix('<').and_then(|r| r.strip_suffix('>')) {
+                return Some((GlueKind::DropGlue, owner));
+            }
+        }
+    }
+    if let Some(owner) = name
+        .strip_prefix('<')
+        .and_then(|r| r.strip_suffix(" as core::ops::drop::Drop>::drop"))
+    {
+        return Some((GlueKind::DropImpl, owner));
+    }
+    // Legacy symbols name closures `{{closure}}`, v0 symbols `{closure#0}`. Nested closures belong to the outermost function.
+    ["::{{closure}}", "::{closure#"]
+        .iter()
+        .filter_map(|marker| name.find(marker))
+        .min()
+        .map(|idx| (GlueKind::Closure, &name[..idx]))
+}
+
+/// Groups the drop glue and closures called in `events` by their owning type or function, most expensive first.
+///
+/// Raw traces scatter their cost across many hardly readable `drop_in_place` and closure symbols, one per generic
+/// instance. This sums them up per owner and kind instead. `events` have to be ordered oldest first, functions are
+/// named by `symbols`, see `glue_kind()`.
+pub fn glue_costs(events: &[Event], symbols: &Symbols) -> Vec<GlueCost> {
+    let mut groups: HashMap<(GlueKind, &str), GlueCost> = HashMap::new();
+    for (addr, p) in profile(events) {
+        let (kind, owner) = match symbols.lookup(addr).and_then(|(name, _)| glue_kind(name)) {
+            Some(glue) => glue,
+            None => continue,
+        };
+        let group = groups.entry((kind, owner)).or_insert_with(|| GlueCost {
+            kind,
+            owner: owner.to_owned(),
+            functions: 0,
+            profile: FunctionProfile::default(),
+        });
+        group.functions += 1;
+        group.profile.calls += p.calls;
+        group.profile.total_time += p.total_time;
+        group.profile.max_time = group.profile.max_time.max(p.max_time);
+        group.profile.max_depth = group.profile.max_depth.max(p.max_depth);
+    }
+
+    let mut costs: Vec<GlueCost> = groups.into_values().collect();
+    costs.sort_by(|a, b| {
+        Reverse(a.profile.total_time)
+            .cmp(&Reverse(b.profile.total_time))
+            .then_with(|| (a.kind, &a.owner).cmp(&(b.kind, &b.owner)))
+    });
+    costs
+}
+
+/// Formats `costs`, such as returned by `glue_costs()`, as human-readable report, one owner per line.
+///
+/// Lists the total time in TSC cycles, the number of calls and functions, and the kind of code along with its owner.
+pub fn glue_report(costs: &[GlueCost]) -> String {
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{:>14}  {:>10}  {:>9}  owner",
+        "total time", "calls", "functions"
+    )
+    .unwrap();
+    for cost in costs {
+        let kind = match cost.kind {
+            GlueKind::DropGlue => "drop glue of",
+            GlueKind::DropImpl => "Drop impl of",
+            GlueKind::Closure => "closures in",
+        };
+        writeln!(
+            report,
+            "{:>14}  {:>10}  {:>9}  {} {}",
+            cost.profile.total_time, cost.profile.calls, cost.functions, kind, cost.owner
+        )
+        .unwrap();
+    }
+    report
+}
@@ -0,0 +1,60 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+
+use crate::frontend::record;
+use crate::interface::*;
+
+thread_local! {
+    static HIGHLIGHT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets or clears the highlight flag of the current thread, returning its previous value.
+///
+/// Marks interesting regions, such as a slow request, to find them again in hour-long traces. All events of the thread
+/// recorded while the flag is set are flagged, see `highlight_flags()`. Toggles are recorded as `RECORD_KIND_HIGHLIGHT`
+/// records, so they are lost while tracing is disabled, or once they are overwritten in a ring buffer.
+pub fn set_highlight(highlight: bool) -> bool {
+    let previous = HIGHLIGHT.with(|flag| flag.replace(highlight));
+    if previous != highlight {
+        record(RECORD_KIND_HIGHLIGHT, &[u8::from(highlight)]);
+    }
+    previous
+}
+
+/// Returns the highlight flag of each event in `events`, which have to be ordered oldest first, see `set_highlight()`.
+///
+/// The flag of each thread is replayed from its highlight records, which are flagged like the events around them.
+/// Exporters can use the flags to color or filter regions.
+pub fn highlight_flags(events: &[Event]) -> Vec<bool> {
+    let mut flags: HashMap<Option<NonZeroU64>, bool> = HashMap::new();
+    events
+        .iter()
+        .map(|e| {
+            let tid = match e {
+                Event::Entry(e) => e.tid,
+                Event::Exit(e) => e.tid,
+                Event::Record(r) if r.kind == RECORD_KIND_HIGHLIGHT && r.len > 0 => {
+                    flags.insert(r.tid, r.payload[0] != 0);
+                    r.tid
+                }
+                Event::Record(r) => r.tid,
+                Event::Empty => return false,
+            };
+            flags.get(&tid).copied().unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Returns only the events of `events`, ordered oldest first, which were recorded while their thread was highlighted.
+///
+/// Calls which started outside of a highlighted region keep only their flagged half, which uftrace tolerates.
+/// The result can be passed to any export, such as `write_full_uftrace()`.
+pub fn highlighted_only(events: &[Event]) -> Vec<Event> {
+    events
+        .iter()
+        .zip(highlight_flags(events))
+        .filter(|(_, flag)| *flag)
+        .map(|(e, _)| *e)
+        .collect()
+}
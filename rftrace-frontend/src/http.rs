@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::{env, process};
+
+use crate::frontend::{
+    attach, disable, enable, is_enabled, stats, write_full_uftrace, DumpOptions,
+};
+use crate::metrics::metrics_text;
+
+/// Serves a minimal HTTP control interface on `addr`, so traces can be pulled from a running service without shell access.
+///
+/// Endpoints:
+/// * `/enable`, `/disable` - toggle tracing
+/// * `/status` - tracing state and backend statistics
+/// * `/metrics` - tracer health in the Prometheus text format, see `metrics_text()`
+/// * `/snapshot` - a tar archive of the uftrace directory of all events recorded so far, see `write_full_uftrace()`
+///
+/// Blocks forever handling one request at a time, so it should run on its own thread.
+/// There is no authentication whatsoever, only bind it to trusted interfaces.
+pub fn serve_control<A: ToSocketAddrs>(addr: A, binary_name: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Serving rftrace control on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        if let Err(err) = handle(stream?, binary_name) {
+            println!("  rftrace control request failed: {}", err);
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, binary_name: &str) -> io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&mut stream).read_line(&mut request)?;
+    // e.g. "GET /snapshot HTTP/1.1"
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    match path {
+        "/enable" => {
+            enable();
+            respond(&mut stream, "200 OK", "text/plain", b"tracing enabled\n")
+        }
+        "/disable" => {
+            disable();
+            respond(&mut stream, "200 OK", "text/plain", b"tracing disabled\n")
+        }
+        "/status" => {
+            let status = format!("enabled: {}\n{:?}\n", is_enabled(), stats());
+            respond(&mut stream, "200 OK", "text/plain", status.as_bytes())
+        }
+        "/metrics" => respond(
+            &mut stream,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics_text().as_bytes(),
+        ),
+        "/snapshot" => match snapshot_archive(binary_name) {
+            Ok(archive) => respond(&mut stream, "200 OK", "application/x-tar", &archive),
+            Err(err) => respond(
+                &mut stream,
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", err).as_bytes(),
+            ),
+        },
+        _ => respond(
+            &mut stream,
+            "404 Not Found",
+            "text/plain",
+            b"endpoints: /enable, /disable, /status, /metrics, /snapshot\n",
+        ),
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+/// Writes a snapshot of the events as uftrace directory into a temporary directory and archives it.
+fn snapshot_archive(binary_name: &str) -> io::Result<Vec<u8>> {
+    let attachment = attach()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no event buffer initialized"))?;
+    let events = attachment.snapshot();
+    drop(attachment);
+
+    let dir = env::temp_dir().join(format!("rftrace-snapshot-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let result = write_full_uftrace(
+        &events,
+        &dir.to_string_lossy(),
+        binary_name,
+        &DumpOptions::default(),
+    )
+    .and_then(|()| tar_dir(&dir));
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+/// Packs all files of `dir` into an uncompressed ustar archive, with paths relative to `dir`.
+fn tar_dir(dir: &std::path::Path) -> io::Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let mut content = Vec::new();
+        File::open(entry.path())?.read_to_end(&mut content)?;
+
+        let mut header = [0u8; 512];
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let name = &name.as_bytes()[..name.len().min(99)];
+        header[..name.len()].copy_from_slice(name);
+        header[100..108].copy_from_slice(b"0000644\0"); // mode
+        header[108..116].copy_from_slice(b"0000000\0"); // uid
+        header[116..124].copy_from_slice(b"0000000\0"); // gid
+        header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+        header[148..156].copy_from_slice(b"        "); // checksum is calculated with spaces here
+        header[156] = b'0'; // regular file
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(&content);
+        // pad content to the block size
+        archive.resize(archive.len() + (512 - content.len() % 512) % 512, 0);
+    }
+    // end of archive: two empty blocks
+    archive.resize(archive.len() + 1024, 0);
+    Ok(archive)
+}
@@ -1,21 +1,122 @@
 #[allow(dead_code)]
 pub const MAX_STACK_HEIGHT: usize = 1000;
 
-#[repr(C)]
+/// Version of the event layout and backend functions shared by backend and frontend.
+/// Has to be bumped whenever `Event`, its variants or the signature of a backend function change in an incompatible way.
+#[allow(dead_code)]
+pub const INTERFACE_VERSION: u32 = 3;
+
+/// Maximum number of payload bytes a `Record` can carry. Chosen so `Record` is no larger than `Call`.
+#[allow(dead_code)]
+pub const RECORD_PAYLOAD_LEN: usize = 14;
+
+/// Record kinds starting at this value are free for application use. Lower kinds are reserved for rftrace.
+#[allow(dead_code)]
+pub const RECORD_KIND_USER: u8 = 0x80;
+
+/// Kind of the `Record` written whenever a thread switches its context, see `rftrace_backend_set_context()`.
+/// The payload is the new context ID as little endian `u64`, 0 meaning no context. It applies to all subsequent events of the thread.
+#[allow(dead_code)]
+pub const RECORD_KIND_CONTEXT: u8 = 0x01;
+
+/// Kind of the `Record` written when a thread exits, see `rftrace_backend_thread_exit()`. Has no payload.
+/// The TID of the record may be reused by later threads.
+#[allow(dead_code)]
+pub const RECORD_KIND_THREAD_EXIT: u8 = 0x02;
+
+/// Kind of the `Record` holding a snapshot of resource usage, written by the frontend.
+/// The payload is the heap usage and resident set size in KiB, followed by the thread count, each as little endian `u32`.
+#[allow(dead_code)]
+pub const RECORD_KIND_RESOURCES: u8 = 0x03;
+
+/// Kind of the `Record` written by the frontend whenever a thread toggles its highlight flag.
+/// The payload is a single byte, 1 if the flag is set and 0 otherwise. It applies to all subsequent events of the thread.
+#[allow(dead_code)]
+pub const RECORD_KIND_HIGHLIGHT: u8 = 0x04;
+
+/// Kind of the `Record` written whenever a thread records on another core than before, with per-core buffers only.
+/// The payload is the previous and the new core ID, each as little endian `u32`. It precedes the first event on the new core.
+#[allow(dead_code)]
+pub const RECORD_KIND_MIGRATION: u8 = 0x05;
+
+/// Kind of the `Record` written whenever tracing is enabled or disabled, so gaps in a trace are explained.
+/// The payload is a single `TRACING_*` byte. Disabling is recorded by the thread which disabled tracing.
+#[allow(dead_code)]
+pub const RECORD_KIND_TRACING: u8 = 0x06;
+
+/// `RECORD_KIND_TRACING` payload: tracing was enabled.
+#[allow(dead_code)]
+pub const TRACING_ENABLED: u8 = 1;
+/// `RECORD_KIND_TRACING` payload: tracing was disabled on request.
+#[allow(dead_code)]
+pub const TRACING_DISABLED: u8 = 0;
+/// `RECORD_KIND_TRACING` payload: tracing was disabled by the backend, since the non-overwriting buffer is full.
+#[allow(dead_code)]
+pub const TRACING_BUFFER_FULL: u8 = 2;
+
+/// Kind of the `Record` written right before the backend halts, since a saved return address failed its canary check.
+/// The payload is the stack location of the corrupted frame as little endian `u64`, followed by its depth on the return
+/// stack as little endian `u32`. Returning to the corrupted address would crash at a random place, so the thread halts instead.
+#[allow(dead_code)]
+pub const RECORD_KIND_CORRUPTION: u8 = 0x07;
+
+/// Kind of the `Record` pairing its timestamp with the wall-clock time, written whenever the frontend enables tracing.
+/// The payload is the Unix time in nanoseconds as little endian `u64`. Kernels can record it with a time from their hypervisor.
+#[allow(dead_code)]
+pub const RECORD_KIND_WALLCLOCK: u8 = 0x08;
+
+/// Kind of the `Record` written by the kernel's network driver for each received packet, see `kernel::net_rx()`.
+/// The payload is the receive queue as little endian `u16`, followed by the packet length in bytes as little endian `u32`.
+#[allow(dead_code)]
+pub const RECORD_KIND_NET_RX: u8 = 0x09;
+
+/// Kind of the `Record` written by the kernel's network driver for each transmitted packet, see `kernel::net_tx()`.
+/// The payload is laid out like that of `RECORD_KIND_NET_RX`, with the transmit queue instead.
+#[allow(dead_code)]
+pub const RECORD_KIND_NET_TX: u8 = 0x0a;
+
+/// Kind of the `Record` written by the kernel's block driver when submitting a request, see `kernel::block_submit()`.
+/// The payload is the first sector as little endian `u64`, followed by the length in bytes as little endian `u32` and
+/// a byte which is 1 for writes and 0 for reads.
+#[allow(dead_code)]
+pub const RECORD_KIND_BLOCK_SUBMIT: u8 = 0x0b;
+
+/// Kind of the `Record` written by the kernel's block driver when a request completed, see `kernel::block_complete()`.
+/// The payload is laid out like that of `RECORD_KIND_BLOCK_SUBMIT`, so completions can be paired with their submission.
+#[allow(dead_code)]
+pub const RECORD_KIND_BLOCK_COMPLETE: u8 = 0x0c;
+
+/// Size of an `Event` in bytes, the same on all targets.
+#[allow(dead_code)]
+pub const EVENT_SIZE: usize = 40;
+
+/// A single entry of the event buffer.
+///
+/// The layout does not depend on the target's pointer width: a `u64` discriminant with the fixed values below,
+/// followed by the variant's fields, all of which are `u64` or bytes. Addresses are stored as `u64`, TIDs as `u64`
+/// with 0 for none, which `Option<NonZeroU64>` guarantees. Only the byte order is native, see `Event::from_bytes()`
+/// for reading buffers captured on another machine.
+#[repr(u64)]
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
 pub enum Event {
-    Empty,
-    Entry(Call),
-    Exit(Exit),
+    Empty = 0,
+    Entry(Call) = 1,
+    Exit(Exit) = 2,
+    Record(Record) = 3,
 }
 
+const _: () = assert!(core::mem::size_of::<Event>() == EVENT_SIZE);
+const _: () = assert!(core::mem::size_of::<Option<core::num::NonZeroU64>>() == 8);
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct Call {
     pub time: u64,
-    pub from: *const usize,
-    pub to: *const usize,
+    /// Address of the call site.
+    pub from: u64,
+    /// Address of the called function.
+    pub to: u64,
     pub tid: Option<core::num::NonZeroU64>,
 }
 
@@ -23,6 +124,429 @@ pub struct Call {
 #[derive(Debug, Clone, Copy)]
 pub struct Exit {
     pub time: u64,
-    pub from: *const usize,
+    /// Address of the function returned from.
+    pub from: u64,
     pub tid: Option<core::num::NonZeroU64>,
 }
+
+/// Generic event consisting of a kind and a variable length payload.
+///
+/// Used for all event types besides function entries and exits, so new ones can be added without changing the layout of `Event`.
+/// Consumers have to skip kinds they do not know.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    pub time: u64,
+    pub tid: Option<core::num::NonZeroU64>,
+    pub kind: u8,
+    /// Number of valid bytes in `payload`.
+    pub len: u8,
+    pub payload: [u8; RECORD_PAYLOAD_LEN],
+}
+
+#[allow(dead_code)]
+impl Event {
+    /// Decodes an event from the first `EVENT_SIZE` bytes of `bytes`, written in little or big endian byte order.
+    ///
+    /// Lets tools parse buffers captured on another machine, such as from a memory image of a guest.
+    /// Returns `None` if `bytes` is too short or holds no valid event.
+    pub fn from_bytes(bytes: &[u8], big_endian: bool) -> Option<Self> {
+        let u64_at = |offset: usize| {
+            let mut word = [0; 8];
+            word.copy_from_slice(bytes.get(offset..offset + 8)?);
+            Some(if big_endian {
+                u64::from_be_bytes(word)
+            } else {
+                u64::from_le_bytes(word)
+            })
+        };
+        let tid_at = |offset: usize| u64_at(offset).map(core::num::NonZeroU64::new);
+        if bytes.len() < EVENT_SIZE {
+            return None;
+        }
+
+        match u64_at(0)? {
+            0 => Some(Event::Empty),
+            1 => Some(Event::Entry(Call {
+                time: u64_at(8)?,
+                from: u64_at(16)?,
+                to: u64_at(24)?,
+                tid: tid_at(32)?,
+            })),
+            2 => Some(Event::Exit(Exit {
+                time: u64_at(8)?,
+                from: u64_at(16)?,
+                tid: tid_at(24)?,
+            })),
+            3 => {
+                let len = bytes[25];
+                if len as usize > RECORD_PAYLOAD_LEN {
+                    return None;
+                }
+                let mut payload = [0; RECORD_PAYLOAD_LEN];
+                payload.copy_from_slice(&bytes[26..26 + RECORD_PAYLOAD_LEN]);
+                Some(Event::Record(Record {
+                    time: u64_at(8)?,
+                    tid: tid_at(16)?,
+                    kind: bytes[24],
+                    len,
+                    payload,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Encodes the event in little or big endian byte order, the inverse of `from_bytes()`.
+    pub fn to_bytes(&self, big_endian: bool) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0; EVENT_SIZE];
+        let mut put = |offset: usize, value: u64| {
+            let word = if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            bytes[offset..offset + 8].copy_from_slice(&word);
+        };
+        let tid = |tid: Option<core::num::NonZeroU64>| tid.map_or(0, core::num::NonZeroU64::get);
+        match self {
+            Event::Empty => put(0, 0),
+            Event::Entry(e) => {
+                put(0, 1);
+                put(8, e.time);
+                put(16, e.from);
+                put(24, e.to);
+                put(32, tid(e.tid));
+            }
+            Event::Exit(e) => {
+                put(0, 2);
+                put(8, e.time);
+                put(16, e.from);
+                put(24, tid(e.tid));
+            }
+            Event::Record(r) => {
+                put(0, 3);
+                put(8, r.time);
+                put(16, tid(r.tid));
+                bytes[24] = r.kind;
+                bytes[25] = r.len;
+                bytes[26..26 + RECORD_PAYLOAD_LEN].copy_from_slice(&r.payload);
+            }
+        }
+        bytes
+    }
+}
+
+/// Result of handing an event buffer to the backend, see `Backend::init()`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum InitStatus {
+    /// The buffer is recorded into from now on.
+    Ok,
+    /// Another buffer is in use already, for example one initialized by the kernel, or frontends are still attached to
+    /// the buffer to be replaced. The new buffer is not used.
+    AlreadyInitialized,
+    /// The buffer is null or not larger than `MAX_STACK_HEIGHT`.
+    InvalidBuffer,
+}
+
+/// Counters maintained by the backend, for post-mortem diagnosis of a trace.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(dead_code)]
+pub struct Stats {
+    /// Function entries seen while tracing was enabled.
+    pub entries: u64,
+    /// Function exits through the return trampoline.
+    pub returns: u64,
+    /// Function entries skipped by a filter.
+    pub filtered: u64,
+    /// Function entries whose return could not be hooked, since the return stack was full.
+    pub retstack_overflows: u64,
+    /// Calls from within the tracer's hooks, which are neither recorded nor hooked.
+    pub recursion_hits: u64,
+    /// Function entries not recorded, since the function exceeded its quota, see `set_function_quota()` in the frontend.
+    pub demoted: u64,
+    /// Function entries not recorded, since their thread exceeded its quota, see `set_thread_quota()` in the frontend.
+    pub quota_exceeded: u64,
+    /// Function entries only sampled, since tracing exceeded its overhead budget, see `set_overhead_budget()` in the frontend.
+    pub throttled: u64,
+}
+
+/// Half-open range of code addresses `start..end`, used for filtering functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct AddrRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[allow(dead_code)]
+impl AddrRange {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// `Descriptor::magic`, to recognize the descriptor in raw memory.
+#[allow(dead_code)]
+pub const DESCRIPTOR_MAGIC: u64 = u64::from_le_bytes(*b"rftrdesc");
+
+/// Describes the event buffer to tools reading it from outside the traced program, such as debuggers.
+///
+/// The backend exports it as `RFTRACE_DESCRIPTOR`. Its layout is stable, new fields are only ever appended,
+/// so tools such as the GDB helpers in `gdb/rftrace_gdb.py` can rely on it without debug info.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct Descriptor {
+    /// Always `DESCRIPTOR_MAGIC`.
+    pub magic: u64,
+    /// `INTERFACE_VERSION` of the backend.
+    pub version: u32,
+    /// Size of a single `Event` in bytes.
+    pub event_size: u32,
+    /// Event buffer, null while no buffer is initialized or it was taken back by the frontend.
+    pub events: *const Event,
+    /// Number of events in the buffer.
+    pub len: usize,
+    /// Event index of the buffer, see `Backend::get_events_index()`. Changes along with `events` when buffers are swapped.
+    pub index: *const usize,
+}
+
+/// Event buffer of a single core, handed to the backend with `rftrace_backend_init_per_core()`.
+/// Also used for the buffers of `rftrace_backend_init_per_thread()`, one per thread instead of per core.
+///
+/// Each core records into its own buffer with its own index, so cores never contend for a cache line.
+/// Aligned to a cache line for the same reason.
+#[repr(C, align(64))]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct CoreBuffer {
+    pub events: *mut Event,
+    /// Number of events in the buffer.
+    pub len: usize,
+    /// Index of the next event of this buffer. The event with index `i` is stored at `i % len`.
+    pub index: core::sync::atomic::AtomicUsize,
+}
+
+// All cores write through the atomic index, so each slot is only written by the core which reserved it
+unsafe impl Sync for CoreBuffer {}
+
+/// Return address the backend replaced with its trampoline on function entry, to restore it once the function returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub struct SavedRet {
+    pub stackloc: *mut *const usize,
+    pub retloc: *const usize,
+    pub childip: *const usize,
+    // whether the entry was recorded, so the exit is only recorded if it is
+    pub recorded: bool,
+    // checksum of the addresses above, see `SavedRet::intact()` in the backend
+    pub canary: usize,
+}
+
+/// Return stack of a suspended fiber or green thread, see `rftrace_backend_switch_stack()`.
+///
+/// The scheduler owns the storage of the frames, the backend only copies them in and out when switching.
+#[repr(C)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct StackContext {
+    /// Storage for up to `capacity` frames.
+    pub frames: *mut SavedRet,
+    pub capacity: usize,
+    /// Number of frames currently saved in `frames`.
+    pub len: usize,
+    /// TID the fiber records with, or 0 if it did not record anything yet.
+    pub tid: u64,
+}
+
+/// C symbols exported by the staticlib backend, which the frontend links against.
+///
+/// Besides `mcount`, only `Backend` is required by the frontend. The other symbols provide optional features,
+/// such as filters, statistics or attaching. Has to be kept in sync with the list of retained symbols in `build.rs`.
+#[allow(dead_code)]
+pub const BACKEND_SYMBOLS: &[&str] = &[
+    "mcount",
+    "RFTRACE_DESCRIPTOR",
+    "rftrace_backend_attach",
+    "rftrace_backend_detach",
+    "rftrace_backend_disable",
+    "rftrace_backend_enable",
+    "rftrace_backend_full_trace_begin",
+    "rftrace_backend_full_trace_end",
+    "rftrace_backend_get_events",
+    "rftrace_backend_get_events_index",
+    "rftrace_backend_get_stats",
+    "rftrace_backend_init",
+    "rftrace_backend_init_per_core",
+    "rftrace_backend_init_per_thread",
+    "rftrace_backend_interface_version",
+    "rftrace_backend_is_enabled",
+    "rftrace_backend_memory_pressure",
+    "rftrace_backend_record",
+    "rftrace_backend_replace_buffer",
+    "rftrace_backend_scope_begin",
+    "rftrace_backend_scope_end",
+    "rftrace_backend_set_context",
+    "rftrace_backend_set_exclude",
+    "rftrace_backend_set_filter",
+    "rftrace_backend_set_flight_recorder",
+    "rftrace_backend_set_function_quota",
+    "rftrace_backend_set_max_depth",
+    "rftrace_backend_set_overhead_budget",
+    "rftrace_backend_set_page_filter",
+    "rftrace_backend_set_pressure_handler",
+    "rftrace_backend_set_sample_period",
+    "rftrace_backend_set_scoped",
+    "rftrace_backend_set_thread_quota",
+    "rftrace_backend_set_tid_offset",
+    "rftrace_backend_suppress",
+    "rftrace_backend_swap_buffer",
+    "rftrace_backend_switch_stack",
+    "rftrace_backend_take_per_thread",
+    "rftrace_backend_task_create",
+    "rftrace_backend_thread_exit",
+    "rftrace_backend_unsuppress",
+];
+
+/// The contract between the frontend and a backend recording events.
+///
+/// The staticlib backend implements it with the `rftrace_backend_*` functions of the same names.
+/// Alternative backends, such as ptrace- or eBPF-based recorders, can implement it to be used by the frontend instead.
+#[allow(dead_code)]
+pub trait Backend: Sync {
+    /// Version of the event layout the backend writes, see `INTERFACE_VERSION`.
+    fn interface_version(&self) -> u32 {
+        INTERFACE_VERSION
+    }
+
+    /// Starts recording events into the buffer passed to `init()`.
+    fn enable(&self);
+
+    /// Stops recording events. Events may still be written for a short while by calls already in progress.
+    fn disable(&self);
+
+    /// Returns whether events are currently recorded.
+    fn is_enabled(&self) -> bool;
+
+    /// Hands a buffer of `len` events to the backend. If `overwriting`, it is used as a ring buffer, otherwise recording stops once it is full.
+    ///
+    /// Only one buffer can be in use at a time. If there is one already, the new buffer is rejected.
+    ///
+    /// # Safety
+    ///
+    /// `buf` has to be valid for writes of `len` events until it is taken back with `get_events()`.
+    unsafe fn init(&self, buf: *mut Event, len: usize, overwriting: bool) -> InitStatus;
+
+    /// Takes the buffer passed to `init()` back, so the backend no longer writes to it.
+    ///
+    /// Writes still in progress on other threads have to be finished before returning, so the caller owns the buffer exclusively.
+    ///
+    /// Returns null if there is no buffer or it can not be taken back right now.
+    fn get_events(&self) -> *const Event;
+
+    /// Returns the global index of the next event, which is the number of events recorded so far.
+    ///
+    /// The event with global index `i` is stored at `i % len` in the buffer. Has to stay valid after the buffer was taken
+    /// back with `get_events()`, returning its final index, since the frontend reads it afterwards to order a wrapped buffer.
+    fn get_events_index(&self) -> usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU64;
+
+    fn events() -> [Event; 4] {
+        let mut payload = [0; RECORD_PAYLOAD_LEN];
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        [
+            Event::Empty,
+            Event::Entry(Call {
+                time: 0x0102_0304_0506_0708,
+                from: 0x1000,
+                to: 0x2000,
+                tid: NonZeroU64::new(2),
+            }),
+            Event::Exit(Exit {
+                time: 0x0102_0304_0506_0709,
+                from: 0x2000,
+                tid: None,
+            }),
+            Event::Record(Record {
+                time: 0x0102_0304_0506_070a,
+                tid: NonZeroU64::new(3),
+                kind: RECORD_KIND_USER,
+                len: 3,
+                payload,
+            }),
+        ]
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for event in events().iter() {
+            for &big_endian in [false, true].iter() {
+                let bytes = event.to_bytes(big_endian);
+                let decoded = Event::from_bytes(&bytes, big_endian).unwrap();
+                assert_eq!(decoded.to_bytes(big_endian), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn golden_bytes() {
+        let [_, entry, exit, record] = events();
+
+        let mut expected = [0; EVENT_SIZE];
+        expected[0] = 1;
+        expected[8..16].copy_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+        expected[17] = 0x10;
+        expected[25] = 0x20;
+        expected[32] = 2;
+        assert_eq!(entry.to_bytes(false), expected);
+
+        let mut expected = [0; EVENT_SIZE];
+        expected[0] = 2;
+        expected[8..16].copy_from_slice(&[9, 7, 6, 5, 4, 3, 2, 1]);
+        expected[17] = 0x20;
+        assert_eq!(exit.to_bytes(false), expected);
+
+        let mut expected = [0; EVENT_SIZE];
+        expected[0] = 3;
+        expected[8..16].copy_from_slice(&[0x0a, 7, 6, 5, 4, 3, 2, 1]);
+        expected[16] = 3;
+        expected[24] = RECORD_KIND_USER;
+        expected[25] = 3;
+        expected[26..40].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+        assert_eq!(record.to_bytes(false), expected);
+
+        // Big endian stores the same words, with their bytes reversed
+        let bytes = record.to_bytes(true);
+        assert_eq!(bytes[7], 3);
+        assert_eq!(bytes[8..16], [1, 2, 3, 4, 5, 6, 7, 0x0a]);
+        assert_eq!(bytes[23], 3);
+        assert_eq!(bytes[24..40], expected[24..40]);
+    }
+
+    #[test]
+    fn invalid_bytes() {
+        let bytes = events()[3].to_bytes(false);
+        assert!(Event::from_bytes(&bytes[..EVENT_SIZE - 1], false).is_none());
+
+        let mut unknown = bytes;
+        unknown[0] = 4;
+        assert!(Event::from_bytes(&unknown, false).is_none());
+
+        let mut too_long = bytes;
+        too_long[25] = RECORD_PAYLOAD_LEN as u8 + 1;
+        assert!(Event::from_bytes(&too_long, false).is_none());
+    }
+}
@@ -1,12 +1,127 @@
 //! This crate provides a possible frontend for rftracer.
 //! It can initialize an event buffer, enable/disable tracing and save the trace to disk in a uftrace compatible format.
 //! A lot of documentation can be found in the parent workspaces [readme](https://github.com/hermit-os/rftrace).
+//!
+//! Without the default `std` feature, only the minimal `no_std + alloc` frontend of `nostd` is available.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+#![feature(c_variadic)]
+#![feature(linkage)]
 #![feature(vec_into_raw_parts)]
+extern crate alloc;
+#[cfg(feature = "std")]
 extern crate byteorder;
 
-mod frontend;
 mod interface;
 
-// Re-export frontend functions
-pub use frontend::*;
+pub use interface::{
+    AddrRange, Backend, Call, Descriptor, Event, Exit, InitStatus, Record, Stats, BACKEND_SYMBOLS,
+    DESCRIPTOR_MAGIC, INTERFACE_VERSION, RECORD_KIND_BLOCK_COMPLETE, RECORD_KIND_BLOCK_SUBMIT,
+    RECORD_KIND_CONTEXT, RECORD_KIND_CORRUPTION, RECORD_KIND_HIGHLIGHT, RECORD_KIND_MIGRATION,
+    RECORD_KIND_NET_RX, RECORD_KIND_NET_TX, RECORD_KIND_RESOURCES, RECORD_KIND_THREAD_EXIT,
+    RECORD_KIND_TRACING, RECORD_KIND_USER, RECORD_KIND_WALLCLOCK, RECORD_PAYLOAD_LEN,
+    TRACING_BUFFER_FULL, TRACING_DISABLED, TRACING_ENABLED,
+};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "std")] {
+        mod anonymize;
+        mod assertions;
+        mod block;
+        mod check;
+        mod chrome;
+        mod collapse;
+        mod compact;
+        mod context;
+        mod control;
+        mod coverage;
+        mod critical;
+        mod ctf;
+        mod dispatch;
+        #[cfg(feature = "encryption")]
+        mod encryption;
+        mod frontend;
+        mod gaps;
+        mod glue;
+        mod highlight;
+        #[cfg(feature = "http")]
+        mod http;
+        mod lines;
+        mod metrics;
+        mod migration;
+        mod net;
+        #[cfg(feature = "otlp")]
+        mod otlp;
+        #[cfg(target_os = "linux")]
+        mod preinit;
+        mod profile;
+        #[cfg(target_os = "linux")]
+        mod ptrace;
+        mod recover;
+        mod resources;
+        mod rotation;
+        mod selftest;
+        #[cfg(target_os = "linux")]
+        mod spawn;
+        #[cfg(feature = "sqlite")]
+        mod sqlite;
+        mod stream;
+        mod symbols;
+        mod timeline;
+        mod uftrace;
+        #[cfg(target_os = "linux")]
+        mod uprobe;
+
+        // Re-export frontend functions
+        pub use anonymize::*;
+        pub use assertions::*;
+        pub use block::*;
+        pub use check::*;
+        pub use chrome::*;
+        pub use collapse::*;
+        pub use compact::*;
+        pub use context::*;
+        pub use control::*;
+        pub use coverage::*;
+        pub use critical::*;
+        pub use ctf::*;
+        pub use dispatch::*;
+        #[cfg(feature = "encryption")]
+        pub use encryption::*;
+        pub use frontend::*;
+        pub use gaps::*;
+        pub use glue::*;
+        pub use highlight::*;
+        #[cfg(feature = "http")]
+        pub use http::*;
+        pub use lines::*;
+        pub use metrics::*;
+        pub use migration::*;
+        pub use net::*;
+        #[cfg(feature = "otlp")]
+        pub use otlp::*;
+        #[cfg(target_os = "linux")]
+        pub use preinit::*;
+        pub use profile::*;
+        #[cfg(target_os = "linux")]
+        pub use ptrace::*;
+        pub use recover::*;
+        pub use resources::*;
+        pub use rotation::*;
+        pub use selftest::*;
+        #[cfg(target_os = "linux")]
+        pub use spawn::*;
+        #[cfg(feature = "sqlite")]
+        pub use sqlite::*;
+        pub use stream::*;
+        pub use symbols::*;
+        pub use timeline::*;
+        pub use uftrace::*;
+        #[cfg(target_os = "linux")]
+        pub use uprobe::*;
+    } else {
+        mod nostd;
+
+        pub use nostd::*;
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::interface::*;
+
+/// Source locations of function addresses as `file:line`, for exporters to show next to each frame.
+#[derive(Clone, Debug, Default)]
+pub struct LineMap {
+    lines: BTreeMap<usize, String>,
+}
+
+impl LineMap {
+    /// Resolves `addrs` with `addr2line`, which reads the DWARF debug info of `binary`.
+    ///
+    /// `load_base` is subtracted from every address before resolving, for binaries loaded at a different address than linked, such as PIEs.
+    /// The map is still keyed by the original addresses. Addresses without debug info are left out.
+    pub fn resolve(
+        binary: &str,
+        addrs: impl IntoIterator<Item = usize>,
+        load_base: usize,
+    ) -> io::Result<Self> {
+        let addrs: Vec<usize> = addrs.into_iter().collect();
+        let mut child = Command::new("addr2line")
+            .arg("-a")
+            .arg("-e")
+            .arg(binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let input: String = addrs
+            .iter()
+            .map(|addr| format!("{:#x}\n", addr.wrapping_sub(load_base)))
+            .collect();
+        // Write from another thread, so neither side blocks on a full pipe
+        let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+        let output = child.wait_with_output()?;
+        writer.join().expect("addr2line writer panicked")?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "addr2line exited with {}",
+                output.status
+            )));
+        }
+
+        // addr2line answers with two lines per address: the address itself and its location
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let lines = addrs
+            .iter()
+            .zip(stdout.lines().skip(1).step_by(2))
+            .filter(|(_, location)| !location.starts_with("??"))
+            .map(|(&addr, location)| (addr, location.to_string()))
+            .collect();
+        Ok(Self { lines })
+    }
+
+    /// Reads a line map written by `write()`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let lines = fs::read_to_string(path)?
+            .lines()
+            .filter_map(|line| {
+                let (addr, location) = line.split_once(' ')?;
+                let addr = usize::from_str_radix(addr, 16).ok()?;
+                Some((addr, location.to_string()))
+            })
+            .collect();
+        Ok(Self { lines })
+    }
+
+    /// Writes the map as lines of hex address and location, such as `401136 src/main.rs:12`.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for (addr, location) in &self.lines {
+            writeln!(out, "{:x} {}", addr, location)?;
+        }
+        out.flush()
+    }
+
+    /// Returns the source location of the function at `addr`.
+    pub fn get(&self, addr: usize) -> Option<&str> {
+        self.lines.get(&addr).map(String::as_str)
+    }
+}
+
+/// Writes the source locations of all functions in `events` to `<out_dir>/<binary name>.lines`, next to a dump.
+///
+/// See `LineMap::resolve()` for `load_base`. Needs `addr2line` and a binary with debug info.
+pub fn write_line_map(
+    events: &[Event],
+    binary: &str,
+    load_base: usize,
+    out_dir: &str,
+) -> io::Result<()> {
+    let addrs: HashSet<usize> = events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Entry(e) => Some(e.to as usize),
+            Event::Exit(e) => Some(e.from as usize),
+            Event::Record(_) | Event::Empty => None,
+        })
+        .collect();
+
+    let binary_name = Path::new(binary)
+        .file_name()
+        .map_or("binary".into(), |name| name.to_string_lossy());
+    let path = format!("{}/{}.lines", out_dir, binary_name);
+    println!("  Creating {} for {} functions", path, addrs.len());
+    LineMap::resolve(binary, addrs, load_base)?.write(&path)
+}
@@ -0,0 +1,132 @@
+use std::fmt::{self, Display, Write};
+
+use crate::frontend::{attach, is_enabled, stats};
+
+/// Rough cost of a single entry or exit hook in TSC cycles, including the trampoline, used for the overhead estimate.
+const HOOK_CYCLES: u64 = 100;
+
+/// Returns the health of the tracer in the Prometheus text exposition format, to be scraped in production.
+///
+/// Contains the tracing state, the backend statistics (see `stats()`), the fill level of the event buffer and a rough
+/// estimate of the cycles spent in the hooks. Buffer metrics are omitted while no event buffer is initialized.
+/// `serve_control()` serves it as `/metrics`.
+pub fn metrics_text() -> String {
+    let mut text = String::new();
+    write_metrics(&mut text).expect("Writing to a String never fails");
+    text
+}
+
+fn write_metrics(out: &mut String) -> fmt::Result {
+    let stats = stats();
+    metric(
+        out,
+        "rftrace_enabled",
+        "gauge",
+        "Whether tracing is currently enabled.",
+        u8::from(is_enabled()),
+    )?;
+
+    if let Some(attachment) = attach() {
+        let len = attachment.buffer_len();
+        let index = attachment.index();
+        drop(attachment);
+        metric(
+            out,
+            "rftrace_events_total",
+            "counter",
+            "Events written into the event buffer, including overwritten ones.",
+            index,
+        )?;
+        metric(
+            out,
+            "rftrace_buffer_events",
+            "gauge",
+            "Size of the event buffer in events.",
+            len,
+        )?;
+        metric(
+            out,
+            "rftrace_buffer_fill_ratio",
+            "gauge",
+            "Fraction of the event buffer holding events.",
+            index.min(len) as f64 / len as f64,
+        )?;
+    }
+
+    metric(
+        out,
+        "rftrace_entries_total",
+        "counter",
+        "Function entries seen while tracing was enabled.",
+        stats.entries,
+    )?;
+    metric(
+        out,
+        "rftrace_returns_total",
+        "counter",
+        "Function exits through the return trampoline.",
+        stats.returns,
+    )?;
+    metric(
+        out,
+        "rftrace_entries_filtered_total",
+        "counter",
+        "Function entries skipped by a filter.",
+        stats.filtered,
+    )?;
+    writeln!(
+        out,
+        "# HELP rftrace_entries_dropped_total Function entries not recorded, since a quota was exceeded."
+    )?;
+    writeln!(out, "# TYPE rftrace_entries_dropped_total counter")?;
+    writeln!(
+        out,
+        "rftrace_entries_dropped_total{{quota=\"function\"}} {}",
+        stats.demoted
+    )?;
+    writeln!(
+        out,
+        "rftrace_entries_dropped_total{{quota=\"thread\"}} {}",
+        stats.quota_exceeded
+    )?;
+    metric(
+        out,
+        "rftrace_entries_throttled_total",
+        "counter",
+        "Function entries only sampled, since tracing exceeded its overhead budget.",
+        stats.throttled,
+    )?;
+    metric(
+        out,
+        "rftrace_retstack_overflows_total",
+        "counter",
+        "Function entries whose exit is not recorded, since the return stack was full.",
+        stats.retstack_overflows,
+    )?;
+    metric(
+        out,
+        "rftrace_recursion_hits_total",
+        "counter",
+        "Calls from within the tracer itself.",
+        stats.recursion_hits,
+    )?;
+    metric(
+        out,
+        "rftrace_overhead_cycles_estimate_total",
+        "counter",
+        "Rough estimate of the TSC cycles spent in the tracing hooks.",
+        (stats.entries + stats.returns) * HOOK_CYCLES,
+    )
+}
+
+fn metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Display,
+) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, kind)?;
+    writeln!(out, "{} {}", name, value)
+}
@@ -0,0 +1,62 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// A thread continuing on another core, see `RECORD_KIND_MIGRATION`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Migration {
+    pub time: u64,
+    pub tid: Option<NonZeroU64>,
+    /// Core the thread recorded its previous event on.
+    pub from: u32,
+    /// Core the thread continued on.
+    pub to: u32,
+}
+
+/// Returns all CPU migrations of threads in `events`, in the order they were recorded.
+///
+/// Migrations are only recorded with per-core buffers, see `init_per_core()` in the backend's kernel API.
+pub fn migrations(events: &[Event]) -> Vec<Migration> {
+    events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Record(r) if r.kind == RECORD_KIND_MIGRATION && r.len >= 8 => Some(Migration {
+                time: r.time,
+                tid: r.tid,
+                from: u32::from_le_bytes(r.payload[0..4].try_into().unwrap()),
+                to: u32::from_le_bytes(r.payload[4..8].try_into().unwrap()),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Formats the CPU migrations in `events` as human-readable report, one thread per paragraph.
+///
+/// Lists how often each thread moved between each pair of cores, most frequent first. Threads which never migrated are left out.
+pub fn migration_report(events: &[Event]) -> String {
+    let mut threads: BTreeMap<u64, BTreeMap<(u32, u32), u64>> = BTreeMap::new();
+    for migration in migrations(events) {
+        *threads
+            .entry(migration.tid.map_or(0, NonZeroU64::get))
+            .or_default()
+            .entry((migration.from, migration.to))
+            .or_default() += 1;
+    }
+
+    let mut report = String::new();
+    for (tid, moves) in threads {
+        let mut moves: Vec<((u32, u32), u64)> = moves.into_iter().collect();
+        moves.sort_by_key(|&(_, count)| Reverse(count));
+        let total: u64 = moves.iter().map(|&(_, count)| count).sum();
+        writeln!(report, "TID {} ({} migrations)", tid, total).unwrap();
+        for ((from, to), count) in moves {
+            writeln!(report, "  {:>10}  core {} -> {}", count, from, to).unwrap();
+        }
+    }
+    report
+}
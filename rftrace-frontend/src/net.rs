@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Write as _;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// A packet passing the kernel's network driver, see `RECORD_KIND_NET_RX` and `RECORD_KIND_NET_TX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub time: u64,
+    pub tid: Option<NonZeroU64>,
+    /// Whether the packet was transmitted rather than received.
+    pub tx: bool,
+    /// Receive or transmit queue of the device.
+    pub queue: u16,
+    /// Length of the packet in bytes.
+    pub len: u32,
+}
+
+impl Packet {
+    /// Decodes a `RECORD_KIND_NET_RX` or `RECORD_KIND_NET_TX` record, returning `None` for other records.
+    pub fn from_record(record: &Record) -> Option<Self> {
+        let tx = match record.kind {
+            RECORD_KIND_NET_RX => false,
+            RECORD_KIND_NET_TX => true,
+            _ => return None,
+        };
+        if record.len < 6 {
+            return None;
+        }
+        Some(Self {
+            time: record.time,
+            tid: record.tid,
+            tx,
+            queue: u16::from_le_bytes(record.payload[0..2].try_into().unwrap()),
+            len: u32::from_le_bytes(record.payload[2..6].try_into().unwrap()),
+        })
+    }
+}
+
+/// Returns all packets in `events`, in the order they were recorded.
+///
+/// Packets are only recorded by kernels whose network driver calls `net_rx()` and `net_tx()` of the backend's kernel API,
+/// such as Hermit's virtio-net driver with the `instrument` feature.
+pub fn packets(events: &[Event]) -> Vec<Packet> {
+    events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Record(r) => Packet::from_record(r),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Formats the packets in `events` as human-readable report, one queue per line.
+///
+/// Lists the number of packets and bytes per direction and queue, along with the TSC cycles between the first and last
+/// packet, so throughput can be compared against the function calls recorded in the same time.
+pub fn network_report(events: &[Event]) -> String {
+    // Packets, bytes, first and last timestamp per direction and queue
+    let mut queues: BTreeMap<(bool, u16), (u64, u64, u64, u64)> = BTreeMap::new();
+    for packet in packets(events) {
+        let (count, bytes, _, last) =
+            queues
+                .entry((packet.tx, packet.queue))
+                .or_insert((0, 0, packet.time, packet.time));
+        *count += 1;
+        *bytes += u64::from(packet.len);
+        *last = packet.time;
+    }
+
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{:>3}  {:>5}  {:>10}  {:>14}  {:>8}  {:>16}",
+        "dir", "queue", "packets", "bytes", "avg size", "duration"
+    )
+    .unwrap();
+    for ((tx, queue), (count, bytes, first, last)) in queues {
+        writeln!(
+            report,
+            "{:>3}  {:>5}  {:>10}  {:>14}  {:>8}  {:>16}",
+            if tx { "TX" } else { "RX" },
+            queue,
+            count,
+            bytes,
+            bytes / count,
+            last - first
+        )
+        .unwrap();
+    }
+    report
+}
@@ -0,0 +1,221 @@
+//! Minimal frontend for `no_std + alloc` environments, used instead of the std frontend without the `std` feature.
+//!
+//! It manages the event buffer and serializes traces into memory, the application stores the files itself.
+//! A uftrace directory consists of `info`, `task.txt`, `sid-00.map` and one `<tid>.dat` per thread ID returned by `thread_ids()`:
+//! ```ignore
+//! let events = rftrace_frontend::try_init(100_000, false).unwrap();
+//! rftrace_frontend::enable();
+//! // ...
+//! let events = rftrace_frontend::take_events(events).unwrap();
+//! let tids = rftrace_frontend::thread_ids(&events);
+//! for &tid in &tids {
+//!     let mut trace = Vec::new();
+//!     rftrace_frontend::write_uftrace_trace(&events, tid, &mut trace);
+//!     store(&format!("{}.dat", tid), &trace);
+//! }
+//! ```
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::interface::*;
+
+extern "C" {
+    fn rftrace_backend_enable();
+    fn rftrace_backend_disable();
+    fn rftrace_backend_is_enabled() -> bool;
+    fn rftrace_backend_init(bufptr: *mut Event, len: usize, overwriting: bool) -> InitStatus;
+    fn rftrace_backend_get_events() -> *const Event;
+    fn rftrace_backend_get_events_index() -> usize;
+    fn rftrace_backend_interface_version() -> u32;
+    fn rftrace_backend_record(kind: u8, payload: *const u8, len: usize);
+    fn rftrace_backend_get_stats(stats: *mut Stats);
+}
+
+/// Event buffer handed to the backend by `try_init()`.
+#[derive(Debug)]
+pub struct Events {
+    ptr: *mut Event,
+    len: usize,
+    cap: usize,
+}
+
+/// Enables tracing in the backend.
+pub fn enable() {
+    unsafe { rftrace_backend_enable() }
+}
+
+/// Disables tracing in the backend.
+pub fn disable() {
+    unsafe { rftrace_backend_disable() }
+}
+
+/// Returns whether the backend is currently recording.
+pub fn is_enabled() -> bool {
+    unsafe { rftrace_backend_is_enabled() }
+}
+
+/// Records a custom event of the given `kind` into the trace, see `Record`.
+///
+/// Kinds below `RECORD_KIND_USER` are reserved for rftrace. `payload` is truncated to `RECORD_PAYLOAD_LEN` bytes.
+pub fn record(kind: u8, payload: &[u8]) {
+    unsafe { rftrace_backend_record(kind, payload.as_ptr(), payload.len()) }
+}
+
+/// Returns the current statistics counters of the backend.
+pub fn stats() -> Stats {
+    let mut stats = Stats::default();
+    unsafe { rftrace_backend_get_stats(&mut stats) };
+    stats
+}
+
+/// Allocates a buffer of `max_event_count` events and passes it to the backend.
+///
+/// If `overwriting`, it is used as a ring buffer, otherwise recording stops once it is full.
+/// Returns the status of the backend if it rejects the buffer, for example since it already records into another one.
+pub fn try_init(max_event_count: usize, overwriting: bool) -> Result<Events, InitStatus> {
+    assert!(
+        max_event_count > MAX_STACK_HEIGHT,
+        "Event buffer has to be larger than maximum stack height!"
+    );
+    let backend_version = unsafe { rftrace_backend_interface_version() };
+    assert!(
+        backend_version == INTERFACE_VERSION,
+        "Backend interface version {} does not match frontend version {}!",
+        backend_version,
+        INTERFACE_VERSION
+    );
+    let buf = vec![Event::Empty; max_event_count];
+    let (ptr, len, cap) = buf.into_raw_parts();
+    let status = unsafe { rftrace_backend_init(ptr, cap, overwriting) };
+    if status != InitStatus::Ok {
+        // The backend did not take the buffer, so it is still ours
+        drop(unsafe { Vec::from_raw_parts(ptr, len, cap) });
+        return Err(status);
+    }
+    Ok(Events { ptr, len, cap })
+}
+
+/// Disables tracing and takes the event buffer back from the backend, returning the events oldest first.
+///
+/// Returns `None` if the backend does not give the buffer back, for example since another frontend is attached to it.
+pub fn take_events(events: Events) -> Option<Vec<Event>> {
+    disable();
+    let ptr = unsafe { rftrace_backend_get_events() };
+    if ptr.is_null() {
+        return None;
+    }
+    assert!(ptr == events.ptr, "Event buffer pointer mismatch!");
+
+    let mut events = unsafe { Vec::from_raw_parts(events.ptr, events.len, events.cap) };
+    // Rotate the ring buffer, so the oldest event comes first
+    let cidx = unsafe { rftrace_backend_get_events_index() } % events.len();
+    events.rotate_left(cidx);
+    Some(events)
+}
+
+/// Returns the IDs of all threads with function entries or exits in `events`, in order of appearance. Threads without ID are 0.
+pub fn thread_ids(events: &[Event]) -> Vec<u64> {
+    let mut tids = Vec::new();
+    for e in events {
+        let tid = match e {
+            Event::Entry(e) => e.tid,
+            Event::Exit(e) => e.tid,
+            Event::Record(_) | Event::Empty => continue,
+        };
+        let tid = tid.map_or(0, |tid| tid.get());
+        if !tids.contains(&tid) {
+            tids.push(tid);
+        }
+    }
+    tids
+}
+
+/// Appends the uftrace trace file (`<tid>.dat`) of thread `tid` in `events`, which have to be ordered oldest first, to `out`.
+///
+/// Returns the number of written records.
+pub fn write_uftrace_trace(events: &[Event], tid: u64, out: &mut Vec<u8>) -> usize {
+    let mut written = 0;
+    for e in events {
+        let (time, addr, kind, event_tid) = match e {
+            Event::Entry(e) => (e.time, e.to, 0, e.tid),
+            Event::Exit(e) => (e.time, e.from, 1, e.tid),
+            Event::Record(_) | Event::Empty => continue,
+        };
+        if event_tid.map_or(0, |tid| tid.get()) != tid {
+            continue;
+        }
+
+        // see `struct uftrace_record`: type, more = 0, magic = 0b101, depth = 0, 48 bit address
+        let merged = kind | (0b101 << 3) | ((addr & ((1 << 48) - 1)) << 16);
+        out.extend_from_slice(&time.to_le_bytes());
+        out.extend_from_slice(&merged.to_le_bytes());
+        written += 1;
+    }
+    written
+}
+
+/// Appends the uftrace `info` file for the threads `tids` to `out`.
+pub fn write_uftrace_info(tids: &[u64], out: &mut Vec<u8>) {
+    const TASK_SESSION: u64 = 1 << 1;
+    const SYM_REL_ADDR: u64 = 1 << 5;
+    const CMDLINE: u64 = 1 << 3;
+    const TASKINFO: u64 = 1 << 7;
+
+    // magic, version 4, header size 40, little endian, 64 bit
+    out.extend_from_slice(b"Ftrace!\x00");
+    out.extend_from_slice(&4u32.to_le_bytes());
+    out.extend_from_slice(&40u16.to_le_bytes());
+    out.extend_from_slice(&[1, 2]);
+    out.extend_from_slice(&(TASK_SESSION | SYM_REL_ADDR).to_le_bytes());
+    out.extend_from_slice(&(CMDLINE | TASKINFO).to_le_bytes());
+    // mstack and reserved
+    out.extend_from_slice(&[0; 8]);
+
+    let mut lines = String::new();
+    writeln!(lines, "cmdline:fakeuftrace").unwrap();
+    writeln!(lines, "taskinfo:lines=2").unwrap();
+    writeln!(lines, "taskinfo:nr_tid={}", tids.len()).unwrap();
+    write!(lines, "taskinfo:tids=").unwrap();
+    for (i, tid) in tids.iter().enumerate() {
+        if i > 0 {
+            lines.push(',');
+        }
+        write!(lines, "{}", tid).unwrap();
+    }
+    lines.push('\n');
+    out.extend_from_slice(lines.as_bytes());
+}
+
+/// Appends the uftrace `task.txt` file for the threads `tids` of `binary_name` to `out`.
+pub fn write_uftrace_task(tids: &[u64], binary_name: &str, out: &mut String) {
+    // arbitrary values for pid and sid, matching `write_uftrace_map()`
+    writeln!(
+        out,
+        "SESS timestamp=0.0 pid=42 sid=00 exename=\"{}\"",
+        binary_name
+    )
+    .unwrap();
+    for tid in tids {
+        writeln!(out, "TASK timestamp=0.0 tid={} pid=42", tid).unwrap();
+    }
+}
+
+/// Appends the uftrace `sid-00.map` file to `out`, mapping all code from `image_start` on to `binary_name`.
+///
+/// Symbols are then read from `<binary_name>.sym`, which can be generated with `nm -n $BINARY`.
+pub fn write_uftrace_map(binary_name: &str, image_start: usize, out: &mut String) {
+    writeln!(
+        out,
+        "{:0>12x}-ffffffffffff r-xp 00000000 00:00 0                          {}",
+        image_start, binary_name
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "ffffffffffff-ffffffffffff rw-p 00000000 00:00 0                          [stack]"
+    )
+    .unwrap();
+}
@@ -0,0 +1,212 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::hash::{BuildHasher, Hasher};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::num::NonZeroU64;
+
+use crate::chrome::json_string;
+use crate::interface::*;
+use crate::symbols::Symbols;
+
+/// Maximum number of spans sent in a single OTLP request.
+const SPANS_PER_REQUEST: usize = 1000;
+
+/// Options for exporting calls as OpenTelemetry spans, see `export_otlp()`.
+#[derive(Clone, Debug)]
+pub struct OtlpOptions {
+    /// `service.name` resource attribute of the exported spans.
+    pub service_name: String,
+    /// Calls shorter than this many TSC cycles are not exported.
+    pub min_duration: u64,
+    /// Frequency of the timestamp counter in Hz, to convert timestamps.
+    pub tsc_freq: u64,
+    /// A TSC timestamp and the Unix time in nanoseconds it corresponds to, anchoring the converted timestamps.
+    pub anchor: (u64, u64),
+}
+
+/// A completed call, which is exported as span.
+struct Span {
+    trace_id: u128,
+    span_id: u64,
+    parent: Option<u64>,
+    addr: usize,
+    tid: u64,
+    start: u64,
+    end: u64,
+}
+
+/// Exports all calls in `events`, ordered oldest first, which took at least `options.min_duration`, as OpenTelemetry spans.
+///
+/// The spans are sent to an OTLP/HTTP collector at `endpoint` (`host:port`) as JSON, so selected traces can be fed into existing observability stacks.
+/// Each outermost call of a thread starts a new trace. Spans of calls which are too short are dropped, their children are attached to the next exported ancestor.
+/// Spans are named after the function, if found in `symbols`, and carry the thread ID as `thread.id` attribute.
+///
+/// Returns the number of exported spans. Fails with `InvalidInput` if `options.tsc_freq` is 0.
+pub fn export_otlp(
+    events: &[Event],
+    endpoint: &str,
+    options: &OtlpOptions,
+    symbols: Option<&Symbols>,
+) -> io::Result<usize> {
+    if options.tsc_freq == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TSC frequency must not be 0",
+        ));
+    }
+    let spans = collect_spans(events, options.min_duration);
+    println!(
+        "Exporting {} spans to OTLP collector {}..",
+        spans.len(),
+        endpoint
+    );
+
+    for chunk in spans.chunks(SPANS_PER_REQUEST) {
+        let body = otlp_json(chunk, options, symbols);
+        post(endpoint, "/v1/traces", body.as_bytes())?;
+    }
+    Ok(spans.len())
+}
+
+/// Matches entries and exits per thread, keeping the calls of at least `min_duration`.
+fn collect_spans(events: &[Event], min_duration: u64) -> Vec<Span> {
+    let random = RandomState::new();
+    let mut next_id = random.build_hasher().finish();
+    let mut new_id = || {
+        next_id = next_id.wrapping_add(1);
+        let mut hasher = random.build_hasher();
+        hasher.write_u64(next_id);
+        hasher.finish()
+    };
+
+    let mut spans = Vec::new();
+    // Parents of dropped spans, so their children can be attached to the next exported ancestor
+    let mut dropped: HashMap<u64, Option<u64>> = HashMap::new();
+    let mut stacks: HashMap<Option<NonZeroU64>, Vec<Span>> = HashMap::new();
+
+    for e in events {
+        match e {
+            Event::Entry(e) => {
+                let stack = stacks.entry(e.tid).or_default();
+                let (trace_id, parent) = match stack.last() {
+                    Some(parent) => (parent.trace_id, Some(parent.span_id)),
+                    None => ((u128::from(new_id()) << 64) | u128::from(new_id()), None),
+                };
+                stack.push(Span {
+                    trace_id,
+                    span_id: new_id(),
+                    parent,
+                    addr: e.to as usize,
+                    tid: e.tid.map_or(0, NonZeroU64::get),
+                    start: e.time,
+                    end: e.time,
+                });
+            }
+            Event::Exit(e) => {
+                // Exits without a matching entry, for example from before an overwritten part of the ring buffer, are skipped
+                if let Some(mut span) = stacks.entry(e.tid).or_default().pop() {
+                    span.end = e.time;
+                    if span.end.saturating_sub(span.start) >= min_duration {
+                        spans.push(span);
+                    } else {
+                        dropped.insert(span.span_id, span.parent);
+                    }
+                }
+            }
+            Event::Record(_) | Event::Empty => {}
+        }
+    }
+
+    for span in &mut spans {
+        while let Some(&parent) = span.parent.as_ref().and_then(|parent| dropped.get(parent)) {
+            span.parent = parent;
+        }
+    }
+    spans
+}
+
+/// Encodes `spans` as OTLP/JSON `ExportTraceServiceRequest`.
+fn otlp_json(spans: &[Span], options: &OtlpOptions, symbols: Option<&Symbols>) -> String {
+    let unix_nanos = |time: u64| {
+        let cycles = i128::from(time) - i128::from(options.anchor.0);
+        let nanos =
+            i128::from(options.anchor.1) + cycles * 1_000_000_000 / i128::from(options.tsc_freq);
+        nanos.max(0)
+    };
+
+    let mut json = String::new();
+    write!(
+        json,
+        r#"{{"resourceSpans":[{{"resource":{{"attributes":[{{"key":"service.name","value":{{"stringValue":{}}}}}]}},"#,
+        json_string(&options.service_name)
+    )
+    .unwrap();
+    write!(
+        json,
+        r#""scopeSpans":[{{"scope":{{"name":"rftrace","version":"{}"}},"spans":["#,
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let name = symbols
+            .and_then(|symbols| symbols.name(span.addr))
+            .map_or_else(|| format!("{:#x}", span.addr), str::to_owned);
+        write!(
+            json,
+            r#"{{"traceId":"{:032x}","spanId":"{:016x}","#,
+            span.trace_id, span.span_id
+        )
+        .unwrap();
+        if let Some(parent) = span.parent {
+            write!(json, r#""parentSpanId":"{:016x}","#, parent).unwrap();
+        }
+        write!(
+            json,
+            r#""name":{},"kind":1,"startTimeUnixNano":"{}","endTimeUnixNano":"{}","#,
+            json_string(&name),
+            unix_nanos(span.start),
+            unix_nanos(span.end)
+        )
+        .unwrap();
+        write!(
+            json,
+            r#""attributes":[{{"key":"thread.id","value":{{"intValue":"{}"}}}},{{"key":"code.address","value":{{"stringValue":"{:#x}"}}}}]}}"#,
+            span.tid, span.addr
+        )
+        .unwrap();
+    }
+
+    json.push_str("]}]}]}");
+    json
+}
+
+/// Sends `body` as JSON HTTP POST request to `path` on `endpoint`, failing unless the response status is successful.
+fn post(endpoint: &str, path: &str, body: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(endpoint)?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        endpoint,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+
+    // e.g. "HTTP/1.1 200 OK"
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "OTLP collector responded with '{}'",
+            status.trim_end()
+        ))),
+    }
+}
@@ -0,0 +1,50 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::frontend::{init_from_env, Events};
+
+// Event buffer initialized by `preinit_from_env()`, until taken by `take_preinit_events()`
+static PREINIT_EVENTS: AtomicPtr<Events> = AtomicPtr::new(ptr::null_mut());
+
+/// Starts tracing before `main` runs, if configured by the `RFTRACE_*` environment variables.
+///
+/// Registers `preinit_from_env()` in the `.init_array` section, whose functions the C runtime calls before `main`.
+/// The trace then also covers the Rust runtime and static initializers, which run before `main` could call `init()`.
+/// Use it once in the traced binary, and get the event buffer with `take_preinit_events()` to dump it.
+///
+/// ```ignore
+/// rftrace_frontend::init_before_main!();
+///
+/// fn main() {
+///     // ...
+///     if let Some(events) = rftrace_frontend::take_preinit_events() {
+///         rftrace_frontend::dump_from_env(events).unwrap();
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! init_before_main {
+    () => {
+        #[used]
+        #[link_section = ".init_array"]
+        static RFTRACE_INIT_BEFORE_MAIN: extern "C" fn() = $crate::preinit_from_env;
+    };
+}
+
+/// Initializes and enables tracing with `init_from_env()`, see `init_before_main!()`.
+///
+/// Has to run before anything else initializes tracing, so only call it from `.init_array`.
+pub extern "C" fn preinit_from_env() {
+    if let Some(events) = init_from_env() {
+        PREINIT_EVENTS.store(events, Ordering::Release);
+    }
+}
+
+/// Returns the event buffer tracing was started with by `init_before_main!()`, if `RFTRACE_OUT_DIR` was set.
+///
+/// Pass it to `dump_from_env()` or any other dump function once done, instead of initializing tracing again.
+/// Returns the buffer only once, `None` afterwards.
+pub fn take_preinit_events() -> Option<&'static mut Events> {
+    let events = PREINIT_EVENTS.swap(ptr::null_mut(), Ordering::Acquire);
+    unsafe { events.as_mut() }
+}
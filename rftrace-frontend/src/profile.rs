@@ -0,0 +1,184 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::sync::Mutex;
+
+use crate::frontend::{attach, disable, enable};
+use crate::interface::*;
+
+/// Aggregated statistics of a single function within a trace, see `profile()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// Number of recorded calls.
+    pub calls: u64,
+    /// Summed duration of all completed calls in TSC cycles, including callees.
+    pub total_time: u64,
+    /// Duration of the longest completed call in TSC cycles.
+    pub max_time: u64,
+    /// Maximum call stack depth the function was called at, starting at 1.
+    pub max_depth: usize,
+}
+
+/// Aggregates per-function statistics of `events`, which have to be ordered oldest first. Keyed by function address.
+///
+/// Entries and exits are matched per thread. Calls without a recorded exit are counted, but do not contribute to the times.
+pub fn profile(events: &[Event]) -> HashMap<usize, FunctionProfile> {
+    let mut profiler = Profiler::default();
+    profiler.update(events);
+    profiler.profiles
+}
+
+/// Duration percentiles of a single function within a trace, see `percentiles()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    /// Number of completed calls the percentiles are computed from.
+    pub calls: u64,
+    /// Median duration in TSC cycles, including callees.
+    pub p50: u64,
+    /// Duration only exceeded by 5% of the calls, in TSC cycles.
+    pub p95: u64,
+    /// Duration only exceeded by 1% of the calls, in TSC cycles.
+    pub p99: u64,
+}
+
+/// Computes the duration percentiles of each function in `events`, which have to be ordered oldest first. Keyed by function address.
+///
+/// Averages, such as `FunctionProfile::total_time` divided by the calls, hide the latency outliers which usually matter.
+/// Entries and exits are matched per thread like in `profile()`, only completed calls are considered.
+/// Percentiles use the nearest-rank method, so they are always durations of actual calls.
+pub fn percentiles(events: &[Event]) -> HashMap<usize, LatencyPercentiles> {
+    let mut durations: HashMap<usize, Vec<u64>> = HashMap::new();
+    let mut stacks: HashMap<Option<NonZeroU64>, Vec<(usize, u64)>> = HashMap::new();
+    for e in events {
+        match e {
+            Event::Entry(e) => stacks
+                .entry(e.tid)
+                .or_default()
+                .push((e.to as usize, e.time)),
+            Event::Exit(e) => {
+                if let Some((addr, start)) = stacks.entry(e.tid).or_default().pop() {
+                    durations
+                        .entry(addr)
+                        .or_default()
+                        .push(e.time.saturating_sub(start));
+                }
+            }
+            Event::Record(_) | Event::Empty => {}
+        }
+    }
+
+    durations
+        .into_iter()
+        .map(|(addr, mut durations)| {
+            durations.sort_unstable();
+            let rank = |percent: usize| durations[(durations.len() * percent).div_ceil(100) - 1];
+            let latency = LatencyPercentiles {
+                calls: durations.len() as u64,
+                p50: rank(50),
+                p95: rank(95),
+                p99: rank(99),
+            };
+            (addr, latency)
+        })
+        .collect()
+}
+
+/// Incrementally aggregated profile, which can be fed consecutive chunks of events.
+#[derive(Debug, Default)]
+struct Profiler {
+    profiles: HashMap<usize, FunctionProfile>,
+    stacks: HashMap<Option<NonZeroU64>, Vec<(usize, u64)>>,
+}
+
+impl Profiler {
+    fn update(&mut self, events: &[Event]) {
+        for e in events {
+            match e {
+                Event::Entry(e) => {
+                    let stack = self.stacks.entry(e.tid).or_default();
+                    stack.push((e.to as usize, e.time));
+
+                    let profile = self.profiles.entry(e.to as usize).or_default();
+                    profile.calls += 1;
+                    profile.max_depth = profile.max_depth.max(stack.len());
+                }
+                Event::Exit(e) => {
+                    // Exits without a matching entry, for example from before an overwritten part of the ring buffer, are skipped
+                    if let Some((addr, start)) = self.stacks.entry(e.tid).or_default().pop() {
+                        let time = e.time.saturating_sub(start);
+                        let profile = self.profiles.entry(addr).or_default();
+                        profile.total_time += time;
+                        profile.max_time = profile.max_time.max(time);
+                    }
+                }
+                Event::Record(_) | Event::Empty => {}
+            }
+        }
+    }
+}
+
+/// Profile of all events since `start_live_profile()`, together with the event index it is current up to.
+static LIVE_PROFILE: Mutex<Option<(usize, Profiler)>> = Mutex::new(None);
+
+/// Starts aggregating per-function statistics of all events recorded from now on, to be queried with `current_top()`.
+///
+/// This lets a service log its own hot functions without full dumps. Restarts from scratch if already running.
+/// The buffer should be overwriting and `current_top()` has to be called often enough that it does not wrap in between.
+/// Returns `false` if no event buffer has been initialized yet, see `init()`.
+pub fn start_live_profile() -> bool {
+    let Some(attachment) = attach() else {
+        return false;
+    };
+    *LIVE_PROFILE.lock().unwrap() = Some((attachment.index(), Profiler::default()));
+    true
+}
+
+/// Stops the aggregation started by `start_live_profile()` and drops its statistics.
+pub fn stop_live_profile() {
+    LIVE_PROFILE.lock().unwrap().take();
+}
+
+/// Returns the `n` functions with the highest cumulative time since `start_live_profile()`, highest first, keyed by address.
+///
+/// Events recorded since the last call are aggregated first, so times only include calls which exited by now.
+/// The buffer is attached for the duration of the call, so the owner can not dump it concurrently.
+/// Returns an empty list if the live profile is not running.
+pub fn current_top(n: usize) -> Vec<(usize, FunctionProfile)> {
+    let mut live = LIVE_PROFILE.lock().unwrap();
+    let Some((index, profiler)) = live.as_mut() else {
+        return Vec::new();
+    };
+    if let Some(attachment) = attach() {
+        let (events, new_index) = attachment.events_since(*index);
+        *index = new_index;
+        profiler.update(&events);
+    }
+
+    let mut top: Vec<(usize, FunctionProfile)> = profiler
+        .profiles
+        .iter()
+        .map(|(&addr, &profile)| (addr, profile))
+        .collect();
+    top.sort_unstable_by_key(|&(_, profile)| Reverse(profile.total_time));
+    top.truncate(n);
+    top
+}
+
+/// Traces a single run of `f`, returning its result together with the per-function statistics of the run, see `profile()`.
+///
+/// Intended for attaching rftrace to existing benchmark suites, for example inside a criterion `iter` closure to get a per-iteration breakdown.
+/// Tracing is enabled for the run and disabled afterwards. Only events recorded during the run are considered, including other threads.
+/// Older events stay in the buffer untouched. The buffer should be overwriting and has to be large enough to hold a whole run.
+///
+/// Returns `None` if no event buffer has been initialized yet, see `init()`.
+pub fn traced_bench<R>(f: impl FnOnce() -> R) -> Option<(R, HashMap<usize, FunctionProfile>)> {
+    let attachment = attach()?;
+    let start = attachment.index();
+
+    enable();
+    let result = f();
+    disable();
+
+    let (events, _) = attachment.events_since(start);
+    Some((result, profile(&events)))
+}
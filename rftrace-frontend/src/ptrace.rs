@@ -0,0 +1,151 @@
+use std::fs::{self, File};
+use std::io::{self};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::ptr;
+
+use crate::frontend::{write_foreign_uftrace, DumpOptions};
+use crate::recover::read_events;
+use crate::symbols::nm_cached;
+
+/// Dumps the trace of the running, instrumented process `pid` into `out_dir`, without its cooperation.
+///
+/// All threads of the process are stopped with ptrace while its event buffer is copied, which is located via the exported `RFTRACE_DESCRIPTOR`.
+/// Tracing continues once the threads are resumed. Useful if the traced program can not be modified to dump its trace itself.
+/// Needs permission to ptrace the process and `nm` to look up the descriptor. `out_dir` has to exist.
+pub fn record_external(pid: u32, out_dir: &str) -> io::Result<()> {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid))?;
+    let descriptor = descriptor_address(pid, &exe)?;
+
+    println!("Stopping process {} to copy its events..", pid);
+    let events = {
+        let _stopped = StoppedProcess::attach(pid)?;
+        let mem = File::open(format!("/proc/{}/mem", pid))?;
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+        read_events(
+            descriptor,
+            &|addr, buf| mem.read_exact_at(buf, addr as u64),
+            &|addr| mapped_len(&maps, addr),
+        )?
+    };
+
+    let binary_name = exe
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "unknown".into());
+    write_foreign_uftrace(&events, out_dir, &binary_name, &DumpOptions::default())?;
+
+    // The metadata describes our own memory map, replace it with the traced one
+    let mapfile = format!("{}/sid-00.map", out_dir);
+    if Path::new(&mapfile).exists() {
+        println!("  Replacing ./sid-00.map with /proc/{}/maps", pid);
+        fs::copy(format!("/proc/{}/maps", pid), mapfile)?;
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes mapped contiguously from `addr` on, according to `maps` in the format of `/proc/<pid>/maps`.
+fn mapped_len(maps: &str, addr: usize) -> usize {
+    let mut end = addr;
+    // Mappings are sorted by address, so adjacent ones extend the range one after the other
+    for line in maps.lines() {
+        let bounds = line.split_whitespace().next().and_then(|range| {
+            let (start, stop) = range.split_once('-')?;
+            Some((
+                usize::from_str_radix(start, 16).ok()?,
+                usize::from_str_radix(stop, 16).ok()?,
+            ))
+        });
+        if let Some((start, stop)) = bounds {
+            if start <= end && end < stop {
+                end = stop;
+            }
+        }
+    }
+    end - addr
+}
+
+/// Returns the runtime address of `RFTRACE_DESCRIPTOR` in process `pid`.
+fn descriptor_address(pid: u32, exe: &Path) -> io::Result<usize> {
+    let nm = nm_cached(exe)?;
+    let addr = nm
+        .lines()
+        .filter_map(|line| line.strip_suffix(" RFTRACE_DESCRIPTOR"))
+        .find_map(|line| usize::from_str_radix(line.split_whitespace().next()?, 16).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "RFTRACE_DESCRIPTOR not found, is the process linked against rftrace?",
+            )
+        })?;
+
+    // Position independent executables (ELF type ET_DYN) are relocated to where their first segment is mapped
+    let mut e_type = [0u8; 2];
+    File::open(exe)?.read_exact_at(&mut e_type, 16)?;
+    if u16::from_le_bytes(e_type) != 3 {
+        return Ok(addr);
+    }
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let exe = exe.to_string_lossy();
+    let base = maps
+        .lines()
+        .filter(|line| line.ends_with(&*exe))
+        .find_map(|line| usize::from_str_radix(line.split('-').next()?, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "executable is not mapped"))?;
+    Ok(base + addr)
+}
+
+/// All threads of a process, stopped with ptrace until dropped.
+struct StoppedProcess {
+    tids: Vec<libc::pid_t>,
+}
+
+impl StoppedProcess {
+    fn attach(pid: u32) -> io::Result<Self> {
+        let mut stopped = Self { tids: Vec::new() };
+        // Threads spawned while attaching may keep running
+        for task in fs::read_dir(format!("/proc/{}/task", pid))? {
+            let tid: libc::pid_t = match task?.file_name().to_string_lossy().parse() {
+                Ok(tid) => tid,
+                Err(_) => continue,
+            };
+            unsafe {
+                if libc::ptrace(
+                    libc::PTRACE_ATTACH,
+                    tid,
+                    ptr::null_mut::<libc::c_void>(),
+                    ptr::null_mut::<libc::c_void>(),
+                ) == -1
+                {
+                    let err = io::Error::last_os_error();
+                    // The thread may have exited in the meantime
+                    if err.raw_os_error() == Some(libc::ESRCH) {
+                        continue;
+                    }
+                    return Err(err);
+                }
+                stopped.tids.push(tid);
+                let mut status = 0;
+                if libc::waitpid(tid, &mut status, libc::__WALL) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(stopped)
+    }
+}
+
+impl Drop for StoppedProcess {
+    fn drop(&mut self) {
+        for &tid in &self.tids {
+            unsafe {
+                libc::ptrace(
+                    libc::PTRACE_DETACH,
+                    tid,
+                    ptr::null_mut::<libc::c_void>(),
+                    ptr::null_mut::<libc::c_void>(),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::convert::TryInto;
+use std::io::{self};
+use std::mem::size_of;
+use std::{fs, ptr};
+
+use crate::frontend::{write_foreign_uftrace, DumpOptions};
+use crate::interface::*;
+
+/// Memory of a crashed program, such as an ELF core dump or a raw memory snapshot of a uhyve guest.
+#[derive(Debug)]
+pub struct MemoryImage {
+    data: Vec<u8>,
+    /// Mapped address, file offset and length of each segment of `data`
+    segments: Vec<(usize, usize, usize)>,
+}
+
+impl MemoryImage {
+    /// Uses an ELF core dump, mapping addresses to its loadable segments.
+    pub fn from_core(data: Vec<u8>) -> io::Result<Self> {
+        let segments = load_segments(&data)?;
+        Ok(Self { data, segments })
+    }
+
+    /// Uses a raw memory snapshot, where the first byte of `data` is mapped at address `base`.
+    pub fn from_raw(data: Vec<u8>, base: usize) -> Self {
+        let segments = vec![(base, 0, data.len())];
+        Self { data, segments }
+    }
+
+    /// Reads the image at `path`. ELF files are used as core dumps, anything else as raw snapshot mapped at `base`.
+    pub fn load(path: &str, base: usize) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.starts_with(b"\x7fELF") {
+            Self::from_core(data)
+        } else {
+            Ok(Self::from_raw(data, base))
+        }
+    }
+
+    /// Copies the memory at `addr` into `buf`.
+    fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.segments
+            .iter()
+            .find(|&&(vaddr, _, len)| {
+                addr >= vaddr && addr - vaddr <= len && buf.len() <= len - (addr - vaddr)
+            })
+            .map(|&(vaddr, offset, _)| {
+                let start = offset + addr - vaddr;
+                buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("address {:#x} is not contained in the image", addr),
+                )
+            })
+    }
+
+    /// Returns the number of bytes contained in the image from `addr` on, up to the end of its segment.
+    fn mapped(&self, addr: usize) -> usize {
+        self.segments
+            .iter()
+            .find(|&&(vaddr, _, len)| addr >= vaddr && addr - vaddr < len)
+            .map_or(0, |&(vaddr, _, len)| len - (addr - vaddr))
+    }
+
+    /// Searches the image for the backend's `RFTRACE_DESCRIPTOR` and returns its address.
+    ///
+    /// Only descriptors of an initialized event buffer contained in the image are considered.
+    fn find_descriptor(&self) -> Option<usize> {
+        let magic = DESCRIPTOR_MAGIC.to_le_bytes();
+        self.segments.iter().find_map(|&(vaddr, offset, len)| {
+            let segment = &self.data[offset..offset + len];
+            (0..len.saturating_sub(size_of::<Descriptor>()))
+                .step_by(8)
+                .filter(|&i| segment[i..i + 8] == magic)
+                .map(|i| vaddr + i)
+                .find(|&addr| {
+                    read_descriptor(addr, &|addr, buf| self.read(addr, buf)).is_ok_and(|d| {
+                        let mut first = [0u8; size_of::<Event>()];
+                        !d.events.is_null() && self.read(d.events as usize, &mut first).is_ok()
+                    })
+                })
+        })
+    }
+}
+
+/// Returns mapped address, file offset and length of each loadable segment of the ELF64 file `data`.
+///
+/// Lengths are cut to the end of `data`, since segments of core dumps may have been truncated, for example by core dump size limits.
+pub(crate) fn load_segments(data: &[u8]) -> io::Result<Vec<(usize, usize, usize)>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if !data.starts_with(b"\x7fELF\x02\x01") {
+        return Err(invalid("not a little-endian ELF64 file"));
+    }
+
+    let u16_at = |offset: usize| -> Option<usize> {
+        Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?) as usize)
+    };
+    let u64_at = |offset: usize| -> Option<usize> {
+        Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?) as usize)
+    };
+
+    let phoff = u64_at(0x20).ok_or_else(|| invalid("truncated ELF header"))?;
+    let phentsize = u16_at(0x36).ok_or_else(|| invalid("truncated ELF header"))?;
+    let phnum = u16_at(0x38).ok_or_else(|| invalid("truncated ELF header"))?;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let phdr = phoff + i * phentsize;
+        const PT_LOAD: usize = 1;
+        let p_type = u64_at(phdr).ok_or_else(|| invalid("truncated program header"))? & 0xffff_ffff;
+        if p_type != PT_LOAD {
+            continue;
+        }
+        let offset = u64_at(phdr + 8).ok_or_else(|| invalid("truncated program header"))?;
+        let vaddr = u64_at(phdr + 16).ok_or_else(|| invalid("truncated program header"))?;
+        let filesz = u64_at(phdr + 32).ok_or_else(|| invalid("truncated program header"))?;
+        let len = filesz.min(data.len().saturating_sub(offset));
+        segments.push((vaddr, offset, len));
+    }
+    Ok(segments)
+}
+
+/// Reconstructs the uftrace directory from the memory of a crashed program, as if it had called `dump_full_uftrace()`.
+///
+/// The event buffer is located by searching for the backend's `RFTRACE_DESCRIPTOR`, so no symbols are needed.
+/// Backend statistics are not recovered. `out_dir` has to exist.
+pub fn recover_uftrace(image: &MemoryImage, out_dir: &str, binary_name: &str) -> io::Result<()> {
+    let descriptor = image.find_descriptor().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no initialized rftrace event buffer found in the image",
+        )
+    })?;
+    println!("Found RFTRACE_DESCRIPTOR at {:#x}", descriptor);
+
+    let events = read_events(descriptor, &|addr, buf| image.read(addr, buf), &|addr| {
+        image.mapped(addr)
+    })?;
+    write_foreign_uftrace(&events, out_dir, binary_name, &DumpOptions::default())
+}
+
+/// Reads and validates the descriptor at `addr`, using `read` to access the traced program's memory.
+fn read_descriptor(
+    addr: usize,
+    read: &dyn Fn(usize, &mut [u8]) -> io::Result<()>,
+) -> io::Result<Descriptor> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut raw = [0u8; size_of::<Descriptor>()];
+    read(addr, &mut raw)?;
+    let descriptor = unsafe { ptr::read_unaligned(raw.as_ptr() as *const Descriptor) };
+    if descriptor.magic != DESCRIPTOR_MAGIC {
+        return Err(invalid("RFTRACE_DESCRIPTOR is corrupt"));
+    }
+    if descriptor.version != INTERFACE_VERSION
+        || descriptor.event_size as usize != size_of::<Event>()
+    {
+        return Err(invalid("backend interface version does not match"));
+    }
+    Ok(descriptor)
+}
+
+/// Copies the events of the descriptor at `addr` out of another program's memory, oldest first.
+///
+/// `mapped` returns the number of bytes readable from an address on, so a corrupted length is rejected before allocating.
+pub(crate) fn read_events(
+    addr: usize,
+    read: &dyn Fn(usize, &mut [u8]) -> io::Result<()>,
+    mapped: &dyn Fn(usize) -> usize,
+) -> io::Result<Vec<Event>> {
+    let descriptor = read_descriptor(addr, read)?;
+    if descriptor.events.is_null() || descriptor.len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no event buffer initialized",
+        ));
+    }
+
+    let mut index = [0u8; size_of::<usize>()];
+    read(descriptor.index as usize, &mut index)?;
+    let index = usize::from_ne_bytes(index);
+
+    let size = descriptor
+        .len
+        .checked_mul(EVENT_SIZE)
+        .filter(|&size| size <= mapped(descriptor.events as usize))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "event buffer of {} events at {:#x} exceeds the mapped memory",
+                    descriptor.len, descriptor.events as usize
+                ),
+            )
+        })?;
+    let mut bytes = vec![0u8; size];
+    read(descriptor.events as usize, &mut bytes)?;
+    // Decoded rather than reinterpreted, since corrupted memory may not hold valid events
+    let big_endian = cfg!(target_endian = "big");
+    let mut events: Vec<Event> = bytes
+        .chunks_exact(EVENT_SIZE)
+        .map(|event| Event::from_bytes(event, big_endian).unwrap_or(Event::Empty))
+        .collect();
+    println!("  Copied {} events", events.len());
+
+    // Rotate the ring buffer, so the oldest event comes first
+    events.rotate_left(index % descriptor.len);
+    Ok(events)
+}
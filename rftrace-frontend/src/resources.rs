@@ -0,0 +1,201 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::convert::{TryFrom, TryInto};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::frontend::record;
+use crate::interface::*;
+
+/// uftrace's builtin event ID of `read:proc/statm`, see `write_resources_event()`.
+const EVENT_ID_READ_PROC_STATM: u64 = 100001;
+
+static HEAP_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Global allocator counting the bytes currently allocated through it, so `resources()` can report the heap usage.
+///
+/// Wraps another allocator, such as `std::alloc::System`:
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOC: rftrace_frontend::TrackingAllocator = rftrace_frontend::TrackingAllocator(std::alloc::System);
+/// ```
+#[derive(Debug, Default)]
+pub struct TrackingAllocator<A = System>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            HEAP_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.0.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            HEAP_BYTES.fetch_add(new_size, Ordering::Relaxed);
+            HEAP_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+/// Snapshot of the resource usage of this process, see `resources()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    /// Bytes currently allocated on the heap. Only counted if `TrackingAllocator` is the global allocator, 0 otherwise.
+    pub heap_bytes: u64,
+    /// Resident set size in bytes, 0 if unknown.
+    pub rss_bytes: u64,
+    /// Number of threads of the process, 0 if unknown.
+    pub threads: u32,
+}
+
+impl Resources {
+    /// Decodes a `RECORD_KIND_RESOURCES` record, returning `None` for other records.
+    ///
+    /// Sizes are recorded in KiB, so they are rounded down to multiples of 1024.
+    pub fn from_record(record: &Record) -> Option<Self> {
+        if record.kind != RECORD_KIND_RESOURCES || (record.len as usize) < 12 {
+            return None;
+        }
+        let field =
+            |i: usize| u32::from_le_bytes(record.payload[4 * i..4 * i + 4].try_into().unwrap());
+        Some(Self {
+            heap_bytes: u64::from(field(0)) * 1024,
+            rss_bytes: u64::from(field(1)) * 1024,
+            threads: field(2),
+        })
+    }
+}
+
+/// Returns the current resource usage of this process.
+///
+/// The resident set size and thread count are read from `/proc/self` on Linux and unknown elsewhere.
+pub fn resources() -> Resources {
+    Resources {
+        heap_bytes: HEAP_BYTES.load(Ordering::Relaxed) as u64,
+        rss_bytes: rss_bytes().unwrap_or(0),
+        threads: thread_count().unwrap_or(0),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rss_bytes() -> Option<u64> {
+    // statm: size resident shared text lib data dt, in pages
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let resident: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(resident * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn thread_count() -> Option<u32> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_count() -> Option<u32> {
+    None
+}
+
+/// Records a snapshot of the current resource usage into the trace as `RECORD_KIND_RESOURCES` record, and returns it.
+///
+/// Call it at phase boundaries, or use `sample_resources()` to record snapshots periodically.
+/// Full uftrace dumps contain the snapshots as `read:proc/statm` events, so they show up next to the calls of the recording thread.
+/// Nothing is recorded while tracing is disabled.
+pub fn record_resources() -> Resources {
+    let resources = resources();
+    let kib = |bytes: u64| u32::try_from(bytes / 1024).unwrap_or(u32::MAX);
+
+    let mut payload = [0; 12];
+    payload[0..4].copy_from_slice(&kib(resources.heap_bytes).to_le_bytes());
+    payload[4..8].copy_from_slice(&kib(resources.rss_bytes).to_le_bytes());
+    payload[8..12].copy_from_slice(&resources.threads.to_le_bytes());
+    record(RECORD_KIND_RESOURCES, &payload);
+    resources
+}
+
+/// Thread recording resource snapshots, started by `sample_resources()`. Stops sampling when dropped.
+#[derive(Debug)]
+pub struct ResourceSampler {
+    _stop: Sender<()>,
+}
+
+/// Starts a thread calling `record_resources()` every `period`, so resource trends can be overlaid on the call timeline.
+pub fn sample_resources(period: Duration) -> io::Result<ResourceSampler> {
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::Builder::new()
+        .name("rftrace-resources".into())
+        .spawn(move || {
+            // Doubles as timer, returning once the sampler is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(period) {
+                record_resources();
+            }
+        })?;
+    Ok(ResourceSampler { _stop: stop })
+}
+
+/// Size of the event written by `write_resources_event()`, in bytes.
+pub(crate) const RESOURCES_EVENT_LEN: usize = 48;
+
+/// Writes `resources` as uftrace `read:proc/statm` event, the only builtin event uftrace shows memory usage with.
+///
+/// Its `vmsize` holds the heap usage and its `vmrss` the resident set size, both in KiB. The thread count is stored as `shared`.
+pub(crate) fn write_resources_event<W: Write>(out: &mut W, time: u64, resources: &Resources) {
+    out.write_u64::<LittleEndian>(time)
+        .expect("Write interrupted");
+
+    let mut merged: u64 = 0;
+    merged |= 0b11; // type = UFTRACE_EVENT
+    merged |= 1 << 2; // more, the event data follows
+    merged |= 0b101 << 3; // magic, always 0b101
+    merged |= EVENT_ID_READ_PROC_STATM << 16; // event id instead of an address
+    out.write_u64::<LittleEndian>(merged)
+        .expect("Write interrupted");
+
+    // struct uftrace_proc_statm, prefixed with its length and padded to 8 bytes
+    out.write_u16::<LittleEndian>(24)
+        .expect("Write interrupted");
+    for value in [
+        resources.heap_bytes / 1024,
+        resources.rss_bytes / 1024,
+        u64::from(resources.threads),
+    ] {
+        out.write_u64::<LittleEndian>(value)
+            .expect("Write interrupted");
+    }
+    out.write_all(&[0; 6]).expect("Write interrupted");
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::io::{self};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::frontend::{init_double_buffered, is_empty_trace, write_full_uftrace, DumpOptions};
+
+/// Continuous tracing started by `rotate_every()`, which rotates the event buffers until stopped.
+///
+/// Dropping it stops the rotation like `stop()`, but without waiting for the last slice to be written.
+#[derive(Debug)]
+pub struct Rotation {
+    stop: Sender<()>,
+    thread: JoinHandle<io::Result<usize>>,
+}
+
+/// Records into two alternating event buffers and writes a completed slice every `period`, turning rftrace into a continuous profiler.
+///
+/// Each buffer holds `max_event_count` events, see `init_double_buffered()`. A dump thread swaps the buffers every `period`
+/// and writes the retired slice as full uftrace directory `out_dir/slice.<n>`, see `write_full_uftrace()`.
+/// Slices without any function entry or exit are skipped. The buffer has to be large enough for a whole period,
+/// since older events are overwritten otherwise. If writing a slice takes longer than `period`, the next rotation is delayed.
+///
+/// Tracing still has to be enabled with `enable()`. `out_dir` is created if it does not exist yet.
+pub fn rotate_every(
+    max_event_count: usize,
+    period: Duration,
+    out_dir: &str,
+    binary_name: &str,
+    options: DumpOptions,
+) -> io::Result<Rotation> {
+    fs::create_dir_all(out_dir)?;
+    let mut buffers = init_double_buffered(max_event_count)?;
+    let out_dir = out_dir.to_owned();
+    let binary_name = binary_name.to_owned();
+
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("rftrace-rotation".into())
+        .spawn(move || {
+            let mut slice = 0;
+            loop {
+                // Doubles as timer, so stopping does not have to wait for the period to pass
+                let last = match stopped.recv_timeout(period) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                };
+
+                let events = buffers.swap()?;
+                let slice_dir = format!("{}/slice.{}", out_dir, slice);
+                fs::create_dir_all(&slice_dir)?;
+                match write_full_uftrace(events, &slice_dir, &binary_name, &options) {
+                    Ok(()) => slice += 1,
+                    Err(err) if is_empty_trace(&err) => fs::remove_dir(&slice_dir)?,
+                    Err(err) => return Err(err),
+                }
+
+                if last {
+                    return Ok(slice);
+                }
+            }
+        })?;
+
+    Ok(Rotation { stop, thread })
+}
+
+impl Rotation {
+    /// Stops rotating after writing a last slice with the events recorded so far. Returns the number of written slices.
+    ///
+    /// Tracing continues into the other buffer, which is not written anymore, so disable it first to not miss anything.
+    /// Fails if writing any slice failed, which also stops the rotation.
+    pub fn stop(self) -> io::Result<usize> {
+        // The thread might already have exited with an error
+        let _ = self.stop.send(());
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("rotation thread panicked")))
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hint::black_box;
+use std::io::{self};
+use std::num::NonZeroU64;
+use std::thread;
+
+use crate::frontend::{disable, enable, get_events, try_init};
+use crate::interface::*;
+
+/// Size of the event buffer used by `self_test()`, enough for all patterns and some noise of other threads.
+const SELF_TEST_EVENTS: usize = 100000;
+/// Depth of the deep recursion pattern, well below the maximum stack height of the backend.
+const RECURSION_DEPTH: u64 = 500;
+/// Number of threads spawned by the threads pattern.
+const THREADS: u64 = 4;
+/// Maximum distance of a recorded function address from the start of the function.
+///
+/// With `mcount`, the backend records the address right after the call in the function's prologue, not its start.
+const PROLOGUE_LEN: usize = 128;
+
+/// Result of running `self_test()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelfTest {
+    /// Number of call patterns run.
+    pub patterns: usize,
+    /// Number of function entries recorded while running them, including the ones of unrelated functions and threads.
+    pub entries: usize,
+    /// Patterns which returned wrong results or were recorded incorrectly, such as clobbered return values or unpaired exits.
+    pub problems: Vec<String>,
+}
+
+impl SelfTest {
+    /// Returns whether all patterns returned the right results and were recorded with paired entries and exits.
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for SelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} call patterns, {} function entries recorded",
+            self.patterns, self.entries
+        )?;
+        if self.passed() {
+            writeln!(f, "passed")
+        } else {
+            writeln!(f, "FAILED:")?;
+            for problem in &self.problems {
+                writeln!(f, "  - {}", problem)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A known call pattern, see `self_test()`.
+struct Pattern {
+    name: &'static str,
+    /// Runs the calls, returning a description of the first wrong result
+    run: fn() -> Result<(), String>,
+    /// Address of the function hooked by the pattern
+    function: usize,
+    /// Number of times `run` calls `function`
+    calls: usize,
+}
+
+/// Runs a battery of known call patterns under tracing and checks the backend's hooks did not break them.
+///
+/// Covers integer and floating point arguments, variadic calls, returns in SSE registers, small structs returned in
+/// two registers and large ones returned in memory, deep recursion and threads. Every pattern checks its results,
+/// which are wrong if the hooks clobber argument or return registers. Afterwards, the recorded trace is checked for
+/// the expected number of calls and for exits paired with their entries. Use it to detect ABI breakage of the
+/// backend on new targets and compilers at runtime.
+///
+/// The frontend itself has to be compiled with `-Z instrument-mcount`, which is the case if it is set in `RUSTFLAGS`.
+/// Initializes tracing with its own buffer and takes it back afterwards, so call it before `init()`, and before
+/// setting filters or sampling, which would skip calls. Fails with `AlreadyExists` if tracing is already initialized.
+pub fn self_test() -> io::Result<SelfTest> {
+    let patterns = patterns();
+    let events = try_init(SELF_TEST_EVENTS, false)?;
+    enable();
+    let results: Vec<_> = patterns.iter().map(|pattern| (pattern.run)()).collect();
+    disable();
+    let events = get_events(events)?;
+
+    let mut test = SelfTest {
+        patterns: patterns.len(),
+        entries: events
+            .iter()
+            .filter(|e| matches!(e, Event::Entry(_)))
+            .count(),
+        problems: Vec::new(),
+    };
+    for (pattern, result) in patterns.iter().zip(&results) {
+        if let Err(err) = result {
+            test.problems.push(format!("{}: {}", pattern.name, err));
+        }
+    }
+    if test.entries == 0 {
+        test.problems.push(String::from(
+            "No function entries were recorded. Is the application compiled with -Z instrument-mcount?",
+        ));
+        return Ok(test);
+    }
+
+    let (unpaired, mismatched) = check_pairing(&events);
+    if mismatched > 0 {
+        test.problems.push(format!(
+            "{} exits did not match the innermost entry of their thread",
+            mismatched
+        ));
+    }
+    for pattern in &patterns {
+        let in_pattern = |addr: u64| {
+            (pattern.function..pattern.function + PROLOGUE_LEN).contains(&(addr as usize))
+        };
+        let mut tids = HashSet::new();
+        let mut entries = 0;
+        let mut exits = 0;
+        for event in &events {
+            match event {
+                Event::Entry(e) if in_pattern(e.to) => {
+                    entries += 1;
+                    tids.insert(e.tid);
+                }
+                Event::Exit(e) if in_pattern(e.from) => exits += 1,
+                _ => {}
+            }
+        }
+        if entries != pattern.calls {
+            test.problems.push(format!(
+                "{}: recorded {} of {} calls",
+                pattern.name, entries, pattern.calls
+            ));
+        }
+        if exits != entries || unpaired.iter().any(|&addr| in_pattern(addr)) {
+            test.problems.push(format!(
+                "{}: recorded {} exits for {} entries",
+                pattern.name, exits, entries
+            ));
+        }
+        if pattern.name == "threads" && (tids.len() as u64) < THREADS {
+            test.problems.push(format!(
+                "threads: recorded calls on {} of {} threads",
+                tids.len(),
+                THREADS
+            ));
+        }
+    }
+    Ok(test)
+}
+
+/// Replays the entries and exits of each thread, returning the functions left without exit and the number of exits
+/// not matching the innermost entry.
+///
+/// Exits of functions entered before tracing was enabled have no entry, so exits on an empty stack are ignored.
+fn check_pairing(events: &[Event]) -> (Vec<u64>, usize) {
+    let mut stacks: HashMap<Option<NonZeroU64>, Vec<u64>> = HashMap::new();
+    let mut mismatched = 0;
+    for event in events {
+        match event {
+            Event::Entry(e) => stacks.entry(e.tid).or_default().push(e.to),
+            Event::Exit(e) => {
+                let stack = stacks.entry(e.tid).or_default();
+                match stack.pop() {
+                    Some(addr) if addr != e.from => mismatched += 1,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    (stacks.into_values().flatten().collect(), mismatched)
+}
+
+fn patterns() -> Vec<Pattern> {
+    vec![
+        Pattern {
+            name: "integer arguments",
+            run: run_integer_arguments,
+            function: integer_arguments as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "float arguments",
+            run: run_float_arguments,
+            function: float_arguments as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "variadic call",
+            run: run_variadic,
+            function: variadic as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "SSE struct return",
+            run: run_float_pair,
+            function: float_pair as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "register pair struct return",
+            run: run_integer_pair,
+            function: integer_pair as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "memory struct return",
+            run: run_large_struct,
+            function: large_struct as *const () as usize,
+            calls: 1,
+        },
+        Pattern {
+            name: "deep recursion",
+            run: run_recursion,
+            function: recursion as *const () as usize,
+            calls: RECURSION_DEPTH as usize + 1,
+        },
+        Pattern {
+            name: "threads",
+            run: run_threads,
+            function: thread_work as *const () as usize,
+            calls: THREADS as usize,
+        },
+    ]
+}
+
+fn expect<T: PartialEq + fmt::Debug>(what: &str, got: T, expected: T) -> Result<(), String> {
+    if got == expected {
+        Ok(())
+    } else {
+        Err(format!("{} is {:?} instead of {:?}", what, got, expected))
+    }
+}
+
+// More arguments than argument registers, so some are passed on the stack
+#[inline(never)]
+#[allow(clippy::too_many_arguments)]
+extern "C" fn integer_arguments(
+    a: u64,
+    b: u64,
+    c: u64,
+    d: u64,
+    e: u64,
+    f: u64,
+    g: u64,
+    h: u64,
+) -> u64 {
+    a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h
+}
+
+fn run_integer_arguments() -> Result<(), String> {
+    let result = integer_arguments(
+        black_box(1),
+        black_box(2),
+        black_box(3),
+        black_box(4),
+        black_box(5),
+        black_box(6),
+        black_box(7),
+        black_box(8),
+    );
+    expect("return value", result, 204)
+}
+
+// Floats are passed in SSE registers and returned in xmm0 on x86_64, which the return trampoline has to preserve
+#[inline(never)]
+#[allow(clippy::too_many_arguments)]
+extern "C" fn float_arguments(
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+    f: f64,
+    g: f64,
+    h: f64,
+    i: u64,
+) -> f64 {
+    a + 2.0 * b + 3.0 * c + 4.0 * d + 5.0 * e + 6.0 * f + 7.0 * g + 8.0 * h + i as f64
+}
+
+fn run_float_arguments() -> Result<(), String> {
+    let result = float_arguments(
+        black_box(1.5),
+        black_box(2.5),
+        black_box(3.5),
+        black_box(4.5),
+        black_box(5.5),
+        black_box(6.5),
+        black_box(7.5),
+        black_box(8.5),
+        black_box(9),
+    );
+    expect("return value", result, 231.0)
+}
+
+// On x86_64, variadic calls pass the number of used vector registers in al, which mcount runs before the callee reads
+#[inline(never)]
+unsafe extern "C" fn variadic(count: usize, mut args: ...) -> f64 {
+    let mut sum = 0.0;
+    for i in 0..count {
+        sum += (i + 1) as f64 * args.arg::<f64>();
+    }
+    sum
+}
+
+fn run_variadic() -> Result<(), String> {
+    let result = unsafe {
+        variadic(
+            black_box(9),
+            black_box(1.5),
+            black_box(2.5),
+            black_box(3.5),
+            black_box(4.5),
+            black_box(5.5),
+            black_box(6.5),
+            black_box(7.5),
+            black_box(8.5),
+            black_box(9.5),
+        )
+    };
+    expect("return value", result, 307.5)
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+struct FloatPair {
+    a: f64,
+    b: f64,
+}
+
+// Returned in xmm0 and xmm1 on x86_64
+#[inline(never)]
+extern "C" fn float_pair(a: f64, b: f64) -> FloatPair {
+    FloatPair {
+        a: a * 2.0,
+        b: b * 3.0,
+    }
+}
+
+fn run_float_pair() -> Result<(), String> {
+    let result = float_pair(black_box(1.25), black_box(2.5));
+    expect("return value", result, FloatPair { a: 2.5, b: 7.5 })
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+struct IntegerPair {
+    a: u64,
+    b: u64,
+}
+
+// Returned in rax and rdx on x86_64
+#[inline(never)]
+extern "C" fn integer_pair(a: u64, b: u64) -> IntegerPair {
+    IntegerPair {
+        a: a.rotate_left(13),
+        b: b ^ 0x5555_5555_5555_5555,
+    }
+}
+
+fn run_integer_pair() -> Result<(), String> {
+    let result = integer_pair(black_box(0x0123_4567_89ab_cdef), black_box(0xfedc_ba98));
+    expect(
+        "return value",
+        result,
+        IntegerPair {
+            a: 0x0123_4567_89ab_cdef_u64.rotate_left(13),
+            b: 0xfedc_ba98 ^ 0x5555_5555_5555_5555,
+        },
+    )
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq)]
+struct LargeStruct {
+    values: [u64; 8],
+}
+
+// Too large for registers, so returned in memory the caller passes a pointer to, which is returned in rax on x86_64
+#[inline(never)]
+extern "C" fn large_struct(seed: u64) -> LargeStruct {
+    let mut values = [0; 8];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = seed * (i as u64 + 1);
+    }
+    LargeStruct { values }
+}
+
+fn run_large_struct() -> Result<(), String> {
+    let result = large_struct(black_box(3));
+    expect(
+        "return value",
+        result,
+        LargeStruct {
+            values: [3, 6, 9, 12, 15, 18, 21, 24],
+        },
+    )
+}
+
+#[inline(never)]
+fn recursion(depth: u64) -> u64 {
+    if depth == 0 {
+        return 0;
+    }
+    // black_box keeps the compiler from turning the recursion into a loop
+    black_box(recursion(black_box(depth - 1))) + depth
+}
+
+fn run_recursion() -> Result<(), String> {
+    let result = recursion(black_box(RECURSION_DEPTH));
+    expect(
+        "return value",
+        result,
+        RECURSION_DEPTH * (RECURSION_DEPTH + 1) / 2,
+    )
+}
+
+#[inline(never)]
+fn thread_work(i: u64) -> u64 {
+    black_box(i).rotate_left(13) + i
+}
+
+fn run_threads() -> Result<(), String> {
+    let threads: Vec<_> = (0..THREADS)
+        .map(|i| thread::spawn(move || thread_work(black_box(i))))
+        .collect();
+    for (i, thread) in (0..THREADS).zip(threads) {
+        let result = thread
+            .join()
+            .map_err(|_| format!("thread {} panicked", i))?;
+        expect("return value", result, i.rotate_left(13) + i)?;
+    }
+    Ok(())
+}
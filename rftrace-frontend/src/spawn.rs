@@ -0,0 +1,93 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use crate::frontend::{ENV_MAX_EVENTS, ENV_OUT_DIR, ENV_OVERWRITING, ENV_TIME_SHIFT};
+use crate::symbols::nm_cached;
+
+/// Environment variable naming a shared object which `spawn_traced()` LD_PRELOADs into the child, see `preload_library()`.
+///
+/// The shared object has to provide `mcount` and call `init_from_env()`/`dump_from_env()` on load and exit.
+/// Children that link rftrace themselves do not need it, set it to empty to preload nothing.
+pub const ENV_PRELOAD: &str = "RFTRACE_PRELOAD";
+
+/// File name of the shared object built by the `rftrace-preload` crate.
+pub const PRELOAD_LIBRARY: &str = "librftrace_preload.so";
+
+/// Returns the backend library `spawn_traced()` LD_PRELOADs into the child.
+///
+/// That is `RFTRACE_PRELOAD` if set, otherwise `librftrace_preload.so` if it was built next to the current executable,
+/// as cargo does for all binaries and libraries of the workspace. Returns `None` if there is none or `RFTRACE_PRELOAD`
+/// is empty.
+pub fn preload_library() -> Option<PathBuf> {
+    if let Some(preload) = env::var_os(ENV_PRELOAD) {
+        return Some(PathBuf::from(preload)).filter(|preload| !preload.as_os_str().is_empty());
+    }
+    // Examples and tests are placed one directory further down than the library
+    let exe = env::current_exe().ok()?;
+    exe.ancestors()
+        .skip(1)
+        .take(2)
+        .map(|dir| dir.join(PRELOAD_LIBRARY))
+        .find(|library| library.is_file())
+}
+
+/// Runs `cmd` as a traced child process and collects its trace into `out_dir`, similar to `uftrace record`.
+///
+/// The tracing configuration is passed to the child via the `RFTRACE_*` environment variables, see `init_from_env()`.
+/// `RFTRACE_MAX_EVENTS`, `RFTRACE_OVERWRITING` and `RFTRACE_TIME_SHIFT` are forwarded from our own environment, if set.
+/// The backend library of `preload_library()` is LD_PRELOADed into the child, in front of what it would preload anyway.
+/// Without it, only children which link rftrace themselves are traced.
+///
+/// Once the child exited, symbols of the traced binary are generated with `nm -n` next to the trace, see `nm_cached()`.
+/// `out_dir` is created if it does not exist yet.
+pub fn spawn_traced(mut cmd: Command, out_dir: &str) -> io::Result<ExitStatus> {
+    fs::create_dir_all(out_dir)?;
+
+    cmd.env(ENV_OUT_DIR, out_dir);
+    for var in [ENV_MAX_EVENTS, ENV_OVERWRITING, ENV_TIME_SHIFT] {
+        if let Some(value) = env::var_os(var) {
+            cmd.env(var, value);
+        }
+    }
+    match preload_library() {
+        Some(preload) => {
+            let preloaded = match cmd.get_envs().find(|&(key, _)| key == "LD_PRELOAD") {
+                Some((_, value)) => value.map(OsStr::to_os_string),
+                None => env::var_os("LD_PRELOAD"),
+            };
+            let mut ld_preload = preload.into_os_string();
+            if let Some(preloaded) = preloaded.filter(|preloaded| !preloaded.is_empty()) {
+                ld_preload.push(":");
+                ld_preload.push(preloaded);
+            }
+            cmd.env("LD_PRELOAD", ld_preload);
+        }
+        None => println!("  No backend to preload, the child has to link rftrace itself"),
+    }
+
+    println!("Spawning traced child {:?}..", cmd.get_program());
+    let status = cmd.status()?;
+    println!("Traced child exited with {}", status);
+
+    let program = Path::new(cmd.get_program());
+    if let Err(err) = dump_symbols(program, out_dir) {
+        println!("  Could not generate symbols for {:?}: {}", program, err);
+    }
+
+    Ok(status)
+}
+
+/// Writes the output of `nm -n` for `binary` into `out_dir/<binary_name>.sym`.
+fn dump_symbols(binary: &Path, out_dir: &str) -> io::Result<()> {
+    let binary_name = binary
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "binary has no file name"))?;
+
+    let symfile = format!("{}/{}.sym", out_dir, binary_name.to_string_lossy());
+    println!("  Creating {}", symfile);
+    fs::write(symfile, nm_cached(binary)?)
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::io::{self};
+
+use rusqlite::{params, Connection};
+
+use crate::interface::*;
+use crate::symbols::Symbols;
+
+/// Writes the function entries and exits of `events`, ordered oldest first, into a new SQLite database at `path`.
+///
+/// This allows running ad-hoc SQL over big traces instead of writing custom parsers. An existing file at `path` is replaced.
+/// All events end up in a single table:
+/// ```sql
+/// CREATE TABLE events (
+///     tid INTEGER NOT NULL,  -- 0 if unknown
+///     ts INTEGER NOT NULL,   -- TSC timestamp
+///     kind TEXT NOT NULL,    -- 'entry' or 'exit'
+///     addr INTEGER NOT NULL, -- function address
+///     symbol TEXT            -- function name, if found in `symbols`
+/// );
+/// ```
+/// Records are left out, as they do not belong to a function.
+pub fn write_sqlite(events: &[Event], path: &str, symbols: Option<&Symbols>) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    println!("Exporting trace to SQLite database {}..", path);
+    let mut db = Connection::open(path).map_err(io::Error::other)?;
+    let tx = db.transaction().map_err(io::Error::other)?;
+    tx.execute(
+        "CREATE TABLE events (tid INTEGER NOT NULL, ts INTEGER NOT NULL, kind TEXT NOT NULL, addr INTEGER NOT NULL, symbol TEXT)",
+        [],
+    )
+    .map_err(io::Error::other)?;
+
+    let mut rows = 0;
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO events VALUES (?1, ?2, ?3, ?4, ?5)")
+            .map_err(io::Error::other)?;
+        for e in events {
+            let (tid, time, kind, addr) = match e {
+                Event::Entry(e) => (e.tid, e.time, "entry", e.to as usize),
+                Event::Exit(e) => (e.tid, e.time, "exit", e.from as usize),
+                Event::Record(_) | Event::Empty => continue,
+            };
+            let symbol = symbols.and_then(|symbols| symbols.name(addr));
+            // SQLite integers are signed, so huge values wrap around
+            insert
+                .execute(params![
+                    tid.map_or(0, |tid| tid.get()) as i64,
+                    time as i64,
+                    kind,
+                    addr as i64,
+                    symbol
+                ])
+                .map_err(io::Error::other)?;
+            rows += 1;
+        }
+    }
+    tx.commit().map_err(io::Error::other)?;
+
+    println!("  Wrote {} events", rows);
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self};
+use std::num::NonZeroU64;
+
+use crate::frontend::{attach, write_event, Attachment};
+use crate::interface::*;
+
+/// Continuously writes newly recorded events into rotating segment files, for recordings too long to keep in memory.
+///
+/// Segments are named `trace.0.dat`, `trace.1.dat`, … and use the same format as `dump_trace()`.
+/// Once a segment would exceed `max_segment_bytes`, the next one is started. Only the most recent `max_segments` segments are kept on disk.
+///
+/// The writer attaches to the event buffer (see `attach()`), so tracing continues while flushing.
+/// The buffer should be overwriting and `flush()` has to be called often enough that it does not wrap in between.
+#[derive(Debug)]
+pub struct RotatingWriter {
+    attachment: Attachment,
+    index: usize,
+    out_dir: String,
+    max_segment_bytes: u64,
+    max_segments: usize,
+    segment: usize,
+    segment_bytes: u64,
+    /// Call depth of each thread at the end of the events flushed so far
+    depths: HashMap<Option<NonZeroU64>, usize>,
+}
+
+impl RotatingWriter {
+    /// Attaches to the backend's event buffer and prepares writing segments into `out_dir`, which has to exist.
+    ///
+    /// Returns `None` if no event buffer has been initialized yet.
+    pub fn new(out_dir: &str, max_segment_bytes: u64, max_segments: usize) -> Option<Self> {
+        assert!(max_segments > 0, "At least one segment has to be kept!");
+        Some(Self {
+            attachment: attach()?,
+            index: 0,
+            out_dir: out_dir.into(),
+            max_segment_bytes,
+            max_segments,
+            segment: 0,
+            segment_bytes: 0,
+            depths: HashMap::new(),
+        })
+    }
+
+    fn segment_path(&self, segment: usize) -> String {
+        format!("{}/trace.{}.dat", self.out_dir, segment)
+    }
+
+    /// Appends all events recorded since the last flush to the current segment, rotating as needed.
+    ///
+    /// Returns the number of written events.
+    pub fn flush(&mut self) -> io::Result<usize> {
+        let (events, index) = self.attachment.events_since(self.index);
+        self.index = index;
+        self.write(&events)
+    }
+
+    /// Appends `events` to the current segment, rotating as needed. Returns the number of written events.
+    fn write(&mut self, events: &[Event]) -> io::Result<usize> {
+        let mut out = Vec::<u8>::with_capacity(16 * events.len());
+        for e in events {
+            match e {
+                Event::Entry(e) => {
+                    let depth = self.depths.entry(e.tid).or_default();
+                    write_event(&mut out, e.time, e.to, 0, *depth);
+                    *depth += 1;
+                }
+                Event::Exit(e) => {
+                    let depth = self.depths.entry(e.tid).or_default();
+                    *depth = depth.saturating_sub(1);
+                    write_event(&mut out, e.time, e.from, 1, *depth);
+                }
+                Event::Record(_) | Event::Empty => {}
+            }
+        }
+
+        // Segments only ever end on record boundaries
+        let mut rest = &out[..];
+        while !rest.is_empty() {
+            if self.segment_bytes > 0 && self.segment_bytes + 16 > self.max_segment_bytes {
+                self.rotate()?;
+            }
+
+            // At least one record per segment, even if max_segment_bytes is tiny
+            let records = self.max_segment_bytes.saturating_sub(self.segment_bytes) / 16;
+            let fit = records.max(1) as usize * 16;
+            let (chunk, remaining) = rest.split_at(fit.min(rest.len()));
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.segment_path(self.segment))?;
+            file.write_all(chunk)?;
+            self.segment_bytes += chunk.len() as u64;
+            rest = remaining;
+        }
+
+        Ok(out.len() / 16)
+    }
+
+    /// Starts the next segment, deleting the oldest one if we keep too many.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.segment += 1;
+        self.segment_bytes = 0;
+        File::create(self.segment_path(self.segment))?;
+
+        if let Some(oldest) = self.segment.checked_sub(self.max_segments) {
+            println!("  Removing old segment {}", self.segment_path(oldest));
+            fs::remove_file(self.segment_path(oldest))?;
+        }
+        Ok(())
+    }
+}
+
+impl EventConsumer for RotatingWriter {
+    /// Appends `events` to the segments, like `flush()` does with the events it reads itself.
+    fn consume(&mut self, events: &[Event]) -> io::Result<()> {
+        self.write(events).map(|_| ())
+    }
+}
+
+/// Receives newly recorded events from a `Broadcast`.
+///
+/// Implemented by `RotatingWriter`, and by closures taking the events, such as ones sending them over the network.
+pub trait EventConsumer {
+    /// Handles `events`, oldest first.
+    ///
+    /// On error, the same events are passed again on the next flush, followed by the ones recorded meanwhile.
+    /// Events handled before failing are thus passed twice.
+    fn consume(&mut self, events: &[Event]) -> io::Result<()>;
+}
+
+impl<F: FnMut(&[Event]) -> io::Result<()>> EventConsumer for F {
+    fn consume(&mut self, events: &[Event]) -> io::Result<()> {
+        self(events)
+    }
+}
+
+/// Hands newly recorded events to several consumers, such as a live viewer over the network and an on-disk archive.
+///
+/// Like `RotatingWriter`, it attaches to the event buffer, which should be overwriting, and has to be flushed regularly.
+/// Each consumer has its own backlog: events it fails to handle are kept and passed again on the next flush. Once a
+/// backlog exceeds its limit, its oldest events are dropped for that consumer alone. A failing consumer, for example
+/// because the network connection was lost, thus never holds back or aborts the others.
+pub struct Broadcast {
+    attachment: Attachment,
+    index: usize,
+    consumers: Vec<Subscriber>,
+}
+
+/// A consumer of a `Broadcast` and its backlog
+struct Subscriber {
+    name: String,
+    consumer: Box<dyn EventConsumer>,
+    backlog: VecDeque<Event>,
+    max_backlog: usize,
+    lost: u64,
+    error: Option<String>,
+}
+
+/// State of a consumer of a `Broadcast`, see `Broadcast::consumers()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerStatus {
+    pub name: String,
+    /// Number of events kept to be passed again on the next flush.
+    pub backlog: usize,
+    /// Number of events dropped since they exceeded the backlog.
+    pub lost: u64,
+    /// Error of the last flush, if the consumer failed on it.
+    pub error: Option<String>,
+}
+
+impl Broadcast {
+    /// Attaches to the backend's event buffer, without any consumers yet.
+    ///
+    /// Returns `None` if no event buffer has been initialized yet.
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            attachment: attach()?,
+            index: 0,
+            consumers: Vec::new(),
+        })
+    }
+
+    /// Adds `consumer`, which receives all events recorded from the next flush on.
+    ///
+    /// If it fails, at most `max_backlog` events are kept for it. `name` identifies it in `consumers()` and `remove()`.
+    pub fn add(&mut self, name: &str, consumer: impl EventConsumer + 'static, max_backlog: usize) {
+        self.consumers.push(Subscriber {
+            name: name.into(),
+            consumer: Box::new(consumer),
+            backlog: VecDeque::new(),
+            max_backlog,
+            lost: 0,
+            error: None,
+        });
+    }
+
+    /// Removes the consumer named `name`, dropping its backlog. Returns whether there was such a consumer.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.consumers.len();
+        self.consumers.retain(|subscriber| subscriber.name != name);
+        self.consumers.len() != len
+    }
+
+    /// Passes all events recorded since the last flush to each consumer, after its backlog.
+    ///
+    /// Returns the number of new events. Failing consumers are reported in `consumers()`, but never fail the flush.
+    pub fn flush(&mut self) -> usize {
+        let (events, index) = self.attachment.events_since(self.index);
+        self.index = index;
+
+        for subscriber in &mut self.consumers {
+            subscriber.backlog.extend(&events);
+            match subscriber
+                .consumer
+                .consume(subscriber.backlog.make_contiguous())
+            {
+                Ok(()) => {
+                    subscriber.backlog.clear();
+                    subscriber.error = None;
+                }
+                Err(err) => {
+                    if subscriber.error.is_none() {
+                        println!(
+                            "  Consumer {} failed, keeping its events: {}",
+                            subscriber.name, err
+                        );
+                    }
+                    subscriber.error = Some(err.to_string());
+                    let excess = subscriber
+                        .backlog
+                        .len()
+                        .saturating_sub(subscriber.max_backlog);
+                    subscriber.backlog.drain(..excess);
+                    subscriber.lost += excess as u64;
+                }
+            }
+        }
+        events.len()
+    }
+
+    /// Returns the state of each consumer, in the order they were added.
+    pub fn consumers(&self) -> Vec<ConsumerStatus> {
+        self.consumers
+            .iter()
+            .map(|subscriber| ConsumerStatus {
+                name: subscriber.name.clone(),
+                backlog: subscriber.backlog.len(),
+                lost: subscriber.lost,
+                error: subscriber.error.clone(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, process};
+
+use crate::frontend::{set_address_filter, set_exclude_filter};
+
+/// Environment variable overriding the directory `nm_cached()` caches symbols in. Caching is disabled if it is empty.
+pub const ENV_SYMBOL_CACHE: &str = "RFTRACE_SYMBOL_CACHE";
+
+/// Function symbols of a traced binary, to refer to functions in traces by name.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// Function address and demangled name, sorted by address.
+    symbols: Vec<(usize, String)>,
+}
+
+impl Symbols {
+    /// Parses the output of `nm -n`, as written next to traces by `spawn_traced()`.
+    ///
+    /// Only text symbols are kept. Rust symbols using the legacy mangling scheme are demangled, without their hash.
+    pub fn from_nm(nm: &str) -> Self {
+        let mut symbols: Vec<(usize, String)> = nm
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                if !matches!(kind, "t" | "T" | "w" | "W") {
+                    return None;
+                }
+                Some((addr, demangle(name)))
+            })
+            .collect();
+        symbols.sort();
+        Self { symbols }
+    }
+
+    /// Reads a symbol file in the format of `nm -n`, see `from_nm()`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self::from_nm(&fs::read_to_string(path)?))
+    }
+
+    /// Reads the symbols of `binary` with `nm -n`, see `nm_cached()`.
+    pub fn from_binary(binary: &Path) -> io::Result<Self> {
+        Ok(Self::from_nm(&nm_cached(binary)?))
+    }
+
+    /// Shifts all addresses by `base`, for binaries loaded at a different address than linked, such as PIEs.
+    pub fn relocate(&mut self, base: usize) {
+        for (addr, _) in &mut self.symbols {
+            *addr = addr.wrapping_add(base);
+        }
+    }
+
+    /// Returns all functions as address and name, sorted by address.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.symbols
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    /// Returns the addresses of all functions named `name`, such as the instances of a generic function.
+    pub fn addresses(&self, name: &str) -> Vec<usize> {
+        self.symbols
+            .iter()
+            .filter(|(_, symbol)| symbol == name)
+            .map(|&(addr, _)| addr)
+            .collect()
+    }
+
+    /// Returns the address ranges of all functions named `name`, see `addresses()`.
+    ///
+    /// Symbols do not record their size, so each function is assumed to extend up to the next symbol.
+    /// The last symbol is assumed to be a single byte.
+    pub fn ranges(&self, name: &str) -> Vec<Range<usize>> {
+        self.ranges_where(|symbol| symbol == name)
+    }
+
+    /// Returns the address ranges of all functions matching `pattern`, like `ranges()`.
+    ///
+    /// A pattern ending in `*` matches all names starting with the rest, such as `my_crate::*` for a whole crate.
+    /// Other patterns have to match the demangled name exactly.
+    pub fn ranges_matching(&self, pattern: &str) -> Vec<Range<usize>> {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => self.ranges_where(|symbol| symbol.starts_with(prefix)),
+            None => self.ranges(pattern),
+        }
+    }
+
+    fn ranges_where(&self, matches: impl Fn(&str) -> bool) -> Vec<Range<usize>> {
+        self.symbols
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, symbol))| matches(symbol))
+            .map(|(i, &(addr, _))| {
+                let end = self.symbols[i + 1..]
+                    .iter()
+                    .map(|&(next, _)| next)
+                    .find(|&next| next > addr)
+                    .unwrap_or(addr + 1);
+                addr..end
+            })
+            .collect()
+    }
+
+    /// Returns the name of the function starting at `addr`.
+    pub fn name(&self, addr: usize) -> Option<&str> {
+        let idx = self
+            .symbols
+            .binary_search_by_key(&addr, |&(addr, _)| addr)
+            .ok()?;
+        Some(&self.symbols[idx].1)
+    }
+
+    /// Returns the name of the function containing `addr` and the offset of `addr` within it, such as for return addresses.
+    ///
+    /// Like `ranges()`, functions are assumed to extend up to the next symbol.
+    pub fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = self
+            .symbols
+            .partition_point(|&(start, _)| start <= addr)
+            .checked_sub(1)?;
+        let (start, name) = &self.symbols[idx];
+        Some((name, addr - start))
+    }
+}
+
+/// Records only the functions named by `include` and drops those named by `exclude`, like uftrace's `-F` and `-N` options.
+///
+/// Names are resolved to address ranges with the symbols of `binary`, see `Symbols::ranges_matching()` for the patterns
+/// understood. If `binary` is mapped into this process as position independent executable, the ranges are relocated to
+/// where it is loaded. An empty `include` records all functions not excluded. The ranges are programmed with
+/// `set_address_filter()` and `set_exclude_filter()`. They are replaced one after the other, so call this while tracing
+/// is disabled to switch both at once.
+///
+/// Fails if `include` is not empty but no function matches, since that would silently record everything.
+pub fn set_symbol_filter(binary: &Path, include: &[&str], exclude: &[&str]) -> io::Result<()> {
+    let mut symbols = Symbols::from_binary(binary)?;
+    // Symbols of position independent executables start near 0, below where the binary is mapped
+    let first = symbols.iter().next().map(|(addr, _)| addr);
+    if let (Some(base), Some(first)) = (mapped_base(binary), first) {
+        if first < base {
+            symbols.relocate(base);
+        }
+    }
+    let resolve = |patterns: &[&str]| -> Vec<Range<usize>> {
+        patterns
+            .iter()
+            .flat_map(|pattern| symbols.ranges_matching(pattern))
+            .collect()
+    };
+    let included = resolve(include);
+    let excluded = resolve(exclude);
+    if !include.is_empty() && included.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no function in {} matches {:?}", binary.display(), include),
+        ));
+    }
+
+    println!(
+        "Filtering functions: {} included, {} excluded",
+        included.len(),
+        excluded.len()
+    );
+    set_address_filter(&included);
+    set_exclude_filter(&excluded);
+    Ok(())
+}
+
+/// Returns the address `binary` is mapped at in this process, according to `/proc/self/maps`.
+fn mapped_base(binary: &Path) -> Option<usize> {
+    let binary = fs::canonicalize(binary).ok()?;
+    let binary = binary.to_str()?;
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .filter(|line| line.ends_with(binary))
+        .find_map(|line| usize::from_str_radix(line.split('-').next()?, 16).ok())
+}
+
+/// Returns the output of `nm -n` for `binary`, cached between runs so repeated dumps of a large binary skip re-parsing it.
+///
+/// The cache is keyed by a hash of the binary's contents, so rebuilt binaries are never served stale symbols. It lives in
+/// `RFTRACE_SYMBOL_CACHE`, or `rftrace/symbols` in the XDG cache directory. Failing to use the cache is not an error.
+pub fn nm_cached(binary: &Path) -> io::Result<String> {
+    let cached = match symbol_cache_dir() {
+        Some(dir) => Some(dir.join(format!("{:016x}.sym", hash_file(binary)?))),
+        None => None,
+    };
+    if let Some(nm) = cached
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+    {
+        println!("  Using cached symbols of {:?}", binary);
+        return Ok(nm);
+    }
+
+    let output = Command::new("nm").arg("-n").arg(binary).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "nm exited with {}",
+            output.status
+        )));
+    }
+    let nm = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    if let Some(path) = cached {
+        if let Err(err) = store(&path, nm.as_bytes()) {
+            println!("  Could not cache symbols in {}: {}", path.display(), err);
+        }
+    }
+    Ok(nm)
+}
+
+fn symbol_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os(ENV_SYMBOL_CACHE) {
+        return (!dir.is_empty()).then(|| dir.into());
+    }
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache.join("rftrace").join("symbols"))
+}
+
+/// Writes `data` to `path` through a temporary file, so concurrent runs never read a partially written cache entry.
+fn store(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    let tmp = path.with_extension(format!("tmp{}", process::id()));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+/// 64 bit FNV-1a hash of the contents of `path`, which unlike `DefaultHasher` is stable across Rust versions.
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 20];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for &b in &buf[..n] {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Escape sequences of the legacy Rust mangling scheme.
+const ESCAPES: [(&str, &str); 14] = [
+    ("$SP$", "@"),
+    ("$BP$", "*"),
+    ("$RF$", "&"),
+    ("$LT$", "<"),
+    ("$GT$", ">"),
+    ("$LP$", "("),
+    ("$RP$", ")"),
+    ("$C$", ","),
+    ("$u20$", " "),
+    ("$u27$", "'"),
+    ("$u5b$", "["),
+    ("$u5d$", "]"),
+    ("$u7b$", "{"),
+    ("$u7d$", "}"),
+];
+
+/// Demangles legacy Rust symbols such as `_ZN8my_crate8hot_path17h0123456789abcdefE` into `my_crate::hot_path`.
+///
+/// Other symbols are returned unchanged.
+fn demangle(symbol: &str) -> String {
+    let mangled = match symbol.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+        Some(mangled) => mangled,
+        None => return symbol.into(),
+    };
+
+    let mut path: Vec<&str> = Vec::new();
+    let mut rest = mangled;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len = match rest[..digits].parse::<usize>() {
+            Ok(len) if rest.len() >= digits + len => len,
+            _ => return symbol.into(),
+        };
+        path.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+
+    // Drop the trailing hash
+    if path.last().is_some_and(|last| {
+        last.len() == 17
+            && last.starts_with('h')
+            && last[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    }) {
+        path.pop();
+    }
+
+    path.iter()
+        .map(|ident| {
+            // Identifiers starting with an escape are prefixed with an underscore
+            let mut ident = ident
+                .strip_prefix("_$")
+                .map_or(*ident, |_| &ident[1..])
+                .replace("..", "::");
+            for (escape, c) in ESCAPES {
+                ident = ident.replace(escape, c);
+            }
+            ident
+        })
+        .collect::<Vec<_>>()
+        .join("::")
+}
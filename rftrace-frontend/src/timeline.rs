@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::num::NonZeroU64;
+
+use crate::interface::*;
+
+/// Overview of the events of a single thread, see `summarize_threads()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadSummary {
+    pub tid: Option<NonZeroU64>,
+    /// Timestamp of the thread's first event.
+    pub first: u64,
+    /// Timestamp of the thread's last event.
+    pub last: u64,
+    /// Number of recorded function entries.
+    pub entries: u64,
+    /// Number of recorded function exits.
+    pub exits: u64,
+    /// Number of other records, such as tracing or resource records.
+    pub records: u64,
+    /// Time in TSC cycles spent within traced functions, between the entry and exit of each outermost call.
+    ///
+    /// Calls entered before the first event count from the first event, calls not yet returned until the last one.
+    pub busy_time: u64,
+    /// Maximum call stack depth, starting at 1.
+    pub max_depth: usize,
+}
+
+impl ThreadSummary {
+    /// Time in TSC cycles between the first and last event of the thread.
+    pub fn duration(&self) -> u64 {
+        self.last - self.first
+    }
+}
+
+/// Summarizes the events of each thread in `events`, which have to be ordered oldest first. Ordered by TID.
+///
+/// A quick overview before deciding which thread's trace to look into, such as one which was busy for long or called deeply.
+pub fn summarize_threads(events: &[Event]) -> Vec<ThreadSummary> {
+    // Per thread summary, current depth and the start of the current outermost call
+    let mut threads: BTreeMap<Option<NonZeroU64>, (ThreadSummary, usize, u64)> = BTreeMap::new();
+    for event in events {
+        let (tid, time) = match event {
+            Event::Entry(e) => (e.tid, e.time),
+            Event::Exit(e) => (e.tid, e.time),
+            Event::Record(r) => (r.tid, r.time),
+            Event::Empty => continue,
+        };
+        let (summary, depth, start) = threads.entry(tid).or_insert_with(|| {
+            (
+                ThreadSummary {
+                    tid,
+                    first: time,
+                    last: time,
+                    ..ThreadSummary::default()
+                },
+                0,
+                0,
+            )
+        });
+        summary.last = time;
+        match event {
+            Event::Entry(_) => {
+                summary.entries += 1;
+                if *depth == 0 {
+                    *start = time;
+                }
+                *depth += 1;
+                summary.max_depth = summary.max_depth.max(*depth);
+            }
+            Event::Exit(_) => {
+                summary.exits += 1;
+                match *depth {
+                    // Returned from a call entered before the first event, so the thread was busy all along
+                    0 => summary.busy_time = time - summary.first,
+                    1 => summary.busy_time += time - *start,
+                    _ => {}
+                }
+                *depth = depth.saturating_sub(1);
+            }
+            _ => summary.records += 1,
+        }
+    }
+
+    threads
+        .into_values()
+        .map(|(mut summary, depth, start)| {
+            if depth > 0 {
+                summary.busy_time += summary.last - start;
+            }
+            summary
+        })
+        .collect()
+}
+
+/// Formats `summaries`, such as returned by `summarize_threads()`, as human-readable table, one thread per line.
+///
+/// Times are in TSC cycles, busy time is also shown relative to the thread's duration.
+pub fn thread_summary_report(summaries: &[ThreadSummary]) -> String {
+    let mut report = String::new();
+    writeln!(
+        report,
+        "{:>8}  {:>16}  {:>16}  {:>10}  {:>10}  {:>8}  {:>16}  {:>6}  {:>5}",
+        "TID", "first", "last", "entries", "exits", "records", "busy", "busy%", "depth"
+    )
+    .unwrap();
+    for summary in summaries {
+        let busy = if summary.duration() > 0 {
+            summary.busy_time as f64 * 100.0 / summary.duration() as f64
+        } else {
+            0.0
+        };
+        writeln!(
+            report,
+            "{:>8}  {:>16}  {:>16}  {:>10}  {:>10}  {:>8}  {:>16}  {:>5.1}%  {:>5}",
+            summary.tid.map_or(0, NonZeroU64::get),
+            summary.first,
+            summary.last,
+            summary.entries,
+            summary.exits,
+            summary.records,
+            summary.busy_time,
+            busy,
+            summary.max_depth
+        )
+        .unwrap();
+    }
+    report
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Magic number starting the footer of a checksummed trace file, see `DumpOptions::checksum`.
+const FOOTER_MAGIC: &[u8; 4] = b"RFTC";
+
+/// Size of the footer of a checksummed trace file, in bytes.
+pub const FOOTER_LEN: usize = 16;
+
+/// CRC-32 (IEEE) lookup table, computed at compile time.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Returns the CRC-32 (IEEE, as used by zlib and Ethernet) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Type of a uftrace record, stored in its lowest 2 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UftraceRecordType {
+    Entry,
+    Exit,
+    Lost,
+    Event,
+}
+
+/// A single record of a uftrace trace file (`<tid>.dat`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UftraceRecord {
+    pub time: u64,
+    pub kind: UftraceRecordType,
+    /// Call depth, starting at 0 for the outermost function of a thread. Exits have the depth of their entry.
+    pub depth: u16,
+    /// Function address, or the event ID of an event.
+    pub addr: u64,
+    /// Additional data following the record, such as the payload of an event. Empty if there is none.
+    pub more: Vec<u8>,
+}
+
+/// Reads a uftrace trace file, see `parse_uftrace_records()`.
+pub fn read_uftrace_records(path: &str, checksum: bool) -> io::Result<Vec<UftraceRecord>> {
+    parse_uftrace_records(&fs::read(path)?, checksum)
+}
+
+/// Parses the records of a uftrace trace file, such as those written by `dump_full_uftrace()`.
+///
+/// If `checksum`, the trace has to end with the footer written with `DumpOptions::checksum`, which is verified and stripped.
+/// Truncated or corrupted traces fail with `InvalidData` instead of being silently cut short.
+/// Without `checksum`, truncation is only detected if a record is cut in half.
+pub fn parse_uftrace_records(data: &[u8], checksum: bool) -> io::Result<Vec<UftraceRecord>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let (data, footer) = if checksum {
+        if data.len() < FOOTER_LEN || &data[data.len() - FOOTER_LEN..][..4] != FOOTER_MAGIC {
+            return Err(invalid(
+                "trace has no checksum footer, it was probably truncated",
+            ));
+        }
+        let (data, footer) = data.split_at(data.len() - FOOTER_LEN);
+        let crc = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+        let count = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        if crc32(data) != crc {
+            return Err(invalid("trace checksum mismatch, it is corrupted"));
+        }
+        (data, Some(count))
+    } else {
+        (data, None)
+    };
+
+    let mut records = Vec::with_capacity(data.len() / 16);
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < 16 {
+            return Err(invalid("trace ends within a record"));
+        }
+        let time = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+        let merged = u64::from_le_bytes(rest[8..16].try_into().unwrap());
+        rest = &rest[16..];
+        if (merged >> 3) & 0b111 != 0b101 {
+            return Err(invalid("invalid record magic"));
+        }
+
+        let kind = match merged & 0b11 {
+            0 => UftraceRecordType::Entry,
+            1 => UftraceRecordType::Exit,
+            2 => UftraceRecordType::Lost,
+            _ => UftraceRecordType::Event,
+        };
+        // The data is prefixed with its length and padded to 8 bytes
+        let more = if merged & (1 << 2) != 0 {
+            if rest.len() < 2 {
+                return Err(invalid("trace ends within record data"));
+            }
+            let len = usize::from(u16::from_le_bytes(rest[0..2].try_into().unwrap()));
+            let padded = (2 + len).div_ceil(8) * 8;
+            if rest.len() < padded {
+                return Err(invalid("trace ends within record data"));
+            }
+            let more = rest[2..2 + len].to_vec();
+            rest = &rest[padded..];
+            more
+        } else {
+            Vec::new()
+        };
+
+        records.push(UftraceRecord {
+            time,
+            kind,
+            depth: ((merged >> 6) & ((1 << 10) - 1)) as u16,
+            addr: merged >> 16,
+            more,
+        });
+    }
+
+    if let Some(count) = footer {
+        if count != records.len() as u64 {
+            return Err(invalid("trace record count does not match its footer"));
+        }
+    }
+    Ok(records)
+}
+
+/// Writes the footer of a checksummed trace file, holding the CRC-32 of `data` and its number of `records`.
+pub(crate) fn write_footer<W: Write>(out: &mut W, data: &[u8], records: u64) -> io::Result<()> {
+    out.write_all(FOOTER_MAGIC)?;
+    out.write_u32::<LittleEndian>(crc32(data))?;
+    out.write_u64::<LittleEndian>(records)
+}
+
+/// Re-parses the uftrace directory `dir`, such as one just written by `dump_full_uftrace()`, and returns all problems found.
+///
+/// Checks the `info` header fields, that the TIDs of `info`, `task.txt` and the trace files agree, and that each trace file
+/// consists of aligned, valid records in time order with matching entries and exits. This catches format regressions
+/// before uftrace rejects or misreads the data. Encrypted traces can only be checked for their metadata.
+/// Fails only if `dir` or its `info` file can not be read.
+pub fn verify_uftrace_dir(dir: &str) -> io::Result<Vec<String>> {
+    let mut warnings = Vec::new();
+
+    let info = fs::read(format!("{}/info", dir))?;
+    if info.len() < 40 {
+        warnings.push(format!("info: header truncated to {} bytes", info.len()));
+        return Ok(warnings);
+    }
+    if &info[0..8] != b"Ftrace!\x00" {
+        warnings.push("info: invalid magic".to_string());
+    }
+    let version = u32::from_le_bytes(info[8..12].try_into().unwrap());
+    if version != 4 {
+        warnings.push(format!("info: version {} instead of 4", version));
+    }
+    let header_size = u16::from_le_bytes(info[12..14].try_into().unwrap());
+    if header_size != 40 {
+        warnings.push(format!("info: header size {} instead of 40", header_size));
+    }
+    if info[14] != 1 || info[15] != 2 {
+        warnings.push("info: not a little endian, 64 bit trace".to_string());
+    }
+
+    let lines = String::from_utf8_lossy(&info[40..]);
+    let value = |key: &str| {
+        lines
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+    };
+    let tids: BTreeSet<u64> = value("taskinfo:tids")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|tid| tid.parse().ok())
+        .collect();
+    if tids.is_empty() {
+        warnings.push("info: no TIDs".to_string());
+    }
+    if value("taskinfo:nr_tid").and_then(|nr| nr.parse().ok()) != Some(tids.len()) {
+        warnings.push("info: taskinfo:nr_tid does not match the number of TIDs".to_string());
+    }
+    let checksum = value("rftrace:checksum").is_some();
+    let encrypted = value("rftrace:encryption").is_some();
+
+    match fs::read_to_string(format!("{}/task.txt", dir)) {
+        Ok(task) => {
+            let task_tids: BTreeSet<u64> = task
+                .lines()
+                .filter(|line| line.starts_with("TASK "))
+                .filter_map(|line| {
+                    line.split_whitespace()
+                        .find_map(|field| field.strip_prefix("tid="))?
+                        .parse()
+                        .ok()
+                })
+                .collect();
+            if task_tids != tids {
+                warnings.push("task.txt: TIDs do not match info".to_string());
+            }
+        }
+        Err(err) => warnings.push(format!("task.txt: {}", err)),
+    }
+    if fs::metadata(format!("{}/sid-00.map", dir)).is_err() {
+        warnings.push("sid-00.map: missing".to_string());
+    }
+
+    // Trace files of threads uftrace does not know about are silently ignored by it
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(tid) = name.strip_suffix(".dat").and_then(|tid| tid.parse().ok()) {
+            if !tids.contains(&tid) {
+                warnings.push(format!("{}: TID {} is missing in info", name, tid));
+            }
+        }
+    }
+
+    if encrypted {
+        warnings.push("trace files are encrypted, their records were not verified".to_string());
+        return Ok(warnings);
+    }
+    for tid in &tids {
+        let name = format!("{}.dat", tid);
+        let data = match fs::read(format!("{}/{}", dir, name)) {
+            Ok(data) => data,
+            Err(err) => {
+                warnings.push(format!("{}: {}", name, err));
+                continue;
+            }
+        };
+        let records = match parse_uftrace_records(&data, checksum) {
+            Ok(records) => records,
+            Err(err) => {
+                warnings.push(format!("{}: {}", name, err));
+                continue;
+            }
+        };
+
+        let mut time = 0;
+        let mut stack: Vec<u64> = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            if record.time < time {
+                warnings.push(format!("{}: record {} goes back in time", name, i));
+            }
+            time = record.time;
+            match record.kind {
+                UftraceRecordType::Entry => stack.push(record.addr),
+                UftraceRecordType::Exit => {
+                    // Exits without any entry are fine, their entry might have been overwritten in the ring buffer
+                    if stack.pop().is_some_and(|addr| addr != record.addr) {
+                        warnings.push(format!(
+                            "{}: record {} exits {:#x}, which was not entered last",
+                            name, i, record.addr
+                        ));
+                    }
+                }
+                UftraceRecordType::Lost | UftraceRecordType::Event => {}
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a checksummed trace file of a single entry and exit.
+    fn trace() -> Vec<u8> {
+        let mut data = Vec::new();
+        for (time, kind) in [(1u64, 0u64), (2, 1)].iter() {
+            data.extend_from_slice(&time.to_le_bytes());
+            data.extend_from_slice(&((0x1000 << 16) | (0b101 << 3) | kind).to_le_bytes());
+        }
+        let mut footer = Vec::new();
+        write_footer(&mut footer, &data, 2).unwrap();
+        data.extend_from_slice(&footer);
+        data
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn parse_checksummed() {
+        let records = parse_uftrace_records(&trace(), true).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kind, UftraceRecordType::Entry);
+        assert_eq!(records[1].kind, UftraceRecordType::Exit);
+        assert_eq!(records[1].time, 2);
+        assert_eq!(records[1].addr, 0x1000);
+    }
+
+    #[test]
+    fn parse_truncated_footer() {
+        let trace = trace();
+        for cut in [1, FOOTER_LEN / 2, FOOTER_LEN, FOOTER_LEN + 8].iter() {
+            let err = parse_uftrace_records(&trace[..trace.len() - cut], true).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
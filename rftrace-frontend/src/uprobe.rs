@@ -0,0 +1,268 @@
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self};
+use std::num::NonZeroU64;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::interface::*;
+use crate::recover::load_segments;
+use crate::symbols::Symbols;
+
+const TRACEFS_PATHS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// Backend recording selected functions of an uninstrumented binary with uprobes, see `set_backend()`.
+///
+/// Entry and return probes are registered in the kernel's tracefs, so the binary does not need to be compiled with mcount instrumentation.
+/// Probes apply to every process running the binary, the events of different threads are told apart by their TID.
+/// Events are kept in the kernel until `get_events()`, which moves them into the event buffer. Timestamps are in nanoseconds.
+///
+/// Requires root, or at least write access to tracefs.
+#[derive(Debug)]
+pub struct UprobeBackend {
+    tracefs: PathBuf,
+    instance: PathBuf,
+    group: String,
+    /// Function address of each probe pair, indexed by probe number
+    functions: Vec<usize>,
+    enabled: AtomicBool,
+    /// Event buffer passed to `init()`, as address and length
+    buffer: Mutex<Option<(usize, usize)>>,
+    overwriting: AtomicBool,
+    index: AtomicUsize,
+}
+
+impl UprobeBackend {
+    /// Registers entry and return probes for all functions in `binary` named by `functions`, as resolved with `symbols`.
+    pub fn new(binary: &str, symbols: &Symbols, functions: &[&str]) -> io::Result<Self> {
+        let tracefs = TRACEFS_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.join("uprobe_events").exists())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"))?;
+        let group = format!("rftrace_{}", process::id());
+
+        let addrs: Vec<usize> = functions
+            .iter()
+            .flat_map(|name| symbols.addresses(name))
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "none of the functions are in symbols",
+            ));
+        }
+
+        let mut backend = Self {
+            instance: tracefs.join("instances").join(&group),
+            tracefs,
+            group,
+            functions: Vec::new(),
+            enabled: AtomicBool::new(false),
+            buffer: Mutex::new(None),
+            overwriting: AtomicBool::new(false),
+            index: AtomicUsize::new(0),
+        };
+
+        // Probes are placed at file offsets, translated from the addresses through the segment containing them
+        let segments = load_segments(&fs::read(binary)?)?;
+        let file_offset = |addr: usize| {
+            segments
+                .iter()
+                .find(|&&(vaddr, _, len)| addr >= vaddr && addr - vaddr < len)
+                .map(|&(vaddr, offset, _)| addr - vaddr + offset)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:#x} is not in a loadable segment of {}", addr, binary),
+                    )
+                })
+        };
+
+        println!(
+            "Registering uprobes for {} functions in {}",
+            addrs.len(),
+            binary
+        );
+        for addr in addrs {
+            let offset = file_offset(addr)?;
+            let probe = backend.functions.len();
+            backend.functions.push(addr);
+            backend.write_uprobe_events(&format!(
+                "p:{}/e{} {}:{:#x}",
+                backend.group, probe, binary, offset
+            ))?;
+            backend.write_uprobe_events(&format!(
+                "r:{}/x{} {}:{:#x}",
+                backend.group, probe, binary, offset
+            ))?;
+        }
+
+        // Use our own tracing instance, so we do not interfere with other users of tracefs
+        fs::create_dir(&backend.instance)?;
+        // The default clock is per-CPU, so events of migrating threads could be out of order
+        fs::write(backend.instance.join("trace_clock"), "mono")?;
+        Ok(backend)
+    }
+
+    fn write_uprobe_events(&self, line: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.tracefs.join("uprobe_events"))?;
+        writeln!(file, "{}", line)
+    }
+
+    fn set_probes_enabled(&self, enabled: bool) -> io::Result<()> {
+        let enable = self
+            .instance
+            .join("events")
+            .join(&self.group)
+            .join("enable");
+        fs::write(enable, if enabled { "1" } else { "0" })
+    }
+
+    /// Moves all events of our tracing instance into `events`, continuing at the current index.
+    ///
+    /// Unless overwriting, events which do not fit anymore are dropped, like the kernel does once its buffer is full.
+    fn read_trace(&self, events: &mut [Event]) -> io::Result<()> {
+        // Unlike `trace`, `trace_pipe` consumes what is read, so no event is copied twice. Without blocking, reading
+        // stops once it is empty.
+        let mut pipe = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(self.instance.join("trace_pipe"))?;
+        let mut trace = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            match pipe.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => trace.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let overwriting = self.overwriting.load(Ordering::Relaxed);
+        for event in String::from_utf8_lossy(&trace)
+            .lines()
+            .filter_map(|line| self.parse_line(line))
+        {
+            let idx = self.index.load(Ordering::Relaxed);
+            if idx >= events.len() && !overwriting {
+                break;
+            }
+            events[idx % events.len()] = event;
+            self.index.store(idx + 1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Parses a line of the tracefs trace, such as `  app-1234  [002] ..... 12345.678901234: e3: (0x55d0c0de1130)`.
+    fn parse_line(&self, line: &str) -> Option<Event> {
+        if line.starts_with('#') {
+            return None;
+        }
+        let (head, rest) = line.split_once(": ")?;
+
+        let (task, _) = head.split_once('[')?;
+        let (_, tid) = task.trim().rsplit_once('-')?;
+        let tid = NonZeroU64::new(tid.parse().ok()?);
+
+        let (secs, frac) = head.split_whitespace().last()?.split_once('.')?;
+        let nanos = format!("{:0<9}", frac).parse::<u64>().ok()?;
+        let time = secs.parse::<u64>().ok()? * 1_000_000_000 + nanos;
+
+        let (name, _) = rest.split_once(':')?;
+        let probe: usize = name.get(1..)?.parse().ok()?;
+        let function = *self.functions.get(probe)? as u64;
+        match name.as_bytes()[0] {
+            b'e' => Some(Event::Entry(Call {
+                time,
+                from: 0,
+                to: function,
+                tid,
+            })),
+            b'x' => Some(Event::Exit(Exit {
+                time,
+                from: function,
+                tid,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl Backend for UprobeBackend {
+    fn enable(&self) {
+        match self.set_probes_enabled(true) {
+            Ok(()) => self.enabled.store(true, Ordering::Relaxed),
+            Err(err) => println!("  Could not enable uprobes: {}", err),
+        }
+    }
+
+    fn disable(&self) {
+        if let Err(err) = self.set_probes_enabled(false) {
+            println!("  Could not disable uprobes: {}", err);
+        }
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    unsafe fn init(&self, buf: *mut Event, len: usize, overwriting: bool) -> InitStatus {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.is_some() {
+            return InitStatus::AlreadyInitialized;
+        }
+
+        // Let the kernel keep about as many events as fit into our buffer
+        let buffer_kb = (len * 64 / 1024).max(1);
+        let results = [
+            fs::write(self.instance.join("buffer_size_kb"), buffer_kb.to_string()),
+            fs::write(
+                self.instance.join("options").join("overwrite"),
+                if overwriting { "1" } else { "0" },
+            ),
+        ];
+        for err in results.iter().filter_map(|result| result.as_ref().err()) {
+            println!("  Could not configure tracing instance: {}", err);
+        }
+        self.overwriting.store(overwriting, Ordering::Relaxed);
+        self.index.store(0, Ordering::Relaxed);
+        *buffer = Some((buf as usize, len));
+        InitStatus::Ok
+    }
+
+    fn get_events(&self) -> *const Event {
+        let (ptr, len) = match self.buffer.lock().unwrap().take() {
+            Some(buffer) => buffer,
+            None => return core::ptr::null(),
+        };
+        // The buffer is ours until handed back, see `init()`
+        let events = unsafe { core::slice::from_raw_parts_mut(ptr as *mut Event, len) };
+        if let Err(err) = self.read_trace(events) {
+            println!("  Could not read uprobe events: {}", err);
+        }
+        ptr as *const Event
+    }
+
+    fn get_events_index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for UprobeBackend {
+    fn drop(&mut self) {
+        let _ = self.set_probes_enabled(false);
+        let _ = fs::remove_dir(&self.instance);
+        for probe in 0..self.functions.len() {
+            let _ = self.write_uprobe_events(&format!("-:{}/e{}", self.group, probe));
+            let _ = self.write_uprobe_events(&format!("-:{}/x{}", self.group, probe));
+        }
+    }
+}
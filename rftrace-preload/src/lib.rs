@@ -0,0 +1,52 @@
+//! This is the rftrace backend together with the frontend as shared library, to be LD_PRELOADed into instrumented binaries
+//! which do not link rftrace themselves.
+//! A lot of documentation can be found in the parent workspaces [readme](https://github.com/hermit-os/rftrace).
+//!
+//! Tracing is started with `init_from_env()` when the library is loaded and dumped with `dump_from_env()` when the
+//! binary exits, so it is configured by the `RFTRACE_*` environment variables like a child of `spawn_traced()`.
+
+#![feature(naked_functions)]
+
+use core::arch::asm;
+
+// Links the backend, whose hooks are kept internal by its preload feature, so they can be exported below
+extern crate rftrace;
+
+extern "C" {
+    fn rftrace_mcount();
+}
+
+rftrace_frontend::init_before_main!();
+
+/// Dumps the trace started by `init_before_main!()`, called from `.fini_array` once the binary exits.
+extern "C" fn dump_at_exit() {
+    if let Some(events) = rftrace_frontend::take_preinit_events() {
+        if let Err(err) = rftrace_frontend::dump_from_env(events) {
+            eprintln!("Could not dump trace: {}", err);
+        }
+    }
+}
+
+#[used]
+#[link_section = ".fini_array"]
+static RFTRACE_DUMP_AT_EXIT: extern "C" fn() = dump_at_exit;
+
+/// Forwards to the backend's `mcount`, leaving the stack untouched.
+///
+/// A cdylib only exports symbols defined in Rust, not those of the static libraries it links, such as the backend.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn mcount() {
+    #[cfg(target_arch = "x86_64")]
+    asm!("jmp {}", sym rftrace_mcount, options(noreturn));
+    #[cfg(target_arch = "riscv64")]
+    asm!("tail {}", sym rftrace_mcount, options(noreturn));
+}
+
+/// GCC calls `_mcount` on RISC-V, see `mcount()`.
+#[cfg(target_arch = "riscv64")]
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _mcount() {
+    asm!("tail {}", sym rftrace_mcount, options(noreturn));
+}
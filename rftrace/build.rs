@@ -10,7 +10,13 @@ fn build_backend() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let full_target_dir = format!("{}/target_static", out_dir);
 
-    let target = "x86_64-unknown-none";
+    // Kernel targets of the architecture we are linked into
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target = match arch.as_str() {
+        "x86_64" => "x86_64-unknown-none",
+        "riscv64" => "riscv64gc-unknown-none-elf",
+        _ => panic!("rftrace does not support the {} architecture!", arch),
+    };
 
     let mut cmd = cargo();
     cmd.arg("+nightly");
@@ -22,11 +28,19 @@ fn build_backend() {
     cmd.args(&["--target-dir", &full_target_dir]);
 
     // Enable the staticlib feature, so we can do #[cfg(feature='staticlib')] gate our code
-    // Pass-through interruptsafe and reexportsymbols features
+    // Pass-through interruptsafe and fentry features
     cmd.arg("--features=staticlib");
     if env::var_os("CARGO_FEATURE_INTERRUPTSAFE").is_some() {
         cmd.arg("--features=interruptsafe");
     }
+    let fentry = env::var_os("CARGO_FEATURE_FENTRY").is_some();
+    if fentry {
+        assert!(
+            arch == "x86_64",
+            "The fentry feature is only supported on x86_64!"
+        );
+        cmd.arg("--features=fentry");
+    }
 
     // Always output color, so eventhough we are cargo-in-cargo, we get nice error messages on build fail
     cmd.args(&["--color", "always"]);
@@ -59,16 +73,66 @@ fn build_backend() {
 
     let dist_dir = format!("{}/{}/release", &full_target_dir, &target);
 
+    // Has to be kept in sync with `interface::BACKEND_SYMBOLS`
+    let mut exported_symbols = HashSet::from([
+        "mcount",
+        "RFTRACE_DESCRIPTOR",
+        "rftrace_backend_attach",
+        "rftrace_backend_detach",
+        "rftrace_backend_disable",
+        "rftrace_backend_enable",
+        "rftrace_backend_full_trace_begin",
+        "rftrace_backend_full_trace_end",
+        "rftrace_backend_get_events",
+        "rftrace_backend_get_events_index",
+        "rftrace_backend_get_stats",
+        "rftrace_backend_init",
+        "rftrace_backend_init_per_core",
+        "rftrace_backend_init_per_thread",
+        "rftrace_backend_interface_version",
+        "rftrace_backend_is_enabled",
+        "rftrace_backend_memory_pressure",
+        "rftrace_backend_record",
+        "rftrace_backend_replace_buffer",
+        "rftrace_backend_scope_begin",
+        "rftrace_backend_scope_end",
+        "rftrace_backend_set_context",
+        "rftrace_backend_set_exclude",
+        "rftrace_backend_set_filter",
+        "rftrace_backend_set_flight_recorder",
+        "rftrace_backend_set_function_quota",
+        "rftrace_backend_set_max_depth",
+        "rftrace_backend_set_overhead_budget",
+        "rftrace_backend_set_page_filter",
+        "rftrace_backend_set_pressure_handler",
+        "rftrace_backend_set_sample_period",
+        "rftrace_backend_set_scoped",
+        "rftrace_backend_set_thread_quota",
+        "rftrace_backend_set_tid_offset",
+        "rftrace_backend_suppress",
+        "rftrace_backend_swap_buffer",
+        "rftrace_backend_switch_stack",
+        "rftrace_backend_take_per_thread",
+        "rftrace_backend_task_create",
+        "rftrace_backend_thread_exit",
+        "rftrace_backend_unsuppress",
+    ]);
+    if fentry {
+        exported_symbols.insert("__fentry__");
+    }
+    if arch == "riscv64" {
+        // GCC calls _mcount on RISC-V
+        exported_symbols.insert("_mcount");
+    }
+    if env::var_os("CARGO_FEATURE_PRELOAD").is_some() {
+        // rftrace-preload exports the hooks itself, since cdylibs do not export symbols of the static libraries they link
+        for hook in ["mcount", "_mcount", "__fentry__"] {
+            exported_symbols.remove(hook);
+        }
+    }
     retain_symbols(
         Path::new(&format!("{}/librftrace.a", &dist_dir)),
-        HashSet::from([
-            "mcount",
-            "rftrace_backend_disable",
-            "rftrace_backend_enable",
-            "rftrace_backend_get_events",
-            "rftrace_backend_get_events_index",
-            "rftrace_backend_init",
-        ]),
+        exported_symbols,
     );
 
     // Link parent-lib against this staticlib
@@ -77,6 +141,7 @@ fn build_backend() {
 
     println!("cargo:rerun-if-changed=Cargo.toml");
     println!("cargo:rerun-if-changed=src/backend.rs");
+    println!("cargo:rerun-if-changed=src/backend");
     println!("cargo:rerun-if-changed=src/interface.rs");
     println!("cargo:rerun-if-changed=src/lib.rs");
 }
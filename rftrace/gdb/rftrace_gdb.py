@@ -0,0 +1,115 @@
+# GDB helpers to extract an rftrace trace from a live or crashed program, for example a Hermit guest via uhyve's or qemu's GDB stub.
+#
+# Load with `source rftrace_gdb.py`, then run `rftrace-dump OUT_DIR [BINARY_NAME]`.
+# The event buffer is located via the backend's RFTRACE_DESCRIPTOR symbol, see `Descriptor` in interface.rs.
+
+import struct
+from pathlib import Path
+
+import gdb
+
+DESCRIPTOR_MAGIC = int.from_bytes(b"rftrdesc", "little")
+INTERFACE_VERSION = 3
+
+# Event variants, see `Event` in interface.rs
+EVENT_ENTRY = 1
+EVENT_EXIT = 2
+
+
+def read_descriptor():
+    addr = int(gdb.parse_and_eval("(unsigned long)&RFTRACE_DESCRIPTOR"))
+    raw = gdb.selected_inferior().read_memory(addr, 40).tobytes()
+    magic, version, event_size, events, length, index = struct.unpack("<QIIQQQ", raw)
+    if magic != DESCRIPTOR_MAGIC:
+        raise gdb.GdbError("RFTRACE_DESCRIPTOR is corrupt")
+    if version != INTERFACE_VERSION:
+        raise gdb.GdbError(f"Unsupported interface version {version}, expected {INTERFACE_VERSION}")
+    if events == 0 or length == 0:
+        raise gdb.GdbError("No event buffer initialized")
+    return event_size, events, length, index
+
+
+def read_events():
+    """ Returns all events as (kind, time, addr, tid) tuples, oldest first. kind is 0 for entries and 1 for exits. """
+    event_size, events, length, index = read_descriptor()
+    inferior = gdb.selected_inferior()
+    index = struct.unpack("<Q", inferior.read_memory(index, 8).tobytes())[0]
+
+    print(f"Reading {length} events at {events:#x}..")
+    raw = inferior.read_memory(events, length * event_size).tobytes()
+
+    parsed = []
+    # Rotate the ring buffer, so the oldest event comes first
+    for i in range(length):
+        slot = (index + i) % length
+        event = raw[slot * event_size:(slot + 1) * event_size]
+        tag = struct.unpack_from("<Q", event)[0]
+        if tag == EVENT_ENTRY:
+            time, _, to, tid = struct.unpack_from("<QQQQ", event, 8)
+            parsed.append((0, time, to, tid))
+        elif tag == EVENT_EXIT:
+            time, frm, tid = struct.unpack_from("<QQQ", event, 8)
+            parsed.append((1, time, frm, tid))
+    return parsed
+
+
+def write_uftrace(out_dir, binary_name, events):
+    """ Writes a uftrace directory in the same format as the frontend's dump_full_uftrace(). """
+    out = Path(out_dir)
+    out.mkdir(parents=True, exist_ok=True)
+
+    tids = []
+    for _, _, _, tid in events:
+        if tid not in tids:
+            tids.append(tid)
+    if not tids:
+        print("Trace is empty!")
+        return
+
+    for tid in tids:
+        with open(out / f"{tid}.dat", "wb") as f:
+            for kind, time, addr, _ in (e for e in events if e[3] == tid):
+                merged = kind | (0b101 << 3) | ((addr & ((1 << 48) - 1)) << 16)
+                f.write(struct.pack("<QQ", time, merged))
+        print(f"  Wrote {out / f'{tid}.dat'}")
+
+    TASK_SESSION = 1 << 1
+    SYM_REL_ADDR = 1 << 5
+    CMDLINE = 1 << 3
+    TASKINFO = 1 << 7
+    header = b"Ftrace!\x00" + struct.pack("<IHBBQQHHHH", 4, 40, 1, 2, TASK_SESSION | SYM_REL_ADDR, CMDLINE | TASKINFO, 0, 0, 0, 0)
+    rest = b"cmdline:fakeuftrace\n"
+    rest += b"taskinfo:lines=2\n"
+    rest += b"taskinfo:nr_tid=%d\n" % len(tids)
+    rest += b"taskinfo:tids=%s\n" % b",".join(b"%d" % tid for tid in tids)
+    (out / "info").write_bytes(header + rest)
+
+    pid = 42
+    task = f'SESS timestamp=0.0 pid={pid} sid=00 exename="{binary_name}"\n'
+    task += "".join(f"TASK timestamp=0.0 tid={tid} pid={pid}\n" for tid in tids)
+    (out / "task.txt").write_text(task)
+
+    memmap = f"000000000000-ffffffffffff r-xp 00000000 00:00 0                          {binary_name}\n"
+    memmap += "ffffffffffff-ffffffffffff rw-p 00000000 00:00 0                          [stack]\n"
+    (out / "sid-00.map").write_text(memmap)
+
+    print(f"You should generate symbols with `nm -n $BINARY > {out_dir}/{binary_name}.sym`")
+
+
+class RftraceDump(gdb.Command):
+    """Dumps the rftrace event buffer of the inferior as uftrace directory.
+
+Usage: rftrace-dump OUT_DIR [BINARY_NAME]"""
+
+    def __init__(self):
+        super().__init__("rftrace-dump", gdb.COMMAND_DATA)
+
+    def invoke(self, arg, from_tty):
+        args = gdb.string_to_argv(arg)
+        if not 1 <= len(args) <= 2:
+            raise gdb.GdbError("Usage: rftrace-dump OUT_DIR [BINARY_NAME]")
+        binary_name = args[1] if len(args) > 1 else "binary"
+        write_uftrace(args[0], binary_name, read_events())
+
+
+RftraceDump()
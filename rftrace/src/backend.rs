@@ -1,28 +1,152 @@
-use core::arch::asm;
-use core::arch::x86_64::_rdtsc;
 use core::slice;
-use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 
 use crate::interface::*;
 
+// The hooks are written in assembly, everything else is shared between architectures
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "riscv64")]
+use self::riscv64::*;
+#[cfg(target_arch = "x86_64")]
+use self::x86_64::*;
+
 #[derive(Clone, Copy)]
 struct RetStack {
     pub stack: [SavedRet; MAX_STACK_HEIGHT],
     pub index: usize,
 }
 
-#[derive(Debug, Clone, Copy)]
-struct SavedRet {
-    pub stackloc: *mut *const usize,
-    pub retloc: *const usize,
-    pub childip: *const usize,
+// Mixed into every canary, so a zeroed or copied frame does not pass as intact
+const CANARY_KEY: usize = 0x5bd1_e995_9e37_79b9;
+
+impl SavedRet {
+    fn new(
+        stackloc: *mut *const usize,
+        retloc: *const usize,
+        childip: *const usize,
+        recorded: bool,
+    ) -> SavedRet {
+        let mut sr = SavedRet {
+            stackloc,
+            retloc,
+            childip,
+            recorded,
+            canary: 0,
+        };
+        sr.canary = sr.checksum();
+        sr
+    }
+
+    fn checksum(&self) -> usize {
+        (self.stackloc as usize)
+            ^ (self.retloc as usize).rotate_left(21)
+            ^ (self.childip as usize).rotate_left(42)
+            ^ CANARY_KEY
+    }
+
+    /// Returns whether the saved addresses are unchanged since the frame was pushed.
+    ///
+    /// A mismatch means the return stack was overwritten, for example by a stray write into thread-local storage.
+    fn intact(&self) -> bool {
+        self.canary == self.checksum()
+    }
 }
 
 #[no_mangle]
 static ENABLED: AtomicBool = AtomicBool::new(false);
 static OVERWRITING: AtomicBool = AtomicBool::new(false); // should the ring-buffer be overwritten once full?
-static INDEX: AtomicUsize = AtomicUsize::new(0);
-static mut EVENTS: Option<&mut [Event]> = None;
+static ATTACHED: AtomicUsize = AtomicUsize::new(0); // number of frontends reading the buffer without owning it
+
+// Statistics, see `Stats`
+static STAT_ENTRIES: AtomicU64 = AtomicU64::new(0);
+static STAT_RETURNS: AtomicU64 = AtomicU64::new(0);
+static STAT_FILTERED: AtomicU64 = AtomicU64::new(0);
+static STAT_RETSTACK_OVERFLOWS: AtomicU64 = AtomicU64::new(0);
+static STAT_RECURSION_HITS: AtomicU64 = AtomicU64::new(0);
+static STAT_DEMOTED: AtomicU64 = AtomicU64::new(0);
+static STAT_QUOTA_EXCEEDED: AtomicU64 = AtomicU64::new(0);
+static STAT_THROTTLED: AtomicU64 = AtomicU64::new(0);
+// Global event buffer, in one of two slots so a new buffer can be published before the old one is retired, see
+// `rftrace_backend_swap_buffer()`. Hooks record into the slot ACTIVE refers to, if it is not NO_BUFFER.
+struct EventBuffer {
+    events: AtomicPtr<Event>,
+    len: AtomicUsize,
+    index: AtomicUsize,
+}
+static BUFFERS: [EventBuffer; 2] = [const {
+    EventBuffer {
+        events: AtomicPtr::new(0 as *mut Event),
+        len: AtomicUsize::new(0),
+        index: AtomicUsize::new(0),
+    }
+}; 2];
+static ACTIVE: AtomicUsize = AtomicUsize::new(NO_BUFFER);
+const NO_BUFFER: usize = usize::MAX;
+// Final index of the buffer taken by `take_eventbuf()`, which the frontend reads after taking it
+static TAKEN_INDEX: AtomicUsize = AtomicUsize::new(0);
+// Mirrors the active slot of BUFFERS for external tools, which can not call into the backend
+#[no_mangle]
+static mut RFTRACE_DESCRIPTOR: Descriptor = Descriptor {
+    magic: DESCRIPTOR_MAGIC,
+    version: INTERFACE_VERSION,
+    event_size: core::mem::size_of::<Event>() as u32,
+    events: 0 as *const Event,
+    len: 0,
+    index: &BUFFERS[0].index as *const AtomicUsize as *const usize,
+};
+// Per-core buffers of the kernel, recorded into instead of BUFFERS if non-empty, see `rftrace_backend_init_per_core()`.
+// Owned by the kernel, which has to keep them alive.
+static mut CORES: &[CoreBuffer] = &[];
+// Returns the ID of the current core, usually from core-local storage. Provided by the kernel along with CORES.
+static mut CORE_ID: Option<extern "C" fn() -> usize> = None;
+// Small always-overwriting ring, recorded into instead of BUFFERS while FLIGHT is set
+static mut FLIGHT_EVENTS: Option<&mut [Event]> = None;
+static FLIGHT: AtomicBool = AtomicBool::new(false);
+static FLIGHT_INDEX: AtomicUsize = AtomicUsize::new(0);
+// functions which are never recorded. Owned by the frontend, which has to keep it alive until it is replaced.
+static EXCLUDE: Table<AddrRange> = Table::new();
+// If non-empty, only functions within these ranges are recorded. Owned by the frontend like EXCLUDE.
+static FILTER: Table<AddrRange> = Table::new();
+// One bit per 4 KiB code page starting at the table's base page. Functions on pages with a set bit are never recorded.
+// Owned by the frontend like EXCLUDE.
+static PAGE_FILTER: Table<u64> = Table::new();
+// Serializes replacing tables, so concurrent setters do not fill the same slot
+static TABLE_LOCK: AtomicBool = AtomicBool::new(false);
+// Functions in the rftrace_notrace linker section, see `notrace!`. Set on init.
+static mut NOTRACE: AddrRange = AddrRange { start: 0, end: 0 };
+
+// Provided by the linker if any function was placed into the rftrace_notrace section, null otherwise
+extern "C" {
+    #[linkage = "extern_weak"]
+    static __start_rftrace_notrace: *const u8;
+    #[linkage = "extern_weak"]
+    static __stop_rftrace_notrace: *const u8;
+}
+// If non-zero, we are in sampling mode and record the call stack every SAMPLE_PERIOD TSC cycles instead of every call
+static SAMPLE_PERIOD: AtomicU64 = AtomicU64::new(0);
+// If non-zero, functions entered more than QUOTA times are demoted to recording only every DEMOTED_RATE-th entry
+static QUOTA: AtomicU64 = AtomicU64::new(0);
+static DEMOTED_RATE: AtomicU64 = AtomicU64::new(0);
+// Entry counters per function, see `within_quota()`. Open addressing keyed by function address, 0 marks a free slot.
+const QUOTA_SLOTS: usize = 4096;
+const QUOTA_PROBES: usize = 8;
+static QUOTA_FUNCTIONS: [AtomicUsize; QUOTA_SLOTS] = [const { AtomicUsize::new(0) }; QUOTA_SLOTS];
+static QUOTA_COUNTS: [AtomicU64; QUOTA_SLOTS] = [const { AtomicU64::new(0) }; QUOTA_SLOTS];
+// If non-zero, each thread records at most THREAD_QUOTA entries. Bumping THREAD_QUOTA_EPOCH resets the counters of all threads.
+static THREAD_QUOTA: AtomicU64 = AtomicU64::new(0);
+static THREAD_QUOTA_EPOCH: AtomicU64 = AtomicU64::new(0);
+// If non-zero, functions are neither recorded nor hooked once MAX_DEPTH frames are on the thread's return stack
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// If non-zero, the hooks may spend at most BUDGET_PPM millionths of the cycles since BUDGET_START, see `over_budget()`.
+// While they spent more, full tracing is throttled to sampling mode with period BUDGET_PERIOD.
+static BUDGET_PPM: AtomicU64 = AtomicU64::new(0);
+static BUDGET_PERIOD: AtomicU64 = AtomicU64::new(0);
+static BUDGET_START: AtomicU64 = AtomicU64::new(0);
+static BUDGET_SPENT: AtomicU64 = AtomicU64::new(0);
 
 // !! Will always be initialized to all 0 by the OS, no matter what. This is just to make the compiler happy
 #[thread_local]
@@ -31,6 +155,8 @@ static mut RETSTACK: RetStack = RetStack {
         stackloc: 0 as *mut *const usize,
         retloc: 0 as *const usize,
         childip: 0 as *const usize,
+        recorded: false,
+        canary: 0,
     }; MAX_STACK_HEIGHT],
     index: 0,
 };
@@ -38,14 +164,136 @@ static mut RETSTACK: RetStack = RetStack {
 #[thread_local]
 static mut TID: Option<core::num::NonZeroU64> = None;
 
+// TSC of the last stack sample taken on this thread
+#[thread_local]
+static mut LAST_SAMPLE: u64 = 0;
+
+// Nesting depth of `rftrace_backend_full_trace_begin()`. While non-zero, this thread is fully traced even in sampling mode.
+#[thread_local]
+static mut FULL_TRACE: usize = 0;
+
+// Logical context (task, request, ..) this thread currently works on, see `rftrace_backend_set_context()`. 0 if none.
+#[thread_local]
+static mut CONTEXT: u64 = 0;
+
+// Nesting depth of `rftrace_backend_suppress()` on this thread. Nothing is recorded while non-zero.
+#[thread_local]
+static mut SUPPRESSED: usize = 0;
+
+// Nesting depth of `rftrace_backend_scope_begin()` on this thread. While SCOPED is set, only threads within a scope are recorded.
+#[thread_local]
+static mut SCOPE: usize = 0;
+static SCOPED: AtomicBool = AtomicBool::new(false);
+
+// Core the current thread recorded its last event on plus 1, or 0 if none, see `reserve()`. Only used with per-core buffers.
+#[thread_local]
+static mut LAST_CORE: usize = 0;
+
+// Buffer claimed by the current thread plus 1, or 0 if none yet, see `thread_slot()`. Only used with per-thread buffers.
+#[thread_local]
+static mut THREAD_SLOT: usize = 0;
+// Next per-thread buffer to be claimed, taken modulo their number
+static THREAD_SLOT_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// Entries this thread recorded since THREAD_ENTRIES_EPOCH, see `within_thread_quota()`
+#[thread_local]
+static mut THREAD_ENTRIES: u64 = 0;
+#[thread_local]
+static mut THREAD_ENTRIES_EPOCH: u64 = 0;
+
 // Everytime we see a new thread (with emtpy thread-locals), we alloc out own TID
-static mut TID_NEXT: AtomicU64 = AtomicU64::new(1);
+static TID_NEXT: AtomicU64 = AtomicU64::new(1);
+// Added to all TIDs, so the threads of several traced programs or guests get distinct TIDs in merged traces
+static TID_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Releases the event buffer under memory pressure, see `rftrace_backend_memory_pressure()`. 0 if none is registered.
+static PRESSURE_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+// Handshake for taking the event buffer away from the hooks, see `take_eventbuf()`.
+// Hooks register in WRITERS[EPOCH % 2] while they run, so only writers of the previous epoch have to be waited for.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static WRITERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+// Epoch the current thread registered as writer in, see `hook_enter()`
+#[thread_local]
+static mut WRITER_EPOCH: usize = 0;
+
+// Set while the current thread is inside one of our hooks, so functions called by the hooks are not traced, see `mcount_entry()`
+#[thread_local]
+static mut IN_HOOK: bool = false;
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
+/// Filter table owned by the frontend and read by the hooks, such as `EXCLUDE`.
+///
+/// Published in one of two slots like the event buffer, so hooks never see a torn slice, see `Table::replace()`.
+struct Table<T> {
+    slots: [TableSlot<T>; 2],
+    active: AtomicUsize,
+}
+
+struct TableSlot<T> {
+    ptr: AtomicPtr<T>,
+    len: AtomicUsize,
+    base: AtomicUsize,
+}
+
+impl<T> Table<T> {
+    const fn new() -> Self {
+        Self {
+            slots: [TableSlot::new(), TableSlot::new()],
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the current table and its base. Has to be called within a hook, so `replace()` waits for us.
+    #[inline(always)]
+    unsafe fn get(&self) -> (&[T], usize) {
+        // Acquire pairs with the Release in `replace()`, so the slot's fields are visible
+        let slot = &self.slots[self.active.load(Ordering::Acquire)];
+        let ptr = slot.ptr.load(Ordering::Relaxed);
+        if ptr.is_null() {
+            return (&[], 0);
+        }
+        (
+            slice::from_raw_parts(ptr, slot.len.load(Ordering::Relaxed)),
+            slot.base.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Makes hooks use `len` entries at `ptr` with `base` from now on, or an empty table if `ptr` is null.
+    ///
+    /// Hooks which might still read the previous table are waited for, so its owner may free it once this returns.
+    /// Must not be called from within a hook, see `wait_for_writers()`.
+    fn replace(&self, ptr: *const T, len: usize, base: usize) {
+        while TABLE_LOCK.swap(true, Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        // The other slot is unused, its readers were waited for when it was replaced
+        let next = 1 - self.active.load(Ordering::Relaxed);
+        let slot = &self.slots[next];
+        slot.ptr.store(ptr as *mut T, Ordering::Relaxed);
+        slot.len.store(len, Ordering::Relaxed);
+        slot.base.store(base, Ordering::Relaxed);
+        self.active.store(next, Ordering::Release);
+        wait_for_writers();
+        TABLE_LOCK.store(false, Ordering::Release);
+    }
+}
+
+impl<T> TableSlot<T> {
+    const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(0 as *mut T),
+            len: AtomicUsize::new(0),
+            base: AtomicUsize::new(0),
+        }
+    }
+}
+
 impl RetStack {
     /*pub fn new(capacity: usize) -> RetStack {
         //println!("Creating retstack...!");
@@ -63,444 +311,1126 @@ impl RetStack {
         Ok(())
     }
 
-    pub fn pop(&mut self) -> Option<SavedRet> {
-        if self.index == 0 {
-            return None;
-        }
+    /// Removes and returns the frame whose return address was saved from `stackloc`.
+    ///
+    /// This is the top frame, unless the thread switched stacks since, for example between fibers or green threads.
+    /// Frames saved after it stay in place, since they might belong to another stack which is still alive.
+    pub fn take(&mut self, stackloc: *mut *const usize) -> Option<SavedRet> {
+        let i = self.stack[..self.index]
+            .iter()
+            .rposition(|sr| sr.stackloc == stackloc)?;
+        let sr = self.stack[i];
+        self.stack.copy_within(i + 1..self.index, i);
         self.index -= 1;
-        Some(self.stack[self.index])
-    }
-}
-
-#[naked]
-#[no_mangle]
-pub unsafe extern "C" fn mcount() {
-    // We need to be careful with hooked naked functions!
-    // Normally, llvm ensures that all needed functions parameters are saved before the embedded mcount() is called, and restored afterwards.
-    // This does NOT happen with naked funktions like `hermit::arch::x86_64::kernel::switch::switch:`
-    // There, the args (like old and new_stack) are clobbered. This is because they are not used, only implicitly in the llvm_asm! code, so llvm does not know they are used!
-    // To be sure the instrumentation never breaks anything, we backup and restore any possible argument registers
-    // TODO: Implement feature to skip this, which can be enabled if we are sure this can't happen with the code we are instrumenting?
-
-    // we need custom assembly that "knows" that mcount is ALWAYS called at the start of each function! no llvm magic can help here.
-    // parents-return-addr is always stored at rbp+8
-    // mcounts ret addr is directly at rsp
-
-    // based on https://github.com/namhyung/uftrace/blob/master/arch/x86_64/mcount.S
-    asm!(
-        // if ENABLED.load(Ordering::Relaxed) {
-        //     return;
-        // }
-        "push rax",
-        "mov rax, [rip + ENABLED@GOTPCREL]",
-        "movzx eax, byte ptr [rax]",
-        "test al, al",
-        "je 2f",
-        // make some space for locals on the stack
-        "sub rsp, 48",
-        // save register arguments in mcount_args. Needed so we can later restore them
-        "mov [rsp + 40], rdi",
-        "mov [rsp + 32], rsi",
-        "mov [rsp + 24], rdx",
-        "mov [rsp + 16], rcx",
-        "mov [rsp + 8], r8",
-        "mov [rsp], r9",
-        // child addr = what function was mcount() called from
-        "mov rsi, [rsp + 56]",
-        // parent location = child-return-addr-ptr = what addr stores the location the child function was called from
-        // needed, since we overwrite it with our own trampoline. This way we can determine when the child function returns
-        "lea rdi, [rbp + 8]",
-        // align stack pointer to 16-byte, remember old value
-        "mov rdx, rsp",
-        "and rsp, -16",
-        // pass mcount_args to mcount_entry's 3rd argument
-        "push rdx",
-        "call mcount_entry",
-        // restore original stack pointer
-        "pop rdx",
-        "mov rsp, rdx",
-        // restore mcount_args
-        "mov r9, [rsp]",
-        "mov r8, [rsp + 8]",
-        "mov rcx, [rsp + 16]",
-        "mov rdx, [rsp + 24]",
-        "mov rsi, [rsp + 32]",
-        "mov rdi, [rsp + 40]",
-        // revert stack pointer to original location and return
-        "add rsp, 48",
-        "2:",
-        "pop rax",
-        "ret",
-        // TODO: ENABLED = sym ENABLED,
-        options(noreturn),
-    );
+        Some(sr)
+    }
+}
+
+/// Returns the TID of the current thread, allocating one the first time we see the thread.
+#[inline(always)]
+unsafe fn current_tid() -> Option<core::num::NonZeroU64> {
+    match TID {
+        Some(tid) => Some(tid),
+        None => alloc_tid(),
+    }
+}
+
+/// Allocates a TID for a thread with empty thread-locals.
+///
+/// Runs once per thread, so it is kept out of the hot path.
+/// Never fails: should TID_NEXT ever wrap around, the thread keeps `None` and is dumped as TID 0.
+#[cold]
+#[inline(never)]
+unsafe fn alloc_tid() -> Option<core::num::NonZeroU64> {
+    let tid = TID_NEXT.fetch_add(1, Ordering::Relaxed);
+    TID = core::num::NonZeroU64::new(tid.wrapping_add(TID_OFFSET.load(Ordering::Relaxed)));
+    TID
+}
+
+/// Marks the current thread as being inside a hook, which might write to the event buffer.
+///
+/// Registers the thread as writer of the current epoch, so `take_eventbuf()` can wait for it.
+/// Debug builds assert that hooks are never re-entered, `mcount_entry()` skips instrumented functions we call instead.
+#[inline(always)]
+unsafe fn hook_enter() {
+    debug_assert!(!IN_HOOK, "rftrace hook re-entered!");
+    IN_HOOK = true;
+
+    // Has to be registered before looking at the buffers or filters, see `wait_for_writers()`
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    WRITERS[epoch % 2].fetch_add(1, Ordering::SeqCst);
+    WRITER_EPOCH = epoch;
+}
+
+#[inline(always)]
+unsafe fn hook_exit() {
+    WRITERS[WRITER_EPOCH % 2].fetch_sub(1, Ordering::Release);
+    IN_HOOK = false;
 }
 
+/// Called by `mcount()` on every function entry.
+///
+/// Part of the hot path: never allocates, never blocks and takes no locks (see crate documentation).
 #[no_mangle]
 pub extern "C" fn mcount_entry(parent_ret: *mut *const usize, child_ret: *const usize) {
     unsafe {
         if ENABLED.load(Ordering::Relaxed) {
-            let tid = match TID {
-                None => {
-                    // We are not yet initialized, do it now
-                    // Would only fail if we overflow TID_NEXT, which is 64bit, then TID stays None (?)
-                    TID = core::num::NonZeroU64::new(TID_NEXT.fetch_add(1, Ordering::Relaxed));
-                    TID
-                }
-                Some(tid) => Some(tid),
-            };
+            // An instrumented function called by one of our hooks, such as a compiler builtin. Tracing it would recurse forever.
+            if IN_HOOK {
+                STAT_RECURSION_HITS.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            hook_enter();
+            let start = budget_start();
+            mcount_entry_enabled(parent_ret, child_ret);
+            charge_budget(start);
+            hook_exit();
+        }
+    }
+}
 
-            // HermitCore's task creation will set rbp to 0 in the first function for the task: task_entry()
-            // This means parent_ret (which is lea 8(%rbp)), will be 8 and we will crash on access.
-            // Other OS's likely do something similar. Don't deref in that case!
-            let (hook_return, parent_ret_deref) = if parent_ret as usize <= 0x100 {
-                (false, 0xd3adb33f as *const usize)
-            } else {
-                (true, *parent_ret)
-            };
+#[inline(always)]
+unsafe fn mcount_entry_enabled(parent_ret: *mut *const usize, child_ret: *const usize) {
+    // Functions entered before the suppression started still record their exit, so pairs stay intact.
+    if SUPPRESSED != 0 || (SCOPE == 0 && SCOPED.load(Ordering::Relaxed)) {
+        return;
+    }
 
-            // Save call to global events ringbuffer
-            if let Some(events) = &mut EVENTS {
-                // Get current globally-unique-event-index
-                let cidx = INDEX.fetch_add(1, Ordering::Relaxed);
-                if !OVERWRITING.load(Ordering::Relaxed) && cidx >= events.len() - MAX_STACK_HEIGHT {
-                    disable();
-                    return;
-                }
-
-                events[cidx % events.len()] = Event::Entry(Call {
-                    time: _rdtsc(),
-                    to: child_ret,
-                    from: parent_ret_deref,
-                    tid,
+    STAT_ENTRIES.fetch_add(1, Ordering::Relaxed);
+
+    // Uninteresting functions are neither recorded nor hooked, their callees still are
+    if NOTRACE.contains(child_ret as usize) {
+        STAT_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let (page_filter, base_page) = PAGE_FILTER.get();
+    let page = (child_ret as usize >> 12).wrapping_sub(base_page);
+    if page < page_filter.len() * 64 && page_filter[page / 64] & (1 << (page % 64)) != 0 {
+        STAT_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let (exclude, _) = EXCLUDE.get();
+    if !exclude.is_empty()
+        && exclude
+            .iter()
+            .any(|range| range.contains(child_ret as usize))
+    {
+        STAT_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let (filter, _) = FILTER.get();
+    if !filter.is_empty()
+        && !filter
+            .iter()
+            .any(|range| range.contains(child_ret as usize))
+    {
+        STAT_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Unhooked calls do not deepen the return stack, so everything below the limit is skipped, like uftrace's -D
+    let max_depth = MAX_DEPTH.load(Ordering::Relaxed);
+    if max_depth != 0 && RETSTACK.index >= max_depth {
+        STAT_FILTERED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let tid = current_tid();
+    let child = function_start(child_ret);
+
+    // HermitCore's task creation will set rbp to 0 in the first function for the task: task_entry()
+    // This means parent_ret (which is lea 8(%rbp)), will be 8 and we will crash on access.
+    // Other OS's likely do something similar. Don't deref in that case!
+    let (hook_return, parent_ret_deref) = if parent_ret as usize <= 0x100 {
+        (false, 0xd3adb33f as *const usize)
+    } else {
+        (true, *parent_ret)
+    };
+
+    let mut sample_period = SAMPLE_PERIOD.load(Ordering::Relaxed);
+    let budget = BUDGET_PPM.load(Ordering::Relaxed);
+    if budget != 0 && sample_period == 0 && FULL_TRACE == 0 && over_budget(budget) {
+        STAT_THROTTLED.fetch_add(1, Ordering::Relaxed);
+        sample_period = BUDGET_PERIOD.load(Ordering::Relaxed);
+    }
+    let mut record = sample_period == 0 || FULL_TRACE != 0;
+    // A thread over its quota records nothing at all, so it does not count against the quotas of its functions either
+    let thread_quota = THREAD_QUOTA.load(Ordering::Relaxed);
+    let exceeded = record && thread_quota != 0 && !within_thread_quota(thread_quota);
+    if exceeded {
+        STAT_QUOTA_EXCEEDED.fetch_add(1, Ordering::Relaxed);
+        record = false;
+    }
+    let quota = QUOTA.load(Ordering::Relaxed);
+    let demoted = record && quota != 0 && !within_quota(child as usize, quota);
+    if demoted {
+        STAT_DEMOTED.fetch_add(1, Ordering::Relaxed);
+        record = false;
+    }
+
+    // Save call to global events ringbuffer. In sampling mode, we only maintain the return stack.
+    if record {
+        let (events, cidx) = match reserve(1, false) {
+            Some(slot) => slot,
+            None => return,
+        };
+        events[cidx % events.len()] = Event::Entry(Call {
+            time: timestamp(),
+            to: child as u64,
+            from: parent_ret_deref as u64,
+            tid,
+        });
+    }
+
+    if hook_return {
+        let sr = SavedRet::new(parent_ret, parent_ret_deref, child, record);
+        // Do not overwrite ret-ptr if returnstack is full
+        // this will lead to truncation of the return events once a too big stack has been reached!
+        // TODO: warn the user about this?
+        if RETSTACK.push(sr).is_ok() {
+            *parent_ret = mcount_return_trampoline as *const usize;
+        } else {
+            STAT_RETSTACK_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    if !record && !demoted && !exceeded {
+        let now = timestamp();
+        if now.wrapping_sub(LAST_SAMPLE) >= sample_period {
+            LAST_SAMPLE = now;
+            record_sample(now, tid);
+        }
+    }
+}
+
+/// Returns whether the hooks spent more than `budget` millionths of the cycles since the budget was set.
+///
+/// Only the cycles spent in `mcount_entry()` and `mcount_return()` are counted, see `charge_budget()`.
+#[inline(always)]
+fn over_budget(budget: u64) -> bool {
+    let elapsed = timestamp().wrapping_sub(BUDGET_START.load(Ordering::Relaxed));
+    let spent = BUDGET_SPENT.load(Ordering::Relaxed);
+    // Widened, so neither side can overflow
+    spent as u128 * 1_000_000 > elapsed as u128 * budget as u128
+}
+
+/// Returns the time a hook started at, if an overhead budget is set, see `charge_budget()`.
+#[inline(always)]
+fn budget_start() -> Option<u64> {
+    if BUDGET_PPM.load(Ordering::Relaxed) != 0 {
+        Some(timestamp())
+    } else {
+        None
+    }
+}
+
+/// Counts the cycles since `start`, as returned by `budget_start()`, against the overhead budget.
+#[inline(always)]
+fn charge_budget(start: Option<u64>) {
+    if let Some(start) = start {
+        BUDGET_SPENT.fetch_add(timestamp().wrapping_sub(start), Ordering::Relaxed);
+    }
+}
+
+/// Counts an entry of `function` and returns whether it should be recorded under the per-function `quota`.
+///
+/// The first `quota` entries of each function are recorded, afterwards only every `DEMOTED_RATE`-th one.
+/// Wait-free: a function which does not find a slot within `QUOTA_PROBES` probes is never demoted.
+#[inline(always)]
+fn within_quota(function: usize, quota: u64) -> bool {
+    // Fibonacci hashing, the upper bits are well mixed
+    let hash = ((function as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 52) as usize;
+    for probe in 0..QUOTA_PROBES {
+        let slot = (hash + probe) % QUOTA_SLOTS;
+        let key = QUOTA_FUNCTIONS[slot].load(Ordering::Relaxed);
+        let found = key == function
+            || (key == 0
+                && match QUOTA_FUNCTIONS[slot].compare_exchange(
+                    0,
+                    function,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => true,
+                    Err(key) => key == function,
                 });
-            }
+        if found {
+            let count = QUOTA_COUNTS[slot].fetch_add(1, Ordering::Relaxed);
+            let rate = DEMOTED_RATE.load(Ordering::Relaxed);
+            return count < quota || (rate != 0 && (count - quota) % rate == 0);
+        }
+    }
+    true
+}
 
-            // TODO: clean up this hack! we check if we are in mcount, or mcount_entry, mcount_return_tampoline or mcount_return
-            if parent_ret_deref >= (mcount as *const usize)
-                && parent_ret_deref <= (rftrace_backend_get_events_index as *const usize)
-            {
-                /*unsafe {
-                    *(0 as *mut u8) = 0;
-                }
-                panic!("BLUB!");*/
-                //disable();
-                // Maybe insert fake end, so uftrace is not confused and crashes because its internal function stack overflows.
-                if let Some(events) = &mut EVENTS {
-                    let cidx = INDEX.fetch_add(1, Ordering::Relaxed);
-                    if !OVERWRITING.load(Ordering::Relaxed)
-                        && cidx >= events.len() - MAX_STACK_HEIGHT
-                    {
-                        disable();
-                        return;
-                    }
-
-                    events[cidx % events.len()] = Event::Exit(Exit {
-                        time: _rdtsc() + 20,
-                        from: child_ret,
-                        tid,
-                    });
-                }
+/// Counts an entry of the current thread and returns whether it should be recorded under the per-thread `quota`.
+#[inline(always)]
+unsafe fn within_thread_quota(quota: u64) -> bool {
+    let epoch = THREAD_QUOTA_EPOCH.load(Ordering::Relaxed);
+    if THREAD_ENTRIES_EPOCH != epoch {
+        THREAD_ENTRIES_EPOCH = epoch;
+        THREAD_ENTRIES = 0;
+    }
+    THREAD_ENTRIES += 1;
+    THREAD_ENTRIES <= quota
+}
 
-                return;
-            }
+/// Records the current call stack of this thread as a sample.
+///
+/// Every frame on the return stack which has not been recorded yet gets an entry at `now` and is marked as recorded,
+/// so its real exit is recorded once it returns. This way, samples of a thread form a coarse but consistent timeline,
+/// which can be exported just like a full trace, even when switching between sampling and full tracing.
+#[inline(never)]
+unsafe fn record_sample(now: u64, tid: Option<core::num::NonZeroU64>) {
+    // Frames below a recorded frame are always recorded themselves, so the new ones are at the top
+    let frames = &mut RETSTACK.stack[..RETSTACK.index];
+    let first = frames
+        .iter()
+        .rposition(|sr| sr.recorded)
+        .map_or(0, |i| i + 1);
+    let new = &mut frames[first..];
+    if new.is_empty() {
+        return;
+    }
 
-            if hook_return {
-                let sr = SavedRet {
-                    stackloc: parent_ret,
-                    retloc: parent_ret_deref,
-                    childip: child_ret,
-                };
-                // Do not overwrite ret-ptr if returnstack is full
-                // this will lead to truncation of the return events once a too big stack has been reached!
-                // TODO: warn the user about this?
-                if RETSTACK.push(sr).is_ok() {
-                    *parent_ret = mcount_return_trampoline as *const usize;
-                }
+    let (events, cidx) = match reserve(new.len(), false) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let len = events.len();
+    for (i, sr) in new.iter_mut().enumerate() {
+        events[(cidx + i) % len] = Event::Entry(Call {
+            time: now,
+            from: sr.retloc as u64,
+            to: sr.childip as u64,
+            tid,
+        });
+        sr.recorded = true;
+    }
+}
+
+/// Called by the return trampoline on every hooked function exit, with the stack location the function returned from.
+/// Returns the original return address.
+///
+/// Part of the hot path: never allocates, never blocks and takes no locks (see crate documentation).
+#[no_mangle]
+pub extern "C" fn mcount_return(stackloc: *mut *const usize) -> *const usize {
+    unsafe {
+        hook_enter();
+        let start = budget_start();
+        let (original_ret, childip, recorded) = {
+            let sr = match RETSTACK.take(stackloc) {
+                Some(sr) => sr,
+                None => retstack_underflow(),
+            };
+            if !sr.intact() {
+                retstack_corrupted(&sr);
             }
+
+            (sr.retloc, sr.childip, sr.recorded)
+        };
+        STAT_RETURNS.fetch_add(1, Ordering::Relaxed);
+        if !recorded {
+            charge_budget(start);
+            hook_exit();
+            return original_ret;
         }
+
+        if let Some((events, cidx)) = reserve(1, true) {
+            events[cidx % events.len()] = Event::Exit(Exit {
+                time: timestamp(),
+                from: childip as u64,
+                tid: TID.as_ref().copied(),
+            });
+        }
+
+        charge_budget(start);
+        hook_exit();
+        original_ret
     }
 }
 
-#[cfg(feature = "interruptsafe")]
-macro_rules! prologue {
-    () => {
-        r#"
-        // space for locals (saved ret values) (if we dont back up xmm0+1, this is too much, but this won't hurt us)
-        // fake return value for later
-        push rax
-        // flags for interrupt stuff
-        pushfq
-        // dont do interrupts here!
-        cli
-        sub rsp, 104
-        "#
-    };
+/// We returned through the trampoline without a return address saved for that stack location, so we do not know where to continue.
+/// There is nothing sensible left to do, so halt like our panic handler does, but without the panic machinery in the hot path.
+#[cold]
+#[inline(never)]
+fn retstack_underflow() -> ! {
+    #[allow(clippy::empty_loop)]
+    loop {}
 }
 
-#[cfg(not(feature = "interruptsafe"))]
-macro_rules! prologue {
-    () => {
-        "sub rsp, 64"
-    };
+/// The frame we returned through failed its canary check, so its return address can not be trusted.
+/// Rather than jumping to a garbage address, record what happened and halt like `retstack_underflow()`.
+#[cold]
+#[inline(never)]
+unsafe fn retstack_corrupted(sr: &SavedRet) -> ! {
+    let mut payload = [0; RECORD_PAYLOAD_LEN];
+    payload[..8].copy_from_slice(&(sr.stackloc as u64).to_le_bytes());
+    payload[8..12].copy_from_slice(&(RETSTACK.index as u32).to_le_bytes());
+    if let Some((events, cidx)) = reserve(1, true) {
+        events[cidx % events.len()] = Event::Record(Record {
+            time: timestamp(),
+            tid: TID,
+            kind: RECORD_KIND_CORRUPTION,
+            len: 12,
+            payload,
+        });
+    }
+    disable();
+    #[allow(clippy::empty_loop)]
+    loop {}
 }
 
-#[cfg(target_feature = "sse2")]
-macro_rules! backup_sse2 {
-    () => {
-        r#"
-        // when we compile against a 'kernel' target we do NOT have sse enabled, otherwise we might. Backup xmm0 and xmm1
-        // even if we are in userspace code that could use sse2, we are guaranteed that mcount_return() will not clobber it in this case
-        movdqu xmmword ptr [rsp + 16], xmm0
-        movdqu xmmword ptr [rsp + 32], xmm1
-        "#
+/// Reserves `count` consecutive slots in the buffer currently recorded into, returning it and the global index of the first slot.
+///
+/// Returns `None` if there is no buffer. Once a non-overwriting buffer is full, tracing is disabled and `None` is returned,
+/// except for function exits, which use the space left for them at the end of the buffer.
+#[inline(always)]
+unsafe fn reserve(count: usize, exit: bool) -> Option<(&'static mut [Event], usize)> {
+    if FLIGHT.load(Ordering::Relaxed) {
+        let events = FLIGHT_EVENTS.as_deref_mut()?;
+        return Some((events, FLIGHT_INDEX.fetch_add(count, Ordering::Relaxed)));
+    }
+
+    // Read once, the buffers might be taken away concurrently, see `rftrace_backend_take_per_thread()`
+    let cores = CORES;
+    let (events, index, migration) = match CORE_ID {
+        Some(core_id) if !cores.is_empty() => {
+            // We might migrate before writing the events, the atomic index keeps that correct, if slower
+            let id = core_id() % cores.len();
+            let core = &cores[id];
+            let previous = core::mem::replace(&mut LAST_CORE, id + 1);
+            let migration = previous.checked_sub(1).filter(|&previous| previous != id);
+            (
+                slice::from_raw_parts_mut(core.events, core.len),
+                &core.index,
+                migration.map(|previous| (previous, id)),
+            )
+        }
+        _ => {
+            let buffer = active_buffer()?;
+            (
+                slice::from_raw_parts_mut(
+                    buffer.events.load(Ordering::Relaxed),
+                    buffer.len.load(Ordering::Relaxed),
+                ),
+                &buffer.index,
+                None,
+            )
+        }
     };
+    // A migration is recorded in front of the reserved slots
+    let count = count + usize::from(migration.is_some());
+    // Get current globally-unique-event-index
+    let cidx = index.fetch_add(count, Ordering::Relaxed);
+    if !exit
+        && !OVERWRITING.load(Ordering::Relaxed)
+        && cidx + count > events.len() - MAX_STACK_HEIGHT
+    {
+        // Only the first writer to hit the end explains the gap, the space left for exits still holds the marker
+        if ENABLED.swap(false, Ordering::Relaxed) {
+            events[cidx % events.len()] = tracing_record(TRACING_BUFFER_FULL);
+        }
+        return None;
+    }
+    match migration {
+        Some((previous, id)) => {
+            events[cidx % events.len()] = migration_record(previous, id);
+            Some((events, cidx + 1))
+        }
+        None => Some((events, cidx)),
+    }
 }
 
-#[cfg(not(target_feature = "sse2"))]
-macro_rules! backup_sse2 {
-    () => {
-        ""
-    };
+/// Returns the record of the current thread changing the tracing state to `state`, see `RECORD_KIND_TRACING`.
+#[cold]
+#[inline(never)]
+unsafe fn tracing_record(state: u8) -> Event {
+    let mut payload = [0; RECORD_PAYLOAD_LEN];
+    payload[0] = state;
+    Event::Record(Record {
+        time: timestamp(),
+        tid: current_tid(),
+        kind: RECORD_KIND_TRACING,
+        len: 1,
+        payload,
+    })
 }
 
-#[cfg(feature = "interruptsafe")]
-macro_rules! backup_interrupts {
-    () => {
-        r#"
-        // If we have to be interrupt safe, also backup non-return scratch registers
-        mov [rsp + 48], rdi
-        mov [rsp + 56], rsi
-        mov [rsp + 64], rcx
-        mov [rsp + 72], r8
-        mov [rsp + 80], r9
-        mov [rsp + 88], r10
-        mov [rsp + 96], r11
-        "#
-    };
+/// Returns the record of the current thread migrating from core `previous` to core `id`.
+#[cold]
+#[inline(never)]
+unsafe fn migration_record(previous: usize, id: usize) -> Event {
+    let mut payload = [0; RECORD_PAYLOAD_LEN];
+    payload[..4].copy_from_slice(&(previous as u32).to_le_bytes());
+    payload[4..8].copy_from_slice(&(id as u32).to_le_bytes());
+    Event::Record(Record {
+        time: timestamp(),
+        tid: TID,
+        kind: RECORD_KIND_MIGRATION,
+        len: 8,
+        payload,
+    })
 }
 
-#[cfg(not(feature = "interruptsafe"))]
-macro_rules! backup_interrupts {
-    () => {
-        ""
+/// Copies the events of the flight recorder into the event buffer, oldest first, and empties it.
+///
+/// Events recorded concurrently into the flight recorder may be torn.
+unsafe fn flush_flight_recorder() {
+    let flight = match FLIGHT_EVENTS.as_deref() {
+        Some(flight) => flight,
+        None => return,
     };
+    let end = FLIGHT_INDEX.swap(0, Ordering::Relaxed);
+    let start = end.saturating_sub(flight.len());
+    if let Some((events, cidx)) = reserve(end - start, false) {
+        for (i, idx) in (start..end).enumerate() {
+            events[(cidx + i) % events.len()] = flight[idx % flight.len()];
+        }
+    }
 }
 
-#[cfg(feature = "interruptsafe")]
-macro_rules! store_parent {
-    () => {
-        "mov qword ptr [rsp + 112], rax"
-    };
+fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
 }
 
-#[cfg(not(feature = "interruptsafe"))]
-macro_rules! store_parent {
-    () => {
-        "mov qword ptr [rsp + 56], rax"
-    };
+fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
 }
 
-#[cfg(feature = "interruptsafe")]
-macro_rules! restore_interrupts {
-    () => {
-        r#"
-        // If we have to be interrupt safe, restore non-return scratch registers
-        mov rdi, [rsp + 48]
-        mov rsi, [rsp + 56]
-        mov rcx, [rsp + 64]
-        mov r8, [rsp + 72]
-        mov r9, [rsp + 80]
-        mov r10, [rsp + 88]
-        mov r11, [rsp + 96]
-        "#
-    };
+/// Returns the slot of the global event buffer hooks record into, if any.
+#[inline(always)]
+fn active_buffer() -> Option<&'static EventBuffer> {
+    // Acquire pairs with the Release in `publish_eventbuf()`, so the slot's buffer is visible
+    BUFFERS.get(ACTIVE.load(Ordering::Acquire))
 }
 
-#[cfg(not(feature = "interruptsafe"))]
-macro_rules! restore_interrupts {
-    () => {
-        ""
-    };
+/// Fills the slot `slot` with `eventbuf`, starting at index 0, and makes hooks record into it from now on.
+///
+/// The slot must not be in use, hooks still writing to its previous buffer have to be waited for.
+fn publish_eventbuf(slot: usize, eventbuf: &'static mut [Event]) {
+    let buffer = &BUFFERS[slot];
+    buffer
+        .events
+        .store(eventbuf.as_mut_ptr(), Ordering::Relaxed);
+    buffer.len.store(eventbuf.len(), Ordering::Relaxed);
+    buffer.index.store(0, Ordering::Relaxed);
+    unsafe {
+        RFTRACE_DESCRIPTOR.events = eventbuf.as_ptr();
+        RFTRACE_DESCRIPTOR.len = eventbuf.len();
+        RFTRACE_DESCRIPTOR.index = &buffer.index as *const AtomicUsize as *const usize;
+    }
+    ACTIVE.store(slot, Ordering::Release);
 }
 
-#[cfg(target_feature = "sse2")]
-macro_rules! restore_sse2 {
-    () => {
-        r#"
-        movdqu xmm0, xmmword ptr [rsp + 16]
-        movdqu xmm1, xmmword ptr [rsp + 32]
-        "#
-    };
+/// Waits until all hooks which might have seen the buffers before now are done.
+///
+/// Must not be called from within a hook, or interrupt one on the same CPU, since it would wait forever.
+fn wait_for_writers() {
+    // Hooks registering from now on see the current buffers. The ones registered before might still write to old ones.
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst);
+    while WRITERS[epoch % 2].load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
 }
 
-#[cfg(not(target_feature = "sse2"))]
-macro_rules! restore_sse2 {
-    () => {
-        ""
-    };
+/// Stops using the current event buffer, returning it or null if there is none.
+///
+/// Hooks which are still writing to the buffer are waited for, so it is no longer touched once this returns.
+/// Must not be called from within a hook, or interrupt one on the same CPU, since it would wait forever.
+fn take_eventbuf() -> *const Event {
+    unsafe { RFTRACE_DESCRIPTOR.events = 0 as *const Event };
+    let slot = ACTIVE.swap(NO_BUFFER, Ordering::AcqRel);
+    wait_for_writers();
+    BUFFERS.get(slot).map_or(0 as *const Event, |buffer| {
+        // All writers are done, so the index is final
+        TAKEN_INDEX.store(buffer.index.load(Ordering::Relaxed), Ordering::Relaxed);
+        buffer.events.load(Ordering::Relaxed)
+    })
 }
 
-#[cfg(feature = "interruptsafe")]
-macro_rules! epilogue {
-    () => {
-        r#"
-        // here we added same amount back we substracted, since space is in rax push.
-        add rsp, 104
-        // This should also restore the interrupt flag?
-        popfq
-        "#
-    };
+fn set_eventbuf(eventbuf: &'static mut [Event]) -> InitStatus {
+    if ACTIVE.load(Ordering::Relaxed) != NO_BUFFER || unsafe { !CORES.is_empty() } {
+        return InitStatus::AlreadyInitialized;
+    }
+    // A previous buffer might have been taken back, start over at the beginning of this one
+    publish_eventbuf(0, eventbuf);
+    InitStatus::Ok
 }
 
-#[cfg(not(feature = "interruptsafe"))]
-macro_rules! epilogue {
-    () => {
-        r#"
-        // add 8 less back to rsp than we substracted. RET will pop the 'missing' value
-        add rsp, 56
-        "#
-    };
+// Value of ATTACHED while the owner takes the buffer away, see `claim_eventbuf()`
+const CLAIMED: usize = usize::MAX;
+
+/// Claims the event buffer for its owner, so it can be taken away without attached frontends still reading it.
+///
+/// Fails while frontends are attached. Until `release_eventbuf()`, no frontend can attach.
+fn claim_eventbuf() -> bool {
+    ATTACHED
+        .compare_exchange(0, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
 }
 
-#[naked]
-pub unsafe extern "C" fn mcount_return_trampoline() {
-    // does 'nothing', except calling mcount_return. Takes care to not clobber any return registers.
-    // based on https://github.com/namhyung/uftrace/blob/master/arch/x86_64/mcount.S
-
-    // System V AMD64 ABI: If the callee wishes to use registers RBX, RBP, and R12–R15, it must restore their original values before returning control to the caller.
-    //                     All other registers must be saved by the caller if it wishes to preserve their values.
-    // We are in a return trampoline -> we only have to save the registers the return value might be stored in.
-    // `call mcount_return` is not allowed to clobber rbx, rbp, ... either, so thats fine.
-    // The only issue are interrupts. If we are tracing kernel code, specifically interrupt handlers, we will break stuff since we might change the scratch registers in the middle of a function.
-    // To solve this, a compile-time 'interruptsafe' feature is defined, which when set, saves and restores all volatile registers.
-
-    /*
-    Stack layout:
-        RBP +120
-            +112    RETURN-ADDRESS
-            +104    rflags   |
-            +96     r11      |
-            +88     r10      |
-            +80     r9       |
-            +72     r8       |  only when interruptsafe
-            +64     rcx      |
-            +56     rsi      |
-            +48     rdi      |
-            +40     xmm1   |
-            +32     xmm1   | only when sse2 is available
-            +24     xmm0   |
-            +16     xmm0   |
-            +8      rdx
-        RSP +0      rax
-    */
-
-    asm!(
-        prologue!(),
-        // always backup return registers
-        "mov [rsp + 8], rdx",
-        "mov [rsp], rax",
-        backup_sse2!(),
-        backup_interrupts!(),
-        // set the first argument of mcount_return as pointer to return values
-        "mov rdi, rsp",
-        // call mcount_return, which returns original parent address in rax.
-        "call mcount_return",
-        // Store original parent address at the correct stack location
-        store_parent!(),
-        // restore saved return values
-        "mov rax, [rsp]",
-        "mov rdx, [rsp + 8]",
-        restore_interrupts!(),
-        // Restore sse return values, if supported
-        restore_sse2!(),
-        epilogue!(),
-        "ret",
-        options(noreturn),
-    );
+fn release_eventbuf() {
+    ATTACHED.store(0, Ordering::Release);
 }
 
+// interface, only used by 'parent' rftrace lib this static backend is linked to!
+
+/// Returns the index of the buffer recorded into, or once it was taken with `rftrace_backend_get_events()`, its final index.
 #[no_mangle]
-pub extern "C" fn mcount_return() -> *const usize {
-    unsafe {
-        let (original_ret, childip) = {
-            let sr = RETSTACK.pop().expect("retstack empty?");
+pub extern "C" fn rftrace_backend_get_events_index() -> usize {
+    match active_buffer() {
+        Some(buffer) => buffer.index.load(Ordering::Relaxed),
+        None => TAKEN_INDEX.load(Ordering::Relaxed),
+    }
+}
 
-            (sr.retloc, sr.childip)
-        };
+#[no_mangle]
+pub extern "C" fn rftrace_backend_get_events() -> *const Event {
+    // Do not hand out the buffer while other frontends are still reading it.
+    if !claim_eventbuf() {
+        return 0 as *const Event;
+    }
 
-        let cidx = INDEX.fetch_add(1, Ordering::Relaxed);
-        if let Some(events) = &mut EVENTS {
-            events[cidx % events.len()] = Event::Exit(Exit {
-                time: _rdtsc(),
-                from: childip,
-                tid: TID.as_ref().copied(),
-            });
-        }
+    let events = take_eventbuf();
+    release_eventbuf();
+    events
+}
 
-        original_ret
+#[no_mangle]
+pub extern "C" fn rftrace_backend_attach(len: *mut usize) -> *const Event {
+    // Count ourselves before looking at the buffer. The owner only takes it away after claiming it while the count is 0,
+    // so once we are counted, it stays alive until we detach.
+    let mut attached = ATTACHED.load(Ordering::Relaxed);
+    loop {
+        if attached == CLAIMED {
+            return 0 as *const Event;
+        }
+        match ATTACHED.compare_exchange_weak(
+            attached,
+            attached + 1,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => attached = current,
+        }
+    }
+    match active_buffer() {
+        Some(buffer) => {
+            unsafe { *len = buffer.len.load(Ordering::Relaxed) };
+            buffer.events.load(Ordering::Relaxed)
+        }
+        None => {
+            ATTACHED.fetch_sub(1, Ordering::Release);
+            0 as *const Event
+        }
     }
 }
 
-fn disable() {
-    ENABLED.store(false, Ordering::Relaxed);
+#[no_mangle]
+pub extern "C" fn rftrace_backend_detach() {
+    ATTACHED.fetch_sub(1, Ordering::Release);
 }
 
-fn enable() {
-    ENABLED.store(true, Ordering::Relaxed);
+#[no_mangle]
+pub extern "C" fn rftrace_backend_disable() {
+    if rftrace_backend_is_enabled() {
+        rftrace_backend_record(RECORD_KIND_TRACING, &TRACING_DISABLED, 1);
+    }
+    // With a flight recorder, we never stop recording, but only record into the flight recorder
+    if unsafe { FLIGHT_EVENTS.is_some() } {
+        FLIGHT.store(true, Ordering::Relaxed);
+        enable();
+    } else {
+        disable();
+    }
+}
+
+#[no_mangle]
+pub fn rftrace_backend_enable() {
+    let enabled = rftrace_backend_is_enabled();
+    if FLIGHT.swap(false, Ordering::Relaxed) {
+        unsafe {
+            hook_enter();
+            flush_flight_recorder();
+            hook_exit();
+        }
+    }
+    enable();
+    if !enabled {
+        rftrace_backend_record(RECORD_KIND_TRACING, &TRACING_ENABLED, 1);
+    }
 }
 
-fn set_eventbuf(eventbuf: &'static mut [Event]) {
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_flight_recorder(bufptr: *mut Event, len: usize) {
     unsafe {
-        if EVENTS.is_some() {
+        if FLIGHT_EVENTS.is_some() || bufptr.is_null() || len == 0 {
             // ERROR! already initialized
             return;
         }
+        FLIGHT_EVENTS.replace(slice::from_raw_parts_mut(bufptr, len));
+    }
 
-        EVENTS.replace(eventbuf);
+    // Start recording into it right away, if tracing is currently disabled
+    if !ENABLED.load(Ordering::Relaxed) {
+        rftrace_backend_disable();
     }
 }
 
-// interface, only used by 'parent' rftrace lib this static backend is linked to!
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_sample_period(period: u64) {
+    SAMPLE_PERIOD.store(period, Ordering::Relaxed);
+}
 
 #[no_mangle]
-pub extern "C" fn rftrace_backend_get_events_index() -> usize {
-    return INDEX.load(Ordering::Relaxed);
+pub extern "C" fn rftrace_backend_set_function_quota(quota: u64, rate: u64) {
+    // Disable the quota first, so no entry is counted against the new quota before the counters are reset
+    QUOTA.store(0, Ordering::Relaxed);
+    DEMOTED_RATE.store(rate, Ordering::Relaxed);
+    for count in &QUOTA_COUNTS {
+        count.store(0, Ordering::Relaxed);
+    }
+    QUOTA.store(quota, Ordering::Relaxed);
 }
 
 #[no_mangle]
-pub extern "C" fn rftrace_backend_get_events() -> *const Event {
-    return unsafe {
-        EVENTS
-            .take()
-            .map(|e| e.as_ptr())
-            .unwrap_or(0 as *const Event)
+pub extern "C" fn rftrace_backend_set_max_depth(depth: usize) {
+    MAX_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_thread_quota(quota: u64) {
+    // Threads notice the new epoch on their next entry and start counting from 0 again
+    THREAD_QUOTA.store(0, Ordering::Relaxed);
+    THREAD_QUOTA_EPOCH.fetch_add(1, Ordering::Relaxed);
+    THREAD_QUOTA.store(quota, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_overhead_budget(budget: u64, period: u64) {
+    // Disable the budget first, so the hooks do not compare the new start against the old spent cycles
+    BUDGET_PPM.store(0, Ordering::Relaxed);
+    BUDGET_PERIOD.store(period.max(1), Ordering::Relaxed);
+    BUDGET_SPENT.store(0, Ordering::Relaxed);
+    BUDGET_START.store(timestamp(), Ordering::Relaxed);
+    BUDGET_PPM.store(budget, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_tid_offset(offset: u64) {
+    TID_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_pressure_handler(handler: Option<extern "C" fn() -> usize>) {
+    PRESSURE_HANDLER.store(
+        handler.map_or(0, |handler| handler as usize),
+        Ordering::Release,
+    );
+}
+
+/// Called by the kernel's allocator under memory pressure. Returns the number of bytes released.
+#[no_mangle]
+pub extern "C" fn rftrace_backend_memory_pressure() -> usize {
+    // The buffer can only be released once, so the handler is only ever called once as well
+    let handler = PRESSURE_HANDLER.swap(0, Ordering::AcqRel);
+    if handler == 0 {
+        return 0;
+    }
+    let handler: extern "C" fn() -> usize = unsafe { core::mem::transmute(handler) };
+    handler()
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_full_trace_begin() {
+    unsafe { FULL_TRACE += 1 };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_full_trace_end() {
+    unsafe { FULL_TRACE = FULL_TRACE.saturating_sub(1) };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_suppress() {
+    unsafe { SUPPRESSED += 1 };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_unsuppress() {
+    unsafe { SUPPRESSED = SUPPRESSED.saturating_sub(1) };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_scoped(scoped: bool) {
+    SCOPED.store(scoped, Ordering::Relaxed);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_scope_begin() {
+    unsafe { SCOPE += 1 };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_scope_end() {
+    unsafe { SCOPE = SCOPE.saturating_sub(1) };
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) && !FLIGHT.load(Ordering::Relaxed)
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_get_stats(stats: *mut Stats) {
+    unsafe {
+        *stats = Stats {
+            entries: STAT_ENTRIES.load(Ordering::Relaxed),
+            returns: STAT_RETURNS.load(Ordering::Relaxed),
+            filtered: STAT_FILTERED.load(Ordering::Relaxed),
+            retstack_overflows: STAT_RETSTACK_OVERFLOWS.load(Ordering::Relaxed),
+            recursion_hits: STAT_RECURSION_HITS.load(Ordering::Relaxed),
+            demoted: STAT_DEMOTED.load(Ordering::Relaxed),
+            quota_exceeded: STAT_QUOTA_EXCEEDED.load(Ordering::Relaxed),
+            throttled: STAT_THROTTLED.load(Ordering::Relaxed),
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_exclude(ranges: *const AddrRange, len: usize) {
+    EXCLUDE.replace(ranges, len, 0);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_filter(ranges: *const AddrRange, len: usize) {
+    FILTER.replace(ranges, len, 0);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_set_page_filter(
+    bitmap: *const u64,
+    len: usize,
+    base_page: usize,
+) {
+    // The base is published along with the bitmap, so hooks never combine the new base with the old bitmap
+    PAGE_FILTER.replace(bitmap, len, base_page);
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_interface_version() -> u32 {
+    INTERFACE_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_record(kind: u8, payload: *const u8, len: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let len = len.min(RECORD_PAYLOAD_LEN);
+    let mut record = Record {
+        time: 0,
+        tid: unsafe { current_tid() },
+        kind,
+        len: len as u8,
+        payload: [0; RECORD_PAYLOAD_LEN],
     };
+    if len > 0 {
+        record.payload[..len].copy_from_slice(unsafe { slice::from_raw_parts(payload, len) });
+    }
+
+    unsafe {
+        hook_enter();
+        if let Some((events, cidx)) = reserve(1, false) {
+            record.time = timestamp();
+            events[cidx % events.len()] = Event::Record(record);
+        }
+        hook_exit();
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn rftrace_backend_disable() {
-    disable();
+pub extern "C" fn rftrace_backend_set_context(context: u64) -> u64 {
+    let previous = unsafe { CONTEXT };
+    unsafe { CONTEXT = context };
+    // Consumers replay these records to find the context of all subsequent events of this thread
+    if previous != context {
+        rftrace_backend_record(
+            RECORD_KIND_CONTEXT,
+            context.to_le_bytes().as_ptr(),
+            core::mem::size_of::<u64>(),
+        );
+    }
+    previous
 }
 
 #[no_mangle]
-pub fn rftrace_backend_enable() {
-    enable();
+pub extern "C" fn rftrace_backend_thread_exit() {
+    unsafe {
+        hook_enter();
+        let tid = TID;
+        let now = timestamp();
+        let sp = stack_pointer();
+
+        // Functions still on the return stack will never return through the trampoline again, so close them now.
+        // Frames above us are still alive and might return normally after all, so give them back their return address.
+        // Frames below the stack pointer are gone already and must not be touched.
+        let frames = &RETSTACK.stack[..RETSTACK.index];
+        let recorded = frames.iter().filter(|sr| sr.recorded).count();
+        let mut reserved = if ENABLED.load(Ordering::Relaxed) {
+            reserve(recorded + 1, true)
+        } else {
+            None
+        };
+        let mut i = 0;
+        for sr in frames.iter().rev() {
+            if sr.intact()
+                && sr.stackloc as usize > sp
+                && *sr.stackloc == mcount_return_trampoline as *const usize
+            {
+                *sr.stackloc = sr.retloc;
+            }
+            if !sr.recorded {
+                continue;
+            }
+            if let Some((events, cidx)) = reserved.as_mut() {
+                let len = events.len();
+                events[(*cidx + i) % len] = Event::Exit(Exit {
+                    time: now,
+                    from: sr.childip as u64,
+                    tid,
+                });
+                i += 1;
+            }
+        }
+        if let Some((events, cidx)) = reserved {
+            events[(cidx + i) % events.len()] = Event::Record(Record {
+                time: now,
+                tid,
+                kind: RECORD_KIND_THREAD_EXIT,
+                len: 0,
+                payload: [0; RECORD_PAYLOAD_LEN],
+            });
+        }
+
+        // A thread reusing our thread-locals starts over with a fresh TID
+        RETSTACK.index = 0;
+        TID = None;
+        CONTEXT = 0;
+        LAST_SAMPLE = 0;
+        FULL_TRACE = 0;
+        SUPPRESSED = 0;
+        SCOPE = 0;
+        LAST_CORE = 0;
+        THREAD_ENTRIES = 0;
+        hook_exit();
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn rftrace_backend_init(bufptr: *mut Event, len: usize, overwriting: bool) {
-    let eventbuf = unsafe {
-        assert!(!bufptr.is_null());
-        slice::from_raw_parts_mut(bufptr, len)
+pub extern "C" fn rftrace_backend_task_create(id: u64) {
+    unsafe {
+        // Kernels might hand out the thread-locals of a finished task again, so start over like `rftrace_backend_thread_exit()`
+        RETSTACK.index = 0;
+        CONTEXT = 0;
+        LAST_SAMPLE = 0;
+        FULL_TRACE = 0;
+        SUPPRESSED = 0;
+        SCOPE = 0;
+        LAST_CORE = 0;
+        THREAD_ENTRIES = 0;
+
+        // Use the kernel's task ID as TID, and make sure allocated TIDs of other threads never collide with it
+        TID = core::num::NonZeroU64::new(id)
+            .and_then(|id| id.checked_add(TID_OFFSET.load(Ordering::Relaxed)));
+        if TID.is_some() {
+            TID_NEXT.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_switch_stack(old: *mut StackContext, new: *mut StackContext) {
+    let (old, new) = match unsafe { (old.as_mut(), new.as_mut()) } {
+        (Some(old), Some(new)) => (old, new),
+        _ => return,
     };
+    unsafe {
+        hook_enter();
+        // Keep the outermost frames. The others are unhooked, the stack of a suspended fiber stays intact, so they return normally.
+        let frames = &RETSTACK.stack[..RETSTACK.index];
+        let kept = frames.len().min(old.capacity);
+        for sr in &frames[kept..] {
+            if sr.intact() && *sr.stackloc == mcount_return_trampoline as *const usize {
+                *sr.stackloc = sr.retloc;
+            }
+            STAT_RETSTACK_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        }
+        if kept > 0 {
+            core::ptr::copy_nonoverlapping(frames.as_ptr(), old.frames, kept);
+        }
+        old.len = kept;
+        old.tid = TID.map_or(0, core::num::NonZeroU64::get);
 
-    assert!(
-        len > MAX_STACK_HEIGHT,
-        "Event buffer has to be larger than maximum stack height!"
-    );
+        let len = new.len.min(MAX_STACK_HEIGHT);
+        if len > 0 {
+            core::ptr::copy_nonoverlapping(new.frames, RETSTACK.stack.as_mut_ptr(), len);
+        }
+        RETSTACK.index = len;
+        new.len = 0;
+        // A fiber which did not record yet gets its own TID on its first event
+        TID = core::num::NonZeroU64::new(new.tid);
+        hook_exit();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_init(
+    bufptr: *mut Event,
+    len: usize,
+    overwriting: bool,
+) -> InitStatus {
+    if bufptr.is_null() || len <= MAX_STACK_HEIGHT {
+        return InitStatus::InvalidBuffer;
+    }
+    let eventbuf = unsafe { slice::from_raw_parts_mut(bufptr, len) };
+
+    unsafe {
+        NOTRACE = AddrRange {
+            start: __start_rftrace_notrace as usize,
+            end: __stop_rftrace_notrace as usize,
+        };
+    }
+
+    // Someone else, such as the kernel, might already record into their buffer. Do not touch its settings then.
+    let status = set_eventbuf(eventbuf);
+    if status == InitStatus::Ok {
+        OVERWRITING.store(overwriting, Ordering::Relaxed);
+    }
+    status
+}
 
+#[no_mangle]
+pub extern "C" fn rftrace_backend_init_per_core(
+    cores: *const CoreBuffer,
+    len: usize,
+    core_id: extern "C" fn() -> usize,
+    overwriting: bool,
+) -> InitStatus {
+    if cores.is_null() || len == 0 {
+        return InitStatus::InvalidBuffer;
+    }
+    let cores = unsafe { slice::from_raw_parts(cores, len) };
+    if cores
+        .iter()
+        .any(|core| core.events.is_null() || core.len <= MAX_STACK_HEIGHT)
+    {
+        return InitStatus::InvalidBuffer;
+    }
+
+    unsafe {
+        if ACTIVE.load(Ordering::Relaxed) != NO_BUFFER || !CORES.is_empty() {
+            return InitStatus::AlreadyInitialized;
+        }
+        NOTRACE = AddrRange {
+            start: __start_rftrace_notrace as usize,
+            end: __stop_rftrace_notrace as usize,
+        };
+        OVERWRITING.store(overwriting, Ordering::Relaxed);
+        // Set the function first, hooks only look at it once CORES is non-empty
+        CORE_ID = Some(core_id);
+        CORES = cores;
+    }
+    InitStatus::Ok
+}
+
+/// Returns the index of the current thread's buffer, claiming the next unused one on its first event.
+///
+/// Used as core ID with per-thread buffers, so a thread always records into the same buffer and never migrates.
+extern "C" fn thread_slot() -> usize {
+    unsafe {
+        if THREAD_SLOT == 0 {
+            THREAD_SLOT = THREAD_SLOT_NEXT.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+        THREAD_SLOT - 1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_init_per_thread(
+    buffers: *const CoreBuffer,
+    len: usize,
+    overwriting: bool,
+) -> InitStatus {
+    // Threads claim their buffer lazily, so only the claim itself touches a shared atomic. Once all
+    // buffers are claimed, further threads share them round robin.
+    rftrace_backend_init_per_core(buffers, len, thread_slot, overwriting)
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_take_per_thread() -> *const CoreBuffer {
+    let cores = unsafe { core::mem::replace(&mut CORES, &[]) };
+
+    // Afterwards no hook writes to the buffers anymore
+    wait_for_writers();
+    if cores.is_empty() {
+        0 as *const CoreBuffer
+    } else {
+        cores.as_ptr()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rftrace_backend_replace_buffer(
+    bufptr: *mut Event,
+    len: usize,
+    overwriting: bool,
+    previous: *mut *const Event,
+) -> InitStatus {
+    if bufptr.is_null() || len <= MAX_STACK_HEIGHT {
+        return InitStatus::InvalidBuffer;
+    }
+    // The previous buffer is given back to be freed or reused, so nobody else may still read it
+    if !claim_eventbuf() {
+        return InitStatus::AlreadyInitialized;
+    }
+
+    // The previous owner keeps its buffer alive, so writers still holding on to it do no harm
+    unsafe { *previous = take_eventbuf() };
     OVERWRITING.store(overwriting, Ordering::Relaxed);
+    let status = set_eventbuf(unsafe { slice::from_raw_parts_mut(bufptr, len) });
+    release_eventbuf();
+    status
+}
 
-    set_eventbuf(eventbuf);
+#[no_mangle]
+pub extern "C" fn rftrace_backend_swap_buffer(
+    bufptr: *mut Event,
+    len: usize,
+    index: *mut usize,
+) -> *const Event {
+    // The frontend reuses the retired buffer, so nobody else may still read it.
+    if bufptr.is_null() || len <= MAX_STACK_HEIGHT || !claim_eventbuf() {
+        return 0 as *const Event;
+    }
+
+    let slot = ACTIVE.load(Ordering::Relaxed);
+    let retired = match BUFFERS.get(slot) {
+        Some(retired) => retired,
+        None => {
+            release_eventbuf();
+            return 0 as *const Event;
+        }
+    };
+    // Publish the new buffer before retiring the old one, so hooks always find a buffer and no event is dropped.
+    // The other slot is unused, its writers were waited for when it was retired.
+    publish_eventbuf(1 - slot, unsafe { slice::from_raw_parts_mut(bufptr, len) });
+    wait_for_writers();
+    // All writers to the retired buffer are done, so its index is final
+    unsafe { *index = retired.index.load(Ordering::Relaxed) };
+    let previous = retired.events.load(Ordering::Relaxed);
+    release_eventbuf();
+    previous
 }
@@ -0,0 +1,303 @@
+//! riscv64 hooks: `mcount()` and the return trampoline, as well as the `time` CSR as clock.
+
+use core::arch::asm;
+
+#[cfg(feature = "fentry")]
+compile_error!("The fentry feature is only supported on x86_64");
+
+/// Current time in ticks of the `time` CSR, used to timestamp events.
+///
+/// Unlike `rdcycle`, `rdtime` counts at a constant frequency regardless of the core clock, like a constant TSC.
+/// Linux also no longer allows reading the cycle counter from user mode by default.
+#[inline(always)]
+pub(super) fn timestamp() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!("rdtime {}", out(reg) time, options(nomem, nostack));
+    }
+    time
+}
+
+/// Current stack pointer.
+#[inline(always)]
+pub(super) fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe {
+        asm!("mv {}, sp", out(reg) sp, options(nomem, nostack));
+    }
+    sp
+}
+
+/// Returns the address recorded for the function which called mcount, given the return address `ret` of that call.
+///
+/// mcount is called after the function's prologue, whose length varies, so this is the return address itself.
+/// Symbolizers have to look up the function containing it, which uftrace does.
+#[inline(always)]
+pub(super) unsafe fn function_start(ret: *const usize) -> *const usize {
+    ret
+}
+
+// GCC calls _mcount instead of mcount. The arguments it passes are not needed, so both share the same implementation.
+core::arch::global_asm!(".globl _mcount", "_mcount:", "j mcount");
+
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn mcount() {
+    // Same as on x86_64, we backup and restore all argument registers, since naked functions might not have saved them.
+    // mcount is called after the function's prologue, which saved the parents return address right below the frame pointer.
+    // This requires frame pointers, as with -Cforce-frame-pointers=yes or -fno-omit-frame-pointer.
+    // mcounts ret addr is in ra
+
+    // based on https://github.com/namhyung/uftrace/blob/master/arch/riscv64/mcount.S
+    asm!(
+        // make some space for locals on the stack, and save the registers we need before calling mcount_entry
+        "addi sp, sp, -80",
+        "sd ra, 72(sp)",
+        "sd t0, 64(sp)",
+        // if !ENABLED.load(Ordering::Relaxed) {
+        //     return;
+        // }
+        "la t0, ENABLED",
+        "lbu t0, 0(t0)",
+        "beqz t0, 2f",
+        // save register arguments. Needed so we can later restore them
+        "sd a0, 56(sp)",
+        "sd a1, 48(sp)",
+        "sd a2, 40(sp)",
+        "sd a3, 32(sp)",
+        "sd a4, 24(sp)",
+        "sd a5, 16(sp)",
+        "sd a6, 8(sp)",
+        "sd a7, 0(sp)",
+        // parent location = child-return-addr-ptr = what addr stores the location the child function was called from
+        // needed, since we overwrite it with our own trampoline. This way we can determine when the child function returns
+        "addi a0, s0, -8",
+        // child addr = what function was mcount() called from
+        "mv a1, ra",
+        // the stack pointer stays 16-byte aligned, since we made space in multiples of 16
+        "call mcount_entry",
+        // restore register arguments
+        "ld a7, 0(sp)",
+        "ld a6, 8(sp)",
+        "ld a5, 16(sp)",
+        "ld a4, 24(sp)",
+        "ld a3, 32(sp)",
+        "ld a2, 40(sp)",
+        "ld a1, 48(sp)",
+        "ld a0, 56(sp)",
+        "2:",
+        // revert stack pointer to original location and return
+        "ld t0, 64(sp)",
+        "ld ra, 72(sp)",
+        "addi sp, sp, 80",
+        "ret",
+        options(noreturn),
+    );
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! prologue {
+    () => {
+        r#"
+        addi sp, sp, -144
+        // t0 is needed to disable interrupts, so back it up first
+        sd t0, 80(sp)
+        // dont do interrupts here! Clears the supervisor interrupt enable bit, and remembers sstatus for later
+        csrrci t0, sstatus, 2
+        sd t0, 136(sp)
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! prologue {
+    () => {
+        "addi sp, sp, -32"
+    };
+}
+
+#[cfg(target_feature = "d")]
+macro_rules! backup_float {
+    () => {
+        r#"
+        // Same as with sse2 on x86_64: if the backend is compiled with floating point support, so might be the code we trace.
+        // Backup the floating point return registers fa0 and fa1
+        fsd fa0, 16(sp)
+        fsd fa1, 24(sp)
+        "#
+    };
+}
+
+#[cfg(not(target_feature = "d"))]
+macro_rules! backup_float {
+    () => {
+        ""
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! backup_interrupts {
+    () => {
+        r#"
+        // If we have to be interrupt safe, also backup non-return scratch registers. t0 was saved by the prologue
+        sd a2, 32(sp)
+        sd a3, 40(sp)
+        sd a4, 48(sp)
+        sd a5, 56(sp)
+        sd a6, 64(sp)
+        sd a7, 72(sp)
+        sd t1, 88(sp)
+        sd t2, 96(sp)
+        sd t3, 104(sp)
+        sd t4, 112(sp)
+        sd t5, 120(sp)
+        sd t6, 128(sp)
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! backup_interrupts {
+    () => {
+        ""
+    };
+}
+
+// The hooked function already popped its frame, so the slot its return address was saved in is right below our frame.
+#[cfg(feature = "interruptsafe")]
+macro_rules! return_location {
+    () => {
+        "addi a0, sp, 136"
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! return_location {
+    () => {
+        "addi a0, sp, 24"
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! restore_interrupts {
+    () => {
+        r#"
+        // If we have to be interrupt safe, restore non-return scratch registers. t0 is restored by the epilogue
+        ld a2, 32(sp)
+        ld a3, 40(sp)
+        ld a4, 48(sp)
+        ld a5, 56(sp)
+        ld a6, 64(sp)
+        ld a7, 72(sp)
+        ld t1, 88(sp)
+        ld t2, 96(sp)
+        ld t3, 104(sp)
+        ld t4, 112(sp)
+        ld t5, 120(sp)
+        ld t6, 128(sp)
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! restore_interrupts {
+    () => {
+        ""
+    };
+}
+
+#[cfg(target_feature = "d")]
+macro_rules! restore_float {
+    () => {
+        r#"
+        fld fa0, 16(sp)
+        fld fa1, 24(sp)
+        "#
+    };
+}
+
+#[cfg(not(target_feature = "d"))]
+macro_rules! restore_float {
+    () => {
+        ""
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! epilogue {
+    () => {
+        r#"
+        // Reenable interrupts if they were enabled before, without touching the rest of sstatus
+        ld t0, 136(sp)
+        andi t0, t0, 2
+        csrs sstatus, t0
+        ld t0, 80(sp)
+        addi sp, sp, 144
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! epilogue {
+    () => {
+        "addi sp, sp, 32"
+    };
+}
+
+#[naked]
+pub unsafe extern "C" fn mcount_return_trampoline() {
+    // does 'nothing', except calling mcount_return. Takes care to not clobber any return registers.
+    // based on https://github.com/namhyung/uftrace/blob/master/arch/riscv64/mcount.S
+
+    // RISC-V calling convention: s0-s11 and sp are callee-saved, so `call mcount_return` will not clobber them either.
+    // Return values are passed in a0/a1, and fa0/fa1 with floating point support. We only have to save these.
+    // ra is free to use as well, since the caller expects it to be clobbered by the call it returns from.
+    // For interrupt handlers, the 'interruptsafe' feature saves and restores all other scratch registers, like on x86_64.
+
+    /*
+    Stack layout:
+        CALLER SP +144
+            +136    sstatus  |
+            +128    t6       |
+            +120    t5       |
+            +112    t4       |
+            +104    t3       |
+            +96     t2       |
+            +88     t1       |
+            +80     t0       |  only when interruptsafe
+            +72     a7       |
+            +64     a6       |
+            +56     a5       |
+            +48     a4       |
+            +40     a3       |
+            +32     a2       |
+            +24     fa1    | only when floating point is available
+            +16     fa0    |
+            +8      a1
+        SP  +0      a0
+    */
+
+    asm!(
+        prologue!(),
+        // always backup return registers
+        "sd a1, 8(sp)",
+        "sd a0, 0(sp)",
+        backup_float!(),
+        backup_interrupts!(),
+        // pass the stack location we returned from as first argument of mcount_return
+        return_location!(),
+        // call mcount_return, which returns original parent address in a0.
+        "call mcount_return",
+        // Return to the original parent address instead of the trampoline
+        "mv ra, a0",
+        // restore saved return values
+        "ld a0, 0(sp)",
+        "ld a1, 8(sp)",
+        restore_interrupts!(),
+        // Restore floating point return values, if supported
+        restore_float!(),
+        epilogue!(),
+        "ret",
+        options(noreturn),
+    );
+}
@@ -0,0 +1,360 @@
+//! x86_64 hooks: `mcount()`, `__fentry__` and the return trampoline, as well as the TSC as clock.
+
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+#[cfg(feature = "fentry")]
+use core::slice;
+
+/// Current time in TSC cycles, used to timestamp events.
+#[inline(always)]
+pub(super) fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Current stack pointer.
+#[inline(always)]
+pub(super) fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack));
+    }
+    sp
+}
+
+#[cfg(not(feature = "fentry"))]
+macro_rules! parent_location {
+    () => {
+        // mcount is called after the function's prologue, which set up rbp
+        "lea rdi, [rbp + 8]"
+    };
+}
+
+#[cfg(feature = "fentry")]
+macro_rules! parent_location {
+    () => {
+        // mcount is called before the function's prologue, so its return address is right above our own.
+        // We pushed rax and made 48 bytes of space since.
+        "lea rdi, [rsp + 64]"
+    };
+}
+
+// Compilers emitting fentry calls (such as clang's -mfentry) call this symbol instead of mcount.
+// Jumping keeps the stack untouched, so mcount sees the same layout.
+#[cfg(feature = "fentry")]
+core::arch::global_asm!(".globl __fentry__", "__fentry__:", "endbr64", "jmp mcount");
+
+/// Returns the start of the function which called `__fentry__`, given the return address `ret` of that call.
+///
+/// The fentry call is the very first instruction, so the function starts right before it. Code compiled with CET/IBT
+/// (`-fcf-protection`) starts each function with an `endbr64` landing pad instead, which shifts the call by 4 bytes.
+#[cfg(feature = "fentry")]
+#[inline(always)]
+pub(super) unsafe fn function_start(ret: *const usize) -> *const usize {
+    const ENDBR64: [u8; 4] = [0xf3, 0x0f, 0x1e, 0xfa];
+
+    let ret = ret as *const u8;
+    // Either `call rel32`, or `call [rip + rel32]` when compiled without PLT
+    let call = if *ret.sub(5) == 0xe8 {
+        ret.sub(5)
+    } else if *ret.sub(6) == 0xff && *ret.sub(5) == 0x15 {
+        ret.sub(6)
+    } else {
+        return ret as *const usize;
+    };
+
+    // Do not look across a page boundary, the previous page might not be mapped
+    if call as usize & 0xfff >= 4 && slice::from_raw_parts(call.sub(4), 4) == ENDBR64 {
+        call.sub(4) as *const usize
+    } else {
+        call as *const usize
+    }
+}
+
+/// Returns the address recorded for the function which called mcount, given the return address `ret` of that call.
+///
+/// mcount is called after the function's prologue, whose length varies, so this is the return address itself.
+/// Symbolizers have to look up the function containing it, which uftrace does.
+#[cfg(not(feature = "fentry"))]
+#[inline(always)]
+pub(super) unsafe fn function_start(ret: *const usize) -> *const usize {
+    ret
+}
+
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn mcount() {
+    // We need to be careful with hooked naked functions!
+    // Normally, llvm ensures that all needed functions parameters are saved before the embedded mcount() is called, and restored afterwards.
+    // This does NOT happen with naked funktions like `hermit::arch::x86_64::kernel::switch::switch:`
+    // There, the args (like old and new_stack) are clobbered. This is because they are not used, only implicitly in the llvm_asm! code, so llvm does not know they are used!
+    // To be sure the instrumentation never breaks anything, we backup and restore any possible argument registers
+    // TODO: Implement feature to skip this, which can be enabled if we are sure this can't happen with the code we are instrumenting?
+
+    // we need custom assembly that "knows" that mcount is ALWAYS called at the start of each function! no llvm magic can help here.
+    // parents-return-addr is always stored at rbp+8, or directly above mcounts ret addr with the fentry feature
+    // mcounts ret addr is directly at rsp
+
+    // based on https://github.com/namhyung/uftrace/blob/master/arch/x86_64/mcount.S
+    asm!(
+        // Landing pad for indirect calls with CET/IBT, such as `call [rip + mcount@GOTPCREL]` without PLT. A nop on older CPUs.
+        "endbr64",
+        // if ENABLED.load(Ordering::Relaxed) {
+        //     return;
+        // }
+        "push rax",
+        "mov rax, [rip + ENABLED@GOTPCREL]",
+        "movzx eax, byte ptr [rax]",
+        "test al, al",
+        "je 2f",
+        // make some space for locals on the stack
+        "sub rsp, 48",
+        // save register arguments in mcount_args. Needed so we can later restore them
+        "mov [rsp + 40], rdi",
+        "mov [rsp + 32], rsi",
+        "mov [rsp + 24], rdx",
+        "mov [rsp + 16], rcx",
+        "mov [rsp + 8], r8",
+        "mov [rsp], r9",
+        // child addr = what function was mcount() called from
+        "mov rsi, [rsp + 56]",
+        // parent location = child-return-addr-ptr = what addr stores the location the child function was called from
+        // needed, since we overwrite it with our own trampoline. This way we can determine when the child function returns
+        parent_location!(),
+        // align stack pointer to 16-byte, remember old value
+        "mov rdx, rsp",
+        "and rsp, -16",
+        // pass mcount_args to mcount_entry's 3rd argument
+        "push rdx",
+        "call mcount_entry",
+        // restore original stack pointer
+        "pop rdx",
+        "mov rsp, rdx",
+        // restore mcount_args
+        "mov r9, [rsp]",
+        "mov r8, [rsp + 8]",
+        "mov rcx, [rsp + 16]",
+        "mov rdx, [rsp + 24]",
+        "mov rsi, [rsp + 32]",
+        "mov rdi, [rsp + 40]",
+        // revert stack pointer to original location and return
+        "add rsp, 48",
+        "2:",
+        "pop rax",
+        "ret",
+        // TODO: ENABLED = sym ENABLED,
+        options(noreturn),
+    );
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! prologue {
+    () => {
+        r#"
+        // space for locals (saved ret values) (if we dont back up xmm0+1, this is too much, but this won't hurt us)
+        // fake return value for later
+        push rax
+        // flags for interrupt stuff
+        pushfq
+        // dont do interrupts here!
+        cli
+        sub rsp, 104
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! prologue {
+    () => {
+        "sub rsp, 64"
+    };
+}
+
+#[cfg(target_feature = "sse2")]
+macro_rules! backup_sse2 {
+    () => {
+        r#"
+        // when we compile against a 'kernel' target we do NOT have sse enabled, otherwise we might. Backup xmm0 and xmm1
+        // even if we are in userspace code that could use sse2, we are guaranteed that mcount_return() will not clobber it in this case
+        movdqu xmmword ptr [rsp + 16], xmm0
+        movdqu xmmword ptr [rsp + 32], xmm1
+        "#
+    };
+}
+
+#[cfg(not(target_feature = "sse2"))]
+macro_rules! backup_sse2 {
+    () => {
+        ""
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! backup_interrupts {
+    () => {
+        r#"
+        // If we have to be interrupt safe, also backup non-return scratch registers
+        mov [rsp + 48], rdi
+        mov [rsp + 56], rsi
+        mov [rsp + 64], rcx
+        mov [rsp + 72], r8
+        mov [rsp + 80], r9
+        mov [rsp + 88], r10
+        mov [rsp + 96], r11
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! backup_interrupts {
+    () => {
+        ""
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! store_parent {
+    () => {
+        "mov qword ptr [rsp + 112], rax"
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! store_parent {
+    () => {
+        "mov qword ptr [rsp + 56], rax"
+    };
+}
+
+// The slot store_parent writes to is the one the hooked function returned from, so it is the saved stack location.
+#[cfg(feature = "interruptsafe")]
+macro_rules! return_location {
+    () => {
+        "lea rdi, [rsp + 112]"
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! return_location {
+    () => {
+        "lea rdi, [rsp + 56]"
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! restore_interrupts {
+    () => {
+        r#"
+        // If we have to be interrupt safe, restore non-return scratch registers
+        mov rdi, [rsp + 48]
+        mov rsi, [rsp + 56]
+        mov rcx, [rsp + 64]
+        mov r8, [rsp + 72]
+        mov r9, [rsp + 80]
+        mov r10, [rsp + 88]
+        mov r11, [rsp + 96]
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! restore_interrupts {
+    () => {
+        ""
+    };
+}
+
+#[cfg(target_feature = "sse2")]
+macro_rules! restore_sse2 {
+    () => {
+        r#"
+        movdqu xmm0, xmmword ptr [rsp + 16]
+        movdqu xmm1, xmmword ptr [rsp + 32]
+        "#
+    };
+}
+
+#[cfg(not(target_feature = "sse2"))]
+macro_rules! restore_sse2 {
+    () => {
+        ""
+    };
+}
+
+#[cfg(feature = "interruptsafe")]
+macro_rules! epilogue {
+    () => {
+        r#"
+        // here we added same amount back we substracted, since space is in rax push.
+        add rsp, 104
+        // This should also restore the interrupt flag?
+        popfq
+        "#
+    };
+}
+
+#[cfg(not(feature = "interruptsafe"))]
+macro_rules! epilogue {
+    () => {
+        r#"
+        // add 8 less back to rsp than we substracted. RET will pop the 'missing' value
+        add rsp, 56
+        "#
+    };
+}
+
+#[naked]
+pub unsafe extern "C" fn mcount_return_trampoline() {
+    // does 'nothing', except calling mcount_return. Takes care to not clobber any return registers.
+    // based on https://github.com/namhyung/uftrace/blob/master/arch/x86_64/mcount.S
+
+    // System V AMD64 ABI: If the callee wishes to use registers RBX, RBP, and R12–R15, it must restore their original values before returning control to the caller.
+    //                     All other registers must be saved by the caller if it wishes to preserve their values.
+    // We are in a return trampoline -> we only have to save the registers the return value might be stored in.
+    // `call mcount_return` is not allowed to clobber rbx, rbp, ... either, so thats fine.
+    // The only issue are interrupts. If we are tracing kernel code, specifically interrupt handlers, we will break stuff since we might change the scratch registers in the middle of a function.
+    // To solve this, a compile-time 'interruptsafe' feature is defined, which when set, saves and restores all volatile registers.
+
+    /*
+    Stack layout:
+        RBP +120
+            +112    RETURN-ADDRESS
+            +104    rflags   |
+            +96     r11      |
+            +88     r10      |
+            +80     r9       |
+            +72     r8       |  only when interruptsafe
+            +64     rcx      |
+            +56     rsi      |
+            +48     rdi      |
+            +40     xmm1   |
+            +32     xmm1   | only when sse2 is available
+            +24     xmm0   |
+            +16     xmm0   |
+            +8      rdx
+        RSP +0      rax
+    */
+
+    asm!(
+        prologue!(),
+        // always backup return registers
+        "mov [rsp + 8], rdx",
+        "mov [rsp], rax",
+        backup_sse2!(),
+        backup_interrupts!(),
+        // pass the stack location we returned from as first argument of mcount_return
+        return_location!(),
+        // call mcount_return, which returns original parent address in rax.
+        "call mcount_return",
+        // Store original parent address at the correct stack location
+        store_parent!(),
+        // restore saved return values
+        "mov rax, [rsp]",
+        "mov rdx, [rsp + 8]",
+        restore_interrupts!(),
+        // Restore sse return values, if supported
+        restore_sse2!(),
+        epilogue!(),
+        "ret",
+        options(noreturn),
+    );
+}
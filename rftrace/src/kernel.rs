@@ -0,0 +1,188 @@
+//! Minimal `no_std` API for kernels linking the backend into themselves, such as the Hermit kernel.
+//!
+//! The kernel records into a buffer it owns, without the std frontend. Applications running on the kernel can still
+//! `attach()` the frontend to read and dump that buffer. The kernel calls `task_create()` and `task_exit()` from its
+//! scheduler, so every task is recorded with the kernel's task ID and its trace is closed when it ends.
+//!
+//! ```ignore
+//! static mut EVENTS: [rftrace::kernel::Event; 100_000] = [rftrace::kernel::Event::Empty; 100_000];
+//!
+//! let status = rftrace::kernel::init(unsafe { &mut *core::ptr::addr_of_mut!(EVENTS) }, true);
+//! assert_eq!(status, rftrace::kernel::InitStatus::Ok);
+//! rftrace::kernel::enable();
+//! ```
+
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub use crate::interface::{
+    CoreBuffer, Event, InitStatus, RECORD_KIND_BLOCK_COMPLETE, RECORD_KIND_BLOCK_SUBMIT,
+    RECORD_KIND_MIGRATION, RECORD_KIND_NET_RX, RECORD_KIND_NET_TX, RECORD_KIND_TRACING,
+    RECORD_KIND_USER, RECORD_KIND_WALLCLOCK, RECORD_PAYLOAD_LEN, TRACING_BUFFER_FULL,
+    TRACING_DISABLED, TRACING_ENABLED,
+};
+
+extern "C" {
+    fn rftrace_backend_enable();
+    fn rftrace_backend_disable();
+    fn rftrace_backend_is_enabled() -> bool;
+    fn rftrace_backend_set_tid_offset(offset: u64);
+    fn rftrace_backend_memory_pressure() -> usize;
+    fn rftrace_backend_init(bufptr: *mut Event, len: usize, overwriting: bool) -> InitStatus;
+    fn rftrace_backend_init_per_core(
+        cores: *const CoreBuffer,
+        len: usize,
+        core_id: extern "C" fn() -> usize,
+        overwriting: bool,
+    ) -> InitStatus;
+    fn rftrace_backend_record(kind: u8, payload: *const u8, len: usize);
+    fn rftrace_backend_task_create(id: u64);
+    fn rftrace_backend_thread_exit();
+}
+
+/// Hands `buf` to the backend. If `overwriting`, it is used as a ring buffer, otherwise recording stops once it is full.
+///
+/// `buf` has to be larger than the maximum stack height of 1000 events. Fails with `InitStatus::AlreadyInitialized`
+/// if the backend already records into another buffer.
+pub fn init(buf: &'static mut [Event], overwriting: bool) -> InitStatus {
+    unsafe { rftrace_backend_init(buf.as_mut_ptr(), buf.len(), overwriting) }
+}
+
+/// Like `init()`, but records into one buffer per core, removing the contention of all cores on a single index in SMP systems.
+///
+/// `core_id` returns the ID of the current core, usually read from core-local storage. IDs are taken modulo the number of
+/// `cores`. It is called on every event, so it has to be fast and must not be instrumented itself. Events of each core are
+/// ordered, events of different cores can be merged by their timestamps. The buffers can be read with `CoreBuffer::events()`
+/// after `disable()`.
+pub fn init_per_core(
+    cores: &'static [CoreBuffer],
+    core_id: extern "C" fn() -> usize,
+    overwriting: bool,
+) -> InitStatus {
+    unsafe { rftrace_backend_init_per_core(cores.as_ptr(), cores.len(), core_id, overwriting) }
+}
+
+impl CoreBuffer {
+    /// Wraps `buf` as buffer of a core, see `init_per_core()`. It has to be larger than the maximum stack height of 1000 events.
+    pub fn new(buf: &'static mut [Event]) -> Self {
+        Self {
+            events: buf.as_mut_ptr(),
+            len: buf.len(),
+            index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the events recorded into this buffer, split in two parts which are ordered oldest first.
+    ///
+    /// Events recorded concurrently, while tracing is still enabled, may be torn.
+    pub fn events(&self) -> (&[Event], &[Event]) {
+        let events = unsafe { slice::from_raw_parts(self.events, self.len) };
+        let index = self.index.load(Ordering::Relaxed);
+        if index < self.len {
+            (&events[..index], &[])
+        } else {
+            let (newer, older) = events.split_at(index % self.len);
+            (older, newer)
+        }
+    }
+}
+
+/// Starts recording.
+pub fn enable() {
+    unsafe { rftrace_backend_enable() }
+}
+
+/// Stops recording.
+pub fn disable() {
+    unsafe { rftrace_backend_disable() }
+}
+
+/// Returns whether the backend is currently recording.
+pub fn is_enabled() -> bool {
+    unsafe { rftrace_backend_is_enabled() }
+}
+
+/// Injects a record of `kind` into the trace of the current task, such as an interrupt number or a scheduling decision.
+///
+/// Only the first `RECORD_PAYLOAD_LEN` bytes of `payload` are kept. Kinds below 128 are reserved, see `RECORD_KIND_USER`.
+/// Nothing is recorded while tracing is disabled.
+pub fn record(kind: u8, payload: &[u8]) {
+    unsafe { rftrace_backend_record(kind, payload.as_ptr(), payload.len()) }
+}
+
+/// Records a packet of `len` bytes received on `queue`, to be called by the kernel's network driver.
+///
+/// Shows network activity alongside the function calls, such as when looking into throughput issues.
+pub fn net_rx(queue: u16, len: usize) {
+    record(RECORD_KIND_NET_RX, &net_payload(queue, len))
+}
+
+/// Records a packet of `len` bytes handed to the device on `queue`, to be called by the kernel's network driver.
+pub fn net_tx(queue: u16, len: usize) {
+    record(RECORD_KIND_NET_TX, &net_payload(queue, len))
+}
+
+/// Payload of network records, see `RECORD_KIND_NET_RX`. Lengths beyond `u32::MAX` are saturated.
+fn net_payload(queue: u16, len: usize) -> [u8; 6] {
+    let len = len.min(u32::MAX as usize) as u32;
+    let mut payload = [0; 6];
+    payload[..2].copy_from_slice(&queue.to_le_bytes());
+    payload[2..].copy_from_slice(&len.to_le_bytes());
+    payload
+}
+
+/// Records a request for `len` bytes starting at `sector` handed to the block device, to be called by the kernel's block driver.
+///
+/// Together with `block_complete()`, file system stalls can be correlated with the function calls waiting on them.
+pub fn block_submit(sector: u64, len: usize, write: bool) {
+    record(RECORD_KIND_BLOCK_SUBMIT, &block_payload(sector, len, write))
+}
+
+/// Records the completion of a request previously passed to `block_submit()`, with the same arguments.
+pub fn block_complete(sector: u64, len: usize, write: bool) {
+    record(
+        RECORD_KIND_BLOCK_COMPLETE,
+        &block_payload(sector, len, write),
+    )
+}
+
+/// Payload of block records, see `RECORD_KIND_BLOCK_SUBMIT`. Lengths beyond `u32::MAX` are saturated.
+fn block_payload(sector: u64, len: usize, write: bool) -> [u8; 13] {
+    let len = len.min(u32::MAX as usize) as u32;
+    let mut payload = [0; 13];
+    payload[..8].copy_from_slice(&sector.to_le_bytes());
+    payload[8..12].copy_from_slice(&len.to_le_bytes());
+    payload[12] = u8::from(write);
+    payload
+}
+
+/// Adds `offset` to all TIDs, including the task IDs passed to `task_create()`, so traces of several guests can be merged.
+///
+/// Only applies to tasks created afterwards, so call it before `init()`.
+pub fn set_tid_offset(offset: u64) {
+    unsafe { rftrace_backend_set_tid_offset(offset) }
+}
+
+/// Lets the application release its event buffer, to be called by the kernel's allocator when it runs out of memory.
+///
+/// If the application registered for it with `release_on_memory_pressure()` in the frontend, its trace is dumped and
+/// the buffer freed. Returns the number of bytes released, after which the allocation can be retried. Releases at most
+/// once, later calls return 0. The frontend allocates while dumping, so this must not be called with allocator locks held.
+pub fn memory_pressure() -> usize {
+    unsafe { rftrace_backend_memory_pressure() }
+}
+
+/// Has to be called by every new task before it runs instrumented code, usually from the kernel's task entry.
+///
+/// Resets the backend's thread-locals, which the kernel might have reused from a finished task, and records the
+/// task with `id` as TID. Tasks with ID 0 get a TID allocated by the backend instead.
+pub fn task_create(id: u64) {
+    unsafe { rftrace_backend_task_create(id) }
+}
+
+/// Has to be called by a task right before it ends, after which it must not run instrumented code anymore.
+///
+/// Records exits for all functions still on the task's stack, so its trace is complete.
+pub fn task_exit() {
+    unsafe { rftrace_backend_thread_exit() }
+}
@@ -1,13 +1,50 @@
 //! Backend for rftrace.
 //! Provides an `mcount` implementation, which does nothing by default but can be enabled via frontend.
 //! A lot of documentation can be found in the parent workspaces [readme](https://github.com/hermit-os/rftrace).
+//!
+//! # Hot path guarantees
+//! `mcount()` and the return trampoline, together with `mcount_entry()` and `mcount_return()` which they call, never allocate,
+//! never block and never take locks. The static library is `no_std` and does not link `alloc`, so allocating is impossible by construction.
+//! Shared state is only accessed with single atomic operations, and the one-time per-thread TID allocation is wait-free.
+//! Taking the event buffer away waits for hooks still writing to it, but hooks never wait for anything.
+//! This makes it safe to trace code which holds locks or runs in interrupt context.
+//! Debug builds of the backend additionally assert that the hooks are never re-entered.
+//!
+//! Kernels linking the backend into themselves use the `no_std` API in `kernel` instead of the std frontend.
 
 #![cfg_attr(feature = "staticlib", feature(asm))]
 #![cfg_attr(feature = "staticlib", feature(naked_functions))]
 #![cfg_attr(feature = "staticlib", feature(thread_local))]
-#![cfg_attr(feature = "staticlib", no_std)]
+#![cfg_attr(feature = "staticlib", feature(linkage))]
+#![no_std]
 
 mod interface;
 
 #[cfg(feature = "staticlib")]
 mod backend;
+
+#[cfg(not(feature = "staticlib"))]
+pub mod kernel;
+
+/// Never records the given functions, like `notrace` in ftrace. Calls made by them are still recorded.
+///
+/// Places the functions into the `rftrace_notrace` linker section, which the backend excludes as a whole once initialized.
+/// This costs nothing at runtime compared to other filters, since the check is done anyway. Only supported for ELF binaries.
+/// Functions which do not fit the macro can be given `#[link_section = "rftrace_notrace"]` directly.
+///
+/// ```ignore
+/// rftrace::notrace! {
+///     fn spin_wait() {
+///         // ...
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! notrace {
+    ($($item:item)*) => {
+        $(
+            #[link_section = "rftrace_notrace"]
+            $item
+        )*
+    };
+}